@@ -6,10 +6,11 @@ use aya_bpf::{
     bindings::BPF_F_NO_PREALLOC,
     bindings::TC_ACT_OK,
     bindings::TC_ACT_SHOT,
+    helpers::bpf_ktime_get_ns,
     macros::{classifier, map},
     maps::{
         lpm_trie::{Key, LpmTrie},
-        HashMap, PerfEventArray,
+        Array, HashMap, LruHashMap, LruPerCpuHashMap, PerCpuArray, PerfEventArray,
     },
     programs::TcContext,
 };
@@ -19,7 +20,10 @@ use strum::EnumCount;
 mod bindings;
 
 use core::mem;
-use firewall_common::{ConfigOpt, PacketLog, RuleStore};
+use firewall_common::{
+    mark_key, ConfigOpt, Counter, HeaderFilter, IdCountersRaw, PacketLog, RuleStore,
+    HEADER_FILTER_PROTO, MAX_HEADER_FILTERS, MAX_MARK_MASKS,
+};
 use memoffset::offset_of;
 
 use crate::bindings::{iphdr, ipv6hdr, tcphdr, udphdr};
@@ -37,36 +41,162 @@ const MAX_NUMBER_OF_RULES: u32 = 64;
 #[cfg(feature = "rules32")]
 const MAX_NUMBER_OF_RULES: u32 = 32;
 
+// Sizes of the blocklist and allowlist maps, per IP version
+const MAX_LISTED_IPS: u32 = 262144;
+const MAX_LISTED_PREFIXES: u32 = 16384;
+
 type ID = [u8; 16];
 
+// With `pinning` maps are pinned by name under the path user space loads the program with,
+// that way a new process can pick up the state of the running firewall
+#[cfg(not(feature = "pinning"))]
+macro_rules! new_map {
+    ($map:ty, $max_entries:expr, $flags:expr) => {
+        <$map>::with_max_entries($max_entries, $flags)
+    };
+}
+
+#[cfg(feature = "pinning")]
+macro_rules! new_map {
+    ($map:ty, $max_entries:expr, $flags:expr) => {
+        <$map>::pinned($max_entries, $flags)
+    };
+}
+
 // Note: I wish we could use const values as map names
 // but alas! this is not supported yet https://github.com/rust-lang/rust/issues/52393
 // As soon as it is: move map names to const in common crate and use that instead of hardcoding
 
 #[map(name = "EVENTS")]
-static mut EVENTS: PerfEventArray<PacketLog> =
-    PerfEventArray::<PacketLog>::with_max_entries(1024, 0);
+static mut EVENTS: PerfEventArray<PacketLog> = new_map!(PerfEventArray<PacketLog>, 1024, 0);
 
 #[map(name = "SOURCE_ID_IPV4")]
-static mut SOURCE_ID_IPV4: HashMap<[u8; 4], ID> = HashMap::<[u8; 4], ID>::with_max_entries(1024, 0);
+static mut SOURCE_ID_IPV4: HashMap<[u8; 4], ID> = new_map!(HashMap<[u8; 4], ID>, 1024, 0);
 
 #[map(name = "RULE_MAP_IPV4")]
 static mut RULE_MAP_IPV4: LpmTrie<[u8; 21], RuleStore> =
-    LpmTrie::<[u8; 21], RuleStore>::with_max_entries(MAX_NUMBER_OF_RULES, BPF_F_NO_PREALLOC);
+    new_map!(LpmTrie<[u8; 21], RuleStore>, MAX_NUMBER_OF_RULES, BPF_F_NO_PREALLOC);
 
 #[map(name = "SOURCE_ID_IPV6")]
-static mut SOURCE_ID_IPV6: HashMap<[u8; 16], ID> =
-    HashMap::<[u8; 16], ID>::with_max_entries(1024, 0);
+static mut SOURCE_ID_IPV6: HashMap<[u8; 16], ID> = new_map!(HashMap<[u8; 16], ID>, 1024, 0);
 
 #[map(name = "RULE_MAP_IPV6")]
 static mut RULE_MAP_IPV6: LpmTrie<[u8; 33], RuleStore> =
-    LpmTrie::<[u8; 33], RuleStore>::with_max_entries(MAX_NUMBER_OF_RULES, BPF_F_NO_PREALLOC);
+    new_map!(LpmTrie<[u8; 33], RuleStore>, MAX_NUMBER_OF_RULES, BPF_F_NO_PREALLOC);
+
+// Shadow copies of the maps above, `ConfigOpt::Generation` picks which ones are in use
+// so a new ruleset can be swapped in atomically
+
+#[map(name = "SOURCE_ID_IPV4_1")]
+static mut SOURCE_ID_IPV4_1: HashMap<[u8; 4], ID> = new_map!(HashMap<[u8; 4], ID>, 1024, 0);
+
+#[map(name = "RULE_MAP_IPV4_1")]
+static mut RULE_MAP_IPV4_1: LpmTrie<[u8; 21], RuleStore> =
+    new_map!(LpmTrie<[u8; 21], RuleStore>, MAX_NUMBER_OF_RULES, BPF_F_NO_PREALLOC);
+
+#[map(name = "SOURCE_ID_IPV6_1")]
+static mut SOURCE_ID_IPV6_1: HashMap<[u8; 16], ID> = new_map!(HashMap<[u8; 16], ID>, 1024, 0);
+
+#[map(name = "RULE_MAP_IPV6_1")]
+static mut RULE_MAP_IPV6_1: LpmTrie<[u8; 33], RuleStore> =
+    new_map!(LpmTrie<[u8; 33], RuleStore>, MAX_NUMBER_OF_RULES, BPF_F_NO_PREALLOC);
+
+// Header filters used by rules, indexed by the slot in their keys' protocol
+
+#[map(name = "HEADER_FILTERS_IPV4")]
+static mut HEADER_FILTERS_IPV4: Array<HeaderFilter> =
+    new_map!(Array<HeaderFilter>, MAX_HEADER_FILTERS, 0);
+
+#[map(name = "HEADER_FILTERS_IPV6")]
+static mut HEADER_FILTERS_IPV6: Array<HeaderFilter> =
+    new_map!(Array<HeaderFilter>, MAX_HEADER_FILTERS, 0);
+
+#[map(name = "HEADER_FILTERS_IPV4_1")]
+static mut HEADER_FILTERS_IPV4_1: Array<HeaderFilter> =
+    new_map!(Array<HeaderFilter>, MAX_HEADER_FILTERS, 0);
+
+#[map(name = "HEADER_FILTERS_IPV6_1")]
+static mut HEADER_FILTERS_IPV6_1: Array<HeaderFilter> =
+    new_map!(Array<HeaderFilter>, MAX_HEADER_FILTERS, 0);
+
+#[map(name = "SOURCE_ID_MARK")]
+static mut SOURCE_ID_MARK: HashMap<u64, ID> = new_map!(HashMap<u64, ID>, 1024, 0);
+
+// Masks currently in use by `SOURCE_ID_MARK`, 0 means the slot is empty
+#[map(name = "MARK_MASKS")]
+static mut MARK_MASKS: Array<u32> = new_map!(Array<u32>, MAX_MARK_MASKS, 0);
+
+#[map(name = "SOURCE_ID_IFACE")]
+static mut SOURCE_ID_IFACE: HashMap<u32, ID> = new_map!(HashMap<u32, ID>, 1024, 0);
+
+// Ids in audit mode, the value is ignored
+#[map(name = "AUDIT_IDS")]
+static mut AUDIT_IDS: HashMap<ID, u8> = new_map!(HashMap<ID, u8>, 1024, 0);
+
+// Default action per id, takes precedence over `ConfigOpt::DefaultAction` for packets of that id
+#[map(name = "DEFAULT_ACTION_IDS")]
+static mut DEFAULT_ACTION_IDS: HashMap<ID, i32> = new_map!(HashMap<ID, i32>, 1024, 0);
+
+// Length of the link layer header by interface index, interfaces not present are assumed to be Ethernet
+#[map(name = "LINK_HDR_LEN")]
+static mut LINK_HDR_LEN: HashMap<u32, u32> = new_map!(HashMap<u32, u32>, 1024, 0);
+
+// Blocked and allowed networks checked before the rules, the value is the action for packets from or to them.
+// Each list has its own maps so a blocked network wins over an allowed one, however specific the allowed one is.
+// Single addresses are kept in hash maps since a trie of that size would be too slow to update
+
+#[map(name = "BLOCKED_IPS_IPV4")]
+static mut BLOCKED_IPS_IPV4: HashMap<[u8; 4], i32> =
+    new_map!(HashMap<[u8; 4], i32>, MAX_LISTED_IPS, BPF_F_NO_PREALLOC);
+
+#[map(name = "BLOCKED_IPS_IPV6")]
+static mut BLOCKED_IPS_IPV6: HashMap<[u8; 16], i32> =
+    new_map!(HashMap<[u8; 16], i32>, MAX_LISTED_IPS, BPF_F_NO_PREALLOC);
+
+#[map(name = "BLOCKED_PREFIXES_IPV4")]
+static mut BLOCKED_PREFIXES_IPV4: LpmTrie<[u8; 4], i32> =
+    new_map!(LpmTrie<[u8; 4], i32>, MAX_LISTED_PREFIXES, BPF_F_NO_PREALLOC);
+
+#[map(name = "BLOCKED_PREFIXES_IPV6")]
+static mut BLOCKED_PREFIXES_IPV6: LpmTrie<[u8; 16], i32> =
+    new_map!(LpmTrie<[u8; 16], i32>, MAX_LISTED_PREFIXES, BPF_F_NO_PREALLOC);
+
+#[map(name = "ALLOWED_IPS_IPV4")]
+static mut ALLOWED_IPS_IPV4: HashMap<[u8; 4], i32> =
+    new_map!(HashMap<[u8; 4], i32>, MAX_LISTED_IPS, BPF_F_NO_PREALLOC);
+
+#[map(name = "ALLOWED_IPS_IPV6")]
+static mut ALLOWED_IPS_IPV6: HashMap<[u8; 16], i32> =
+    new_map!(HashMap<[u8; 16], i32>, MAX_LISTED_IPS, BPF_F_NO_PREALLOC);
+
+#[map(name = "ALLOWED_PREFIXES_IPV4")]
+static mut ALLOWED_PREFIXES_IPV4: LpmTrie<[u8; 4], i32> =
+    new_map!(LpmTrie<[u8; 4], i32>, MAX_LISTED_PREFIXES, BPF_F_NO_PREALLOC);
+
+#[map(name = "ALLOWED_PREFIXES_IPV6")]
+static mut ALLOWED_PREFIXES_IPV6: LpmTrie<[u8; 16], i32> =
+    new_map!(LpmTrie<[u8; 16], i32>, MAX_LISTED_PREFIXES, BPF_F_NO_PREALLOC);
+
+// Last time in `bpf_ktime` a rule entry matched, keyed by the entry's hit key.
+// Both generations share it and entries removed from the rule maps are eventually evicted
+#[map(name = "RULE_HITS")]
+static mut RULE_HITS: LruHashMap<u64, u64> =
+    new_map!(LruHashMap<u64, u64>, 4 * MAX_NUMBER_OF_RULES, 0);
+
+// Packets seen by outcome and by IP version, indexed by `Counter`
+#[map(name = "COUNTERS")]
+static mut COUNTERS: PerCpuArray<u64> = new_map!(PerCpuArray<u64>, Counter::COUNT as u32, 0);
+
+// Accepted and dropped packets by source id, ids that stop sending are eventually evicted
+#[map(name = "ID_COUNTERS")]
+static mut ID_COUNTERS: LruPerCpuHashMap<ID, IdCountersRaw> =
+    new_map!(LruPerCpuHashMap<ID, IdCountersRaw>, 4096, 0);
 
 // For now this just configs the default action
 // However! We can use this eventually to share more runtime configs
 #[map(name = "CONFIG")]
 static mut CONFIG: HashMap<ConfigOpt, i32> =
-    HashMap::<ConfigOpt, i32>::with_max_entries(ConfigOpt::COUNT as u32, 0);
+    new_map!(HashMap<ConfigOpt, i32>, ConfigOpt::COUNT as u32, 0);
 
 macro_rules! offsets_off {
     ($parent:path, $($field:tt),+) => {
@@ -78,8 +208,31 @@ macro_rules! offsets_off {
 pub fn ebpf_firewall(ctx: TcContext) -> i32 {
     match unsafe { try_ebpf_firewall(ctx) } {
         Ok(ret) => ret,
-        Err(_) => TC_ACT_SHOT,
+        Err(_) => {
+            count(Counter::ParseFailure);
+            TC_ACT_SHOT
+        }
+    }
+}
+
+// Each CPU has its own copy of the counters so there's no need for atomics
+fn count(counter: Counter) {
+    if let Some(value) = unsafe { COUNTERS.get_mut(counter as u32) } {
+        *value += 1;
+    }
+}
+
+unsafe fn count_id(class: Option<ID>, accepted: bool) {
+    let Some(id) = class else {
+        return;
+    };
+    let mut counters = ID_COUNTERS.get(&id).copied().unwrap_or_default();
+    if accepted {
+        counters.accepted += 1;
+    } else {
+        counters.dropped += 1;
     }
+    let _ = ID_COUNTERS.insert(&id, &counters, 0);
 }
 
 fn version(hd: u8) -> u8 {
@@ -88,26 +241,131 @@ fn version(hd: u8) -> u8 {
 
 unsafe fn try_ebpf_firewall(ctx: TcContext) -> Result<i32, i64> {
     // Endianess??
-    let version = version(ctx.load(ETH_HDR_LEN)?);
-    match version {
-        6 => process(ctx, version, &SOURCE_ID_IPV6, &RULE_MAP_IPV6),
-        4 => process(ctx, version, &SOURCE_ID_IPV4, &RULE_MAP_IPV4),
-        _ => Err(-1),
+    let hdr_len = link_hdr_len(&ctx);
+    let version = version(ctx.load(hdr_len)?);
+    match (version, get_generation()) {
+        (6, 0) => process(
+            ctx,
+            hdr_len,
+            version,
+            &SOURCE_ID_IPV6,
+            &RULE_MAP_IPV6,
+            &HEADER_FILTERS_IPV6,
+            &IpList {
+                ips: &BLOCKED_IPS_IPV6,
+                prefixes: &BLOCKED_PREFIXES_IPV6,
+            },
+            &IpList {
+                ips: &ALLOWED_IPS_IPV6,
+                prefixes: &ALLOWED_PREFIXES_IPV6,
+            },
+        ),
+        (6, _) => process(
+            ctx,
+            hdr_len,
+            version,
+            &SOURCE_ID_IPV6_1,
+            &RULE_MAP_IPV6_1,
+            &HEADER_FILTERS_IPV6_1,
+            &IpList {
+                ips: &BLOCKED_IPS_IPV6,
+                prefixes: &BLOCKED_PREFIXES_IPV6,
+            },
+            &IpList {
+                ips: &ALLOWED_IPS_IPV6,
+                prefixes: &ALLOWED_PREFIXES_IPV6,
+            },
+        ),
+        (4, 0) => process(
+            ctx,
+            hdr_len,
+            version,
+            &SOURCE_ID_IPV4,
+            &RULE_MAP_IPV4,
+            &HEADER_FILTERS_IPV4,
+            &IpList {
+                ips: &BLOCKED_IPS_IPV4,
+                prefixes: &BLOCKED_PREFIXES_IPV4,
+            },
+            &IpList {
+                ips: &ALLOWED_IPS_IPV4,
+                prefixes: &ALLOWED_PREFIXES_IPV4,
+            },
+        ),
+        (4, _) => process(
+            ctx,
+            hdr_len,
+            version,
+            &SOURCE_ID_IPV4_1,
+            &RULE_MAP_IPV4_1,
+            &HEADER_FILTERS_IPV4_1,
+            &IpList {
+                ips: &BLOCKED_IPS_IPV4,
+                prefixes: &BLOCKED_PREFIXES_IPV4,
+            },
+            &IpList {
+                ips: &ALLOWED_IPS_IPV4,
+                prefixes: &ALLOWED_PREFIXES_IPV4,
+            },
+        ),
+        // Non-IP packets were always dropped, through the error path before they were counted,
+        // they're counted apart from the packets whose headers couldn't be read
+        _ => {
+            count(Counter::NonIp);
+            Ok(TC_ACT_SHOT)
+        }
     }
 }
 
+unsafe fn link_hdr_len(ctx: &TcContext) -> usize {
+    LINK_HDR_LEN
+        .get(&(*ctx.skb.skb).ifindex)
+        .map_or(ETH_HDR_LEN, |&len| len as usize)
+}
+
 unsafe fn process<const N: usize, const M: usize>(
     ctx: TcContext,
+    hdr_len: usize,
     version: u8,
     source_map: &HashMap<[u8; N], ID>,
     rule_map: &LpmTrie<[u8; M], RuleStore>,
+    header_filters: &Array<HeaderFilter>,
+    blocklist: &IpList<N>,
+    allowlist: &IpList<N>,
 ) -> Result<i32, i64> {
-    let (source, dest, proto) = load_ntw_headers(&ctx, version)?;
-    let (dest_port, src_port) = get_port(&ctx, version, proto)?;
-    let class = source_class(source_map, source);
-    let action = get_action(class, dest, rule_map, dest_port, proto);
+    count(if version == 6 {
+        Counter::Ipv6
+    } else {
+        Counter::Ipv4
+    });
+    let (source, dest, proto) = load_ntw_headers(&ctx, hdr_len, version)?;
+    let (dest_port, src_port) = get_port(&ctx, hdr_len, version, proto)?;
+    let fields = HeaderFields {
+        proto,
+        tcp_flags: get_tcp_flags(&ctx, hdr_len, version, proto)?,
+        ttl: get_ttl(&ctx, hdr_len, version)?,
+        dscp: get_dscp(&ctx, hdr_len, version)?,
+    };
+    let class = source_class(&ctx, source_map, source);
+    let (action, outcome) = match listed_action(blocklist, allowlist, source, dest) {
+        Some(TC_ACT_SHOT) => (TC_ACT_SHOT, Counter::DroppedByList),
+        Some(action) => (action, Counter::Accepted),
+        None => get_action(
+            class,
+            dest,
+            rule_map,
+            header_filters,
+            dest_port,
+            proto,
+            &fields,
+        ),
+    };
+    let audit = action != TC_ACT_OK && is_audit(class);
     let source = as_log_array(source);
     let dest = as_log_array(dest);
+    let packet_len = ctx.len();
+    // Asking for more bytes than the packet has makes the whole output fail
+    let captured_len = get_capture_len().min(packet_len);
     let log_entry = PacketLog {
         source,
         dest,
@@ -117,18 +375,34 @@ unsafe fn process<const N: usize, const M: usize>(
         proto,
         version,
         class: class.unwrap_or([0; 16]),
-        pad: [0; 2],
+        audit: audit as u8,
+        tcp_flags: fields.tcp_flags.unwrap_or(0),
+        ttl: fields.ttl,
+        dscp: fields.dscp,
+        captured_len: captured_len as u16,
+        packet_len,
+        ifindex: (*ctx.skb.skb).ifindex,
     };
-    EVENTS.output(&ctx, &log_entry, 0);
-    Ok(action)
+    // The flags tell `bpf_perf_event_output` how many bytes of the packet to append to the entry
+    EVENTS.output(&ctx, &log_entry, captured_len);
+    let accepted = audit || action == TC_ACT_OK;
+    count_id(class, accepted);
+    if accepted {
+        count(Counter::Accepted);
+        Ok(get_accept_verdict())
+    } else {
+        count(outcome);
+        Ok(action)
+    }
 }
 
-fn load_sk_buff<T>(ctx: &TcContext, offset: usize) -> Result<T, i64> {
-    ctx.load::<T>(ETH_HDR_LEN + offset)
+fn load_sk_buff<T>(ctx: &TcContext, hdr_len: usize, offset: usize) -> Result<T, i64> {
+    ctx.load::<T>(hdr_len + offset)
 }
 
 fn load_ntw_headers<const N: usize>(
     ctx: &TcContext,
+    hdr_len: usize,
     version: u8,
 ) -> Result<([u8; N], [u8; N], u8), i64> {
     let (source_off, dest_off, proto_off) = match version {
@@ -136,33 +410,71 @@ fn load_ntw_headers<const N: usize>(
         4 => offsets_off!(iphdr, saddr, daddr, protocol),
         _ => unreachable!("Should only call with valid packet"),
     };
-    let source = load_sk_buff(ctx, source_off)?;
-    let dest = load_sk_buff(ctx, dest_off)?;
-    let next_header = load_sk_buff(ctx, proto_off)?;
+    let source = load_sk_buff(ctx, hdr_len, source_off)?;
+    let dest = load_sk_buff(ctx, hdr_len, dest_off)?;
+    let next_header = load_sk_buff(ctx, hdr_len, proto_off)?;
     Ok((source, dest, next_header))
 }
 
-fn get_port(ctx: &TcContext, version: u8, proto: u8) -> Result<(u16, u16), i64> {
+fn get_port(ctx: &TcContext, hdr_len: usize, version: u8, proto: u8) -> Result<(u16, u16), i64> {
     let ip_len = match version {
         6 => IPV6_HDR_LEN,
         4 => IP_HDR_LEN,
         _ => unreachable!("Should only call with valid packet"),
     };
     let dest_port = match proto {
-        TCP => u16::from_be(ctx.load(ETH_HDR_LEN + ip_len + offset_of!(tcphdr, dest))?),
-        UDP => u16::from_be(ctx.load(ETH_HDR_LEN + ip_len + offset_of!(udphdr, dest))?),
+        TCP => u16::from_be(ctx.load(hdr_len + ip_len + offset_of!(tcphdr, dest))?),
+        UDP => u16::from_be(ctx.load(hdr_len + ip_len + offset_of!(udphdr, dest))?),
         _ => 0,
     };
 
     let src_port = match proto {
-        TCP => u16::from_be(ctx.load(ETH_HDR_LEN + ip_len + offset_of!(tcphdr, source))?),
-        UDP => u16::from_be(ctx.load(ETH_HDR_LEN + ip_len + offset_of!(udphdr, source))?),
+        TCP => u16::from_be(ctx.load(hdr_len + ip_len + offset_of!(tcphdr, source))?),
+        UDP => u16::from_be(ctx.load(hdr_len + ip_len + offset_of!(udphdr, source))?),
         _ => 0,
     };
 
     Ok((dest_port, src_port))
 }
 
+fn get_tcp_flags(
+    ctx: &TcContext,
+    hdr_len: usize,
+    version: u8,
+    proto: u8,
+) -> Result<Option<u8>, i64> {
+    if proto != TCP {
+        return Ok(None);
+    }
+
+    let ip_len = match version {
+        6 => IPV6_HDR_LEN,
+        4 => IP_HDR_LEN,
+        _ => unreachable!("Should only call with valid packet"),
+    };
+    Ok(Some(ctx.load(hdr_len + ip_len + TCP_FLAGS_OFFSET)?))
+}
+
+fn get_ttl(ctx: &TcContext, hdr_len: usize, version: u8) -> Result<u8, i64> {
+    let ttl_off = match version {
+        6 => offset_of!(ipv6hdr, hop_limit),
+        4 => offset_of!(iphdr, ttl),
+        _ => unreachable!("Should only call with valid packet"),
+    };
+    load_sk_buff(ctx, hdr_len, ttl_off)
+}
+
+// DSCP are the upper 6 bits of the IPv4 TOS or the IPv6 traffic class
+fn get_dscp(ctx: &TcContext, hdr_len: usize, version: u8) -> Result<u8, i64> {
+    let traffic_class = match version {
+        // The traffic class starts 4 bits into the header, right after the version
+        6 => (u16::from_be(load_sk_buff(ctx, hdr_len, 0)?) >> 4) as u8,
+        4 => load_sk_buff(ctx, hdr_len, offset_of!(iphdr, tos))?,
+        _ => unreachable!("Should only call with valid packet"),
+    };
+    Ok(traffic_class >> 2)
+}
+
 fn as_log_array<const N: usize>(from: [u8; N]) -> [u8; 16] {
     let mut to = [0u8; 16];
     let (to_l, _) = to.split_at_mut(N);
@@ -170,37 +482,149 @@ fn as_log_array<const N: usize>(from: [u8; N]) -> [u8; 16] {
     to
 }
 
+// The source address takes precedence, then the skb mark and last the ingress interface
 unsafe fn source_class<const N: usize>(
+    ctx: &TcContext,
     source_map: &HashMap<[u8; N], ID>,
     address: [u8; N],
 ) -> Option<[u8; 16]> {
     // Race condition if ip changes group?
-    source_map.get(&address).copied()
+    source_map
+        .get(&address)
+        .copied()
+        .or_else(|| mark_class(ctx))
+        .or_else(|| iface_class(ctx))
+}
+
+unsafe fn mark_class(ctx: &TcContext) -> Option<[u8; 16]> {
+    let mark = (*ctx.skb.skb).mark;
+    if mark == 0 {
+        return None;
+    }
+
+    for i in 0..MAX_MARK_MASKS {
+        let mask = match MARK_MASKS.get(i) {
+            Some(&mask) if mask != 0 => mask,
+            _ => continue,
+        };
+        if let Some(id) = SOURCE_ID_MARK.get(&mark_key(mark, mask)) {
+            return Some(*id);
+        }
+    }
+    None
+}
+
+unsafe fn iface_class(ctx: &TcContext) -> Option<[u8; 16]> {
+    SOURCE_ID_IFACE
+        .get(&(*ctx.skb.skb).ingress_ifindex)
+        .copied()
+}
+
+// Maps of the blocklist or the allowlist for one IP version
+struct IpList<'a, const N: usize> {
+    ips: &'a HashMap<[u8; N], i32>,
+    prefixes: &'a LpmTrie<[u8; N], i32>,
+}
+
+impl<const N: usize> IpList<'_, N> {
+    unsafe fn lookup(&self, address: [u8; N]) -> Option<i32> {
+        self.ips
+            .get(&address)
+            .or_else(|| self.prefixes.get(&Key::new((N * 8) as u32, address)))
+            .copied()
+    }
+}
+
+// Action for packets from or to a listed network, blocking takes precedence over allowing
+unsafe fn listed_action<const N: usize>(
+    blocklist: &IpList<N>,
+    allowlist: &IpList<N>,
+    source: [u8; N],
+    dest: [u8; N],
+) -> Option<i32> {
+    if blocklist.lookup(source).is_some() || blocklist.lookup(dest).is_some() {
+        return Some(TC_ACT_SHOT);
+    }
+    allowlist.lookup(source).or_else(|| allowlist.lookup(dest))
+}
+
+// Header fields checked by `HeaderFilter`s
+struct HeaderFields {
+    // Protocol of the packet, unlike the one used for rule keys it isn't rewritten for port-less packets
+    proto: u8,
+    tcp_flags: Option<u8>,
+    ttl: u8,
+    dscp: u8,
 }
 
 fn get_action<const N: usize, const M: usize>(
     group: Option<[u8; 16]>,
     address: [u8; N],
     rule_map: &LpmTrie<[u8; M], RuleStore>,
+    header_filters: &Array<HeaderFilter>,
     port: u16,
     proto: u8,
-) -> i32 {
+    fields: &HeaderFields,
+) -> (i32, Counter) {
     let proto = if port == 0 { TCP } else { proto };
     let default_action = get_default_action();
+    // Rules for an id invert the default of that id, rules for all ids invert the global default
+    let id_default_action = get_id_default_action(group).unwrap_or(default_action);
+
+    let matches = |group| {
+        is_match(
+            group,
+            proto,
+            address,
+            rule_map,
+            header_filters,
+            port,
+            fields,
+        )
+    };
+
+    // The counter is only used if the packet ends up dropped
+    if matches(group) {
+        return (invert_action(id_default_action), Counter::DroppedByRule);
+    }
+
+    if group.is_some() && matches(None) {
+        return (invert_action(default_action), Counter::DroppedByRule);
+    }
+
+    (id_default_action, Counter::DroppedByDefault)
+}
 
+// Rules with a header filter are only looked up if the packet satisfies the filter
+fn is_match<const N: usize, const M: usize>(
+    group: Option<[u8; 16]>,
+    proto: u8,
+    address: [u8; N],
+    rule_map: &LpmTrie<[u8; M], RuleStore>,
+    header_filters: &Array<HeaderFilter>,
+    port: u16,
+    fields: &HeaderFields,
+) -> bool {
     let rule_store = rule_map.get(&Key::new((M * 8) as u32, get_key(group, proto, address)));
     if is_stored(&rule_store, port) {
-        return invert_action(default_action);
+        record_hit(&rule_store);
+        return true;
     }
 
-    if group.is_some() {
-        let rule_store = rule_map.get(&Key::new((M * 8) as u32, get_key(None, proto, address)));
+    for i in 0..MAX_HEADER_FILTERS {
+        match header_filters.get(i) {
+            Some(filter)
+                if filter.matches(fields.proto, fields.tcp_flags, fields.ttl, fields.dscp) => {}
+            _ => continue,
+        }
+        let proto = HEADER_FILTER_PROTO + i as u8;
+        let rule_store = rule_map.get(&Key::new((M * 8) as u32, get_key(group, proto, address)));
         if is_stored(&rule_store, port) {
-            return invert_action(default_action);
+            record_hit(&rule_store);
+            return true;
         }
     }
-
-    default_action
+    false
 }
 
 fn invert_action(action: i32) -> i32 {
@@ -215,10 +639,45 @@ fn get_default_action() -> i32 {
     *unsafe { CONFIG.get(&ConfigOpt::DefaultAction) }.unwrap_or(&DEFAULT_ACTION)
 }
 
+fn get_capture_len() -> u32 {
+    let len = *unsafe { CONFIG.get(&ConfigOpt::CaptureLength) }.unwrap_or(&0);
+    (len.max(0) as u32).min(MAX_CAPTURE_LEN as u32)
+}
+
+fn get_accept_verdict() -> i32 {
+    *unsafe { CONFIG.get(&ConfigOpt::AcceptVerdict) }.unwrap_or(&TC_ACT_OK)
+}
+
+fn get_id_default_action(group: Option<[u8; 16]>) -> Option<i32> {
+    group.and_then(|group| unsafe { DEFAULT_ACTION_IDS.get(&group) }.copied())
+}
+
+fn get_generation() -> i32 {
+    *unsafe { CONFIG.get(&ConfigOpt::Generation) }.unwrap_or(&0)
+}
+
+// Either the whole firewall or the id of the packet are in audit mode
+unsafe fn is_audit(class: Option<[u8; 16]>) -> bool {
+    if *CONFIG.get(&ConfigOpt::Audit).unwrap_or(&0) != 0 {
+        return true;
+    }
+    class.map_or(false, |class| AUDIT_IDS.get(&class).is_some())
+}
+
 fn is_stored(rule_store: &Option<&RuleStore>, port: u16) -> bool {
     rule_store.map(|store| store.lookup(port)).unwrap_or(false)
 }
 
+// Only the matching entry is known, not which of its ranges matched
+fn record_hit(rule_store: &Option<&RuleStore>) {
+    if let Some(store) = rule_store {
+        if store.hit_key() != 0 {
+            let now = unsafe { bpf_ktime_get_ns() };
+            let _ = unsafe { RULE_HITS.insert(&store.hit_key(), &now, 0) };
+        }
+    }
+}
+
 fn get_key<const N: usize, const M: usize>(
     group: Option<[u8; 16]>,
     proto: u8,
@@ -245,10 +704,8 @@ const IP_HDR_LEN: usize = mem::size_of::<iphdr>();
 const IPV6_HDR_LEN: usize = mem::size_of::<ipv6hdr>();
 const TCP: u8 = 0x06;
 const UDP: u8 = 0x11;
+// Flags are the 14th byte of the TCP header
+const TCP_FLAGS_OFFSET: usize = 13;
 const DEFAULT_ACTION: i32 = TC_ACT_SHOT;
 
-#[cfg(not(feature = "wireguard"))]
 const ETH_HDR_LEN: usize = mem::size_of::<bindings::ethhdr>();
-
-#[cfg(feature = "wireguard")]
-const ETH_HDR_LEN: usize = 0;
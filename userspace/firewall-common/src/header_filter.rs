@@ -0,0 +1,118 @@
+use crate::GENERIC_PROTO;
+
+/// Maximum number of distinct header filters that can be used by rules at the same time.
+pub const MAX_HEADER_FILTERS: u32 = 8;
+
+/// Protocol used in rule keys for rules with a header filter.
+///
+/// The index of the filter in the filters map is added to it,
+/// so values from `HEADER_FILTER_PROTO` up to `HEADER_FILTER_PROTO + MAX_HEADER_FILTERS` are taken.
+/// These are unassigned IP protocol numbers.
+pub const HEADER_FILTER_PROTO: u8 = 0xF0;
+
+/// Predicate on header fields other than addresses and ports, checked before looking up the rules using it.
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "user", derive(Debug, PartialEq, Eq, Hash))]
+pub struct HeaderFilter {
+    /// Protocol of the rules using this filter, `GENERIC_PROTO` for any. 0 means the slot is empty.
+    pub proto: u8,
+    pub tcp_flags: u8,
+    /// Flags not in the mask are ignored, 0 matches any packet even if it isn't TCP.
+    pub tcp_flags_mask: u8,
+    /// Inclusive range for the TTL or hop limit.
+    pub ttl_min: u8,
+    pub ttl_max: u8,
+    pub dscp: u8,
+    /// Bits of the DSCP not in the mask are ignored.
+    pub dscp_mask: u8,
+    pub pad: u8,
+}
+
+impl Default for HeaderFilter {
+    fn default() -> Self {
+        Self {
+            proto: GENERIC_PROTO,
+            tcp_flags: 0,
+            tcp_flags_mask: 0,
+            ttl_min: 0,
+            ttl_max: u8::MAX,
+            dscp: 0,
+            dscp_mask: 0,
+            pad: 0,
+        }
+    }
+}
+
+impl HeaderFilter {
+    /// Whether a packet with the given fields satisfies the filter, `tcp_flags` is `None` for non-TCP packets.
+    #[inline]
+    pub fn matches(&self, proto: u8, tcp_flags: Option<u8>, ttl: u8, dscp: u8) -> bool {
+        if self.proto == 0 || (self.proto != GENERIC_PROTO && self.proto != proto) {
+            return false;
+        }
+
+        if self.tcp_flags_mask != 0 {
+            match tcp_flags {
+                Some(flags) if flags & self.tcp_flags_mask == self.tcp_flags => {}
+                _ => return false,
+            }
+        }
+
+        self.ttl_min <= ttl && ttl <= self.ttl_max && dscp & self.dscp_mask == self.dscp
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for HeaderFilter {}
+
+#[cfg(test)]
+mod test {
+    use super::HeaderFilter;
+
+    const TCP: u8 = 0x06;
+    const UDP: u8 = 0x11;
+
+    #[test]
+    fn default_matches_everything() {
+        let filter = HeaderFilter::default();
+        assert!(filter.matches(TCP, Some(0x12), 64, 0));
+        assert!(filter.matches(UDP, None, 1, 46));
+    }
+
+    #[test]
+    fn empty_slot_matches_nothing() {
+        let filter = HeaderFilter {
+            proto: 0,
+            ..Default::default()
+        };
+        assert!(!filter.matches(TCP, Some(0), 64, 0));
+    }
+
+    #[test]
+    fn tcp_flags_only_match_tcp() {
+        let filter = HeaderFilter {
+            tcp_flags: 0x02,
+            tcp_flags_mask: 0x12,
+            ..Default::default()
+        };
+        assert!(filter.matches(TCP, Some(0x02), 64, 0));
+        assert!(!filter.matches(TCP, Some(0x12), 64, 0));
+        assert!(!filter.matches(UDP, None, 64, 0));
+    }
+
+    #[test]
+    fn ttl_and_dscp_match() {
+        let filter = HeaderFilter {
+            proto: UDP,
+            ttl_max: 4,
+            dscp: 46,
+            dscp_mask: 0x3F,
+            ..Default::default()
+        };
+        assert!(filter.matches(UDP, None, 4, 46));
+        assert!(!filter.matches(UDP, None, 5, 46));
+        assert!(!filter.matches(UDP, None, 4, 0));
+        assert!(!filter.matches(TCP, Some(0), 4, 46));
+    }
+}
@@ -1,7 +1,10 @@
 #![cfg_attr(not(feature = "user"), no_std)]
 #![cfg_attr(not(feature = "user"), feature(int_log))]
+mod header_filter;
 mod rule_store;
 
+pub use header_filter::{HeaderFilter, HEADER_FILTER_PROTO, MAX_HEADER_FILTERS};
+
 pub use rule_store::{Action, RuleStore, GENERIC_PROTO};
 
 #[cfg(feature = "user")]
@@ -20,13 +23,81 @@ pub struct PacketLog {
     pub proto: u8,
     pub version: u8,
     pub class: [u8; 16],
-    pub pad: [u8; 2],
+    /// Non-zero if the packet was accepted only because of audit mode.
+    pub audit: u8,
+    /// Flags of the TCP header, 0 for other protocols.
+    pub tcp_flags: u8,
+    /// TTL for IPv4 or hop limit for IPv6.
+    pub ttl: u8,
+    pub dscp: u8,
+    /// Bytes of the packet copied right after this in the event, see [ConfigOpt::CaptureLength].
+    pub captured_len: u16,
+    /// Length of the whole packet, including its link layer header.
+    pub packet_len: u32,
+    /// Interface the packet went through.
+    pub ifindex: u32,
+}
+
+/// Most bytes of a packet that can be copied along with its [PacketLog].
+pub const MAX_CAPTURE_LEN: u16 = 2048;
+
+/// Maximum number of distinct masks that can be used at the same time to classify packets by mark.
+pub const MAX_MARK_MASKS: u32 = 8;
+
+/// Key used in the mark classification map.
+///
+/// The upper 32 bits hold the mask and the lower 32 bits the masked mark,
+/// that way the same mark can be associated with different ids under different masks.
+#[inline]
+pub fn mark_key(mark: u32, mask: u32) -> u64 {
+    ((mask as u64) << 32) | (mark & mask) as u64
 }
 
 #[repr(u8)]
 #[derive(Clone, Copy, EnumCount)]
 pub enum ConfigOpt {
     DefaultAction = 0,
+    /// Which set of rule and source id maps is in use, either 0 or 1.
+    Generation = 1,
+    /// Non-zero to accept every packet while still logging the action that would've been taken.
+    Audit = 2,
+    /// Verdict returned for accepted packets, so other tc filters can still see them.
+    AcceptVerdict = 3,
+    /// Interface, priority and handle of the tc filter of a pinned firewall, only used by userspace to detach it.
+    FilterIfindex = 4,
+    FilterPriority = 5,
+    FilterHandle = 6,
+    /// Bytes copied from the start of each logged packet, 0 to copy none.
+    CaptureLength = 7,
+}
+
+/// Index in the per-CPU counters map, each packet increments its outcome and its IP version.
+#[repr(u32)]
+#[derive(Clone, Copy, EnumCount)]
+#[cfg_attr(feature = "user", derive(Debug, PartialEq, Eq))]
+pub enum Counter {
+    Accepted = 0,
+    /// Dropped because a rule matched.
+    DroppedByRule = 1,
+    /// Dropped because no rule matched and the default action is to drop.
+    DroppedByDefault = 2,
+    /// Dropped because the source or destination is blocklisted.
+    DroppedByList = 3,
+    /// Dropped because the headers couldn't be read.
+    ParseFailure = 4,
+    /// Neither IPv4 nor IPv6, these are dropped too.
+    NonIp = 5,
+    Ipv4 = 6,
+    Ipv6 = 7,
+}
+
+/// Value of `ID_COUNTERS` for one CPU, userspace sums them into `firewall::IdCounters`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+#[cfg_attr(feature = "user", derive(Debug))]
+pub struct IdCountersRaw {
+    pub accepted: u64,
+    pub dropped: u64,
 }
 
 // Safety ConfigOpt is repr(u8)
@@ -35,3 +106,6 @@ unsafe impl aya::Pod for ConfigOpt {}
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for PacketLog {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for IdCountersRaw {}
@@ -35,12 +35,20 @@ const TC_ACT_SHOT: i32 = 2;
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[cfg_attr(
     feature = "user",
-    derive(Debug, Hash, num_derive::FromPrimitive, serde::Serialize)
+    derive(
+        Debug,
+        Hash,
+        num_derive::FromPrimitive,
+        serde::Serialize,
+        serde::Deserialize
+    )
 )]
 pub enum Action {
     /// Accept packets.
+    #[cfg_attr(feature = "user", serde(alias = "accept"))]
     Accept = TC_ACT_OK,
     /// Reject packets.
+    #[cfg_attr(feature = "user", serde(alias = "reject"))]
     Reject = TC_ACT_SHOT,
 }
 
@@ -81,6 +89,17 @@ pub struct RuleStore {
     /// Keep this to < usize::MAX pretty please
     /// But we do need the padding
     rules_len: u32,
+    pad: u32,
+    /// Key for the last time this entry matched in the hits map, 0 if hits aren't recorded.
+    /// It's derived from the entry's trie key so it's kept when the entry is rewritten.
+    hit_key: u64,
+}
+
+impl RuleStore {
+    #[inline]
+    pub fn hit_key(&self) -> u64 {
+        self.hit_key
+    }
 }
 
 #[cfg(feature = "user")]
@@ -66,3 +66,10 @@ fn test_well_formed_rules(port_start: u16, port_end: u16) {
     assert_eq!(start(rule), port_start);
     assert_eq!(end(rule), port_end);
 }
+
+#[test]
+fn ranges_round_trip() {
+    let ports = [(3, 6), (10, 50), (6000, 6000)];
+    let rule_store = RuleStore::new(&ports).unwrap();
+    assert_eq!(rule_store.ranges().collect::<Vec<_>>(), ports);
+}
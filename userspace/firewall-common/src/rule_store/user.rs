@@ -3,7 +3,7 @@
 use crate::rule_store::{RuleStore, MAX_RANGES};
 use thiserror::Error;
 
-use super::new_rule;
+use super::{end, new_rule, start};
 
 impl RuleStore {
     pub fn new(ports: &[(u16, u16)]) -> Result<RuleStore, RuleStoreError> {
@@ -16,6 +16,8 @@ impl RuleStore {
                 Ok(RuleStore {
                     rules,
                     rules_len: (rule_len as u32),
+                    pad: 0,
+                    hit_key: 0,
                 })
             } else {
                 Err(RuleStoreError::MalFormed)
@@ -25,6 +27,19 @@ impl RuleStore {
         }
     }
 
+    /// Sets the key under which the eBPF program records when this entry last matched.
+    pub fn with_hit_key(mut self, hit_key: u64) -> Self {
+        self.hit_key = hit_key;
+        self
+    }
+
+    /// Returns the stored port ranges, sorted and non-overlapping.
+    pub fn ranges(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.rules[..self.rules_len as usize]
+            .iter()
+            .map(|&rule| (start(rule), end(rule)))
+    }
+
     fn wellformed(ports: &[(u16, u16)]) -> bool {
         // is_sorted is not stable yet
         let mut last_start = None;
@@ -0,0 +1,84 @@
+use tokio::net::unix::UCred;
+
+use crate::protocol::Call;
+
+/// Users and groups allowed to call the daemon, checked against the credentials of the socket peer.
+///
+/// Root can always call every method. Only the primary group of the peer is known,
+/// supplementary groups aren't taken into account.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Authorization {
+    /// Can call every method.
+    pub admin: Principals,
+    /// Can only call methods that leave the firewall as it is, see [Call::is_read_only].
+    pub read_only: Principals,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Principals {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+}
+
+impl Principals {
+    fn contains(&self, uid: u32, gid: u32) -> bool {
+        self.uids.contains(&uid) || self.gids.contains(&gid)
+    }
+}
+
+impl Authorization {
+    /// Whether `peer` can call any method at all, connections of other peers are dropped.
+    pub fn allows_peer(&self, peer: &UCred) -> bool {
+        self.allows_peer_ids(peer.uid(), peer.gid())
+    }
+
+    pub fn allows(&self, peer: &UCred, call: &Call) -> bool {
+        self.allows_ids(peer.uid(), peer.gid(), call)
+    }
+
+    fn allows_peer_ids(&self, uid: u32, gid: u32) -> bool {
+        uid == 0 || self.admin.contains(uid, gid) || self.read_only.contains(uid, gid)
+    }
+
+    fn allows_ids(&self, uid: u32, gid: u32, call: &Call) -> bool {
+        uid == 0
+            || self.admin.contains(uid, gid)
+            || (call.is_read_only() && self.read_only.contains(uid, gid))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use firewall::Action;
+
+    use super::{Authorization, Principals};
+    use crate::protocol::Call;
+
+    #[test]
+    fn read_only_principals_cant_change_the_firewall() {
+        let auth = Authorization {
+            admin: Principals {
+                uids: vec![1000],
+                gids: vec![],
+            },
+            read_only: Principals {
+                uids: vec![],
+                gids: vec![100],
+            },
+        };
+        let write = Call::SetDefaultAction {
+            action: Action::Accept,
+        };
+
+        assert!(auth.allows_ids(0, 0, &write));
+        assert!(auth.allows_ids(1000, 1000, &write));
+        assert!(auth.allows_ids(1001, 100, &Call::Stats));
+        assert!(!auth.allows_ids(1001, 100, &write));
+        assert!(!auth.allows_ids(1001, 1001, &Call::Stats));
+
+        assert!(auth.allows_peer_ids(0, 0));
+        assert!(auth.allows_peer_ids(1000, 1000));
+        assert!(auth.allows_peer_ids(1001, 100));
+        assert!(!auth.allows_peer_ids(1001, 1001));
+    }
+}
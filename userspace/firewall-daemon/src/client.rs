@@ -0,0 +1,153 @@
+use std::{io, net::IpAddr, path::Path};
+
+use firewall::{Action, Counters, Rule};
+use ipnet::IpNet;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixStream,
+    },
+};
+
+use crate::protocol::{Call, IdEntry, Notification, Request, Response, RpcError, LOG_METHOD};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The daemon sent something that isn't a valid message.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    /// The daemon answered with an error, e.g. [UNAUTHORIZED](crate::protocol::UNAUTHORIZED).
+    #[error(transparent)]
+    Rpc(#[from] RpcError),
+    #[error("Connection closed by the daemon")]
+    Closed,
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+/// Connection to the daemon's control socket.
+///
+/// # Example
+/// ```no_run
+/// # async fn example() -> firewall_daemon::client::Result<()> {
+/// use firewall_daemon::{Client, DEFAULT_SOCKET_PATH};
+///
+/// let mut client = Client::connect(DEFAULT_SOCKET_PATH).await?;
+/// client.add_rule(&"tcp 10.0.0.0/8 ports 22".parse().unwrap()).await?;
+/// println!("{:?}", client.stats().await?);
+///
+/// let mut logs = client.subscribe_logs().await?;
+/// while let Some(packet) = logs.next().await? {
+///     println!("{packet}");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Client {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    next_id: u64,
+}
+
+impl Client {
+    pub async fn connect(path: impl AsRef<Path>) -> Result<Self> {
+        let (read, writer) = UnixStream::connect(path).await?.into_split();
+        Ok(Self {
+            lines: BufReader::new(read).lines(),
+            writer,
+            next_id: 1,
+        })
+    }
+
+    pub async fn add_rule(&mut self, rule: &Rule) -> Result<()> {
+        self.call(Call::AddRule { rule: rule.clone() }).await
+    }
+
+    pub async fn remove_rule(&mut self, rule: &Rule) -> Result<()> {
+        self.call(Call::RemoveRule { rule: rule.clone() }).await
+    }
+
+    pub async fn rules(&mut self) -> Result<Vec<Rule>> {
+        self.call(Call::ListRules).await
+    }
+
+    pub async fn add_id(&mut self, ip: IpNet, id: u128) -> Result<()> {
+        self.call(Call::AddId { ip, id }).await
+    }
+
+    pub async fn remove_id(&mut self, ip: IpNet) -> Result<()> {
+        self.call(Call::RemoveId { ip }).await
+    }
+
+    /// Ids and their IPs.
+    pub async fn ids(&mut self) -> Result<Vec<(u128, Vec<IpAddr>)>> {
+        let ids: Vec<IdEntry> = self.call(Call::ListIds).await?;
+        Ok(ids.into_iter().map(|entry| (entry.id, entry.ips)).collect())
+    }
+
+    pub async fn set_default_action(&mut self, action: Action) -> Result<()> {
+        self.call(Call::SetDefaultAction { action }).await
+    }
+
+    pub async fn stats(&mut self) -> Result<Counters> {
+        self.call(Call::Stats).await
+    }
+
+    /// Turns the connection into a stream of logged packets.
+    pub async fn subscribe_logs(mut self) -> Result<LogStream> {
+        self.call::<Value>(Call::SubscribeLogs).await?;
+        Ok(LogStream { lines: self.lines })
+    }
+
+    async fn call<T: DeserializeOwned>(&mut self, call: Call) -> Result<T> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut line = serde_json::to_vec(&Request::new(id, &call))?;
+        line.push(b'\n');
+        self.writer.write_all(&line).await?;
+
+        loop {
+            let line = self.lines.next_line().await?.ok_or(ClientError::Closed)?;
+            let message: Value = serde_json::from_str(&line)?;
+            // Packets logged before the subscription was answered
+            if message.get("method").is_some() {
+                continue;
+            }
+            let response: Response = serde_json::from_value(message)?;
+            if response.id != Some(id) {
+                continue;
+            }
+            if let Some(error) = response.error {
+                return Err(error.into());
+            }
+            return Ok(serde_json::from_value(response.result.unwrap_or_default())?);
+        }
+    }
+}
+
+/// Packets logged by the firewall, as the JSON objects it logs, see [Client::subscribe_logs].
+pub struct LogStream {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+}
+
+impl LogStream {
+    /// Waits for the next packet, `None` once the daemon closes the connection.
+    pub async fn next(&mut self) -> Result<Option<Value>> {
+        while let Some(line) = self.lines.next_line().await? {
+            let notification: Notification = match serde_json::from_str(&line) {
+                Ok(notification) => notification,
+                // Responses don't have a method
+                Err(_) => continue,
+            };
+            if notification.method == LOG_METHOD {
+                return Ok(Some(notification.params));
+            }
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,17 @@
+//! Daemon that owns a [Firewall](firewall::Firewall) and exposes it over a Unix socket with JSON-RPC 2.0,
+//! along with a [Client] to talk to it.
+//!
+//! Only one process can own the eBPF objects of a firewall, services that need to change it
+//! go through the daemon instead. Requests are authorized with the credentials of the socket peer,
+//! see [Authorization], and the messages are described in [protocol].
+mod auth;
+pub mod client;
+pub mod protocol;
+mod server;
+
+pub use auth::{Authorization, Principals};
+pub use client::{Client, ClientError, LogStream};
+pub use server::Server;
+
+/// Socket the daemon listens on unless told otherwise.
+pub const DEFAULT_SOCKET_PATH: &str = "/run/firewall-daemon.sock";
@@ -0,0 +1,74 @@
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use clap::Parser;
+use firewall::Firewall;
+use firewall_daemon::{Authorization, Principals, Server, DEFAULT_SOCKET_PATH};
+use tokio::{net::UnixListener, signal};
+
+#[derive(Debug, Parser)]
+#[command(about = "Owns an eBPF firewall and serves JSON-RPC on a Unix socket")]
+pub struct Opt {
+    #[arg(short, long, default_value = "eth0")]
+    iface: String,
+    #[arg(long, default_value = DEFAULT_SOCKET_PATH)]
+    socket: PathBuf,
+    /// User allowed to call every method, root always is.
+    #[arg(long)]
+    admin_uid: Vec<u32>,
+    /// Group allowed to call every method.
+    #[arg(long)]
+    admin_gid: Vec<u32>,
+    /// User only allowed to read rules, ids, stats and logs.
+    #[arg(long)]
+    read_only_uid: Vec<u32>,
+    /// Group only allowed to read rules, ids, stats and logs.
+    #[arg(long)]
+    read_only_gid: Vec<u32>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    tracing_subscriber::fmt::init();
+    let opt = Opt::parse();
+    let authorization = Authorization {
+        admin: Principals {
+            uids: opt.admin_uid,
+            gids: opt.admin_gid,
+        },
+        read_only: Principals {
+            uids: opt.read_only_uid,
+            gids: opt.read_only_gid,
+        },
+    };
+
+    let firewall = Arc::new(Mutex::new(Firewall::new(&opt.iface)?));
+    let server = Server::new(firewall.clone(), authorization);
+    firewall
+        .lock()
+        .expect("firewall lock poisoned")
+        .add_packet_sink(server.packet_sink())?;
+
+    // Left behind by a previous run
+    let _ = fs::remove_file(&opt.socket);
+    let listener = UnixListener::bind(&opt.socket)
+        .with_context(|| format!("Couldn't bind {}", opt.socket.display()))?;
+    // Anyone can connect, peers that aren't authorized are dropped and requests are checked
+    // against the peer credentials
+    fs::set_permissions(&opt.socket, fs::Permissions::from_mode(0o666))?;
+
+    tracing::info!("Listening on {}", opt.socket.display());
+    tokio::select! {
+        res = server.serve(listener) => res?,
+        res = signal::ctrl_c() => res?,
+    }
+    tracing::info!("Exiting...");
+    let _ = fs::remove_file(&opt.socket);
+
+    Ok(())
+}
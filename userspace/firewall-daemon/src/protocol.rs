@@ -0,0 +1,282 @@
+//! JSON-RPC 2.0 messages exchanged over the control socket, one message per line.
+//!
+//! ```text
+//! -> {"jsonrpc": "2.0", "id": 1, "method": "add_rule", "params": {"rule": {"dest": "10.0.0.0/8", "ports": 22}}}
+//! <- {"jsonrpc": "2.0", "id": 1, "result": null}
+//! -> {"jsonrpc": "2.0", "id": 2, "method": "subscribe_logs"}
+//! <- {"jsonrpc": "2.0", "id": 2, "result": null}
+//! <- {"jsonrpc": "2.0", "method": "log", "params": {"source_ip": "10.0.0.5", ...}}
+//! ```
+use std::net::IpAddr;
+
+use firewall::{Action, Rule};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub const JSONRPC_VERSION: &str = "2.0";
+/// Method of the notifications sent to connections subscribed to the packet log.
+pub const LOG_METHOD: &str = "log";
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The firewall returned an error, the message is the [firewall::Error].
+pub const FIREWALL_ERROR: i64 = -32000;
+/// The peer isn't allowed to call the method, see [Authorization](crate::Authorization).
+pub const UNAUTHORIZED: i64 = -32001;
+
+/// Method and parameters of a request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum Call {
+    AddRule {
+        rule: Rule,
+    },
+    RemoveRule {
+        rule: Rule,
+    },
+    /// Returns a list of rules.
+    ListRules,
+    AddId {
+        ip: IpNet,
+        id: u128,
+    },
+    RemoveId {
+        ip: IpNet,
+    },
+    /// Returns a list of [IdEntry].
+    ListIds,
+    SetDefaultAction {
+        action: Action,
+    },
+    /// Returns the [Counters](firewall::Counters).
+    Stats,
+    /// Starts sending a notification with [LOG_METHOD] for every logged packet on this connection.
+    SubscribeLogs,
+}
+
+impl Call {
+    const METHODS: [&'static str; 9] = [
+        "add_rule",
+        "remove_rule",
+        "list_rules",
+        "add_id",
+        "remove_id",
+        "list_ids",
+        "set_default_action",
+        "stats",
+        "subscribe_logs",
+    ];
+
+    /// Whether the call leaves the firewall as it is.
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Call::ListRules | Call::ListIds | Call::Stats | Call::SubscribeLogs
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    pub id: u64,
+    pub method: String,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub params: Value,
+}
+
+impl Request {
+    pub fn new(id: u64, call: &Call) -> Self {
+        // Serializing a `Call` can't fail, it only holds plain data
+        let call = serde_json::to_value(call).expect("calls serialize to JSON");
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id,
+            method: call["method"].as_str().unwrap_or_default().to_string(),
+            params: call.get("params").cloned().unwrap_or_default(),
+        }
+    }
+
+    /// Parses a request line into its id and call.
+    ///
+    /// The id is `None` if the line isn't a request at all, which is answered with a null id.
+    pub fn parse(line: &str) -> (Option<u64>, Result<Call, RpcError>) {
+        let value: Value = match serde_json::from_str(line) {
+            Ok(value) => value,
+            Err(e) => return (None, Err(RpcError::new(PARSE_ERROR, e.to_string()))),
+        };
+        let request: Request = match serde_json::from_value(value) {
+            Ok(request) => request,
+            Err(e) => return (None, Err(RpcError::new(INVALID_REQUEST, e.to_string()))),
+        };
+        (Some(request.id), request.call())
+    }
+
+    fn call(self) -> Result<Call, RpcError> {
+        if self.jsonrpc != JSONRPC_VERSION {
+            return Err(RpcError::new(
+                INVALID_REQUEST,
+                format!("unsupported jsonrpc version {}", self.jsonrpc),
+            ));
+        }
+        if !Call::METHODS.contains(&self.method.as_str()) {
+            return Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method {}", self.method),
+            ));
+        }
+        let mut call = serde_json::Map::new();
+        call.insert("method".to_string(), Value::String(self.method));
+        // Methods without parameters don't take a null either
+        if !self.params.is_null() {
+            call.insert("params".to_string(), self.params);
+        }
+        serde_json::from_value(Value::Object(call))
+            .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    /// `None` if the request couldn't be parsed.
+    pub id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+impl Response {
+    pub fn new(id: Option<u64>, result: Result<Value, RpcError>) -> Self {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+/// Message from the daemon without an id, only used for the packet log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    pub jsonrpc: String,
+    pub method: String,
+    pub params: Value,
+}
+
+impl Notification {
+    pub fn log(packet: Value) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            method: LOG_METHOD.to_string(),
+            params: packet,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+#[error("{message} ({code})")]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<firewall::Error> for RpcError {
+    fn from(e: firewall::Error) -> Self {
+        Self::new(FIREWALL_ERROR, e.to_string())
+    }
+}
+
+/// An id and the IPs associated with it, returned by [Call::ListIds].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdEntry {
+    pub id: u128,
+    pub ips: Vec<IpAddr>,
+}
+
+#[cfg(test)]
+mod test {
+    use firewall::{Action, Protocol, Rule};
+
+    use super::{Call, Request, INVALID_PARAMS, METHOD_NOT_FOUND, PARSE_ERROR};
+
+    fn parse(line: &str) -> (Option<u64>, Result<Call, i64>) {
+        let (id, call) = Request::parse(line);
+        (id, call.map_err(|e| e.code))
+    }
+
+    #[test]
+    fn parses_requests() {
+        assert_eq!(
+            parse(
+                r#"{"jsonrpc": "2.0", "id": 1, "method": "add_rule", "params": {"rule": {"dest": "10.0.0.0/8", "ports": 22, "protocol": "tcp"}}}"#
+            ),
+            (
+                Some(1),
+                Ok(Call::AddRule {
+                    rule: Rule::new("10.0.0.0/8".parse().unwrap())
+                        .with_range(22..=22, Protocol::TCP)
+                })
+            )
+        );
+        assert_eq!(
+            parse(r#"{"jsonrpc": "2.0", "id": 2, "method": "list_rules"}"#),
+            (Some(2), Ok(Call::ListRules))
+        );
+        assert_eq!(
+            parse(
+                r#"{"jsonrpc": "2.0", "id": 3, "method": "set_default_action", "params": {"action": "accept"}}"#
+            ),
+            (
+                Some(3),
+                Ok(Call::SetDefaultAction {
+                    action: Action::Accept
+                })
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_requests_have_error_codes() {
+        assert_eq!(parse("{"), (None, Err(PARSE_ERROR)));
+        assert_eq!(
+            parse(r#"{"jsonrpc": "2.0", "id": 1, "method": "flush"}"#),
+            (Some(1), Err(METHOD_NOT_FOUND))
+        );
+        assert_eq!(
+            parse(r#"{"jsonrpc": "2.0", "id": 1, "method": "remove_id", "params": {}}"#),
+            (Some(1), Err(INVALID_PARAMS))
+        );
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let call = Call::AddId {
+            ip: "10.0.0.5/32".parse().unwrap(),
+            id: 7,
+        };
+        let line = serde_json::to_string(&Request::new(4, &call)).unwrap();
+        assert_eq!(Request::parse(&line), (Some(4), Ok(call)));
+
+        let line = serde_json::to_string(&Request::new(5, &Call::Stats)).unwrap();
+        assert_eq!(Request::parse(&line), (Some(5), Ok(Call::Stats)));
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use firewall::{Firewall, PacketEvent, PacketSink};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{unix::UCred, UnixListener, UnixStream},
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::{self, error::TrySendError, Sender},
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    protocol::{Call, IdEntry, Notification, Request, Response, RpcError, UNAUTHORIZED},
+    Authorization,
+};
+
+// Packets kept for a slow subscriber before it starts missing them
+const LOG_CAPACITY: usize = 1024;
+// Lines queued for a connection, log notifications are dropped while it's full
+const CONNECTION_CAPACITY: usize = 256;
+// Longest request line read, connections sending a longer one are closed
+const MAX_LINE_LEN: u64 = 1 << 20;
+const INTERNAL_ERROR: i64 = -32603;
+
+/// Owns the [Firewall] and answers the requests of the clients connected to the control socket.
+pub struct Server {
+    firewall: Arc<Mutex<Firewall>>,
+    authorization: Authorization,
+    logs: broadcast::Sender<Value>,
+}
+
+impl Server {
+    pub fn new(firewall: Arc<Mutex<Firewall>>, authorization: Authorization) -> Self {
+        let (logs, _) = broadcast::channel(LOG_CAPACITY);
+        Self {
+            firewall,
+            authorization,
+            logs,
+        }
+    }
+
+    /// Sink that forwards the packets seen by the firewall to the clients subscribed through
+    /// [Call::SubscribeLogs], register it with [Firewall::add_packet_sink].
+    pub fn packet_sink(&self) -> impl PacketSink {
+        let logs = self.logs.clone();
+        move |event: &PacketEvent| {
+            if logs.receiver_count() == 0 {
+                return;
+            }
+            if let Ok(packet) = serde_json::to_value(event) {
+                // Only fails if every subscriber left in the meantime
+                let _ = logs.send(packet);
+            }
+        }
+    }
+
+    /// Accepts connections until accepting fails, each connection is served in its own task.
+    pub async fn serve(self, listener: UnixListener) -> io::Result<()> {
+        let server = Arc::new(self);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = server.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.handle(stream).await {
+                    tracing::warn!("Control connection failed: {e}");
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, stream: UnixStream) -> io::Result<()> {
+        let peer = stream.peer_cred()?;
+        // Nothing is read from peers that can't call anything
+        if !self.authorization.allows_peer(&peer) {
+            tracing::debug!("Dropped control connection of uid {}", peer.uid());
+            return Ok(());
+        }
+        let (read, mut write) = stream.into_split();
+
+        // Responses and log notifications share the connection
+        let (tx, mut rx) = mpsc::channel::<String>(CONNECTION_CAPACITY);
+        let writer = tokio::spawn(async move {
+            while let Some(line) = rx.recv().await {
+                write.write_all(line.as_bytes()).await?;
+                write.write_all(b"\n").await?;
+            }
+            Ok::<_, io::Error>(())
+        });
+
+        let mut subscription = None;
+        let mut read = BufReader::new(read);
+        while let Some(line) = read_line(&mut read).await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (id, call) = Request::parse(&line);
+            let result = call.and_then(|call| self.call(&peer, call, &tx, &mut subscription));
+            if tx
+                .send(serde_json::to_string(&Response::new(id, result))?)
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+
+        if let Some(subscription) = subscription {
+            subscription.abort();
+        }
+        drop(tx);
+        writer.await.unwrap_or(Ok(()))
+    }
+
+    fn call(
+        &self,
+        peer: &UCred,
+        call: Call,
+        tx: &Sender<String>,
+        subscription: &mut Option<JoinHandle<()>>,
+    ) -> Result<Value, RpcError> {
+        if !self.authorization.allows(peer, &call) {
+            return Err(RpcError::new(
+                UNAUTHORIZED,
+                format!("uid {} isn't allowed to call this method", peer.uid()),
+            ));
+        }
+
+        let mut firewall = self
+            .firewall
+            .lock()
+            .map_err(|_| RpcError::new(INTERNAL_ERROR, "firewall lock poisoned"))?;
+        match call {
+            Call::AddRule { rule } => json(firewall.add_rule(&rule)?),
+            Call::RemoveRule { rule } => json(firewall.remove_rule(&rule)?),
+            Call::ListRules => json(firewall.rules()),
+            Call::AddId { ip, id } => json(firewall.add_id(ip, id)?),
+            Call::RemoveId { ip } => json(firewall.remove_id(&ip)?),
+            Call::ListIds => json(
+                firewall
+                    .ids()
+                    .into_iter()
+                    .map(|id| IdEntry {
+                        id,
+                        ips: firewall.ips_for_id(id),
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            Call::SetDefaultAction { action } => json(firewall.set_default_action(action)?),
+            Call::Stats => json(firewall.counters()?),
+            Call::SubscribeLogs => {
+                if subscription.is_none() {
+                    *subscription = Some(tokio::spawn(forward_logs(
+                        self.logs.subscribe(),
+                        tx.clone(),
+                    )));
+                }
+                Ok(Value::Null)
+            }
+        }
+    }
+}
+
+fn json(value: impl Serialize) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))
+}
+
+// Reads a line without its line ending, `None` at the end of the stream.
+// Lines longer than `MAX_LINE_LEN` are an error so a client can't make the daemon buffer without limit
+async fn read_line(read: &mut (impl AsyncBufRead + Unpin)) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let len = read
+        .take(MAX_LINE_LEN + 1)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    } else if len as u64 > MAX_LINE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("request longer than {MAX_LINE_LEN} bytes"),
+        ));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Packets are dropped rather than queued while the connection is full, a client that doesn't read
+// its notifications only holds up to `CONNECTION_CAPACITY` lines
+async fn forward_logs(mut logs: broadcast::Receiver<Value>, tx: Sender<String>) {
+    // Packets the subscriber missed since the last one it got
+    let mut skipped = 0;
+    loop {
+        let packet = match logs.recv().await {
+            Ok(packet) => packet,
+            Err(RecvError::Lagged(lagged)) => {
+                skipped += lagged;
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let Ok(line) = serde_json::to_string(&Notification::log(packet)) else {
+            continue;
+        };
+        match tx.try_send(line) {
+            Ok(()) if skipped > 0 => {
+                tracing::warn!("Log subscriber is too slow, {skipped} packets skipped");
+                skipped = 0;
+            }
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => skipped += 1,
+            Err(TrySendError::Closed(_)) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_line, MAX_LINE_LEN};
+
+    #[tokio::test]
+    async fn lines_are_read_up_to_the_limit() {
+        let mut read = &b"{\"id\":1}\r\n\nlast"[..];
+        assert_eq!(read_line(&mut read).await.unwrap().unwrap(), "{\"id\":1}");
+        assert_eq!(read_line(&mut read).await.unwrap().unwrap(), "");
+        assert_eq!(read_line(&mut read).await.unwrap().unwrap(), "last");
+        assert_eq!(read_line(&mut read).await.unwrap(), None);
+
+        let mut longest = vec![b'x'; MAX_LINE_LEN as usize];
+        longest.push(b'\n');
+        assert!(read_line(&mut &longest[..]).await.unwrap().is_some());
+        let too_long = vec![b'x'; MAX_LINE_LEN as usize + 1];
+        assert!(read_line(&mut &too_long[..]).await.is_err());
+    }
+}
@@ -1,6 +1,6 @@
 use std::{path::PathBuf, process::ExitStatus};
 
-const EBPF_FEATURES: &[&str] = &["rules", "wireguard"];
+const EBPF_FEATURES: &[&str] = &["rules", "pinning"];
 
 fn main() {
     println!("cargo:rerun-if-changed=../../ebpf/");
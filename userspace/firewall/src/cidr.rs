@@ -4,6 +4,9 @@ use std::ops::{BitAnd, Not, Shr};
 
 use crate::as_octet::AsOctets;
 
+// Length in bits of the id and protocol prepended to every key
+const KEY_HEADER_BITS: u32 = 17 * 8;
+
 pub trait AsNum {
     type Num: BitAnd<Output = Self::Num>
         + CheckedShr
@@ -136,10 +139,14 @@ where
     prot[0] = proto;
     id.copy_from_slice(&key_id);
     cidr.copy_from_slice(key_cidr.as_ref());
-    Key::new(
-        u32::from(ip.prefix()) + (left_key_data.len() * 8) as u32,
-        key_data,
-    )
+    Key::new(u32::from(ip.prefix()) + KEY_HEADER_BITS, key_data)
+}
+
+fn split_key<const N: usize>(key: &Key<[u8; N]>) -> Option<(u128, u8, u8, [u8; N])> {
+    let data = key.data;
+    let prefix = u8::try_from(key.prefix_len.checked_sub(KEY_HEADER_BITS)?).ok()?;
+    let id = u128::from_be_bytes(data[..16].try_into().ok()?);
+    Some((id, data[16], prefix, data))
 }
 
 pub trait AsKey {
@@ -161,13 +168,38 @@ impl AsKey for Ipv6Net {
     }
 }
 
+/// Inverse of [AsKey], used to read back the keys stored in the rule tries.
+pub trait FromKey: AsKey + Sized {
+    /// Returns the id, protocol and cidr for a given key or `None` if the key is malformed.
+    fn from_key(key: &Key<Self::KeySize>) -> Option<(u128, u8, Self)>;
+}
+
+impl FromKey for Ipv4Net {
+    fn from_key(key: &Key<Self::KeySize>) -> Option<(u128, u8, Self)> {
+        let (id, proto, prefix, data) = split_key(key)?;
+        let addr: [u8; 4] = data[17..].try_into().ok()?;
+        Some((id, proto, Ipv4Net::new(addr.into(), prefix).ok()?))
+    }
+}
+
+impl FromKey for Ipv6Net {
+    fn from_key(key: &Key<Self::KeySize>) -> Option<(u128, u8, Self)> {
+        let (id, proto, prefix, data) = split_key(key)?;
+        let addr: [u8; 16] = data[17..].try_into().ok()?;
+        Some((id, proto, Ipv6Net::new(addr.into(), prefix).ok()?))
+    }
+}
+
 #[cfg(test)]
 mod test {
 
     use aya::maps::lpm_trie::Key;
     use ipnet::{Ipv4Net, Ipv6Net};
 
-    use crate::{cidr::AsKey, Protocol};
+    use crate::{
+        cidr::{AsKey, FromKey},
+        Protocol,
+    };
 
     #[test]
     fn as_key_works() {
@@ -223,4 +255,21 @@ mod test {
         let expected_len = y.prefix_len;
         assert_eq!(actual_len, expected_len);
     }
+
+    #[test]
+    fn from_key_is_inverse_of_as_key() {
+        let cidr: Ipv4Net = "10.1.0.0/16".parse().unwrap();
+        let key = cidr.as_key(7, Protocol::UDP as u8);
+        assert_eq!(
+            Ipv4Net::from_key(&key),
+            Some((7, Protocol::UDP as u8, cidr))
+        );
+
+        let cidr: Ipv6Net = "fafa::1:0:0:0/96".parse().unwrap();
+        let key = cidr.as_key(0, Protocol::TCP as u8);
+        assert_eq!(
+            Ipv6Net::from_key(&key),
+            Some((0, Protocol::TCP as u8, cidr))
+        );
+    }
 }
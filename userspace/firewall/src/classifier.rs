@@ -1,12 +1,61 @@
-use std::{collections::HashSet, hash::Hash};
+use std::{collections::HashSet, ffi::CString, hash::Hash, io};
 
-use aya::{maps::HashMap, maps::MapData, Bpf, Pod};
+use aya::{
+    maps::{Array, HashMap, MapData, MapError},
+    Bpf, Pod,
+};
+use firewall_common::{mark_key, MAX_MARK_MASKS};
 use ipnet::{Ipv4Net, Ipv6Net};
 
-use crate::{as_octet::AsOctets, Error, Result, SOURCE_ID_IPV4, SOURCE_ID_IPV6};
+use crate::{
+    as_octet::AsOctets, Error, Result, MARK_MASKS, SOURCE_ID_IFACE, SOURCE_ID_IPV4, SOURCE_ID_IPV6,
+    SOURCE_ID_MARK,
+};
 
 type ID = [u8; 16];
 
+/// Packet mark, only the bits set in `mask` are compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Mark {
+    pub(crate) mark: u32,
+    pub(crate) mask: u32,
+}
+
+impl AsOctets for Mark {
+    type Octets = u64;
+
+    fn as_octets(&self) -> Self::Octets {
+        mark_key(self.mark, self.mask)
+    }
+}
+
+/// Index of the interface a packet arrived on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct IfIndex(pub(crate) u32);
+
+impl IfIndex {
+    pub(crate) fn from_name(iface: impl AsRef<str>) -> Result<Self> {
+        let name = CString::new(iface.as_ref())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // SAFETY: name is a valid nul-terminated string that outlives the call
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            Err(io::Error::last_os_error().into())
+        } else {
+            Ok(Self(index))
+        }
+    }
+}
+
+impl AsOctets for IfIndex {
+    type Octets = u32;
+
+    fn as_octets(&self) -> Self::Octets {
+        self.0
+    }
+}
+
+#[derive(Clone)]
 pub struct Classifier<T: AsOctets>
 where
     T::Octets: Pod + Eq + Hash,
@@ -17,16 +66,23 @@ where
 
 pub(crate) type ClassifierV6 = Classifier<Ipv6Net>;
 pub(crate) type ClassifierV4 = Classifier<Ipv4Net>;
+pub(crate) type ClassifierIface = Classifier<IfIndex>;
 
 impl Classifier<Ipv4Net> {
-    pub fn new() -> Result<Self> {
-        Self::new_with_name(SOURCE_ID_IPV4)
+    pub fn new(generation: usize) -> Result<Self> {
+        Self::new_with_name(SOURCE_ID_IPV4[generation])
     }
 }
 
 impl Classifier<Ipv6Net> {
+    pub fn new(generation: usize) -> Result<Self> {
+        Self::new_with_name(SOURCE_ID_IPV6[generation])
+    }
+}
+
+impl Classifier<IfIndex> {
     pub fn new() -> Result<Self> {
-        Self::new_with_name(SOURCE_ID_IPV6)
+        Self::new_with_name(SOURCE_ID_IFACE)
     }
 }
 
@@ -34,15 +90,52 @@ impl<T: AsOctets> Classifier<T>
 where
     T::Octets: Pod + Hash + Eq,
 {
+    /// Rebuilds the tracked ids from the entries in the classifier map.
+    #[cfg(feature = "pinning")]
+    pub fn restore(&mut self, bpf: &Bpf) -> Result<()> {
+        let store: HashMap<_, T::Octets, ID> =
+            HashMap::try_from(bpf.map(&self.store_name).ok_or(Error::MapNotFound)?)?;
+        for entry in store.iter() {
+            let (key, id) = entry?;
+            self.userland_map
+                .entry(u128::from_le_bytes(id))
+                .or_default()
+                .insert(key);
+        }
+        Ok(())
+    }
+
+    /// Removes all ids from the classifier.
+    pub fn clear(&mut self, bpf: &mut Bpf) -> Result<()> {
+        let mut store = self.get_store(bpf)?;
+        for ip in self.userland_map.values().flatten() {
+            store.remove(ip)?;
+        }
+        self.userland_map.clear();
+        Ok(())
+    }
+
     fn get_store<'a>(&self, bpf: &'a mut Bpf) -> Result<HashMap<&'a mut MapData, T::Octets, ID>> {
         Ok(HashMap::try_from(
             bpf.map_mut(&self.store_name).ok_or(Error::MapNotFound)?,
         )?)
     }
     pub fn insert(&mut self, bpf: &mut Bpf, ip: T, id: u128) -> Result<()> {
+        self.insert_local(&ip, id)?;
+        self.get_store(bpf)?
+            .insert(ip.as_octets(), id.to_le_bytes(), 0)?;
+        Ok(())
+    }
+
+    /// Same as [insert](Self::insert) without touching the map.
+    pub fn insert_local(&mut self, ip: &T, id: u128) -> Result<()> {
         if id == 0 {
             return Err(Error::InvalidId);
         }
+        // An ip can only have one id, the map entry is overwritten
+        if let Some(previous) = self.id_of(&ip.as_octets()) {
+            self.remove_from_id(previous, &ip.as_octets());
+        }
         self.userland_map
             .entry(id)
             .and_modify(|e| {
@@ -53,11 +146,90 @@ where
                 set.insert(ip.as_octets());
                 set
             });
-        self.get_store(bpf)?
-            .insert(ip.as_octets(), id.to_le_bytes(), 0)?;
         Ok(())
     }
 
+    /// Same as [remove](Self::remove) without touching the map.
+    pub fn remove_local(&mut self, ip: &T) -> Result<()> {
+        let id = self.id_of(&ip.as_octets()).ok_or(MapError::KeyNotFound)?;
+        self.remove_from_id(id, &ip.as_octets());
+        Ok(())
+    }
+
+    /// Entries whose id in `self` differs from the one in `other`, including the entries only one of them has.
+    pub fn changed(&self, other: &Self) -> Vec<T::Octets> {
+        let changed = self.userland_map.iter().flat_map(|(&id, ips)| {
+            ips.iter()
+                .filter(move |ip| other.id_of(ip) != Some(id))
+                .copied()
+        });
+        let removed = other
+            .userland_map
+            .values()
+            .flatten()
+            .filter(|ip| self.id_of(ip).is_none())
+            .copied();
+        changed.chain(removed).collect()
+    }
+
+    /// Writes to the map the ids `self` has for each of the `touched` entries,
+    /// entries without an id in `self` are removed.
+    pub fn write_entries(&self, bpf: &mut Bpf, touched: &[T::Octets]) -> Result<()> {
+        self.write_entries_impl(bpf, touched, false)
+    }
+
+    /// Same as [write_entries](Self::write_entries) ignoring the entries that can't be removed.
+    ///
+    /// Used with the classifier as it was to undo partially written entries.
+    pub fn rollback(&self, bpf: &mut Bpf, touched: &[T::Octets]) -> Result<()> {
+        self.write_entries_impl(bpf, touched, true)
+    }
+
+    fn write_entries_impl(&self, bpf: &mut Bpf, touched: &[T::Octets], undo: bool) -> Result<()> {
+        let mut store = self.get_store(bpf)?;
+        for ip in touched {
+            match self.id_of(ip) {
+                Some(id) => store.insert(*ip, id.to_le_bytes(), 0)?,
+                None => match store.remove(ip) {
+                    Err(_) if undo => {}
+                    res => res?,
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Ids with at least one entry.
+    pub fn ids(&self) -> impl Iterator<Item = u128> + '_ {
+        self.userland_map.keys().copied()
+    }
+
+    /// Number of entries in the map, one per classified address, mark or interface.
+    pub fn len(&self) -> usize {
+        self.userland_map.values().map(HashSet::len).sum()
+    }
+
+    /// Entries associated with `id`.
+    pub fn entries(&self, id: u128) -> impl Iterator<Item = &T::Octets> {
+        self.userland_map.get(&id).into_iter().flatten()
+    }
+
+    pub(crate) fn id_of(&self, ip: &T::Octets) -> Option<u128> {
+        self.userland_map
+            .iter()
+            .find(|(_, ips)| ips.contains(ip))
+            .map(|(&id, _)| id)
+    }
+
+    fn remove_from_id(&mut self, id: u128, ip: &T::Octets) {
+        if let Some(set) = self.userland_map.get_mut(&id) {
+            set.remove(ip);
+            if set.is_empty() {
+                self.userland_map.remove(&id);
+            }
+        }
+    }
+
     pub fn remove(&mut self, bpf: &mut Bpf, ip: &T) -> Result<()> {
         let mut store = self.get_store(bpf)?;
         let id = u128::from_le_bytes(store.get(&ip.as_octets(), 0)?);
@@ -90,3 +262,207 @@ where
         })
     }
 }
+
+/// Classifier by packet mark.
+///
+/// On top of the mark to id map it keeps the set of masks in use up to date,
+/// the eBPF program tries each one of them in order.
+pub(crate) struct MarkClassifier {
+    classifier: Classifier<Mark>,
+    masks: [u32; MAX_MARK_MASKS as usize],
+    masks_name: String,
+}
+
+impl MarkClassifier {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            classifier: Classifier::new_with_name(SOURCE_ID_MARK)?,
+            masks: [0; MAX_MARK_MASKS as usize],
+            masks_name: MARK_MASKS.to_string(),
+        })
+    }
+
+    pub fn insert(&mut self, bpf: &mut Bpf, mark: Mark, id: u128) -> Result<()> {
+        check_mark(&mark, id)?;
+        self.add_mask(bpf, mark.mask)?;
+        self.classifier.insert(bpf, mark, id)
+    }
+
+    pub fn remove(&mut self, bpf: &mut Bpf, mark: &Mark) -> Result<()> {
+        self.classifier.remove(bpf, mark)?;
+        self.release_mask(bpf, mark.mask)
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = u128> + '_ {
+        self.classifier.ids()
+    }
+
+    pub fn len(&self) -> usize {
+        self.classifier.len()
+    }
+
+    pub fn remove_by_id(&mut self, bpf: &mut Bpf, id: u128) -> Result<()> {
+        let masks: HashSet<_> = self
+            .classifier
+            .userland_map
+            .get(&id)
+            .ok_or(Error::NotExistingId)?
+            .iter()
+            .map(|&key| mask_of(key))
+            .collect();
+        self.classifier.remove_by_id(bpf, id)?;
+        for mask in masks {
+            self.release_mask(bpf, mask)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "pinning")]
+    pub fn restore(&mut self, bpf: &Bpf) -> Result<()> {
+        self.classifier.restore(bpf)?;
+        let masks: Array<_, u32> =
+            Array::try_from(bpf.map(&self.masks_name).ok_or(Error::MapNotFound)?)?;
+        for (slot, mask) in self.masks.iter_mut().enumerate() {
+            *mask = masks.get(&(slot as u32), 0)?;
+        }
+        Ok(())
+    }
+
+    fn get_masks<'a>(&self, bpf: &'a mut Bpf) -> Result<Array<&'a mut MapData, u32>> {
+        Ok(Array::try_from(
+            bpf.map_mut(&self.masks_name).ok_or(Error::MapNotFound)?,
+        )?)
+    }
+
+    fn add_mask(&mut self, bpf: &mut Bpf, mask: u32) -> Result<()> {
+        if let Some(slot) = self.free_slot(mask)? {
+            self.get_masks(bpf)?.set(slot as u32, mask, 0)?;
+            self.masks[slot] = mask;
+        }
+        Ok(())
+    }
+
+    fn release_mask(&mut self, bpf: &mut Bpf, mask: u32) -> Result<()> {
+        if let Some(slot) = self.released_slot(mask) {
+            self.get_masks(bpf)?.set(slot as u32, 0, 0)?;
+            self.masks[slot] = 0;
+        }
+        Ok(())
+    }
+
+    // Slot `mask` has to be written to, `None` if it already has one
+    fn free_slot(&self, mask: u32) -> Result<Option<usize>> {
+        if self.masks.contains(&mask) {
+            return Ok(None);
+        }
+        self.masks
+            .iter()
+            .position(|&m| m == 0)
+            .map(Some)
+            .ok_or(Error::MarkMasksExhausted)
+    }
+
+    // Slot holding `mask` once no entry uses it anymore
+    fn released_slot(&self, mask: u32) -> Option<usize> {
+        let in_use = self
+            .classifier
+            .userland_map
+            .values()
+            .flatten()
+            .any(|&key| mask_of(key) == mask);
+        if in_use {
+            return None;
+        }
+        self.masks.iter().position(|&m| m == mask)
+    }
+}
+
+// The eBPF program doesn't classify packets without a mark, so neither can the masked mark be 0
+fn check_mark(mark: &Mark, id: u128) -> Result<()> {
+    if mark.mask == 0 {
+        return Err(Error::InvalidMask);
+    }
+    if mark.mark & mark.mask == 0 {
+        return Err(Error::InvalidMark);
+    }
+    if id == 0 {
+        return Err(Error::InvalidId);
+    }
+    Ok(())
+}
+
+fn mask_of(key: u64) -> u32 {
+    (key >> 32) as u32
+}
+
+#[cfg(test)]
+mod test {
+    use firewall_common::MAX_MARK_MASKS;
+
+    use super::{check_mark, Mark, MarkClassifier};
+    use crate::Error;
+
+    #[test]
+    fn masked_marks_of_0_are_rejected() {
+        let mark = |mark, mask| Mark { mark, mask };
+        assert!(check_mark(&mark(0x10, 0xff), 1).is_ok());
+        assert!(matches!(
+            check_mark(&mark(0x10, 0), 1),
+            Err(Error::InvalidMask)
+        ));
+        assert!(matches!(
+            check_mark(&mark(0x100, 0xff), 1),
+            Err(Error::InvalidMark)
+        ));
+        assert!(matches!(
+            check_mark(&mark(0x10, 0xff), 0),
+            Err(Error::InvalidId)
+        ));
+    }
+
+    #[test]
+    fn mask_slots_are_reused_once_released() {
+        let mut classifier = MarkClassifier::new().unwrap();
+        for (mark, mask, id) in [(0x1, 0xf, 1), (0x2, 0xf, 2), (0x10, 0xf0, 3)] {
+            let mark = Mark { mark, mask };
+            if let Some(slot) = classifier.free_slot(mask).unwrap() {
+                classifier.masks[slot] = mask;
+            }
+            classifier.classifier.insert_local(&mark, id).unwrap();
+        }
+        assert_eq!(classifier.masks[..2], [0xf, 0xf0]);
+
+        // Still used by the other mark
+        let mark = Mark {
+            mark: 0x1,
+            mask: 0xf,
+        };
+        classifier.classifier.remove_local(&mark).unwrap();
+        assert_eq!(classifier.released_slot(0xf), None);
+
+        let mark = Mark {
+            mark: 0x10,
+            mask: 0xf0,
+        };
+        classifier.classifier.remove_local(&mark).unwrap();
+        assert_eq!(classifier.released_slot(0xf0), Some(1));
+        classifier.masks[1] = 0;
+        assert_eq!(classifier.free_slot(0xff00).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn mask_slots_run_out() {
+        let mut classifier = MarkClassifier::new().unwrap();
+        for slot in 0..MAX_MARK_MASKS as usize {
+            let mask = 1 << slot;
+            assert_eq!(classifier.free_slot(mask).unwrap(), Some(slot));
+            classifier.masks[slot] = mask;
+        }
+        // Masks already in a slot don't take another one
+        assert_eq!(classifier.free_slot(1).unwrap(), None);
+        assert!(matches!(
+            classifier.free_slot(u32::MAX),
+            Err(Error::MarkMasksExhausted)
+        ));
+    }
+}
@@ -1,7 +1,13 @@
-use aya::{maps::HashMap, Bpf};
+use aya::{
+    maps::{HashMap, MapError},
+    Bpf,
+};
 use firewall_common::{Action, ConfigOpt};
+use num_traits::FromPrimitive;
 
-use crate::{Error, Result, CONFIG};
+#[cfg(feature = "pinning")]
+use crate::{classifier::IfIndex, tc::TcFilter};
+use crate::{tc::AcceptVerdict, Error, Result, AUDIT_IDS, CONFIG, DEFAULT_ACTION_IDS};
 
 pub struct ConfigHandler {
     store_name: String,
@@ -24,4 +30,129 @@ impl ConfigHandler {
         store.insert(ConfigOpt::DefaultAction, action as i32, 0)?;
         Ok(())
     }
+
+    pub fn default_action(&self, bpf: &Bpf) -> Result<Action> {
+        let store = HashMap::try_from(bpf.map(&self.store_name).ok_or(Error::MapNotFound)?)?;
+        match store.get(&ConfigOpt::DefaultAction, 0) {
+            Ok(action) => Ok(Action::from_i32(action).unwrap_or_default()),
+            // Same default as the eBPF program
+            Err(MapError::KeyNotFound) => Ok(Action::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn set_default_action_for_id(
+        &mut self,
+        bpf: &mut Bpf,
+        id: u128,
+        action: Option<Action>,
+    ) -> Result<()> {
+        if id == 0 {
+            return Err(Error::InvalidId);
+        }
+
+        let mut store =
+            HashMap::try_from(bpf.map_mut(DEFAULT_ACTION_IDS).ok_or(Error::MapNotFound)?)?;
+        // Ids are stored the same way as in the classifiers
+        match action {
+            Some(action) => store.insert(id.to_le_bytes(), action as i32, 0)?,
+            None => match store.remove(&id.to_le_bytes()) {
+                Ok(()) | Err(MapError::KeyNotFound) => {}
+                Err(e) => return Err(e.into()),
+            },
+        }
+        Ok(())
+    }
+
+    pub fn set_audit(&mut self, bpf: &mut Bpf, enabled: bool) -> Result<()> {
+        let mut store =
+            HashMap::try_from(bpf.map_mut(&self.store_name).ok_or(Error::MapNotFound)?)?;
+        store.insert(ConfigOpt::Audit, enabled as i32, 0)?;
+        Ok(())
+    }
+
+    pub fn set_audit_for_id(&mut self, bpf: &mut Bpf, id: u128, enabled: bool) -> Result<()> {
+        if id == 0 {
+            return Err(Error::InvalidId);
+        }
+
+        let mut store = HashMap::try_from(bpf.map_mut(AUDIT_IDS).ok_or(Error::MapNotFound)?)?;
+        // Ids are stored the same way as in the classifiers
+        if enabled {
+            store.insert(id.to_le_bytes(), 1u8, 0)?;
+        } else {
+            match store.remove(&id.to_le_bytes()) {
+                Ok(()) | Err(MapError::KeyNotFound) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_accept_verdict(&mut self, bpf: &mut Bpf, verdict: AcceptVerdict) -> Result<()> {
+        let mut store =
+            HashMap::try_from(bpf.map_mut(&self.store_name).ok_or(Error::MapNotFound)?)?;
+        store.insert(ConfigOpt::AcceptVerdict, verdict.as_tc_action(), 0)?;
+        Ok(())
+    }
+
+    pub fn set_capture_length(&mut self, bpf: &mut Bpf, len: u16) -> Result<()> {
+        let mut store =
+            HashMap::try_from(bpf.map_mut(&self.store_name).ok_or(Error::MapNotFound)?)?;
+        store.insert(ConfigOpt::CaptureLength, len as i32, 0)?;
+        Ok(())
+    }
+
+    pub fn set_generation(&mut self, bpf: &mut Bpf, generation: usize) -> Result<()> {
+        let mut store =
+            HashMap::try_from(bpf.map_mut(&self.store_name).ok_or(Error::MapNotFound)?)?;
+        store.insert(ConfigOpt::Generation, generation as i32, 0)?;
+        Ok(())
+    }
+
+    /// Records where a pinned firewall is attached, the filter outlives the process that attached it.
+    #[cfg(feature = "pinning")]
+    pub fn set_filter(&mut self, bpf: &mut Bpf, filter: &TcFilter) -> Result<()> {
+        let mut store =
+            HashMap::try_from(bpf.map_mut(&self.store_name).ok_or(Error::MapNotFound)?)?;
+        // Stored as i32 like every other option, the bits are kept as is
+        store.insert(ConfigOpt::FilterIfindex, filter.ifindex().0 as i32, 0)?;
+        store.insert(ConfigOpt::FilterPriority, filter.priority() as i32, 0)?;
+        store.insert(ConfigOpt::FilterHandle, filter.handle() as i32, 0)?;
+        Ok(())
+    }
+
+    /// Filter recorded by [set_filter](Self::set_filter), `None` if there is none.
+    #[cfg(feature = "pinning")]
+    pub fn filter(&self, bpf: &Bpf) -> Result<Option<TcFilter>> {
+        let store = HashMap::try_from(bpf.map(&self.store_name).ok_or(Error::MapNotFound)?)?;
+        let get = |opt| match store.get(&opt, 0) {
+            Ok(value) => Ok(Some(value as u32)),
+            Err(MapError::KeyNotFound) => Ok(None),
+            Err(e) => Err(e),
+        };
+        let filter = match (
+            get(ConfigOpt::FilterIfindex)?,
+            get(ConfigOpt::FilterPriority)?,
+            get(ConfigOpt::FilterHandle)?,
+        ) {
+            (Some(ifindex), Some(priority), Some(handle)) => Some(TcFilter::from_parts(
+                IfIndex(ifindex),
+                priority as u16,
+                handle,
+            )),
+            _ => None,
+        };
+        Ok(filter)
+    }
+
+    #[cfg(feature = "pinning")]
+    pub fn generation(&self, bpf: &Bpf) -> Result<usize> {
+        let store = HashMap::try_from(bpf.map(&self.store_name).ok_or(Error::MapNotFound)?)?;
+        match store.get(&ConfigOpt::Generation, 0) {
+            Ok(generation) => Ok(generation as usize),
+            Err(MapError::KeyNotFound) => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
 }
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use aya::{
+    maps::{PerCpuArray, PerCpuHashMap},
+    Bpf,
+};
+use firewall_common::{Counter, IdCountersRaw};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result, COUNTERS, ID_COUNTERS};
+
+/// Number of packets seen by the firewall since it was loaded, by outcome and by IP version.
+///
+/// Every packet counts towards exactly one outcome, IPv4 and IPv6 packets also count towards their version.
+/// See [Firewall::counters](crate::Firewall::counters).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counters {
+    /// Accepted packets, including those only accepted because of audit mode.
+    pub accepted: u64,
+    /// Packets dropped because a rule matched.
+    pub dropped_by_rule: u64,
+    /// Packets dropped because no rule matched and the default action is [Reject](crate::Action::Reject).
+    pub dropped_by_default: u64,
+    /// Packets dropped because their source or destination is in the blocklist.
+    pub dropped_by_list: u64,
+    /// Packets dropped because their headers couldn't be read, e.g. truncated packets.
+    pub parse_failures: u64,
+    /// Packets that are neither IPv4 nor IPv6, these are dropped and don't count as parse failures.
+    pub non_ip: u64,
+    /// IPv4 packets, whatever their outcome.
+    pub ipv4: u64,
+    /// IPv6 packets, whatever their outcome.
+    pub ipv6: u64,
+}
+
+impl Counters {
+    /// Sums the counters of every CPU.
+    pub(crate) fn read(bpf: &Bpf) -> Result<Self> {
+        let counters: PerCpuArray<_, u64> =
+            PerCpuArray::try_from(bpf.map(COUNTERS).ok_or(Error::MapNotFound)?)?;
+        Self::sum(|counter| Ok(counters.get(&(counter as u32), 0)?.to_vec()))
+    }
+
+    // Sums the values of each counter given by `per_cpu`, one per CPU
+    fn sum(per_cpu: impl Fn(Counter) -> Result<Vec<u64>>) -> Result<Self> {
+        let get = |counter: Counter| -> Result<u64> { Ok(per_cpu(counter)?.iter().sum()) };
+
+        Ok(Self {
+            accepted: get(Counter::Accepted)?,
+            dropped_by_rule: get(Counter::DroppedByRule)?,
+            dropped_by_default: get(Counter::DroppedByDefault)?,
+            dropped_by_list: get(Counter::DroppedByList)?,
+            parse_failures: get(Counter::ParseFailure)?,
+            non_ip: get(Counter::NonIp)?,
+            ipv4: get(Counter::Ipv4)?,
+            ipv6: get(Counter::Ipv6)?,
+        })
+    }
+}
+
+/// Packets seen from the sources classified with an id, see [Firewall::id_counters](crate::Firewall::id_counters).
+///
+/// The counters of an id start over from 0 if it's evicted from the map holding them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdCounters {
+    /// Accepted packets, including those only accepted because of audit mode.
+    pub accepted: u64,
+    pub dropped: u64,
+}
+
+impl IdCounters {
+    /// Sums the counters of every CPU, by id.
+    pub(crate) fn read(bpf: &Bpf) -> Result<HashMap<u128, Self>> {
+        let counters: PerCpuHashMap<_, [u8; 16], IdCountersRaw> =
+            PerCpuHashMap::try_from(bpf.map(ID_COUNTERS).ok_or(Error::MapNotFound)?)?;
+        let mut by_id = HashMap::new();
+        for entry in counters.iter() {
+            let (id, per_cpu) = entry?;
+            let total = per_cpu.iter().fold(Self::default(), |total, cpu| Self {
+                accepted: total.accepted + cpu.accepted,
+                dropped: total.dropped + cpu.dropped,
+            });
+            by_id.insert(u128::from_le_bytes(id), total);
+        }
+        Ok(by_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use firewall_common::{Counter, IdCountersRaw};
+
+    use super::Counters;
+
+    #[test]
+    fn counters_are_summed_across_cpus() {
+        // Each CPU counted the index of the counter times its own number
+        let counters =
+            Counters::sum(|counter| Ok((1..=4).map(|cpu| cpu * counter as u64).collect())).unwrap();
+        assert_eq!(
+            counters,
+            Counters {
+                accepted: 0,
+                dropped_by_rule: 10,
+                dropped_by_default: 20,
+                dropped_by_list: 30,
+                parse_failures: 40,
+                non_ip: 50,
+                ipv4: 60,
+                ipv6: 70,
+            }
+        );
+    }
+
+    #[test]
+    fn read_errors_are_returned() {
+        let res = Counters::sum(|counter| match counter {
+            Counter::Ipv6 => Err(crate::Error::MapNotFound),
+            _ => Ok(vec![1]),
+        });
+        assert!(res.is_err());
+    }
+}
@@ -25,6 +25,9 @@ pub enum Error {
     /// eBPF-related error
     #[error(transparent)]
     BpfError(#[from] BpfError),
+    /// Error while pinning the program or maps.
+    #[error(transparent)]
+    PinError(#[from] aya::pin::PinError),
     /// IO error
     #[error(transparent)]
     IoError(#[from] io::Error),
@@ -37,11 +40,65 @@ pub enum Error {
     /// Used 0 as id number.
     #[error("Id number is not valid, must be greater than 0")]
     InvalidId,
+    /// Used 0 as mask for a mark classification.
+    #[error("Mark mask is not valid, must be greater than 0")]
+    InvalidMask,
+    /// Mark with none of the bits of its mask set, packets without a mark aren't classified by mark.
+    #[error("Mark is not valid, it must have at least one bit of the mask set")]
+    InvalidMark,
+    /// All slots for distinct mark masks are in use.
+    #[error("Maximum number of distinct mark masks reached")]
+    MarkMasksExhausted,
+    /// TCP flags predicate with a mask of 0 or flags outside the mask, or on a UDP port range
+    /// which has no TCP flags to match.
+    #[error("TCP flags predicate is not valid, mask must be non-zero, hold the flags and not UDP only")]
+    InvalidTcpFlags,
+    /// TTL predicate with an empty range.
+    #[error("TTL range is invalid")]
+    InvalidTtl,
+    /// DSCP predicate with a value that doesn't fit in 6 bits.
+    #[error("DSCP is not valid, must be lower than 64")]
+    InvalidDscp,
+    /// All slots for distinct header predicates are in use,
+    /// each combination of protocol, TCP flags, TTL and DSCP used by the rules takes one.
+    #[error("Maximum number of distinct header predicates reached")]
+    HeaderFiltersExhausted,
+    /// Line of an IP list that isn't a network or address, counting from 1.
+    #[error("Invalid entry in IP list at line {0}")]
+    InvalidIpList(usize),
+    /// Policy that couldn't be parsed, `line` counts from 1 and is `None` if the format doesn't tell.
+    #[error("Invalid policy: {message}")]
+    InvalidPolicy {
+        line: Option<usize>,
+        message: String,
+    },
+    /// Rule in the one-line syntax that couldn't be parsed, `column` of the offending token counts from 1.
+    #[error("Invalid rule at column {column}, {token:?}: {reason}")]
+    InvalidRuleSyntax {
+        token: String,
+        column: usize,
+        reason: String,
+    },
     /// Id doesn't exist in the classifier.
     #[error("Id not stored in classifier")]
     NotExistingId,
     #[error("Packet format is erroneous for logging")]
     LogFormatError,
+    /// Page count for the per-CPU log buffers that isn't a power of 2.
+    #[error("Log buffer page count must be a power of 2")]
+    InvalidPageCount,
+    /// Capture length above [MAX_CAPTURE_LEN](firewall_common::MAX_CAPTURE_LEN).
+    #[error("Capture length must be at most {}", firewall_common::MAX_CAPTURE_LEN)]
+    InvalidCaptureLength,
+    /// The logger can't be configured once it started reading packets.
+    #[error("Logging already started")]
+    LoggingStarted,
     #[error("Expected map not found")]
     MapNotFound,
+    /// A firewall is already pinned at the given path.
+    #[error("Firewall already pinned at path")]
+    AlreadyPinned,
+    /// No firewall pinned at the given path.
+    #[error("No firewall pinned at path")]
+    NotPinned,
 }
@@ -0,0 +1,342 @@
+use std::{
+    mem,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use firewall_common::{Action, PacketLog};
+use num_traits::FromPrimitive;
+use serde::{Serialize, Serializer};
+use uuid::Uuid;
+
+use crate::{Error, Result};
+
+// Target the tracing sink logs packets to
+const PACKET_LOG_TARGET: &str = "packet_log";
+const TCP: u8 = 0x06;
+
+/// Packet seen by the firewall along with the action it took, see [Firewall::packet_events](crate::Firewall::packet_events).
+///
+/// Serializes to the JSON object logged by [Firewall::start_logging](crate::Firewall::start_logging).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PacketEvent {
+    pub source_ip: IpAddr,
+    pub destination_ip: IpAddr,
+    /// `None` for protocols without ports.
+    pub destination_port: Option<u16>,
+    pub source_port: Option<u16>,
+    pub action: Action,
+    /// The packet was accepted only because of audit mode, `action` is what would've happened otherwise.
+    pub would_drop: bool,
+    pub protocol: u8,
+    /// `None` for protocols other than TCP.
+    pub tcp_flags: Option<u8>,
+    /// TTL for IPv4 or hop limit for IPv6.
+    pub ttl: u8,
+    pub dscp: u8,
+    /// Id the source was classified with, if any.
+    #[serde(rename = "uuid", serialize_with = "serialize_id")]
+    pub id: Option<u128>,
+    /// When userspace read the event.
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub timestamp: SystemTime,
+    /// Length of the whole packet, including its link layer header.
+    pub packet_len: u32,
+    /// Interface the packet went through.
+    pub ifindex: u32,
+    /// First bytes of the packet, empty unless enabled with [Firewall::set_capture_length](crate::Firewall::set_capture_length).
+    #[serde(skip)]
+    pub data: Vec<u8>,
+}
+
+impl PacketEvent {
+    /// Parses an event read from the event array, a [PacketLog] followed by the captured bytes.
+    pub(crate) fn parse(bytes: &[u8]) -> Result<Self> {
+        let header_len = mem::size_of::<PacketLog>();
+        if bytes.len() < header_len {
+            return Err(Error::LogFormatError);
+        }
+        // SAFETY: PacketLog is plain old data and `bytes` holds at least one
+        let log = unsafe { (bytes.as_ptr() as *const PacketLog).read_unaligned() };
+        let data = bytes
+            .get(header_len..header_len + log.captured_len as usize)
+            .ok_or(Error::LogFormatError)?;
+        Ok(Self {
+            data: data.to_vec(),
+            ..Self::try_from(log)?
+        })
+    }
+}
+
+impl TryFrom<PacketLog> for PacketEvent {
+    type Error = Error;
+
+    fn try_from(value: PacketLog) -> Result<Self> {
+        let destination_port = match value.dest_port {
+            0 => None,
+            x => Some(x),
+        };
+
+        let source_port = match value.src_port {
+            0 => None,
+            x => Some(x),
+        };
+        let tcp_flags = match value.proto {
+            TCP => Some(value.tcp_flags),
+            _ => None,
+        };
+        let action = Action::from_i32(value.action).ok_or(Error::LogFormatError)?;
+        let id = if value.class == [0; 16] {
+            None
+        } else {
+            Some(u128::from_le_bytes(value.class))
+        };
+
+        let (source_ip, destination_ip) = match value.version {
+            6 => (IpAddr::from(value.source), IpAddr::from(value.dest)),
+            4 => (to_ip(value.source), to_ip(value.dest)),
+            _ => return Err(Error::LogFormatError),
+        };
+        Ok(Self {
+            source_ip,
+            destination_ip,
+            destination_port,
+            source_port,
+            action,
+            would_drop: value.audit != 0,
+            protocol: value.proto,
+            tcp_flags,
+            ttl: value.ttl,
+            dscp: value.dscp,
+            id,
+            timestamp: SystemTime::now(),
+            packet_len: value.packet_len,
+            ifindex: value.ifindex,
+            data: Vec::new(),
+        })
+    }
+}
+
+fn to_ip(ip: [u8; 16]) -> IpAddr {
+    IpAddr::from([ip[0], ip[1], ip[2], ip[3]])
+}
+
+fn serialize_id<S: Serializer>(
+    id: &Option<u128>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    id.map(Uuid::from_u128).serialize(serializer)
+}
+
+fn serialize_timestamp<S: Serializer>(
+    timestamp: &SystemTime,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    chrono::DateTime::<chrono::Local>::from(*timestamp)
+        .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+        .serialize(serializer)
+}
+
+/// Receives every packet event read by the logger, see [Firewall::add_packet_sink](crate::Firewall::add_packet_sink).
+///
+/// Sinks are called from the tasks reading the events, a sink that blocks delays the events
+/// of every other sink and makes the eBPF program lose events. Closures taking a `&PacketEvent` are sinks.
+pub trait PacketSink: Send + Sync {
+    fn handle(&self, event: &PacketEvent);
+
+    /// Closed sinks are removed and don't receive any more events.
+    fn is_closed(&self) -> bool {
+        false
+    }
+}
+
+impl<F> PacketSink for F
+where
+    F: Fn(&PacketEvent) + Send + Sync,
+{
+    fn handle(&self, event: &PacketEvent) {
+        self(event)
+    }
+}
+
+/// Logs events as JSON to `info` level of the [tracing] crate, with the target `packet_log`.
+///
+/// This is the sink used by [Firewall::start_logging](crate::Firewall::start_logging).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TracingSink;
+
+impl PacketSink for TracingSink {
+    fn handle(&self, event: &PacketEvent) {
+        if let Ok(packet) = serde_json::to_string(event) {
+            tracing::info!(target: PACKET_LOG_TARGET, "{packet}");
+        }
+    }
+}
+
+/// Sends events to the receiver returned by [Firewall::packet_events](crate::Firewall::packet_events),
+/// events are dropped while the channel is full.
+pub(crate) struct ChannelSink(pub(crate) async_channel::Sender<PacketEvent>);
+
+impl PacketSink for ChannelSink {
+    fn handle(&self, event: &PacketEvent) {
+        let _ = self.0.try_send(event.clone());
+    }
+
+    fn is_closed(&self) -> bool {
+        self.0.is_closed()
+    }
+}
+
+/// Sinks registered in the logger, shared with the tasks reading the events.
+#[derive(Clone, Default)]
+pub(crate) struct Sinks(Arc<RwLock<Vec<Box<dyn PacketSink>>>>);
+
+impl Sinks {
+    pub(crate) fn add(&self, sink: impl PacketSink + 'static) {
+        self.0
+            .write()
+            .expect("packet sinks poisoned")
+            .push(Box::new(sink));
+    }
+
+    pub(crate) fn dispatch(&self, event: &PacketEvent) {
+        let mut closed = false;
+        for sink in self.0.read().expect("packet sinks poisoned").iter() {
+            if sink.is_closed() {
+                closed = true;
+                continue;
+            }
+            sink.handle(event);
+        }
+        if closed {
+            self.0
+                .write()
+                .expect("packet sinks poisoned")
+                .retain(|sink| !sink.is_closed());
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.0.read().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        mem,
+        net::IpAddr,
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+
+    use firewall_common::{Action, PacketLog};
+
+    use super::{ChannelSink, PacketEvent, Sinks};
+
+    fn packet_log() -> PacketLog {
+        PacketLog {
+            source: [10, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            dest: [10, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            action: 2,
+            dest_port: 443,
+            src_port: 0,
+            proto: 6,
+            version: 4,
+            class: 7u128.to_le_bytes(),
+            audit: 1,
+            tcp_flags: 0x02,
+            ttl: 64,
+            dscp: 0,
+            captured_len: 0,
+            packet_len: 60,
+            ifindex: 2,
+        }
+    }
+
+    #[test]
+    fn converts_packet_logs() {
+        let event = PacketEvent::try_from(packet_log()).unwrap();
+
+        assert_eq!(event.source_ip, "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(event.destination_ip, "10.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(event.destination_port, Some(443));
+        assert_eq!(event.source_port, None);
+        assert_eq!(event.action, Action::Reject);
+        assert!(event.would_drop);
+        assert_eq!(event.tcp_flags, Some(0x02));
+        assert_eq!(event.id, Some(7));
+
+        let invalid = PacketLog {
+            version: 5,
+            ..packet_log()
+        };
+        assert!(PacketEvent::try_from(invalid).is_err());
+    }
+
+    #[test]
+    fn parses_captured_bytes() {
+        let log = PacketLog {
+            captured_len: 4,
+            ..packet_log()
+        };
+        let mut bytes = unsafe {
+            std::slice::from_raw_parts(
+                &log as *const PacketLog as *const u8,
+                mem::size_of::<PacketLog>(),
+            )
+        }
+        .to_vec();
+        // Padding added by perf after the packet bytes
+        bytes.extend_from_slice(&[0x45, 0, 0, 60, 0, 0, 0, 0]);
+
+        let event = PacketEvent::parse(&bytes).unwrap();
+        assert_eq!(event.data, [0x45, 0, 0, 60]);
+        assert_eq!(event.packet_len, 60);
+        assert_eq!(event.ifindex, 2);
+
+        assert!(PacketEvent::parse(&bytes[..mem::size_of::<PacketLog>() + 2]).is_err());
+        assert!(PacketEvent::parse(&bytes[..10]).is_err());
+    }
+
+    #[test]
+    fn serializes_like_the_packet_log() {
+        let event = PacketEvent {
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(1500),
+            ..PacketEvent::try_from(packet_log()).unwrap()
+        };
+        let json = serde_json::to_value(&event).unwrap();
+
+        assert_eq!(json["uuid"], "00000000-0000-0000-0000-000000000007");
+        assert_eq!(
+            json["action"],
+            serde_json::to_value(Action::Reject).unwrap()
+        );
+        assert_eq!(json["source_port"], serde_json::Value::Null);
+        assert!(json["timestamp"].as_str().unwrap().contains(":01.500"));
+    }
+
+    #[test]
+    fn closed_sinks_are_removed() {
+        let sinks = Sinks::default();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink_seen = seen.clone();
+        sinks.add(move |event: &PacketEvent| sink_seen.lock().unwrap().push(event.id));
+        let (tx, rx) = async_channel::bounded(1);
+        sinks.add(ChannelSink(tx));
+
+        let event = PacketEvent::try_from(packet_log()).unwrap();
+        sinks.dispatch(&event);
+        // Full channel, the event is dropped
+        sinks.dispatch(&event);
+        assert_eq!(rx.try_recv().unwrap(), event);
+        assert!(rx.try_recv().is_err());
+
+        drop(rx);
+        sinks.dispatch(&event);
+        assert_eq!(sinks.len(), 1);
+        assert_eq!(*seen.lock().unwrap(), vec![Some(7); 3]);
+    }
+}
@@ -1,83 +1,292 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+#[cfg(feature = "pinning")]
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
 use aya::{
     include_bytes_aligned,
-    maps::LpmTrie,
-    programs::{tc, SchedClassifier, TcAttachType},
-    Bpf,
+    programs::{tc, SchedClassifier},
+    Bpf, BpfLoader,
 };
-use firewall_common::Action;
+use firewall_common::{Action, MAX_CAPTURE_LEN};
 use ipnet::IpNet;
 
 use crate::{
-    classifier::{ClassifierV4, ClassifierV6},
+    classifier::{ClassifierIface, IfIndex, Mark, MarkClassifier},
     config::ConfigHandler,
-    logger::Logger,
-    rule_tracker::{RuleTrackerV4, RuleTrackerV6},
-    Error::MapNotFound,
-    Result, Rule, RULE_MAP_IPV4, RULE_MAP_IPV6,
+    counters::{Counters, IdCounters},
+    event::{ChannelSink, PacketEvent, PacketSink},
+    hostname::{self, Change, HostnameRule, HostnameTracker, Resolution, Resolver, SystemResolver},
+    ip_list::IpLists,
+    link::{self, LinkType},
+    logger::{Logger, LoggerHealth},
+    policy::Policy,
+    ruleset::Ruleset,
+    sync::SyncReport,
+    tc::{AcceptVerdict, AttachOptions, TcFilter},
+    transaction::Transaction,
+    Error, Result, Rule, GENERATIONS, PROGRAM,
 };
 
+#[cfg(feature = "metrics")]
+use crate::metrics::Snapshot;
+#[cfg(feature = "pinning")]
+use crate::PINNED_OBJECTS;
+
+// Events kept for each receiver of `packet_events`
+const PACKET_EVENTS_CAPACITY: usize = 1024;
+
+/// Default path to pin the program and maps under, see [Firewall::new_pinned].
+#[cfg(feature = "pinning")]
+pub const DEFAULT_PIN_PATH: &str = "/sys/fs/bpf/firewall";
+
+// Firewalls loaded by this process with `new`, to give each one a directory of its own
+#[cfg(feature = "pinning")]
+static UNPINNED_LOADS: AtomicUsize = AtomicUsize::new(0);
+
 /// Represents a Firewall currently blocking/allowing packets.
 ///
 /// Packets will be dropped or accepted given the action set by [`set_default_action`](Firewall::set_default_action).
 /// Specific rules will invert this behavior for a given IP and optionally port range.
 ///
-/// Firewall can also log incoming packets using [tracing], currently hardcoded at `info` level by using [start_logging](Self::start_logging).
+/// Firewall can also log incoming packets using [tracing], currently hardcoded at `info` level by using [start_logging](Self::start_logging),
+/// or hand them to your own code with [packet_events](Self::packet_events) and [add_packet_sink](Self::add_packet_sink).
 ///
 /// See example at the [crate-level doc](crate#example).
 pub struct Firewall {
     bpf: Bpf,
-    rule_tracker_v4: RuleTrackerV4,
-    rule_tracker_v6: RuleTrackerV6,
-    classifier_v4: ClassifierV4,
-    classifier_v6: ClassifierV6,
+    ruleset: Ruleset,
+    // Ruleset replaced by the last `replace_ruleset`, its maps are cleared before they're used again
+    // since packets that got the previous generation might still be going through them
+    previous: Option<Ruleset>,
+    classifier_mark: MarkClassifier,
+    classifier_iface: ClassifierIface,
+    ip_lists: IpLists,
+    hostnames: HostnameTracker,
+    resolver: Arc<dyn Resolver>,
     logger: Logger,
     config: ConfigHandler,
+    // Detaches the program when dropped, `None` if it must outlive the firewall
+    _filter: Option<TcFilter>,
+    // `None` for firewalls created with `new`
+    #[cfg(feature = "pinning")]
+    pin_path: Option<PathBuf>,
 }
 
 impl Firewall {
     /// Creates a new [Firewall] for the given interface.
     ///
     /// The interface must already exist when calling this function.
+    /// Whether its packets have an Ethernet header is detected from the interface, see [LinkType].
     ///
     /// As soon as the [Firewall] is created it will start filtering packets.
     ///
+    /// The program and maps aren't pinned even with the `pinning` feature, the firewall stops when this is dropped.
+    /// Use [new_pinned](Self::new_pinned) to keep it running.
+    ///
     /// # Example
     /// ```no_run
     /// # use firewall::Firewall;
     /// let fw = Firewall::new("eth0").unwrap();
     /// ```
     pub fn new(iface: impl AsRef<str>) -> Result<Firewall> {
-        #[cfg(debug_assertions)]
-        let mut bpf = Bpf::load(include_bytes_aligned!(
-            "../../target/artifacts/bpfel-unknown-none/debug/firewall-ebpf"
-        ))?;
-        #[cfg(not(debug_assertions))]
-        let mut bpf = Bpf::load(include_bytes_aligned!(
-            "../../target/artifacts/bpfel-unknown-none/release/firewall-ebpf"
-        ))?;
-
-        // error adding clsact to the interface if it is already added is harmless
-        // the full cleanup can be done with 'sudo tc qdisc del dev eth0 clsact'.
-        let _ = tc::qdisc_add_clsact(iface.as_ref());
-        let program: &mut SchedClassifier = bpf.program_mut("ebpf_firewall").unwrap().try_into()?;
-        program.load()?;
-        program.attach(iface.as_ref(), TcAttachType::Ingress, 0)?;
-
-        let rule_tracker_v4 = RuleTrackerV4::new()?;
-        let rule_tracker_v6 = RuleTrackerV6::new()?;
-        let classifier_v4 = ClassifierV4::new()?;
-        let classifier_v6 = ClassifierV6::new()?;
+        Self::new_with_options(iface, AttachOptions::default())
+    }
+
+    /// Same as [new](Self::new) choosing the priority and handle of the tc filter, see [AttachOptions].
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{AttachOptions, Firewall};
+    /// // Run before filters with a higher priority value, e.g. a traffic shaper at priority 20
+    /// let options = AttachOptions {
+    ///     priority: 10,
+    ///     handle: 1,
+    /// };
+    /// let fw = Firewall::new_with_options("eth0", options).unwrap();
+    /// ```
+    pub fn new_with_options(iface: impl AsRef<str>, options: AttachOptions) -> Result<Firewall> {
+        Self::new_impl(iface.as_ref(), options)
+    }
+
+    #[cfg(not(feature = "pinning"))]
+    fn new_impl(iface: &str, options: AttachOptions) -> Result<Firewall> {
+        let mut bpf = load_bpf(&mut BpfLoader::new())?;
+        let filter = attach(&mut bpf, iface, options)?;
+        Self::with_bpf(bpf, Some(filter))
+    }
+
+    #[cfg(feature = "pinning")]
+    fn new_impl(iface: &str, options: AttachOptions) -> Result<Firewall> {
+        // The program always pins its maps by name, they're loaded in a directory no other firewall uses
+        // and unpinned right away so they go away with this firewall
+        let path = PathBuf::from(format!(
+            "{DEFAULT_PIN_PATH}-{}-{}",
+            std::process::id(),
+            UNPINNED_LOADS.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path)?;
+        let bpf = load_bpf(BpfLoader::new().map_pin_path(&path));
+        remove_pins(&path)?;
+        let mut bpf = bpf?;
+        let filter = attach(&mut bpf, iface, options)?;
+        Self::with_bpf(bpf, Some(filter), None)
+    }
+
+    /// Creates a new [Firewall] for the given interface pinning the program and all of its maps under `path`.
+    ///
+    /// `path` must be in a mounted bpffs and must not contain the pins of another firewall,
+    /// to pick up a running firewall use [open_pinned](Self::open_pinned) instead.
+    ///
+    /// The firewall keeps filtering packets with the latest configuration after this [Firewall] is dropped.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let fw = Firewall::new_pinned("eth0", "/sys/fs/bpf/firewall").unwrap();
+    /// ```
+    #[cfg(feature = "pinning")]
+    pub fn new_pinned(iface: impl AsRef<str>, path: impl AsRef<Path>) -> Result<Firewall> {
+        Self::new_pinned_with_options(iface, path, AttachOptions::default())
+    }
+
+    /// Same as [new_pinned](Self::new_pinned) choosing the priority and handle of the tc filter, see [AttachOptions].
+    #[cfg(feature = "pinning")]
+    pub fn new_pinned_with_options(
+        iface: impl AsRef<str>,
+        path: impl AsRef<Path>,
+        options: AttachOptions,
+    ) -> Result<Firewall> {
+        let path = path.as_ref();
+        if PINNED_OBJECTS.iter().any(|name| path.join(name).exists()) {
+            return Err(Error::AlreadyPinned);
+        }
+        std::fs::create_dir_all(path)?;
+
+        let mut bpf = load_bpf(BpfLoader::new().map_pin_path(path))?;
+        let filter = attach(&mut bpf, iface.as_ref(), options)?;
+        program(&mut bpf)?.pin(path.join(PROGRAM))?;
+        ConfigHandler::new()?.set_filter(&mut bpf, &filter)?;
+        // Pinned firewalls keep filtering after being dropped
+        filter.keep();
+        Self::with_bpf(bpf, None, Some(path))
+    }
+
+    /// Opens a [Firewall] previously pinned under `path` by [new_pinned](Self::new_pinned).
+    ///
+    /// The program is still attached to its interface, so filtering isn't interrupted.
+    /// Ids and rules are read back from the pinned maps, so they can be removed or extended as usual.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Firewall, Rule};
+    /// let mut fw = Firewall::open_pinned("/sys/fs/bpf/firewall").unwrap();
+    /// let rule = Rule::new("10.0.0.5/32".parse().unwrap());
+    /// fw.remove_rule(&rule).unwrap();
+    /// ```
+    #[cfg(feature = "pinning")]
+    pub fn open_pinned(path: impl AsRef<Path>) -> Result<Firewall> {
+        let path = path.as_ref();
+        if !path.join(PROGRAM).exists() {
+            return Err(Error::NotPinned);
+        }
+
+        // The pinned program is the one attached, we only need to pick up the maps
+        let bpf = load_bpf(BpfLoader::new().map_pin_path(path))?;
+        let mut firewall = Self::with_bpf(bpf, None, Some(path))?;
+        firewall.restore()?;
+        Ok(firewall)
+    }
+
+    /// Removes the pins of the program and maps, firewalls created with [new](Self::new) have none.
+    ///
+    /// The program stays attached until the interface's `clsact` qdisc is removed,
+    /// but the firewall can no longer be opened with [open_pinned](Self::open_pinned) once this [Firewall] is dropped.
+    #[cfg(feature = "pinning")]
+    pub fn unpin(&self) -> Result<()> {
+        let Some(pin_path) = &self.pin_path else {
+            return Ok(());
+        };
+        for name in PINNED_OBJECTS {
+            match std::fs::remove_file(pin_path.join(name)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Detaches the program from its interface and removes the pins, the firewall stops filtering right away.
+    ///
+    /// Unlike dropping, this works from any process that [opened](Self::open_pinned) the firewall.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let fw = Firewall::open_pinned("/sys/fs/bpf/firewall").unwrap();
+    /// fw.detach().unwrap();
+    /// ```
+    #[cfg(feature = "pinning")]
+    pub fn detach(self) -> Result<()> {
+        if let Some(filter) = self.config.filter(&self.bpf)? {
+            match filter.remove() {
+                // Already gone, e.g. the clsact qdisc was removed
+                Err(Error::IoError(e)) if e.raw_os_error() == Some(libc::ENOENT) => {}
+                res => res?,
+            }
+        }
+        self.unpin()
+    }
+
+    #[cfg(feature = "pinning")]
+    fn restore(&mut self) -> Result<()> {
+        let generation = self.config.generation(&self.bpf)?;
+        self.ruleset = Ruleset::new(generation)?;
+        self.ruleset.restore(&self.bpf)?;
+
+        // Leftovers of the ruleset replaced last or of an interrupted `replace_ruleset`
+        let mut shadow = Ruleset::new(shadow_generation(generation))?;
+        shadow.restore(&self.bpf)?;
+        shadow.clear(&mut self.bpf)?;
+
+        self.classifier_mark.restore(&self.bpf)?;
+        self.classifier_iface.restore(&self.bpf)?;
+        self.ip_lists.restore(&self.bpf)?;
+        Ok(())
+    }
+
+    fn with_bpf(
+        bpf: Bpf,
+        filter: Option<TcFilter>,
+        #[cfg(feature = "pinning")] pin_path: Option<&Path>,
+    ) -> Result<Firewall> {
+        let ruleset = Ruleset::new(0)?;
+        let classifier_mark = MarkClassifier::new()?;
+        let classifier_iface = ClassifierIface::new()?;
         let logger = Logger::new()?;
         let config = ConfigHandler::new()?;
 
         Ok(Self {
             bpf,
-            rule_tracker_v4,
-            rule_tracker_v6,
-            classifier_v4,
-            classifier_v6,
+            ruleset,
+            previous: None,
+            classifier_mark,
+            classifier_iface,
+            ip_lists: IpLists::new(),
+            hostnames: HostnameTracker::new(),
+            resolver: Arc::new(SystemResolver),
             logger,
             config,
+            _filter: filter,
+            #[cfg(feature = "pinning")]
+            pin_path: pin_path.map(Path::to_path_buf),
         })
     }
 
@@ -98,6 +307,89 @@ impl Firewall {
         self.config.set_default_action(&mut self.bpf, action)
     }
 
+    /// Sets the default action for packets classified with `id`, overriding the global one
+    /// set with [`set_default_action`](Firewall::set_default_action).
+    ///
+    /// Rules added for `id` invert this action, while rules added for all ids keep inverting the global default.
+    /// So with a global `Reject` and `Accept` for `id`, a rule for `id` drops matching packets of `id`
+    /// and a rule for all ids accepts matching packets of any id, `id` included.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Firewall, Action, Rule};
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.set_default_action(Action::Reject).unwrap();
+    /// fw.add_id("10.0.0.6/32".parse().unwrap(), 1).unwrap();
+    /// fw.set_default_action_for_id(1, Action::Accept).unwrap();
+    /// // Id 1 can reach anything but 10.0.1.0/24
+    /// fw.add_rule(&Rule::new("10.0.1.0/24".parse().unwrap()).with_id(1)).unwrap();
+    /// ```
+    pub fn set_default_action_for_id(&mut self, id: u128, action: Action) -> Result<()> {
+        self.config
+            .set_default_action_for_id(&mut self.bpf, id, Some(action))
+    }
+
+    /// Removes the default action set for `id`, packets of `id` go back to using the global default.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Firewall, Action};
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.set_default_action_for_id(1, Action::Accept).unwrap();
+    /// fw.clear_default_action_for_id(1).unwrap();
+    /// ```
+    pub fn clear_default_action_for_id(&mut self, id: u128) -> Result<()> {
+        self.config
+            .set_default_action_for_id(&mut self.bpf, id, None)
+    }
+
+    /// Sets the verdict given to accepted packets, by default [AcceptVerdict::Ok].
+    ///
+    /// With [AcceptVerdict::Unspec] accepted packets are passed on to
+    /// the filters attached after the firewall, see [AttachOptions] to order them.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{AcceptVerdict, Firewall};
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.set_accept_verdict(AcceptVerdict::Unspec).unwrap();
+    /// ```
+    pub fn set_accept_verdict(&mut self, verdict: AcceptVerdict) -> Result<()> {
+        self.config.set_accept_verdict(&mut self.bpf, verdict)
+    }
+
+    /// Enables or disables audit mode for the whole firewall.
+    ///
+    /// In audit mode every packet is accepted, but the action that would have been taken
+    /// is still computed and logged, packets that would've been dropped are flagged as `would_drop`.
+    /// Useful to try out a new policy without dropping anything.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.set_audit_mode(true).unwrap();
+    /// ```
+    pub fn set_audit_mode(&mut self, enabled: bool) -> Result<()> {
+        self.config.set_audit(&mut self.bpf, enabled)
+    }
+
+    /// Enables or disables audit mode only for packets classified with `id`.
+    ///
+    /// See [`set_audit_mode`](Firewall::set_audit_mode), packets of other ids keep being filtered
+    /// unless audit mode is enabled for the whole firewall.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.add_id("10.0.0.6/32".parse().unwrap(), 1).unwrap();
+    /// fw.set_audit_mode_for_id(1, true).unwrap();
+    /// ```
+    pub fn set_audit_mode_for_id(&mut self, id: u128, enabled: bool) -> Result<()> {
+        self.config.set_audit_for_id(&mut self.bpf, id, enabled)
+    }
+
     /// Adds a [Rule] for the firewall.
     ///
     /// The behavior of a rule is determined by the [`set_default_action`](Firewall::set_default_action).
@@ -110,16 +402,7 @@ impl Firewall {
     /// fw.add_rule(&rule).unwrap();
     /// ```
     pub fn add_rule(&mut self, rule: &Rule) -> Result<()> {
-        match &rule {
-            Rule::V4(r) => self.rule_tracker_v4.add_rule(
-                &mut LpmTrie::try_from(self.bpf.map_mut(RULE_MAP_IPV4).ok_or(MapNotFound)?)?,
-                r,
-            ),
-            Rule::V6(r) => self.rule_tracker_v6.add_rule(
-                &mut LpmTrie::try_from(self.bpf.map_mut(RULE_MAP_IPV6).ok_or(MapNotFound)?)?,
-                r,
-            ),
-        }
+        self.ruleset.add_rule(&mut self.bpf, rule)
     }
 
     /// Removes an existing [Rule] from the firewall.
@@ -133,16 +416,58 @@ impl Firewall {
     /// fw.remove_rule(&rule).unwrap();
     /// ```
     pub fn remove_rule(&mut self, rule: &Rule) -> Result<()> {
-        match &rule {
-            Rule::V4(r) => self.rule_tracker_v4.remove_rule(
-                &mut LpmTrie::try_from(self.bpf.map_mut(RULE_MAP_IPV4).ok_or(MapNotFound)?)?,
-                r,
-            ),
-            Rule::V6(r) => self.rule_tracker_v6.remove_rule(
-                &mut LpmTrie::try_from(self.bpf.map_mut(RULE_MAP_IPV6).ok_or(MapNotFound)?)?,
-                r,
-            ),
-        }
+        self.ruleset.remove_rule(&mut self.bpf, rule)
+    }
+
+    /// Returns the [Rule]s installed in the firewall as they were added, in no particular order.
+    ///
+    /// This includes the rules currently resolved from [HostnameRule]s.
+    /// After [open_pinned](Self::open_pinned) rules are rebuilt from the maps,
+    /// so they cover the same packets as the original ones but might be split differently.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Firewall, Rule};
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.add_rule(&Rule::new("10.0.0.5/32".parse().unwrap())).unwrap();
+    /// assert_eq!(fw.rules().len(), 1);
+    /// ```
+    pub fn rules(&self) -> Vec<Rule> {
+        let rules: HashSet<Rule> = self
+            .ruleset
+            .rules()
+            .into_iter()
+            .chain(self.hostnames.rules().cloned())
+            .collect();
+        rules.into_iter().collect()
+    }
+
+    /// Returns the rules that haven't matched any packet in the last `older_than`, including rules that never did.
+    ///
+    /// A rule counts as matched when a packet matched an entry holding any of its ranges,
+    /// that includes the entries of more specific rules its ranges were propagated to.
+    /// Rules that share an entry, e.g. rules for the same network, also share their matches.
+    /// Matches are kept when the ruleset is replaced, but the eBPF program tracks a limited
+    /// number of entries so the least recently matched might be forgotten.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use firewall::Firewall;
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// for rule in fw.stale_rules(Duration::from_secs(30 * 24 * 60 * 60)).unwrap() {
+    ///     fw.remove_rule(&rule).unwrap();
+    /// }
+    /// ```
+    pub fn stale_rules(&self, older_than: Duration) -> Result<Vec<Rule>> {
+        let threshold = monotonic_now()?.saturating_sub(older_than.as_nanos() as u64);
+        Ok(self
+            .ruleset
+            .last_hits(&self.bpf)?
+            .into_iter()
+            .filter(|(_, hit)| hit.map_or(true, |hit| hit < threshold))
+            .map(|(rule, _)| rule)
+            .collect())
     }
 
     /// Associates an `id` which is any `u128` except for 0 with a given IP.
@@ -162,10 +487,102 @@ impl Firewall {
     /// fw.add_rule(&rule).unwrap();
     /// ```
     pub fn add_id(&mut self, ip: IpNet, id: u128) -> Result<()> {
-        match ip {
-            IpNet::V4(ip) => self.classifier_v4.insert(&mut self.bpf, ip, id),
-            IpNet::V6(ip) => self.classifier_v6.insert(&mut self.bpf, ip, id),
-        }
+        self.ruleset.add_id(&mut self.bpf, ip, id)
+    }
+
+    /// Adds a [HostnameRule] to the firewall, resolving its hostname right away.
+    ///
+    /// A [Rule] is added for every address the hostname resolves to and the addresses are kept up to date
+    /// by [refresh_hostnames](Self::refresh_hostnames), see [HostnameRefresher](crate::HostnameRefresher)
+    /// to do that in the background.
+    ///
+    /// If the hostname can't be resolved no rule is added until a later refresh succeeds.
+    /// Rules added for a hostname are tracked apart from identical rules added through [add_rule](Self::add_rule),
+    /// removing one of them keeps the other in place.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Firewall, Protocol, Rule};
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// let rule = Rule::for_hostname("repo.example.com").with_range(443..=443, Protocol::TCP);
+    /// fw.add_hostname_rule(&rule).unwrap();
+    /// ```
+    pub fn add_hostname_rule(&mut self, rule: &HostnameRule) -> Result<()> {
+        self.hostnames.insert(rule, Instant::now());
+        self.refresh_hostnames().map(|_| ())
+    }
+
+    /// Removes a [HostnameRule] along with the rules added for its addresses.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Firewall, Rule};
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// let rule = Rule::for_hostname("repo.example.com");
+    /// fw.add_hostname_rule(&rule).unwrap();
+    /// fw.remove_hostname_rule(&rule).unwrap();
+    /// ```
+    pub fn remove_hostname_rule(&mut self, rule: &HostnameRule) -> Result<()> {
+        let (bpf, ruleset) = (&mut self.bpf, &mut self.ruleset);
+        self.hostnames
+            .remove(rule, |change| apply_change(bpf, ruleset, change))
+    }
+
+    /// Resolves again the hostnames of the [HostnameRule]s whose records expired,
+    /// adding and removing rules as their addresses change.
+    ///
+    /// Returns the time until the next hostname expires, `None` if there are no hostname rules.
+    /// If the rules of a hostname can't be updated it's retried later, the other hostnames are still
+    /// refreshed and the first error is returned.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Firewall, Rule};
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.add_hostname_rule(&Rule::for_hostname("repo.example.com")).unwrap();
+    /// if let Some(next) = fw.refresh_hostnames().unwrap() {
+    ///     std::thread::sleep(next);
+    ///     fw.refresh_hostnames().unwrap();
+    /// }
+    /// ```
+    pub fn refresh_hostnames(&mut self) -> Result<Option<Duration>> {
+        let now = Instant::now();
+        let resolutions = hostname::resolve(&*self.resolver, self.hostnames.due(now));
+        self.apply_resolutions(resolutions, now)
+    }
+
+    // Hostnames to refresh by `now` and the resolver to use, so they can be resolved without borrowing the firewall
+    pub(crate) fn due_hostnames(&self, now: Instant) -> (Vec<HostnameRule>, Arc<dyn Resolver>) {
+        (self.hostnames.due(now), self.resolver.clone())
+    }
+
+    // Second half of `refresh_hostnames`, `resolutions` were obtained at `now`
+    pub(crate) fn apply_resolutions(
+        &mut self,
+        resolutions: Vec<Resolution>,
+        now: Instant,
+    ) -> Result<Option<Duration>> {
+        let (bpf, ruleset) = (&mut self.bpf, &mut self.ruleset);
+        let next = self
+            .hostnames
+            .apply_resolutions(resolutions, now, |change| {
+                apply_change(bpf, ruleset, change)
+            })?;
+        Ok(next.map(|next| next.saturating_duration_since(Instant::now())))
+    }
+
+    /// Sets the [Resolver] used for [HostnameRule]s, by default [SystemResolver].
+    ///
+    /// Hostnames already resolved keep their addresses until they're refreshed.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Firewall, SystemResolver};
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.set_resolver(SystemResolver);
+    /// ```
+    pub fn set_resolver(&mut self, resolver: impl Resolver + 'static) {
+        self.resolver = Arc::new(resolver);
     }
 
     /// Removes the association between a given ip and its id.
@@ -178,13 +595,81 @@ impl Firewall {
     /// fw.remove_id(&"10.0.0.6/32".parse().unwrap()).unwrap();
     /// ```
     pub fn remove_id(&mut self, ip: &IpNet) -> Result<()> {
-        match ip {
-            IpNet::V4(ip) => self.classifier_v4.remove(&mut self.bpf, ip),
-            IpNet::V6(ip) => self.classifier_v6.remove(&mut self.bpf, ip),
-        }
+        self.ruleset.remove_id(&mut self.bpf, ip)
+    }
+
+    /// Associates an `id` with packets whose mark, masked by `mask`, equals `mark & mask`.
+    ///
+    /// This is useful when another component (e.g. an iptables mangle rule) already tagged the packets with an fwmark.
+    ///
+    /// Packets whose source IP is associated with an id through [add_id](Self::add_id) keep that id,
+    /// the mark is only used for packets with an unclassified source.
+    ///
+    /// Up to 8 distinct masks can be in use at the same time,
+    /// an exact match on the mark is done by using `u32::MAX` as mask.
+    /// Packets without a mark are never classified by it, so `mark & mask` can't be 0.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Firewall, Rule};
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// // Packets with the lower byte of the mark set to 0x10 belong to id 1
+    /// fw.add_id_by_mark(0x10, 0xff, 1).unwrap();
+    /// let rule = Rule::new("10.0.1.0/24".parse().unwrap()).with_id(1);
+    /// fw.add_rule(&rule).unwrap();
+    /// ```
+    pub fn add_id_by_mark(&mut self, mark: u32, mask: u32, id: u128) -> Result<()> {
+        self.classifier_mark
+            .insert(&mut self.bpf, Mark { mark, mask }, id)
+    }
+
+    /// Removes the association between a given mark and mask and its id.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.add_id_by_mark(0x10, 0xff, 1).unwrap();
+    /// fw.remove_id_by_mark(0x10, 0xff).unwrap();
+    /// ```
+    pub fn remove_id_by_mark(&mut self, mark: u32, mask: u32) -> Result<()> {
+        self.classifier_mark
+            .remove(&mut self.bpf, &Mark { mark, mask })
+    }
+
+    /// Associates an `id` with packets arriving through the interface `iface`.
+    ///
+    /// Packets classified either by source IP or by mark keep that id,
+    /// the interface is only used when neither of those matched.
+    ///
+    /// The interface must already exist when calling this function.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Firewall, Rule};
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.add_id_by_iface("eth0", 1).unwrap();
+    /// ```
+    pub fn add_id_by_iface(&mut self, iface: impl AsRef<str>, id: u128) -> Result<()> {
+        self.classifier_iface
+            .insert(&mut self.bpf, IfIndex::from_name(iface)?, id)
     }
 
-    /// Given the id removes all associated IPs
+    /// Removes the association between an interface and its id.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.add_id_by_iface("eth0", 1).unwrap();
+    /// fw.remove_id_by_iface("eth0").unwrap();
+    /// ```
+    pub fn remove_id_by_iface(&mut self, iface: impl AsRef<str>) -> Result<()> {
+        self.classifier_iface
+            .remove(&mut self.bpf, &IfIndex::from_name(iface)?)
+    }
+
+    /// Given the id removes all associated IPs, marks and interfaces
     ///
     /// # Example
     /// ```no_run
@@ -196,13 +681,378 @@ impl Firewall {
     /// fw.remove_by_id(1).unwrap();
     /// ```
     pub fn remove_by_id(&mut self, id: u128) -> Result<()> {
-        self.classifier_v4.remove_by_id(&mut self.bpf, id)?;
-        self.classifier_v6.remove_by_id(&mut self.bpf, id)?;
+        let removed = [
+            self.ruleset.classifier_v4.remove_by_id(&mut self.bpf, id),
+            self.ruleset.classifier_v6.remove_by_id(&mut self.bpf, id),
+            self.classifier_mark.remove_by_id(&mut self.bpf, id),
+            self.classifier_iface.remove_by_id(&mut self.bpf, id),
+        ];
+
+        let mut found = false;
+        for res in removed {
+            match res {
+                Ok(()) => found = true,
+                Err(Error::NotExistingId) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if found {
+            Ok(())
+        } else {
+            Err(Error::NotExistingId)
+        }
+    }
+
+    /// Returns every id associated with an ip, a mark or an interface, sorted.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.add_id("10.0.0.5/32".parse().unwrap(), 1).unwrap();
+    /// fw.add_id_by_iface("eth1", 2).unwrap();
+    /// assert_eq!(fw.ids(), vec![1, 2]);
+    /// ```
+    pub fn ids(&self) -> Vec<u128> {
+        let mut ids: Vec<_> = self
+            .ruleset
+            .ids()
+            .chain(self.classifier_mark.ids())
+            .chain(self.classifier_iface.ids())
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// Returns the ips associated with `id` using [add_id](Self::add_id), in no particular order.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.add_id("10.0.0.5/32".parse().unwrap(), 1).unwrap();
+    /// assert_eq!(fw.ips_for_id(1), vec!["10.0.0.5".parse::<std::net::IpAddr>().unwrap()]);
+    /// ```
+    pub fn ips_for_id(&self, id: u128) -> Vec<IpAddr> {
+        self.ruleset.ips_for_id(id)
+    }
+
+    /// Returns the id associated with `ip` using [add_id](Self::add_id), if any.
+    ///
+    /// Marks and interfaces aren't taken into account.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.add_id("10.0.0.5/32".parse().unwrap(), 1).unwrap();
+    /// assert_eq!(fw.id_for_ip("10.0.0.5".parse().unwrap()), Some(1));
+    /// ```
+    pub fn id_for_ip(&self, ip: IpAddr) -> Option<u128> {
+        self.ruleset.id_for_ip(ip)
+    }
+
+    /// Replaces all rules and ids associated with IPs by `rules` and `ids` in a single step.
+    ///
+    /// The new ruleset is built in a shadow set of maps while the current one keeps filtering packets,
+    /// once it's complete the eBPF program is switched over to it with a single map update,
+    /// so packets never see a partially applied ruleset. The previous maps are left as they are
+    /// for packets still going through them and cleared on the next replacement.
+    ///
+    /// If any rule or id can't be added the current ruleset is kept as is and the error is returned.
+    /// Failing to clear the maps left by the previous replacement is reported the same way,
+    /// the call can be retried.
+    ///
+    /// Ids associated with marks or interfaces aren't part of the ruleset and are kept as they are.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Firewall, Rule};
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// let rules = [
+    ///     Rule::new("10.0.0.5/32".parse().unwrap()),
+    ///     Rule::new("10.0.1.0/24".parse().unwrap()).with_id(1),
+    /// ];
+    /// let ids = [("10.0.0.6/32".parse().unwrap(), 1)];
+    /// fw.replace_ruleset(&rules, &ids).unwrap();
+    /// ```
+    pub fn replace_ruleset(&mut self, rules: &[Rule], ids: &[(IpNet, u128)]) -> Result<()> {
+        if let Some(previous) = &mut self.previous {
+            previous.clear(&mut self.bpf)?;
+            self.previous = None;
+        }
+        let mut ruleset = Ruleset::new(shadow_generation(self.ruleset.generation()))?;
+        // Rules of hostname rules are carried over, they aren't part of the given ones
+        let resolved = self.hostnames.rules();
+        if let Err(e) = fill_ruleset(&mut self.bpf, &mut ruleset, rules, resolved, ids) {
+            // Best effort, the shadow maps aren't in use
+            let _ = ruleset.clear(&mut self.bpf);
+            return Err(e);
+        }
+
+        self.config
+            .set_generation(&mut self.bpf, ruleset.generation())?;
+        self.previous = Some(std::mem::replace(&mut self.ruleset, ruleset));
+        Ok(())
+    }
+
+    /// Makes `policy` the configuration of the firewall.
+    ///
+    /// Rules and ids associated with IPs are replaced as a whole with [replace_ruleset](Self::replace_ruleset).
+    /// Ids associated with marks or interfaces and hostname rules are kept.
+    ///
+    /// The default action can't change along with the rules, so [Reject](Action::Reject) is kept in effect
+    /// in between: it's set before the rules when going to `Reject` and after them when going to
+    /// [Accept](Action::Accept), so only packets matching a rule are accepted in the meantime.
+    /// If the rules can't be replaced the default action is left as it was, if the default action can't be
+    /// set afterwards the new rules stay in place under `Reject`.
+    ///
+    /// The policy is checked before anything is changed, rules with an invalid port range give
+    /// [Error::InvalidPort] and ids of 0 give [Error::InvalidId]. Policies parsed from a file
+    /// have already been checked and [Error::InvalidPolicy] gives the line of the offending value.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Firewall, Policy};
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// let policy = Policy::from_json(&std::fs::read_to_string("policy.json").unwrap()).unwrap();
+    /// fw.apply_policy(&policy).unwrap();
+    /// ```
+    pub fn apply_policy(&mut self, policy: &Policy) -> Result<()> {
+        policy.check()?;
+        let current = self.config.default_action(&self.bpf)?;
+        self.with_default_action(current, policy.default_action, |fw| {
+            fw.replace_ruleset(&policy.rules, &policy.id_pairs())
+        })
+    }
+
+    // Runs `change` and goes from the `current` default action to `desired`, keeping `Reject` in effect in between
+    fn with_default_action(
+        &mut self,
+        current: Action,
+        desired: Action,
+        change: impl FnOnce(&mut Self) -> Result<()>,
+    ) -> Result<()> {
+        if current == Action::Accept && desired == Action::Reject {
+            self.set_default_action(desired)?;
+            if let Err(e) = change(self) {
+                // Best effort, nothing else was changed
+                let _ = self.set_default_action(current);
+                return Err(e);
+            }
+            return Ok(());
+        }
+
+        change(self)?;
+        if current != desired {
+            self.set_default_action(desired)?;
+        }
         Ok(())
     }
 
+    /// Brings the firewall to the `desired` policy with the fewest changes to rules and ids associated with IPs.
+    ///
+    /// Unlike [apply_policy](Self::apply_policy), rules and ids that are already installed are left untouched
+    /// and only the difference is applied, as a single [Transaction]. The changes are ordered so that no packet
+    /// is accepted in between unless either the previous or the desired state accepts it:
+    /// with a default [Reject](Action::Reject) rules and ids are removed before adding the new ones,
+    /// with a default [Accept](Action::Accept) they are added first.
+    /// The order assumes the global default action applies, see [set_default_action_for_id](Self::set_default_action_for_id).
+    ///
+    /// If the default action changes every rule changes meaning and there is no order that avoids accepting
+    /// packets neither state accepts. [Reject](Action::Reject) is then kept in effect in between as with
+    /// [apply_policy](Self::apply_policy), so only packets matching a rule are accepted in the meantime.
+    ///
+    /// Rules of hostname rules, ids associated with marks or interfaces are kept.
+    /// Rules restored by [open_pinned](Self::open_pinned) might be split differently than the desired ones,
+    /// in which case they are replaced.
+    ///
+    /// Returns what changed, nothing is changed if the policy is invalid or if applying the transaction fails.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Firewall, Policy};
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// let policy = Policy::from_json(&std::fs::read_to_string("policy.json").unwrap()).unwrap();
+    /// let report = fw.sync(&policy).unwrap();
+    /// println!("{} rules added, {} removed", report.rules_added.len(), report.rules_removed.len());
+    /// ```
+    pub fn sync(&mut self, desired: &Policy) -> Result<SyncReport> {
+        desired.check()?;
+        let default_action = self.config.default_action(&self.bpf)?;
+        let ids = self
+            .ruleset
+            .ids()
+            .flat_map(|id| {
+                self.ruleset
+                    .ips_for_id(id)
+                    .into_iter()
+                    .map(move |ip| (ip, id))
+            })
+            .collect();
+        let report = SyncReport::diff(&self.ruleset.rules(), &ids, default_action, desired);
+
+        let in_effect = match report.default_action {
+            Some(_) => Action::Reject,
+            None => default_action,
+        };
+        let operations = report.operations(in_effect);
+        self.with_default_action(default_action, desired.default_action, |fw| {
+            let mut tx = fw.transaction();
+            for operation in operations {
+                tx.push(operation);
+            }
+            tx.commit()
+        })?;
+        Ok(report)
+    }
+
+    /// Drops every packet from or to any network in `ips`, regardless of rules, ids and default actions.
+    ///
+    /// Meant for large lists such as threat feeds, see [IpList] to parse them.
+    /// Single addresses are stored in hash maps that hold up to 262144 entries per IP version
+    /// and are loaded in batches, other networks are stored by prefix with up to 16384 entries per IP version.
+    ///
+    /// Lists are checked before the rules and a network added to one list is taken off the other.
+    /// The blocklist takes precedence, a packet matching both lists is dropped even if the allowed network
+    /// is more specific, e.g. an allowed host within a blocked prefix.
+    /// Audit mode still applies, see [set_audit_mode](Self::set_audit_mode).
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Firewall, IpList};
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// let list: IpList = std::fs::read_to_string("drop.txt").unwrap().parse().unwrap();
+    /// fw.add_to_blocklist(list).unwrap();
+    /// ```
+    pub fn add_to_blocklist(&mut self, ips: impl IntoIterator<Item = IpNet>) -> Result<()> {
+        self.ip_lists.insert(&mut self.bpf, ips, Action::Reject)
+    }
+
+    /// Accepts every packet from or to any network in `ips`, regardless of rules, ids and default actions.
+    ///
+    /// See [add_to_blocklist](Self::add_to_blocklist), which takes precedence over this list.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.add_to_allowlist(["10.0.0.1/32".parse().unwrap()]).unwrap();
+    /// ```
+    pub fn add_to_allowlist(&mut self, ips: impl IntoIterator<Item = IpNet>) -> Result<()> {
+        self.ip_lists.insert(&mut self.bpf, ips, Action::Accept)
+    }
+
+    /// Removes `ips` from the blocklist or allowlist, networks not listed are ignored.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.add_to_blocklist(["192.0.2.0/24".parse().unwrap()]).unwrap();
+    /// fw.remove_from_ip_lists(["192.0.2.0/24".parse().unwrap()]).unwrap();
+    /// ```
+    pub fn remove_from_ip_lists(&mut self, ips: impl IntoIterator<Item = IpNet>) -> Result<()> {
+        self.ip_lists.remove(&mut self.bpf, ips)
+    }
+
+    /// Empties both the blocklist and allowlist.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.clear_ip_lists().unwrap();
+    /// ```
+    pub fn clear_ip_lists(&mut self) -> Result<()> {
+        self.ip_lists.clear(&mut self.bpf)
+    }
+
+    /// Overrides the [LinkType] detected for `iface`.
+    ///
+    /// The link type is detected when creating the [Firewall], interfaces with an unknown
+    /// hardware type are treated as [Ethernet](LinkType::Ethernet).
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Firewall, LinkType};
+    /// let mut fw = Firewall::new("wg0").unwrap();
+    /// fw.set_link_type("wg0", LinkType::RawIp).unwrap();
+    /// ```
+    pub fn set_link_type(&mut self, iface: impl AsRef<str>, link_type: LinkType) -> Result<()> {
+        link::set_link_type(&mut self.bpf, iface.as_ref(), link_type)
+    }
+
+    /// Starts a [Transaction] to apply several changes to rules and ids as a whole.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Firewall, Rule};
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// let mut tx = fw.transaction();
+    /// tx.add_rule(&Rule::new("10.0.0.5/32".parse().unwrap()))
+    ///     .add_rule(&Rule::new("10.0.0.6/32".parse().unwrap()));
+    /// tx.commit().unwrap();
+    /// ```
+    pub fn transaction(&mut self) -> Transaction<'_> {
+        Transaction::new(&mut self.bpf, &mut self.ruleset)
+    }
+
+    /// Reads how many packets the firewall has accepted or dropped and why.
+    ///
+    /// Counters start at 0 when the program is loaded and keep counting for pinned firewalls.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let fw = Firewall::new("eth0").unwrap();
+    /// let counters = fw.counters().unwrap();
+    /// println!("{} packets dropped by rules", counters.dropped_by_rule);
+    /// ```
+    pub fn counters(&self) -> Result<Counters> {
+        Counters::read(&self.bpf)
+    }
+
+    /// Reads how many packets were accepted or dropped for each source id.
+    ///
+    /// Only ids that have seen packets are returned. At most 4096 ids are counted, the least recently
+    /// seen one is evicted to count a new id and its counters start over from 0 if it's seen again,
+    /// so exported counters such as the Prometheus ones can reset.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let fw = Firewall::new("eth0").unwrap();
+    /// for (id, counters) in fw.id_counters().unwrap() {
+    ///     println!("{id}: {} packets dropped", counters.dropped);
+    /// }
+    /// ```
+    pub fn id_counters(&self) -> Result<HashMap<u128, IdCounters>> {
+        IdCounters::read(&self.bpf)
+    }
+
+    #[cfg(feature = "metrics")]
+    pub(crate) fn metrics(&self) -> Result<Snapshot> {
+        Ok(Snapshot {
+            counters: self.counters()?,
+            id_counters: self.id_counters()?,
+            rule_trie_v4: self.ruleset.rule_tracker_v4.trie_entries(),
+            rule_trie_v6: self.ruleset.rule_tracker_v6.trie_entries(),
+            classifier_v4: self.ruleset.classifier_v4.len(),
+            classifier_v6: self.ruleset.classifier_v6.len(),
+            classifier_mark: self.classifier_mark.len(),
+            classifier_iface: self.classifier_iface.len(),
+            logger: self.logger.health(),
+        })
+    }
+
     /// Starts logging incoming packets to `info` level fo the [tracing] crate.
     ///
+    /// With the `tokio` or `async-std` feature the packets are read in tasks of that runtime, so this must be called
+    /// from within it. Without either they're read by a thread that stops when the firewall is dropped.
+    ///
     /// # Example
     /// ```no_run
     /// # use firewall::Firewall;
@@ -210,6 +1060,187 @@ impl Firewall {
     /// fw.start_logging().unwrap();
     /// ```
     pub fn start_logging(&mut self) -> Result<()> {
-        self.logger.init(&mut self.bpf)
+        self.logger.log_to_tracing(&mut self.bpf)
+    }
+
+    /// Returns a channel receiving every packet the firewall sees, it also implements `futures::Stream`.
+    ///
+    /// Up to 1024 events are kept while the receiver falls behind, newer events are dropped for this receiver
+    /// until there's room again. Each call returns a new receiver that gets every event.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn example() -> firewall::Result<()> {
+    /// # use firewall::Firewall;
+    /// let mut fw = Firewall::new("eth0")?;
+    /// let events = fw.packet_events()?;
+    /// while let Ok(event) = events.recv().await {
+    ///     println!("{} -> {}: {:?}", event.source_ip, event.destination_ip, event.action);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn packet_events(&mut self) -> Result<async_channel::Receiver<PacketEvent>> {
+        let (tx, rx) = async_channel::bounded(PACKET_EVENTS_CAPACITY);
+        self.logger.add_sink(&mut self.bpf, ChannelSink(tx))?;
+        Ok(rx)
+    }
+
+    /// Copies the first `len` bytes of each logged packet into its [PacketEvent], 0 to stop copying them.
+    ///
+    /// The bytes start at the link layer header of packets that have one, see [LinkType].
+    /// `len` can be at most 2048, larger captures fill the log buffers sooner so consider
+    /// [set_log_page_count](Self::set_log_page_count) too. See [PcapngSink](crate::PcapngSink) to open them in Wireshark.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.set_capture_length(128).unwrap();
+    /// ```
+    pub fn set_capture_length(&mut self, len: u16) -> Result<()> {
+        if len > MAX_CAPTURE_LEN {
+            return Err(Error::InvalidCaptureLength);
+        }
+        self.config.set_capture_length(&mut self.bpf, len)
+    }
+
+    /// Sets the number of memory pages of the buffer each CPU logs packets to, it must be a power of 2.
+    ///
+    /// Packets are lost when a buffer fills up before it's read, see [logger_health](Self::logger_health).
+    /// Must be called before logging starts.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.set_log_page_count(64).unwrap();
+    /// fw.start_logging().unwrap();
+    /// ```
+    pub fn set_log_page_count(&mut self, page_count: usize) -> Result<()> {
+        self.logger.set_page_count(page_count)
+    }
+
+    /// Reports whether packets are still being logged, how many were lost and the errors that stopped the logger.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::Firewall;
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.start_logging().unwrap();
+    /// let health = fw.logger_health();
+    /// if !health.running {
+    ///     eprintln!("logger stopped: {:?}", health.errors);
+    /// }
+    /// ```
+    pub fn logger_health(&self) -> LoggerHealth {
+        self.logger.health()
+    }
+
+    /// Hands every packet the firewall sees to `sink`, see [PacketSink].
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use firewall::{Action, Firewall, PacketEvent};
+    /// let mut fw = Firewall::new("eth0").unwrap();
+    /// fw.add_packet_sink(|event: &PacketEvent| {
+    ///     if event.action == Action::Reject {
+    ///         eprintln!("dropped packet from {}", event.source_ip);
+    ///     }
+    /// })
+    /// .unwrap();
+    /// ```
+    pub fn add_packet_sink(&mut self, sink: impl PacketSink + 'static) -> Result<()> {
+        self.logger.add_sink(&mut self.bpf, sink)
+    }
+}
+
+// Nanoseconds in the clock `bpf_ktime_get_ns` uses
+fn monotonic_now() -> Result<u64> {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `now` is a valid timespec to write to
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64)
+}
+
+// Removes every pin under `path` and the directory itself, maps still in use by a loaded program remain
+#[cfg(feature = "pinning")]
+fn remove_pins(path: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(path)? {
+        std::fs::remove_file(entry?.path())?;
+    }
+    std::fs::remove_dir(path)?;
+    Ok(())
+}
+
+fn load_bpf(loader: &mut BpfLoader) -> Result<Bpf> {
+    #[cfg(debug_assertions)]
+    let bpf = loader.load(include_bytes_aligned!(
+        "../../target/artifacts/bpfel-unknown-none/debug/firewall-ebpf"
+    ))?;
+    #[cfg(not(debug_assertions))]
+    let bpf = loader.load(include_bytes_aligned!(
+        "../../target/artifacts/bpfel-unknown-none/release/firewall-ebpf"
+    ))?;
+    Ok(bpf)
+}
+
+fn attach(bpf: &mut Bpf, iface: &str, options: AttachOptions) -> Result<TcFilter> {
+    // error adding clsact to the interface if it is already added is harmless
+    // the full cleanup can be done with 'sudo tc qdisc del dev eth0 clsact'.
+    let _ = tc::qdisc_add_clsact(iface);
+    let link_type = match LinkType::detect(iface) {
+        Ok(Some(link_type)) => link_type,
+        Ok(None) => {
+            tracing::warn!("Unknown link type for {iface}, assuming Ethernet");
+            LinkType::Ethernet
+        }
+        Err(e) => {
+            tracing::warn!("Couldn't read the link type of {iface}, assuming Ethernet: {e}");
+            LinkType::Ethernet
+        }
+    };
+    link::set_link_type(bpf, iface, link_type)?;
+    let program = program(bpf)?;
+    program.load()?;
+    TcFilter::attach(program, PROGRAM, iface, options)
+}
+
+fn program(bpf: &mut Bpf) -> Result<&mut SchedClassifier> {
+    Ok(bpf.program_mut(PROGRAM).unwrap().try_into()?)
+}
+
+fn shadow_generation(generation: usize) -> usize {
+    (generation + 1) % GENERATIONS
+}
+
+fn fill_ruleset<'a>(
+    bpf: &mut Bpf,
+    ruleset: &mut Ruleset,
+    rules: &[Rule],
+    resolved: impl IntoIterator<Item = &'a Rule>,
+    ids: &[(IpNet, u128)],
+) -> Result<()> {
+    for (ip, id) in ids {
+        ruleset.add_id(bpf, *ip, *id)?;
+    }
+    for rule in rules {
+        ruleset.add_rule(bpf, rule)?;
+    }
+    for rule in resolved {
+        ruleset.add_resolved_rule(bpf, rule)?;
+    }
+    Ok(())
+}
+
+fn apply_change(bpf: &mut Bpf, ruleset: &mut Ruleset, change: Change) -> Result<()> {
+    match change {
+        Change::Add(rule) => ruleset.add_resolved_rule(bpf, &rule),
+        Change::Remove(rule) => ruleset.remove_resolved_rule(bpf, &rule),
     }
 }
@@ -0,0 +1,617 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, ToSocketAddrs},
+    ops::RangeInclusive,
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use ipnet::IpNet;
+
+use crate::{rule::RuleImpl, Firewall, Protocol, Result, Rule};
+
+// Answers are never cached for less than this, to avoid hammering the resolver
+const MIN_TTL: Duration = Duration::from_secs(5);
+// Time before retrying a hostname that couldn't be resolved
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+// Time before resolving again a hostname without addresses
+const NEGATIVE_TTL: Duration = Duration::from_secs(60);
+// Used by `SystemResolver`, which doesn't expose record TTLs
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+// Time the refresher waits when there are no hostname rules
+const IDLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Address resolved for a hostname along with the TTL of its record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Answer {
+    pub ip: IpAddr,
+    pub ttl: Duration,
+}
+
+/// Resolves the hostnames of [HostnameRule]s, see [Firewall::set_resolver].
+pub trait Resolver: Send + Sync {
+    /// Resolves `hostname` into its IPv4 and IPv6 addresses.
+    ///
+    /// Returns an empty list if the hostname exists but has no addresses,
+    /// errors are retried later keeping the addresses of the previous resolution.
+    fn resolve(&self, hostname: &str) -> io::Result<Vec<Answer>>;
+}
+
+/// [Resolver] using the system's resolver through [ToSocketAddrs].
+///
+/// The system's resolver doesn't expose the TTL of the records so every answer is refreshed after 60 seconds.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, hostname: &str) -> io::Result<Vec<Answer>> {
+        let ips: HashSet<_> = (hostname, 0).to_socket_addrs()?.map(|a| a.ip()).collect();
+        Ok(ips
+            .into_iter()
+            .map(|ip| Answer {
+                ip,
+                ttl: DEFAULT_TTL,
+            })
+            .collect())
+    }
+}
+
+/// Rule whose destination is given by a hostname, created with [Rule::for_hostname].
+///
+/// Once added with [Firewall::add_hostname_rule] the hostname is resolved and a [Rule] is added
+/// for every address, as a single host, with the same id, port range and header predicates as this rule.
+/// The hostname is resolved again when the TTL of its records expires, see [Firewall::refresh_hostnames].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HostnameRule {
+    hostname: String,
+    template: RuleImpl<()>,
+}
+
+impl HostnameRule {
+    pub(crate) fn new(hostname: impl Into<String>) -> Self {
+        Self {
+            hostname: hostname.into(),
+            template: RuleImpl::new(()),
+        }
+    }
+
+    /// Hostname of the rule.
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// Same as [Rule::with_id].
+    pub fn with_id(self, id: u128) -> Self {
+        Self {
+            template: self.template.with_id(id),
+            ..self
+        }
+    }
+
+    /// Same as [Rule::with_range].
+    pub fn with_range(self, range: RangeInclusive<u16>, proto: Protocol) -> Self {
+        Self {
+            template: self.template.with_range(range, proto),
+            ..self
+        }
+    }
+
+    /// Same as [Rule::with_ranges].
+    pub fn with_ranges(
+        self,
+        ranges: impl IntoIterator<Item = RangeInclusive<u16>>,
+        proto: Protocol,
+    ) -> Self {
+        Self {
+            template: self.template.with_ranges(ranges, proto),
+            ..self
+        }
+    }
+
+    /// Same as [Rule::with_tcp_flags].
+    pub fn with_tcp_flags(self, flags: u8, mask: u8) -> Self {
+        Self {
+            template: self.template.with_tcp_flags(flags, mask),
+            ..self
+        }
+    }
+
+    /// Same as [Rule::with_ttl].
+    pub fn with_ttl(self, ttl: RangeInclusive<u8>) -> Self {
+        Self {
+            template: self.template.with_ttl(ttl),
+            ..self
+        }
+    }
+
+    /// Same as [Rule::with_dscp].
+    pub fn with_dscp(self, dscp: u8) -> Self {
+        Self {
+            template: self.template.with_dscp(dscp),
+            ..self
+        }
+    }
+
+    // Rule for a single resolved address
+    fn rule_for(&self, ip: IpAddr) -> Rule {
+        match IpNet::from(ip) {
+            IpNet::V4(dest) => Rule::V4(self.template.clone().with_dest(dest)),
+            IpNet::V6(dest) => Rule::V6(self.template.clone().with_dest(dest)),
+        }
+    }
+}
+
+/// Hostname rule along with the answers for its hostname, see [resolve].
+pub(crate) type Resolution = (HostnameRule, io::Result<Vec<Answer>>);
+
+/// Change to the rules of the firewall needed to follow the answers for a hostname.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Change {
+    Add(Rule),
+    Remove(Rule),
+}
+
+#[derive(Debug, Clone)]
+struct Resolved {
+    ips: HashSet<IpAddr>,
+    refresh_at: Instant,
+}
+
+/// Keeps track of the hostname rules and the rules added for their addresses.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HostnameTracker {
+    rules: HashMap<HostnameRule, Resolved>,
+    // Number of hostname rules that need each of the added rules,
+    // different hostnames can resolve to the same address
+    concrete: HashMap<Rule, usize>,
+}
+
+impl HostnameTracker {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tracks `rule`, it's resolved on the next [refresh](Self::refresh).
+    pub(crate) fn insert(&mut self, rule: &HostnameRule, now: Instant) {
+        self.rules.entry(rule.clone()).or_insert(Resolved {
+            ips: HashSet::new(),
+            refresh_at: now,
+        });
+    }
+
+    /// Stops tracking `rule` removing the rules added for its addresses through `apply`.
+    pub(crate) fn remove(
+        &mut self,
+        rule: &HostnameRule,
+        apply: impl FnMut(Change) -> Result<()>,
+    ) -> Result<()> {
+        let Some(resolved) = self.rules.get_mut(rule) else {
+            return Ok(());
+        };
+        update(&mut self.concrete, rule, resolved, HashSet::new(), apply)?;
+        self.rules.remove(rule);
+        Ok(())
+    }
+
+    /// Rules added for the addresses of all hostname rules.
+    pub(crate) fn rules(&self) -> impl Iterator<Item = &Rule> {
+        self.concrete.keys()
+    }
+
+    /// Hostname rules whose answers expired by `now`.
+    pub(crate) fn due(&self, now: Instant) -> Vec<HostnameRule> {
+        self.rules
+            .iter()
+            .filter(|(_, resolved)| resolved.refresh_at <= now)
+            .map(|(rule, _)| rule.clone())
+            .collect()
+    }
+
+    /// Applies the answers of `resolutions`, obtained at `now`, to the rules through `apply`.
+    ///
+    /// New rules are added before removing stale ones. Hostnames that couldn't be resolved or whose rules
+    /// couldn't be updated are retried later without stopping the others, the first update error is returned.
+    /// Otherwise returns when the next hostname has to be refreshed.
+    pub(crate) fn apply_resolutions(
+        &mut self,
+        resolutions: Vec<Resolution>,
+        now: Instant,
+        mut apply: impl FnMut(Change) -> Result<()>,
+    ) -> Result<Option<Instant>> {
+        let mut res = Ok(());
+        for (rule, answers) in resolutions {
+            // Removed while it was being resolved
+            let Some(resolved) = self.rules.get_mut(&rule) else {
+                continue;
+            };
+
+            let answers = match answers {
+                Ok(answers) => answers,
+                Err(e) => {
+                    tracing::warn!("Couldn't resolve {}: {e}", rule.hostname);
+                    resolved.refresh_at = now + RETRY_INTERVAL;
+                    continue;
+                }
+            };
+            let ttl = answers
+                .iter()
+                .map(|a| a.ttl.max(MIN_TTL))
+                .min()
+                .unwrap_or(NEGATIVE_TTL);
+            let ips = answers.into_iter().map(|a| a.ip).collect();
+            let updated = update(&mut self.concrete, &rule, resolved, ips, &mut apply);
+            resolved.refresh_at = match updated {
+                Ok(()) => now + ttl,
+                Err(e) => {
+                    if res.is_ok() {
+                        res = Err(e);
+                    } else {
+                        tracing::warn!("Couldn't update the rules of {}: {e}", rule.hostname);
+                    }
+                    now + RETRY_INTERVAL
+                }
+            };
+        }
+
+        res.map(|()| self.rules.values().map(|r| r.refresh_at).min())
+    }
+}
+
+/// Resolves the hostnames of `rules`, see [HostnameTracker::due].
+///
+/// This doesn't need the [Firewall] so it can be done without holding it.
+pub(crate) fn resolve(resolver: &dyn Resolver, rules: Vec<HostnameRule>) -> Vec<Resolution> {
+    rules
+        .into_iter()
+        .map(|rule| {
+            let answers = resolver.resolve(&rule.hostname);
+            (rule, answers)
+        })
+        .collect()
+}
+
+// Moves the addresses of `rule` from `resolved.ips` to `ips`, on error `resolved` keeps the changes applied so far
+fn update(
+    concrete: &mut HashMap<Rule, usize>,
+    rule: &HostnameRule,
+    resolved: &mut Resolved,
+    ips: HashSet<IpAddr>,
+    mut apply: impl FnMut(Change) -> Result<()>,
+) -> Result<()> {
+    let added: Vec<_> = ips.difference(&resolved.ips).copied().collect();
+    for ip in added {
+        let concrete_rule = rule.rule_for(ip);
+        match concrete.get_mut(&concrete_rule) {
+            Some(count) => *count += 1,
+            None => {
+                apply(Change::Add(concrete_rule.clone()))?;
+                concrete.insert(concrete_rule, 1);
+            }
+        }
+        resolved.ips.insert(ip);
+    }
+
+    let removed: Vec<_> = resolved.ips.difference(&ips).copied().collect();
+    for ip in removed {
+        let concrete_rule = rule.rule_for(ip);
+        match concrete.get_mut(&concrete_rule) {
+            Some(count) if *count > 1 => *count -= 1,
+            _ => {
+                apply(Change::Remove(concrete_rule.clone()))?;
+                concrete.remove(&concrete_rule);
+            }
+        }
+        resolved.ips.remove(&ip);
+    }
+    Ok(())
+}
+
+/// Background thread that does what [Firewall::refresh_hostnames] does whenever a hostname has to be resolved again.
+///
+/// Hostnames are resolved without holding the lock on the [Firewall], it's only taken to update the rules.
+/// The thread is stopped when this is dropped.
+///
+/// # Example
+/// ```no_run
+/// # use std::sync::{Arc, Mutex};
+/// # use firewall::{Firewall, HostnameRefresher, Rule};
+/// let fw = Arc::new(Mutex::new(Firewall::new("eth0").unwrap()));
+/// fw.lock()
+///     .unwrap()
+///     .add_hostname_rule(&Rule::for_hostname("repo.example.com"))
+///     .unwrap();
+/// let refresher = HostnameRefresher::spawn(fw.clone());
+/// ```
+pub struct HostnameRefresher {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl HostnameRefresher {
+    /// Spawns the thread refreshing the hostname rules of `firewall`.
+    pub fn spawn(firewall: Arc<Mutex<Firewall>>) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+        let handle = std::thread::spawn(move || {
+            let (stopped, condvar) = &*thread_stop;
+            let mut wait = Duration::ZERO;
+            loop {
+                let guard = stopped.lock().expect("refresher stop flag poisoned");
+                let (guard, _) = condvar
+                    .wait_timeout_while(guard, wait, |stopped| !*stopped)
+                    .expect("refresher stop flag poisoned");
+                if *guard {
+                    return;
+                }
+                drop(guard);
+
+                let now = Instant::now();
+                let Ok((due, resolver)) = firewall.lock().map(|f| f.due_hostnames(now)) else {
+                    return;
+                };
+                let resolutions = resolve(&*resolver, due);
+                let Ok(mut firewall) = firewall.lock() else {
+                    return;
+                };
+                wait = match firewall.apply_resolutions(resolutions, now) {
+                    Ok(next) => next.unwrap_or(IDLE_INTERVAL),
+                    Err(e) => {
+                        tracing::warn!("Couldn't refresh hostname rules: {e}");
+                        RETRY_INTERVAL
+                    }
+                };
+            }
+        });
+
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for HostnameRefresher {
+    fn drop(&mut self) {
+        let (stopped, condvar) = &*self.stop;
+        if let Ok(mut stopped) = stopped.lock() {
+            *stopped = true;
+        }
+        condvar.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        io,
+        net::IpAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::{Duration, Instant},
+    };
+
+    use ipnet::IpNet;
+
+    use super::{resolve, Answer, Change, HostnameTracker, Resolver};
+    use crate::{Protocol, Rule};
+
+    // Answers with the addresses set for each hostname, `None` fails the resolution
+    #[derive(Default)]
+    struct StubResolver {
+        answers: Mutex<HashMap<String, Option<Vec<Answer>>>>,
+        calls: AtomicUsize,
+    }
+
+    impl StubResolver {
+        fn set(&self, hostname: &str, answers: Option<&[(&str, u64)]>) {
+            let answers = answers.map(|answers| {
+                answers
+                    .iter()
+                    .map(|(ip, ttl)| Answer {
+                        ip: ip.parse().unwrap(),
+                        ttl: Duration::from_secs(*ttl),
+                    })
+                    .collect()
+            });
+            self.answers
+                .lock()
+                .unwrap()
+                .insert(hostname.to_string(), answers);
+        }
+    }
+
+    impl Resolver for StubResolver {
+        fn resolve(&self, hostname: &str) -> io::Result<Vec<Answer>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.answers
+                .lock()
+                .unwrap()
+                .get(hostname)
+                .cloned()
+                .flatten()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "stub failure"))
+        }
+    }
+
+    fn refresh(
+        tracker: &mut HostnameTracker,
+        resolver: &StubResolver,
+        now: Instant,
+    ) -> (Vec<Change>, Option<Instant>) {
+        let mut changes = Vec::new();
+        let resolutions = resolve(resolver, tracker.due(now));
+        let next = tracker
+            .apply_resolutions(resolutions, now, |change| {
+                changes.push(change);
+                Ok(())
+            })
+            .unwrap();
+        (changes, next)
+    }
+
+    fn host_rule(ip: &str) -> Rule {
+        Rule::new(IpNet::from(ip.parse::<IpAddr>().unwrap()))
+            .with_id(1)
+            .with_range(443..=443, Protocol::TCP)
+    }
+
+    #[test]
+    fn resolved_addresses_are_added_as_host_rules() {
+        let resolver = StubResolver::default();
+        resolver.set(
+            "repo.example.com",
+            Some(&[("10.0.0.1", 300), ("fd00::1", 60)]),
+        );
+        let rule = Rule::for_hostname("repo.example.com")
+            .with_id(1)
+            .with_range(443..=443, Protocol::TCP);
+        let mut tracker = HostnameTracker::new();
+        let now = Instant::now();
+        tracker.insert(&rule, now);
+
+        let (mut changes, next) = refresh(&mut tracker, &resolver, now);
+        changes.sort_by_key(|c| format!("{c:?}"));
+        let mut expected = vec![
+            Change::Add(host_rule("10.0.0.1")),
+            Change::Add(host_rule("fd00::1")),
+        ];
+        expected.sort_by_key(|c| format!("{c:?}"));
+        assert_eq!(changes, expected);
+        assert_eq!(next, Some(now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn refresh_respects_ttl() {
+        let resolver = StubResolver::default();
+        resolver.set("repo.example.com", Some(&[("10.0.0.1", 300)]));
+        let mut tracker = HostnameTracker::new();
+        let now = Instant::now();
+        tracker.insert(&Rule::for_hostname("repo.example.com"), now);
+
+        refresh(&mut tracker, &resolver, now);
+        let (changes, _) = refresh(&mut tracker, &resolver, now + Duration::from_secs(299));
+        assert!(changes.is_empty());
+        assert_eq!(resolver.calls.load(Ordering::SeqCst), 1);
+
+        refresh(&mut tracker, &resolver, now + Duration::from_secs(300));
+        assert_eq!(resolver.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn changed_answers_add_before_removing() {
+        let resolver = StubResolver::default();
+        resolver.set("repo.example.com", Some(&[("10.0.0.1", 30)]));
+        let rule = Rule::for_hostname("repo.example.com")
+            .with_id(1)
+            .with_range(443..=443, Protocol::TCP);
+        let mut tracker = HostnameTracker::new();
+        let now = Instant::now();
+        tracker.insert(&rule, now);
+        refresh(&mut tracker, &resolver, now);
+
+        resolver.set("repo.example.com", Some(&[("10.0.0.2", 30)]));
+        let (changes, _) = refresh(&mut tracker, &resolver, now + Duration::from_secs(30));
+        assert_eq!(
+            changes,
+            vec![
+                Change::Add(host_rule("10.0.0.2")),
+                Change::Remove(host_rule("10.0.0.1")),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolver_error_keeps_previous_rules() {
+        let resolver = StubResolver::default();
+        resolver.set("repo.example.com", Some(&[("10.0.0.1", 30)]));
+        let mut tracker = HostnameTracker::new();
+        let now = Instant::now();
+        tracker.insert(&Rule::for_hostname("repo.example.com"), now);
+        refresh(&mut tracker, &resolver, now);
+
+        resolver.set("repo.example.com", None);
+        let later = now + Duration::from_secs(30);
+        let (changes, next) = refresh(&mut tracker, &resolver, later);
+        assert!(changes.is_empty());
+        assert_eq!(next, Some(later + super::RETRY_INTERVAL));
+        assert_eq!(tracker.rules().count(), 1);
+    }
+
+    #[test]
+    fn shared_addresses_are_added_once() {
+        let resolver = StubResolver::default();
+        resolver.set("a.example.com", Some(&[("10.0.0.1", 30)]));
+        resolver.set("b.example.com", Some(&[("10.0.0.1", 30)]));
+        let a = Rule::for_hostname("a.example.com");
+        let b = Rule::for_hostname("b.example.com");
+        let mut tracker = HostnameTracker::new();
+        let now = Instant::now();
+        tracker.insert(&a, now);
+        tracker.insert(&b, now);
+
+        let (changes, _) = refresh(&mut tracker, &resolver, now);
+        assert_eq!(changes.len(), 1);
+
+        let mut changes = Vec::new();
+        tracker
+            .remove(&a, |change| {
+                changes.push(change);
+                Ok(())
+            })
+            .unwrap();
+        assert!(changes.is_empty());
+
+        tracker
+            .remove(&b, |change| {
+                changes.push(change);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            changes,
+            vec![Change::Remove(Rule::new("10.0.0.1/32".parse().unwrap()))]
+        );
+    }
+
+    #[test]
+    fn failed_update_is_retried_without_stopping_others() {
+        let resolver = StubResolver::default();
+        resolver.set("a.example.com", Some(&[("10.0.0.1", 300)]));
+        resolver.set("b.example.com", Some(&[("10.0.0.2", 300)]));
+        let mut tracker = HostnameTracker::new();
+        let now = Instant::now();
+        tracker.insert(&Rule::for_hostname("a.example.com"), now);
+        tracker.insert(&Rule::for_hostname("b.example.com"), now);
+
+        let failing = Change::Add(Rule::new("10.0.0.1/32".parse().unwrap()));
+        let mut changes = Vec::new();
+        let resolutions = resolve(&resolver, tracker.due(now));
+        let res = tracker.apply_resolutions(resolutions, now, |change| {
+            if change == failing {
+                return Err(crate::Error::MapNotFound);
+            }
+            changes.push(change);
+            Ok(())
+        });
+        assert!(res.is_err());
+        assert_eq!(
+            changes,
+            vec![Change::Add(Rule::new("10.0.0.2/32".parse().unwrap()))]
+        );
+
+        // Only the failed hostname is due again, after the retry interval rather than its TTL
+        let later = now + super::RETRY_INTERVAL;
+        let (changes, next) = refresh(&mut tracker, &resolver, later);
+        assert_eq!(changes, vec![failing]);
+        assert_eq!(resolver.calls.load(Ordering::SeqCst), 3);
+        assert_eq!(next, Some(now + Duration::from_secs(300)));
+    }
+}
@@ -0,0 +1,678 @@
+use std::{
+    collections::HashMap,
+    io, mem,
+    os::unix::io::{AsRawFd, RawFd},
+    str::FromStr,
+};
+
+use aya::{
+    maps::{lpm_trie::Key, HashMap as BpfHashMap, LpmTrie, Map, MapError},
+    Bpf, Pod,
+};
+use firewall_common::Action;
+use ipnet::IpNet;
+
+use crate::{
+    as_octet::AsOctets, Error, Result, LISTED_IPS_IPV4, LISTED_IPS_IPV6, LISTED_PREFIXES_IPV4,
+    LISTED_PREFIXES_IPV6,
+};
+
+// Not exported by libc
+const BPF_MAP_UPDATE_BATCH: libc::c_long = 26;
+const ENOTSUPP: i32 = 524;
+
+// Entries written per batch syscall
+const BATCH_SIZE: usize = 8192;
+
+/// List of IP networks, e.g. a threat feed, to use with [add_to_blocklist](crate::Firewall::add_to_blocklist)
+/// or [add_to_allowlist](crate::Firewall::add_to_allowlist).
+///
+/// Can be parsed from a list with one network per line, addresses without a prefix length are taken as a single host.
+/// Empty lines and anything after `#` or `;` are ignored, so lists in the Spamhaus DROP format
+/// (`1.10.16.0/20 ; SBL256894`) are accepted as well.
+///
+/// # Example
+/// ```
+/// # use firewall::IpList;
+/// let list: IpList = "; Spamhaus DROP List\n1.10.16.0/20 ; SBL256894\n192.0.2.1 # single host\n"
+///     .parse()
+///     .unwrap();
+/// assert_eq!(list.len(), 2);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpList(Vec<IpNet>);
+
+impl IpList {
+    /// Number of networks in the list.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether the list has no networks.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterates over the networks in the list.
+    pub fn iter(&self) -> impl Iterator<Item = &IpNet> {
+        self.0.iter()
+    }
+}
+
+impl FromStr for IpList {
+    type Err = Error;
+
+    /// Fails with [InvalidIpList](Error::InvalidIpList) holding the first line, counting from 1, that can't be parsed.
+    fn from_str(list: &str) -> Result<Self> {
+        let mut ips = Vec::new();
+        for (i, line) in list.lines().enumerate() {
+            let entry = line.split(['#', ';']).next().unwrap_or_default().trim();
+            if entry.is_empty() {
+                continue;
+            }
+            let ip = entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<std::net::IpAddr>().map(IpNet::from))
+                .map_err(|_| Error::InvalidIpList(i + 1))?;
+            ips.push(ip);
+        }
+        Ok(Self(ips))
+    }
+}
+
+impl FromIterator<IpNet> for IpList {
+    fn from_iter<I: IntoIterator<Item = IpNet>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl IntoIterator for IpList {
+    type Item = IpNet;
+    type IntoIter = std::vec::IntoIter<IpNet>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a IpList {
+    type Item = &'a IpNet;
+    type IntoIter = std::slice::Iter<'a, IpNet>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+/// Maps holding the listed networks, tests replace the ones of [Bpf].
+pub(crate) trait ListMaps {
+    /// Writes `ips` to the list of `action`, networks must be truncated.
+    fn insert(&mut self, ips: &[IpNet], action: Action) -> Result<()>;
+
+    /// Removes `ips` from the list of `action`, networks that aren't in the maps are ignored.
+    fn remove(&mut self, ips: &[IpNet], action: Action) -> Result<()>;
+}
+
+impl ListMaps for Bpf {
+    fn insert(&mut self, ips: &[IpNet], action: Action) -> Result<()> {
+        insert_entries(self, ips, action)
+    }
+
+    fn remove(&mut self, ips: &[IpNet], action: Action) -> Result<()> {
+        remove_entries(self, ips, action)
+    }
+}
+
+/// Networks blocked or allowed before looking at the rules.
+///
+/// Single hosts are stored in hash maps, which can hold many more entries than a trie,
+/// the rest of the networks are stored in a trie by prefix.
+/// Each list has its own maps, the program checks the blocklist first so a blocked network
+/// wins over any allowed one it contains or is contained in.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IpLists {
+    entries: HashMap<IpNet, Action>,
+}
+
+impl IpLists {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lists `ips` with `action`, networks already on the other list are moved to this one.
+    ///
+    /// If writing fails the networks are put back as they were, so the maps only hold tracked entries.
+    pub(crate) fn insert(
+        &mut self,
+        maps: &mut impl ListMaps,
+        ips: impl IntoIterator<Item = IpNet>,
+        action: Action,
+    ) -> Result<()> {
+        let ips: Vec<_> = ips
+            .into_iter()
+            .map(|ip| ip.trunc())
+            .filter(|ip| self.entries.get(ip) != Some(&action))
+            .collect();
+        // Anything listed is on the other list, they're only removed from it once they're on this one
+        let moved: Vec<_> = ips
+            .iter()
+            .filter(|ip| self.entries.contains_key(*ip))
+            .copied()
+            .collect();
+
+        let res = maps
+            .insert(&ips, action)
+            .and_then(|()| maps.remove(&moved, other(action)));
+        if let Err(e) = res {
+            // Part of the entries might have been written before the error
+            return self.rollback(maps, &ips, action).and(Err(e));
+        }
+        self.entries.extend(ips.into_iter().map(|ip| (ip, action)));
+        Ok(())
+    }
+
+    // Takes `ips` off the list of `action`, writing back the ones listed on the other list first
+    fn rollback(&self, maps: &mut impl ListMaps, ips: &[IpNet], action: Action) -> Result<()> {
+        let listed: Vec<_> = ips
+            .iter()
+            .filter(|ip| self.entries.contains_key(*ip))
+            .copied()
+            .collect();
+        maps.insert(&listed, other(action))?;
+        maps.remove(ips, action)
+    }
+
+    /// Removes `ips` from the lists, networks that aren't listed are ignored.
+    pub(crate) fn remove(
+        &mut self,
+        maps: &mut impl ListMaps,
+        ips: impl IntoIterator<Item = IpNet>,
+    ) -> Result<()> {
+        let ips: Vec<_> = ips
+            .into_iter()
+            .map(|ip| ip.trunc())
+            .filter(|ip| self.entries.contains_key(ip))
+            .collect();
+        // Entries stay tracked until they're all removed, removing them again is harmless
+        self.remove_listed(maps, &ips)?;
+        for ip in &ips {
+            self.entries.remove(ip);
+        }
+        Ok(())
+    }
+
+    /// Removes every listed network.
+    pub(crate) fn clear(&mut self, maps: &mut impl ListMaps) -> Result<()> {
+        let ips: Vec<_> = self.entries.keys().copied().collect();
+        self.remove_listed(maps, &ips)?;
+        self.entries.clear();
+        Ok(())
+    }
+
+    // Removes `ips` from the list each one is tracked on
+    fn remove_listed(&self, maps: &mut impl ListMaps, ips: &[IpNet]) -> Result<()> {
+        for action in [Action::Reject, Action::Accept] {
+            let listed: Vec<_> = ips
+                .iter()
+                .filter(|ip| self.entries[*ip] == action)
+                .copied()
+                .collect();
+            maps.remove(&listed, action)?;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the listed networks from the maps.
+    #[cfg(feature = "pinning")]
+    pub(crate) fn restore(&mut self, bpf: &Bpf) -> Result<()> {
+        use std::net::{Ipv4Addr, Ipv6Addr};
+
+        use crate::LISTS;
+
+        for list in 0..LISTS {
+            let hosts: BpfHashMap<_, [u8; 4], i32> =
+                BpfHashMap::try_from(bpf.map(LISTED_IPS_IPV4[list]).ok_or(Error::MapNotFound)?)?;
+            for entry in hosts.iter() {
+                let (ip, action) = entry?;
+                self.restore_entry(Ipv4Addr::from(ip), 32, action);
+            }
+            let hosts: BpfHashMap<_, [u8; 16], i32> =
+                BpfHashMap::try_from(bpf.map(LISTED_IPS_IPV6[list]).ok_or(Error::MapNotFound)?)?;
+            for entry in hosts.iter() {
+                let (ip, action) = entry?;
+                self.restore_entry(Ipv6Addr::from(ip), 128, action);
+            }
+
+            let prefixes: LpmTrie<_, [u8; 4], i32> = LpmTrie::try_from(
+                bpf.map(LISTED_PREFIXES_IPV4[list])
+                    .ok_or(Error::MapNotFound)?,
+            )?;
+            for entry in prefixes.iter() {
+                let (key, action) = entry?;
+                self.restore_entry(Ipv4Addr::from(key.data), key.prefix_len, action);
+            }
+            let prefixes: LpmTrie<_, [u8; 16], i32> = LpmTrie::try_from(
+                bpf.map(LISTED_PREFIXES_IPV6[list])
+                    .ok_or(Error::MapNotFound)?,
+            )?;
+            for entry in prefixes.iter() {
+                let (key, action) = entry?;
+                self.restore_entry(Ipv6Addr::from(key.data), key.prefix_len, action);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "pinning")]
+    fn restore_entry(&mut self, ip: impl Into<std::net::IpAddr>, prefix_len: u32, action: i32) {
+        use num_traits::FromPrimitive;
+
+        // Entries are only written by `insert`, so both are always valid
+        let Ok(ip) = IpNet::new(ip.into(), prefix_len as u8) else {
+            return;
+        };
+        let action = Action::from_i32(action).unwrap_or_default();
+        self.entries.insert(ip, action);
+    }
+}
+
+#[derive(Default)]
+struct ByVersion<T, U> {
+    v4: Vec<T>,
+    v6: Vec<U>,
+}
+
+type Hosts = ByVersion<[u8; 4], [u8; 16]>;
+type Prefixes = ByVersion<ipnet::Ipv4Net, ipnet::Ipv6Net>;
+
+// Splits single hosts from the rest of the networks
+fn split(ips: &[IpNet]) -> (Hosts, Prefixes) {
+    let mut hosts = Hosts::default();
+    let mut prefixes = Prefixes::default();
+    for ip in ips {
+        match ip {
+            IpNet::V4(ip) if ip.prefix_len() == ip.max_prefix_len() => {
+                hosts.v4.push(ip.as_octets())
+            }
+            IpNet::V6(ip) if ip.prefix_len() == ip.max_prefix_len() => {
+                hosts.v6.push(ip.as_octets())
+            }
+            IpNet::V4(ip) => prefixes.v4.push(*ip),
+            IpNet::V6(ip) => prefixes.v6.push(*ip),
+        }
+    }
+    (hosts, prefixes)
+}
+
+// Action of the other list, there's only the blocklist and the allowlist
+fn other(action: Action) -> Action {
+    match action {
+        Action::Accept => Action::Reject,
+        Action::Reject => Action::Accept,
+    }
+}
+
+// Index of the maps of the list holding networks with `action`
+fn list(action: Action) -> usize {
+    match action {
+        Action::Reject => 0,
+        Action::Accept => 1,
+    }
+}
+
+fn insert_entries(bpf: &mut Bpf, ips: &[IpNet], action: Action) -> Result<()> {
+    let (hosts, prefixes) = split(ips);
+    let list = list(action);
+    batch_insert(bpf, LISTED_IPS_IPV4[list], &hosts.v4, action as i32)?;
+    batch_insert(bpf, LISTED_IPS_IPV6[list], &hosts.v6, action as i32)?;
+    let mut trie = LpmTrie::try_from(
+        bpf.map_mut(LISTED_PREFIXES_IPV4[list])
+            .ok_or(Error::MapNotFound)?,
+    )?;
+    for ip in &prefixes.v4 {
+        trie.insert(
+            &Key::new(ip.prefix_len() as u32, ip.as_octets()),
+            action as i32,
+            0,
+        )?;
+    }
+    let mut trie = LpmTrie::try_from(
+        bpf.map_mut(LISTED_PREFIXES_IPV6[list])
+            .ok_or(Error::MapNotFound)?,
+    )?;
+    for ip in &prefixes.v6 {
+        trie.insert(
+            &Key::new(ip.prefix_len() as u32, ip.as_octets()),
+            action as i32,
+            0,
+        )?;
+    }
+    Ok(())
+}
+
+fn remove_entries(bpf: &mut Bpf, ips: &[IpNet], action: Action) -> Result<()> {
+    let (hosts, prefixes) = split(ips);
+    let list = list(action);
+    let mut map = BpfHashMap::try_from(
+        bpf.map_mut(LISTED_IPS_IPV4[list])
+            .ok_or(Error::MapNotFound)?,
+    )?;
+    for ip in &hosts.v4 {
+        ignore_not_found(map.remove(ip))?;
+    }
+    let mut map = BpfHashMap::try_from(
+        bpf.map_mut(LISTED_IPS_IPV6[list])
+            .ok_or(Error::MapNotFound)?,
+    )?;
+    for ip in &hosts.v6 {
+        ignore_not_found(map.remove(ip))?;
+    }
+    let mut trie: LpmTrie<_, [u8; 4], i32> = LpmTrie::try_from(
+        bpf.map_mut(LISTED_PREFIXES_IPV4[list])
+            .ok_or(Error::MapNotFound)?,
+    )?;
+    for ip in &prefixes.v4 {
+        ignore_not_found(trie.remove(&Key::new(ip.prefix_len() as u32, ip.as_octets())))?;
+    }
+    let mut trie: LpmTrie<_, [u8; 16], i32> = LpmTrie::try_from(
+        bpf.map_mut(LISTED_PREFIXES_IPV6[list])
+            .ok_or(Error::MapNotFound)?,
+    )?;
+    for ip in &prefixes.v6 {
+        ignore_not_found(trie.remove(&Key::new(ip.prefix_len() as u32, ip.as_octets())))?;
+    }
+    Ok(())
+}
+
+fn ignore_not_found(res: std::result::Result<(), MapError>) -> Result<()> {
+    match res {
+        Ok(()) | Err(MapError::KeyNotFound) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Inserts all `keys` with `value` using batched updates,
+// falls back to one update per key on kernels without batch support for hash maps
+fn batch_insert<K: Pod>(bpf: &mut Bpf, map_name: &str, keys: &[K], value: i32) -> Result<()> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    let fd = match bpf.map(map_name).ok_or(Error::MapNotFound)? {
+        Map::HashMap(data) => data.as_raw_fd(),
+        _ => return Err(Error::MapNotFound),
+    };
+    let values = vec![value; keys.len().min(BATCH_SIZE)];
+    for (i, chunk) in keys.chunks(BATCH_SIZE).enumerate() {
+        match update_batch(fd, chunk, &values[..chunk.len()]) {
+            Ok(()) => {}
+            Err(e) if batch_unsupported(&e) => {
+                let mut map =
+                    BpfHashMap::try_from(bpf.map_mut(map_name).ok_or(Error::MapNotFound)?)?;
+                for key in &keys[i * BATCH_SIZE..] {
+                    map.insert(*key, value, 0)?;
+                }
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+fn batch_unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP | ENOTSUPP)
+    )
+}
+
+// `bpf_attr` as used by the `BPF_MAP_*_BATCH` commands
+#[repr(C)]
+#[derive(Default)]
+struct BatchAttr {
+    in_batch: u64,
+    out_batch: u64,
+    keys: u64,
+    values: u64,
+    count: u32,
+    map_fd: u32,
+    elem_flags: u64,
+    flags: u64,
+}
+
+fn update_batch<K: Pod, V: Pod>(fd: RawFd, keys: &[K], values: &[V]) -> io::Result<()> {
+    debug_assert_eq!(keys.len(), values.len());
+    let mut attr = BatchAttr {
+        keys: keys.as_ptr() as u64,
+        values: values.as_ptr() as u64,
+        count: keys.len() as u32,
+        map_fd: fd as u32,
+        ..Default::default()
+    };
+    // SAFETY: `keys` and `values` hold `count` elements of the map's key and value types
+    // and outlive the call, the kernel only reads them
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_MAP_UPDATE_BATCH,
+            &mut attr as *mut BatchAttr,
+            mem::size_of::<BatchAttr>(),
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+
+    use firewall_common::Action;
+    use ipnet::IpNet;
+
+    use super::{IpList, IpLists, ListMaps};
+    use crate::{Error, Result};
+
+    // Maps in memory, once `writes_left` writes went through the next one fails
+    #[derive(Default)]
+    struct FakeMaps {
+        blocked: HashSet<IpNet>,
+        allowed: HashSet<IpNet>,
+        writes_left: Option<usize>,
+    }
+
+    impl FakeMaps {
+        fn list(&mut self, action: Action) -> &mut HashSet<IpNet> {
+            match action {
+                Action::Reject => &mut self.blocked,
+                Action::Accept => &mut self.allowed,
+            }
+        }
+
+        // Networks by the list they're on, none can be on both
+        fn entries(&self) -> HashMap<IpNet, Action> {
+            assert!(self.blocked.is_disjoint(&self.allowed));
+            let blocked = self.blocked.iter().map(|ip| (*ip, Action::Reject));
+            let allowed = self.allowed.iter().map(|ip| (*ip, Action::Accept));
+            blocked.chain(allowed).collect()
+        }
+    }
+
+    impl ListMaps for FakeMaps {
+        fn insert(&mut self, ips: &[IpNet], action: Action) -> Result<()> {
+            for ip in ips {
+                match &mut self.writes_left {
+                    Some(0) => {
+                        self.writes_left = None;
+                        return Err(Error::MapNotFound);
+                    }
+                    Some(left) => *left -= 1,
+                    None => {}
+                }
+                self.list(action).insert(*ip);
+            }
+            Ok(())
+        }
+
+        fn remove(&mut self, ips: &[IpNet], action: Action) -> Result<()> {
+            for ip in ips {
+                self.list(action).remove(ip);
+            }
+            Ok(())
+        }
+    }
+
+    fn nets(ips: &[&str]) -> Vec<IpNet> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn failed_insert_leaves_the_maps_as_they_were() {
+        let mut lists = IpLists::new();
+        let mut maps = FakeMaps::default();
+        lists
+            .insert(
+                &mut maps,
+                nets(&["10.0.0.1/32", "10.1.0.0/16"]),
+                Action::Accept,
+            )
+            .unwrap();
+        let before = maps.entries();
+
+        maps.writes_left = Some(2);
+        let ips = nets(&["10.0.0.2/32", "10.1.0.0/16", "10.2.0.0/16", "2001:db8::/32"]);
+        assert!(lists.insert(&mut maps, ips, Action::Reject).is_err());
+
+        assert_eq!(maps.entries(), before);
+        assert_eq!(lists.entries, before);
+    }
+
+    #[test]
+    fn listed_entries_can_be_removed() {
+        let mut lists = IpLists::new();
+        let mut maps = FakeMaps::default();
+        // Truncated to the network
+        lists
+            .insert(
+                &mut maps,
+                nets(&["10.0.0.1/32", "10.1.2.3/16"]),
+                Action::Reject,
+            )
+            .unwrap();
+        assert_eq!(maps.entries(), lists.entries);
+        assert!(lists.entries.contains_key(&"10.1.0.0/16".parse().unwrap()));
+
+        lists
+            .remove(&mut maps, nets(&["10.1.0.0/16", "10.9.0.0/16"]))
+            .unwrap();
+        assert_eq!(maps.entries().len(), 1);
+        lists.clear(&mut maps).unwrap();
+        assert!(maps.entries().is_empty());
+        assert!(lists.entries.is_empty());
+    }
+
+    #[test]
+    fn lists_are_kept_in_separate_maps() {
+        let mut lists = IpLists::new();
+        let mut maps = FakeMaps::default();
+        lists
+            .insert(&mut maps, nets(&["10.0.0.0/8"]), Action::Reject)
+            .unwrap();
+        // A more specific allowed network doesn't replace the blocked one containing it
+        lists
+            .insert(
+                &mut maps,
+                nets(&["10.0.0.1/32", "10.1.0.0/16"]),
+                Action::Accept,
+            )
+            .unwrap();
+        assert_eq!(maps.blocked, nets(&["10.0.0.0/8"]).into_iter().collect());
+        assert_eq!(
+            maps.allowed,
+            nets(&["10.0.0.1/32", "10.1.0.0/16"]).into_iter().collect()
+        );
+
+        // Listing a network again moves it to the other list
+        lists
+            .insert(&mut maps, nets(&["10.1.0.0/16"]), Action::Reject)
+            .unwrap();
+        lists
+            .insert(&mut maps, nets(&["10.0.0.0/8"]), Action::Accept)
+            .unwrap();
+        assert_eq!(maps.entries(), lists.entries);
+        assert_eq!(maps.blocked, nets(&["10.1.0.0/16"]).into_iter().collect());
+
+        lists
+            .remove(&mut maps, nets(&["10.0.0.0/8", "10.1.0.0/16"]))
+            .unwrap();
+        assert_eq!(maps.entries(), lists.entries);
+        assert_eq!(maps.allowed, nets(&["10.0.0.1/32"]).into_iter().collect());
+    }
+
+    #[test]
+    fn failed_move_leaves_networks_on_their_list() {
+        let mut lists = IpLists::new();
+        let mut maps = FakeMaps::default();
+        lists
+            .insert(
+                &mut maps,
+                nets(&["10.0.0.1/32", "10.1.0.0/16"]),
+                Action::Accept,
+            )
+            .unwrap();
+        let before = maps.entries();
+
+        maps.writes_left = Some(1);
+        let ips = nets(&["10.0.0.1/32", "10.1.0.0/16"]);
+        assert!(lists.insert(&mut maps, ips, Action::Reject).is_err());
+
+        assert_eq!(maps.entries(), before);
+        assert_eq!(lists.entries, before);
+    }
+
+    #[test]
+    fn parse_plain_list() {
+        let list: IpList = "10.0.0.0/8\n\n192.0.2.1\n2001:db8::/32\n2001:db8::1\n"
+            .parse()
+            .unwrap();
+        let expected: Vec<ipnet::IpNet> = vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "192.0.2.1/32".parse().unwrap(),
+            "2001:db8::/32".parse().unwrap(),
+            "2001:db8::1/128".parse().unwrap(),
+        ];
+        assert_eq!(list.into_iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn parse_spamhaus_drop_list() {
+        let drop = "; Spamhaus DROP List 2023/01/10 - (c) 2023 The Spamhaus Project\n\
+                    ; Last-Modified: Tue, 10 Jan 2023 12:22:16 GMT\n\
+                    1.10.16.0/20 ; SBL256894\n\
+                    1.19.0.0/16 ; SBL434604\n";
+        let list: IpList = drop.parse().unwrap();
+        let expected: Vec<ipnet::IpNet> = vec![
+            "1.10.16.0/20".parse().unwrap(),
+            "1.19.0.0/16".parse().unwrap(),
+        ];
+        assert_eq!(list.into_iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn parse_comments_and_whitespace() {
+        let list: IpList = "# feed\n  10.0.0.1  # trailing\n\t10.0.0.2\t\n"
+            .parse()
+            .unwrap();
+        assert_eq!(list.len(), 2);
+    }
+
+    #[test]
+    fn parse_invalid_line_errors() {
+        let res = "10.0.0.1\n10.0.0.300\n".parse::<IpList>();
+        assert!(matches!(res, Err(Error::InvalidIpList(2))));
+    }
+}
@@ -37,22 +37,107 @@ mod as_octet;
 mod cidr;
 mod classifier;
 mod config;
+mod counters;
 mod error;
+mod event;
 mod firewall;
+mod hostname;
+mod ip_list;
+mod link;
 mod logger;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod pcap;
+mod policy;
 mod rule;
 mod rule_tracker;
+mod ruleset;
+mod sync;
+mod tc;
+mod transaction;
 
 pub use crate::firewall::Firewall;
+#[cfg(feature = "pinning")]
+pub use crate::firewall::DEFAULT_PIN_PATH;
 pub use firewall_common::Action;
+pub use link::LinkType;
+pub use tc::{AcceptVerdict, AttachOptions};
 
+pub use counters::{Counters, IdCounters};
 pub use error::Error;
-pub use rule::{Protocol, Rule};
+pub use event::{PacketEvent, PacketSink, TracingSink};
+pub use hostname::{Answer, HostnameRefresher, HostnameRule, Resolver, SystemResolver};
+pub use ip_list::IpList;
+pub use logger::LoggerHealth;
+#[cfg(feature = "metrics")]
+pub use metrics::MetricsServer;
+pub use pcap::PcapngSink;
+pub use policy::{IdAssignment, Policy};
+pub use rule::{Protocol, Rule, TcpFlags};
+pub use sync::SyncReport;
+pub use transaction::Transaction;
 pub type Result<T> = std::result::Result<T, Error>;
 
+const PROGRAM: &str = "ebpf_firewall";
 const EVENT_ARRAY: &str = "EVENTS";
-const SOURCE_ID_IPV4: &str = "SOURCE_ID_IPV4";
-const RULE_MAP_IPV4: &str = "RULE_MAP_IPV4";
-const SOURCE_ID_IPV6: &str = "SOURCE_ID_IPV6";
-const RULE_MAP_IPV6: &str = "RULE_MAP_IPV6";
+// Maps with one copy per generation, indexed by generation
+const GENERATIONS: usize = 2;
+const SOURCE_ID_IPV4: [&str; GENERATIONS] = ["SOURCE_ID_IPV4", "SOURCE_ID_IPV4_1"];
+const RULE_MAP_IPV4: [&str; GENERATIONS] = ["RULE_MAP_IPV4", "RULE_MAP_IPV4_1"];
+const SOURCE_ID_IPV6: [&str; GENERATIONS] = ["SOURCE_ID_IPV6", "SOURCE_ID_IPV6_1"];
+const RULE_MAP_IPV6: [&str; GENERATIONS] = ["RULE_MAP_IPV6", "RULE_MAP_IPV6_1"];
+const HEADER_FILTERS_IPV4: [&str; GENERATIONS] = ["HEADER_FILTERS_IPV4", "HEADER_FILTERS_IPV4_1"];
+const HEADER_FILTERS_IPV6: [&str; GENERATIONS] = ["HEADER_FILTERS_IPV6", "HEADER_FILTERS_IPV6_1"];
+const SOURCE_ID_MARK: &str = "SOURCE_ID_MARK";
+const MARK_MASKS: &str = "MARK_MASKS";
+const SOURCE_ID_IFACE: &str = "SOURCE_ID_IFACE";
+const AUDIT_IDS: &str = "AUDIT_IDS";
+const DEFAULT_ACTION_IDS: &str = "DEFAULT_ACTION_IDS";
+const LINK_HDR_LEN: &str = "LINK_HDR_LEN";
+// Maps with one copy per IP list, the blocklist first and then the allowlist
+const LISTS: usize = 2;
+const LISTED_IPS_IPV4: [&str; LISTS] = ["BLOCKED_IPS_IPV4", "ALLOWED_IPS_IPV4"];
+const LISTED_IPS_IPV6: [&str; LISTS] = ["BLOCKED_IPS_IPV6", "ALLOWED_IPS_IPV6"];
+const LISTED_PREFIXES_IPV4: [&str; LISTS] = ["BLOCKED_PREFIXES_IPV4", "ALLOWED_PREFIXES_IPV4"];
+const LISTED_PREFIXES_IPV6: [&str; LISTS] = ["BLOCKED_PREFIXES_IPV6", "ALLOWED_PREFIXES_IPV6"];
+const RULE_HITS: &str = "RULE_HITS";
+const COUNTERS: &str = "COUNTERS";
+const ID_COUNTERS: &str = "ID_COUNTERS";
 const CONFIG: &str = "CONFIG";
+
+// Everything pinned under the pin path with the `pinning` feature
+#[cfg(feature = "pinning")]
+const PINNED_OBJECTS: &[&str] = &[
+    PROGRAM,
+    EVENT_ARRAY,
+    SOURCE_ID_IPV4[0],
+    SOURCE_ID_IPV4[1],
+    RULE_MAP_IPV4[0],
+    RULE_MAP_IPV4[1],
+    SOURCE_ID_IPV6[0],
+    SOURCE_ID_IPV6[1],
+    RULE_MAP_IPV6[0],
+    RULE_MAP_IPV6[1],
+    HEADER_FILTERS_IPV4[0],
+    HEADER_FILTERS_IPV4[1],
+    HEADER_FILTERS_IPV6[0],
+    HEADER_FILTERS_IPV6[1],
+    SOURCE_ID_MARK,
+    MARK_MASKS,
+    SOURCE_ID_IFACE,
+    AUDIT_IDS,
+    DEFAULT_ACTION_IDS,
+    LINK_HDR_LEN,
+    LISTED_IPS_IPV4[0],
+    LISTED_IPS_IPV4[1],
+    LISTED_IPS_IPV6[0],
+    LISTED_IPS_IPV6[1],
+    LISTED_PREFIXES_IPV4[0],
+    LISTED_PREFIXES_IPV4[1],
+    LISTED_PREFIXES_IPV6[0],
+    LISTED_PREFIXES_IPV6[1],
+    RULE_HITS,
+    COUNTERS,
+    ID_COUNTERS,
+    CONFIG,
+];
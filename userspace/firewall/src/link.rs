@@ -0,0 +1,89 @@
+use std::{fs, io};
+
+use aya::{maps::HashMap, Bpf};
+
+use crate::{classifier::IfIndex, Error, Result, LINK_HDR_LEN};
+
+// Not exported by libc
+const ARPHRD_RAWIP: u16 = 519;
+
+const ETH_HDR_LEN: u32 = 14;
+
+/// Link layer of an interface, it determines where the IP header starts in a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkType {
+    /// Packets start with an Ethernet header, e.g. physical NICs, veths or bridges.
+    Ethernet,
+    /// Packets start directly with the IP header, e.g. WireGuard, tun or other tunnel devices.
+    RawIp,
+}
+
+impl LinkType {
+    /// Detects the link type of `iface` from its ARP hardware type.
+    ///
+    /// Returns `None` if the hardware type isn't known to either have an Ethernet header or not.
+    pub fn detect(iface: impl AsRef<str>) -> Result<Option<Self>> {
+        let hw_type = fs::read_to_string(format!("/sys/class/net/{}/type", iface.as_ref()))?;
+        let hw_type: u16 = hw_type
+            .trim()
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Self::from_hw_type(hw_type))
+    }
+
+    fn from_hw_type(hw_type: u16) -> Option<Self> {
+        match hw_type {
+            libc::ARPHRD_ETHER | libc::ARPHRD_LOOPBACK => Some(Self::Ethernet),
+            libc::ARPHRD_NONE
+            | libc::ARPHRD_PPP
+            | libc::ARPHRD_TUNNEL
+            | libc::ARPHRD_TUNNEL6
+            | libc::ARPHRD_IPGRE
+            | libc::ARPHRD_SIT
+            | ARPHRD_RAWIP => Some(Self::RawIp),
+            _ => None,
+        }
+    }
+
+    fn header_len(&self) -> u32 {
+        match self {
+            Self::Ethernet => ETH_HDR_LEN,
+            Self::RawIp => 0,
+        }
+    }
+}
+
+/// Tells the eBPF program the link type of `iface`.
+pub(crate) fn set_link_type(bpf: &mut Bpf, iface: &str, link_type: LinkType) -> Result<()> {
+    let mut store = HashMap::try_from(bpf.map_mut(LINK_HDR_LEN).ok_or(Error::MapNotFound)?)?;
+    store.insert(IfIndex::from_name(iface)?.0, link_type.header_len(), 0)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LinkType, ARPHRD_RAWIP};
+
+    #[test]
+    fn maps_hardware_types() {
+        for (hw_type, link_type) in [
+            (libc::ARPHRD_ETHER, Some(LinkType::Ethernet)),
+            (libc::ARPHRD_LOOPBACK, Some(LinkType::Ethernet)),
+            (libc::ARPHRD_NONE, Some(LinkType::RawIp)),
+            (libc::ARPHRD_PPP, Some(LinkType::RawIp)),
+            (libc::ARPHRD_TUNNEL, Some(LinkType::RawIp)),
+            (libc::ARPHRD_TUNNEL6, Some(LinkType::RawIp)),
+            (libc::ARPHRD_IPGRE, Some(LinkType::RawIp)),
+            (libc::ARPHRD_SIT, Some(LinkType::RawIp)),
+            (ARPHRD_RAWIP, Some(LinkType::RawIp)),
+            (libc::ARPHRD_INFINIBAND, None),
+        ] {
+            assert_eq!(LinkType::from_hw_type(hw_type), link_type, "{hw_type}");
+        }
+    }
+
+    #[test]
+    fn missing_interfaces_error() {
+        assert!(LinkType::detect("fw-missing0").is_err());
+    }
+}
@@ -1,42 +1,129 @@
-#![cfg(any(feature = "tokio", feature = "async_std"))]
+// Events are read in tasks of the async runtime enabled by the `tokio` or `async-std` features,
+// without a runtime they're read by a thread blocking on every per-CPU buffer.
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+mod async_reader;
+#[cfg(any(test, not(any(feature = "tokio", feature = "async-std"))))]
+mod blocking;
 
-use crate::{Error, Result};
-use num_traits::FromPrimitive;
+use crate::{
+    event::{PacketEvent, PacketSink, Sinks, TracingSink},
+    Error, Result,
+};
 use serde::Serialize;
-use std::{convert::TryFrom, net::IpAddr};
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-// This module could be expanded to be used with `PerfEventArray`
-// That way we wouldn't depend on having a tokio or async_std runtime
-// to log the events and that could expand our supported platforms.
 use aya::{
-    maps::{
-        perf::{AsyncPerfEventArray, AsyncPerfEventArrayBuffer},
-        MapData,
-    },
-    util::online_cpus,
+    maps::{perf::Events, Map},
     Bpf,
 };
 use bytes::BytesMut;
-use firewall_common::{Action, PacketLog};
-
-#[cfg(feature = "tokio")]
-use tokio::spawn;
-
-#[cfg(feature = "async-std")]
-use async_std::task::spawn;
-
-use uuid::Uuid;
 
 use crate::EVENT_ARRAY;
 
+// Events read at once from each per-CPU buffer
+const BUFFERS_PER_READ: usize = 10;
+// Lost events are warned about at most this often
+const LOST_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+// Errors kept for `LoggerHealth`, older ones are discarded
+const MAX_ERRORS: usize = 16;
+
 pub struct Logger {
     map_name: String,
+    stats: Arc<LoggerStats>,
+    sinks: Sinks,
+    // The event array can only be taken once, events are read until the program is unloaded
+    started: bool,
+    tracing: bool,
+    // Size of each per-CPU buffer, `None` for aya's default
+    page_count: Option<usize>,
+    // Stopped when the logger is dropped
+    #[cfg(not(any(feature = "tokio", feature = "async-std")))]
+    reader: Option<blocking::BlockingReader>,
+}
+
+/// State of the logger, see [Firewall::logger_health](crate::Firewall::logger_health).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct LoggerHealth {
+    /// Whether packets are being read, `false` before logging starts or once every buffer failed.
+    pub running: bool,
+    /// Per-CPU buffers still being read.
+    pub readers: usize,
+    /// Packets overwritten in the per-CPU buffers before they were read,
+    /// a larger [page count](crate::Firewall::set_log_page_count) or faster sinks reduce them.
+    pub lost_events: u64,
+    /// Packets read that couldn't be formatted.
+    pub dropped_events: u64,
+    /// Errors that stopped reading a buffer, oldest first.
+    pub errors: Vec<String>,
+}
+
+/// Packets the logger couldn't log and errors reading them, shared with the tasks reading the events.
+#[derive(Debug, Default)]
+struct LoggerStats {
+    // Overwritten in the perf buffers before they were read
+    lost: AtomicU64,
+    // Read but not valid packet logs
+    dropped: AtomicU64,
+    readers: AtomicUsize,
+    errors: Mutex<Vec<String>>,
+    // Lost events not warned about yet and when the last warning was logged
+    unreported: Mutex<(u64, Option<Instant>)>,
+}
+
+impl LoggerStats {
+    // Called on every read, lost events still pending are warned about once the interval passed
+    // even if no more are lost
+    fn record_lost(&self, lost: usize) {
+        if lost > 0 {
+            self.lost.fetch_add(lost as u64, Ordering::Relaxed);
+        }
+        let mut unreported = self.unreported.lock().expect("logger stats poisoned");
+        unreported.0 += lost as u64;
+        if unreported.0 == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let due = unreported.1.map_or(true, |last| {
+            now.duration_since(last) >= LOST_WARNING_INTERVAL
+        });
+        if due {
+            tracing::warn!(
+                "{} packet events lost, packets are logged faster than they're read",
+                unreported.0
+            );
+            *unreported = (0, Some(now));
+        }
+    }
+
+    // A reader stopped because of `error`
+    fn record_failure(&self, error: impl std::fmt::Display) {
+        tracing::error!("Stopped reading packet events: {error}");
+        self.readers.fetch_sub(1, Ordering::Relaxed);
+        let mut errors = self.errors.lock().expect("logger stats poisoned");
+        if errors.len() == MAX_ERRORS {
+            errors.remove(0);
+        }
+        errors.push(error.to_string());
+    }
 }
 
 impl Logger {
     fn new_with_name(map_name: impl AsRef<str>) -> Result<Self> {
         Ok(Self {
             map_name: map_name.as_ref().to_string(),
+            stats: Default::default(),
+            sinks: Default::default(),
+            started: false,
+            tracing: false,
+            page_count: None,
+            #[cfg(not(any(feature = "tokio", feature = "async-std")))]
+            reader: None,
         })
     }
 
@@ -44,95 +131,163 @@ impl Logger {
         Self::new_with_name(EVENT_ARRAY)
     }
 
-    pub fn init(&mut self, bpf: &mut Bpf) -> Result<()> {
-        let map = bpf.take_map(&self.map_name).ok_or(Error::MapNotFound)?;
-        let mut event_array = AsyncPerfEventArray::try_from(map)?;
-        for cpu_id in online_cpus()? {
-            let buf = event_array.open(cpu_id, None)?;
-            spawn(log_events(buf));
+    /// Sets the number of pages of each per-CPU buffer, must be a power of 2.
+    pub fn set_page_count(&mut self, page_count: usize) -> Result<()> {
+        if self.started {
+            return Err(Error::LoggingStarted);
+        }
+        if !page_count.is_power_of_two() {
+            return Err(Error::InvalidPageCount);
         }
+        self.page_count = Some(page_count);
+        Ok(())
+    }
 
+    /// Registers `sink` and starts reading the events if it wasn't already.
+    pub fn add_sink(&mut self, bpf: &mut Bpf, sink: impl PacketSink + 'static) -> Result<()> {
+        self.init(bpf)?;
+        self.sinks.add(sink);
         Ok(())
     }
+
+    /// Logs the events with [TracingSink], only the first call adds it.
+    pub fn log_to_tracing(&mut self, bpf: &mut Bpf) -> Result<()> {
+        if !self.tracing {
+            self.add_sink(bpf, TracingSink)?;
+            self.tracing = true;
+        }
+        Ok(())
+    }
+
+    fn init(&mut self, bpf: &mut Bpf) -> Result<()> {
+        if self.started {
+            return Ok(());
+        }
+        let map = bpf.take_map(&self.map_name).ok_or(Error::MapNotFound)?;
+        self.start_reading(map)?;
+        self.started = true;
+
+        Ok(())
+    }
+
+    #[cfg(any(feature = "tokio", feature = "async-std"))]
+    fn start_reading(&mut self, map: Map) -> Result<()> {
+        async_reader::start(map, self.page_count, self.stats.clone(), self.sinks.clone())
+    }
+
+    #[cfg(not(any(feature = "tokio", feature = "async-std")))]
+    fn start_reading(&mut self, map: Map) -> Result<()> {
+        self.reader = Some(blocking::BlockingReader::open(
+            map,
+            self.page_count,
+            self.stats.clone(),
+            self.sinks.clone(),
+        )?);
+        Ok(())
+    }
+
+    pub fn health(&self) -> LoggerHealth {
+        let readers = self.stats.readers.load(Ordering::Relaxed);
+        LoggerHealth {
+            running: self.started && readers > 0,
+            readers,
+            lost_events: self.stats.lost.load(Ordering::Relaxed),
+            dropped_events: self.stats.dropped.load(Ordering::Relaxed),
+            errors: self
+                .stats
+                .errors
+                .lock()
+                .expect("logger stats poisoned")
+                .clone(),
+        }
+    }
 }
 
-pub async fn log_events<T: AsMut<MapData> + AsRef<MapData>>(mut buf: AsyncPerfEventArrayBuffer<T>) {
-    let mut buffers = (0..10)
+fn new_buffers() -> Vec<BytesMut> {
+    (0..BUFFERS_PER_READ)
         .map(|_| BytesMut::with_capacity(1024))
-        .collect::<Vec<_>>();
-    loop {
-        // TODO: If events are lost(Events produced by ebpf overflow the internal ring)
-        let events = buf.read_events(&mut buffers).await.unwrap();
-        buffers[0..events.read]
-            .iter_mut()
-            // SAFETY: read_event makes sure buf is initialized to a Packetlog
-            // Also Packetlog is Copy
-            .map(|buf| unsafe { buf_to_packet(buf) })
-            .for_each(|data| {
-                let Ok(packet) = PacketFormatted::try_from(data) else {return;};
-                let Ok(packet) = serde_json::to_string(&packet) else {return;};
-                tracing::info!(target: "packet_log", "{packet}");
-            });
-    }
+        .collect()
 }
 
-#[derive(Debug, Clone, Serialize)]
-struct PacketFormatted {
-    source_ip: IpAddr,
-    destination_ip: IpAddr,
-    destination_port: Option<u16>,
-    source_port: Option<u16>,
-    action: Action,
-    protocol: u8,
-    uuid: Option<uuid::Uuid>,
-    timestamp: String,
+// Hands the packets read into the first `events.read` buffers to the sinks
+fn handle_events(buffers: &[BytesMut], events: Events, stats: &LoggerStats, sinks: &Sinks) {
+    stats.record_lost(events.lost);
+    buffers[0..events.read]
+        .iter()
+        .for_each(|buf| match PacketEvent::parse(buf) {
+            Ok(event) => sinks.dispatch(&event),
+            Err(_) => {
+                stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        });
 }
 
-impl TryFrom<PacketLog> for PacketFormatted {
-    type Error = Error;
-
-    fn try_from(value: PacketLog) -> Result<Self> {
-        let destination_port = match value.dest_port {
-            0 => None,
-            x => Some(x),
-        };
-
-        let source_port = match value.src_port {
-            0 => None,
-            x => Some(x),
-        };
-        let action = Action::from_i32(value.action).ok_or(Error::LogFormatError)?;
-        let timestamp =
-            chrono::offset::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
-        let uuid = if value.class == [0; 16] {
-            None
-        } else {
-            Some(Uuid::from_u128(u128::from_le_bytes(value.class)))
-        };
-
-        let (source_ip, destination_ip) = match value.version {
-            6 => (IpAddr::from(value.source), IpAddr::from(value.dest)),
-            4 => (to_ip(value.source), to_ip(value.dest)),
-            _ => return Err(Error::LogFormatError),
-        };
-        Ok(Self {
-            source_ip,
-            destination_ip,
-            destination_port,
-            source_port,
-            action,
-            protocol: value.proto,
-            uuid,
-            timestamp,
-        })
+#[cfg(test)]
+mod test {
+    use std::{sync::atomic::Ordering, time::Instant};
+
+    use super::{Logger, LoggerStats, LOST_WARNING_INTERVAL};
+    use crate::Error;
+
+    #[test]
+    fn lost_events_are_warned_about_at_most_once_per_interval() {
+        let stats = LoggerStats::default();
+        stats.record_lost(0);
+        assert_eq!(stats.unreported.lock().unwrap().1, None);
+
+        stats.record_lost(3);
+        assert_eq!(stats.unreported.lock().unwrap().0, 0);
+        stats.record_lost(2);
+        stats.record_lost(4);
+        assert_eq!(stats.unreported.lock().unwrap().0, 6);
+        assert_eq!(stats.lost.load(Ordering::Relaxed), 9);
     }
-}
 
-fn to_ip(ip: [u8; 16]) -> IpAddr {
-    IpAddr::from([ip[0], ip[1], ip[2], ip[3]])
-}
+    #[test]
+    fn pending_lost_events_are_warned_about_without_new_losses() {
+        let stats = LoggerStats::default();
+        stats.record_lost(3);
+        stats.record_lost(2);
+        stats.record_lost(0);
+        assert_eq!(stats.unreported.lock().unwrap().0, 2);
+
+        let last = Instant::now().checked_sub(LOST_WARNING_INTERVAL).unwrap();
+        stats.unreported.lock().unwrap().1 = Some(last);
+        stats.record_lost(0);
+        assert_eq!(stats.unreported.lock().unwrap().0, 0);
+        assert_eq!(stats.lost.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn failed_readers_show_in_health() {
+        let mut logger = Logger::new().unwrap();
+        logger.started = true;
+        logger.stats.readers.store(2, Ordering::Relaxed);
 
-unsafe fn buf_to_packet(buf: &mut BytesMut) -> PacketLog {
-    let ptr = buf.as_ptr() as *const PacketLog;
-    ptr.read_unaligned()
+        logger.stats.record_failure("CPU 0: no buffers");
+        let health = logger.health();
+        assert!(health.running);
+        assert_eq!(health.readers, 1);
+
+        logger.stats.record_failure("CPU 1: no buffers");
+        let health = logger.health();
+        assert!(!health.running);
+        assert_eq!(health.errors, ["CPU 0: no buffers", "CPU 1: no buffers"]);
+    }
+
+    #[test]
+    fn page_count_is_validated() {
+        let mut logger = Logger::new().unwrap();
+        assert!(matches!(
+            logger.set_page_count(3),
+            Err(Error::InvalidPageCount)
+        ));
+        logger.set_page_count(64).unwrap();
+
+        logger.started = true;
+        assert!(matches!(
+            logger.set_page_count(128),
+            Err(Error::LoggingStarted)
+        ));
+    }
 }
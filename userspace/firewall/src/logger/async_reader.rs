@@ -0,0 +1,55 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use aya::{
+    maps::{
+        perf::{AsyncPerfEventArray, AsyncPerfEventArrayBuffer},
+        Map, MapData,
+    },
+    util::online_cpus,
+};
+
+#[cfg(feature = "tokio")]
+use tokio::spawn;
+
+#[cfg(feature = "async-std")]
+use async_std::task::spawn;
+
+use super::{handle_events, new_buffers, LoggerStats};
+use crate::{event::Sinks, Result};
+
+/// Spawns a task per CPU reading its buffer of the event array.
+pub(super) fn start(
+    map: Map,
+    page_count: Option<usize>,
+    stats: Arc<LoggerStats>,
+    sinks: Sinks,
+) -> Result<()> {
+    let mut event_array = AsyncPerfEventArray::try_from(map)?;
+    let buffers = online_cpus()?
+        .into_iter()
+        .map(|cpu_id| Ok((cpu_id, event_array.open(cpu_id, page_count)?)))
+        .collect::<Result<Vec<_>>>()?;
+    stats.readers.fetch_add(buffers.len(), Ordering::Relaxed);
+    for (cpu_id, buf) in buffers {
+        spawn(log_events(cpu_id, buf, stats.clone(), sinks.clone()));
+    }
+    Ok(())
+}
+
+async fn log_events<T: AsMut<MapData> + AsRef<MapData>>(
+    cpu_id: u32,
+    mut buf: AsyncPerfEventArrayBuffer<T>,
+    stats: Arc<LoggerStats>,
+    sinks: Sinks,
+) {
+    let mut buffers = new_buffers();
+    loop {
+        match buf.read_events(&mut buffers).await {
+            Ok(events) => handle_events(&buffers, events, &stats, &sinks),
+            Err(e) => {
+                stats.record_failure(format!("CPU {cpu_id}: {e}"));
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use std::{
+    io,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::{atomic::Ordering, Arc},
+    thread::JoinHandle,
+};
+
+use aya::maps::{
+    perf::{Events, PerfBufferError, PerfEventArrayBuffer},
+    MapData,
+};
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+use aya::{
+    maps::{perf::PerfEventArray, Map},
+    util::online_cpus,
+};
+use bytes::BytesMut;
+
+use super::{handle_events, new_buffers, LoggerStats};
+use crate::event::Sinks;
+
+// Marks the shutdown eventfd in the epoll events, sources use their index
+const SHUTDOWN: u64 = u64::MAX;
+// Ready file descriptors handled per wakeup
+const MAX_READY: usize = 16;
+
+/// Per-CPU buffer of the event array, abstracted so the reader can be tested without loading the program.
+pub(super) trait EventSource: AsRawFd + Send + 'static {
+    /// Whether there are events left to read.
+    fn readable(&self) -> bool;
+    fn read_events(&mut self, buffers: &mut [BytesMut]) -> Result<Events, PerfBufferError>;
+}
+
+impl<T> EventSource for PerfEventArrayBuffer<T>
+where
+    T: AsMut<MapData> + AsRef<MapData> + Send + 'static,
+{
+    fn readable(&self) -> bool {
+        PerfEventArrayBuffer::readable(self)
+    }
+
+    fn read_events(&mut self, buffers: &mut [BytesMut]) -> Result<Events, PerfBufferError> {
+        PerfEventArrayBuffer::read_events(self, buffers)
+    }
+}
+
+/// Thread reading every per-CPU buffer, waiting for them with epoll.
+///
+/// The thread is stopped when this is dropped.
+pub(super) struct BlockingReader {
+    shutdown: OwnedFd,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BlockingReader {
+    /// Opens the buffer of every online CPU in the event array and starts reading them.
+    #[cfg(not(any(feature = "tokio", feature = "async-std")))]
+    pub(super) fn open(
+        map: Map,
+        page_count: Option<usize>,
+        stats: Arc<LoggerStats>,
+        sinks: Sinks,
+    ) -> crate::Result<Self> {
+        let mut event_array = PerfEventArray::try_from(map)?;
+        let sources = online_cpus()?
+            .into_iter()
+            .map(|cpu_id| event_array.open(cpu_id, page_count))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::spawn(sources, stats, sinks)?)
+    }
+
+    pub(super) fn spawn<S: EventSource>(
+        sources: Vec<S>,
+        stats: Arc<LoggerStats>,
+        sinks: Sinks,
+    ) -> io::Result<Self> {
+        // SAFETY: the returned descriptors are checked and owned from here on
+        let epoll = owned(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        let shutdown = owned(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) })?;
+        watch(&epoll, shutdown.as_raw_fd(), SHUTDOWN)?;
+        for (index, source) in sources.iter().enumerate() {
+            watch(&epoll, source.as_raw_fd(), index as u64)?;
+        }
+
+        // Counted before the thread runs, a failure it records takes one off
+        let readers = sources.len();
+        stats.readers.fetch_add(readers, Ordering::Relaxed);
+        let thread_stats = stats.clone();
+        let spawned = std::thread::Builder::new()
+            .name("firewall-logger".to_string())
+            .spawn(move || {
+                let stats = thread_stats;
+                let mut sources: Vec<_> = sources.into_iter().map(Some).collect();
+                if let Err(e) = read_loop(&epoll, &mut sources, &stats, &sinks) {
+                    for _ in sources.iter().flatten() {
+                        stats.record_failure(&e);
+                    }
+                }
+            });
+        let handle = match spawned {
+            Ok(handle) => handle,
+            Err(e) => {
+                stats.readers.fetch_sub(readers, Ordering::Relaxed);
+                return Err(e);
+            }
+        };
+
+        Ok(Self {
+            shutdown,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for BlockingReader {
+    fn drop(&mut self) {
+        let one = 1u64.to_ne_bytes();
+        // SAFETY: writes 8 bytes from a valid buffer to the eventfd
+        let written = unsafe {
+            libc::write(
+                self.shutdown.as_raw_fd(),
+                one.as_ptr() as *const libc::c_void,
+                one.len(),
+            )
+        };
+        // The thread would never wake up to see the shutdown
+        if written < 0 {
+            return;
+        }
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// Sources that fail are dropped from `sources`, the loop only fails if epoll does
+fn read_loop<S: EventSource>(
+    epoll: &OwnedFd,
+    sources: &mut [Option<S>],
+    stats: &LoggerStats,
+    sinks: &Sinks,
+) -> io::Result<()> {
+    let mut buffers = new_buffers();
+    let mut ready = [libc::epoll_event { events: 0, u64: 0 }; MAX_READY];
+    loop {
+        // SAFETY: `ready` is valid for `MAX_READY` events
+        let count = unsafe {
+            libc::epoll_wait(epoll.as_raw_fd(), ready.as_mut_ptr(), MAX_READY as i32, -1)
+        };
+        if count < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        for event in &ready[..count as usize] {
+            let token = event.u64;
+            if token == SHUTDOWN {
+                return Ok(());
+            }
+            let slot = &mut sources[token as usize];
+            let Some(source) = slot else {
+                continue;
+            };
+            while source.readable() {
+                match source.read_events(&mut buffers) {
+                    Ok(events) => handle_events(&buffers, events, stats, sinks),
+                    Err(e) => {
+                        stats.record_failure(format!("buffer {token}: {e}"));
+                        unwatch(epoll, source.as_raw_fd())?;
+                        *slot = None;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn owned(fd: RawFd) -> io::Result<OwnedFd> {
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` was just opened and nothing else owns it
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn watch(epoll: &OwnedFd, fd: RawFd, token: u64) -> io::Result<()> {
+    let mut event = libc::epoll_event {
+        events: libc::EPOLLIN as u32,
+        u64: token,
+    };
+    // SAFETY: `event` is a valid epoll_event, the kernel copies it
+    if unsafe { libc::epoll_ctl(epoll.as_raw_fd(), libc::EPOLL_CTL_ADD, fd, &mut event) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn unwatch(epoll: &OwnedFd, fd: RawFd) -> io::Result<()> {
+    // SAFETY: the event is ignored when removing a file descriptor
+    if unsafe {
+        libc::epoll_ctl(
+            epoll.as_raw_fd(),
+            libc::EPOLL_CTL_DEL,
+            fd,
+            std::ptr::null_mut(),
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::VecDeque,
+        io, mem,
+        os::unix::io::{AsRawFd, OwnedFd, RawFd},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    };
+
+    use aya::maps::perf::{Events, PerfBufferError};
+    use bytes::BytesMut;
+    use firewall_common::PacketLog;
+
+    use super::{owned, BlockingReader, EventSource};
+    use crate::{event::Sinks, logger::LoggerStats, PacketEvent};
+
+    // Queue of packets that signals an eventfd while it isn't empty, like a perf buffer
+    #[derive(Clone)]
+    struct FakeSource {
+        packets: Arc<Mutex<VecDeque<PacketLog>>>,
+        lost: Arc<Mutex<usize>>,
+        failing: Arc<AtomicBool>,
+        fd: Arc<OwnedFd>,
+    }
+
+    impl FakeSource {
+        fn new() -> Self {
+            Self {
+                packets: Default::default(),
+                lost: Default::default(),
+                failing: Default::default(),
+                fd: Arc::new(
+                    owned(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) })
+                        .unwrap(),
+                ),
+            }
+        }
+
+        fn push(&self, packet: PacketLog, lost: usize) {
+            *self.lost.lock().unwrap() += lost;
+            // Signaled with the queue locked so `readable` can't miss it
+            let mut packets = self.packets.lock().unwrap();
+            packets.push_back(packet);
+            let one = 1u64.to_ne_bytes();
+            let written = unsafe {
+                libc::write(
+                    self.fd.as_raw_fd(),
+                    one.as_ptr() as *const libc::c_void,
+                    one.len(),
+                )
+            };
+            assert_eq!(written, 8, "{}", io::Error::last_os_error());
+        }
+    }
+
+    impl AsRawFd for FakeSource {
+        fn as_raw_fd(&self) -> RawFd {
+            self.fd.as_raw_fd()
+        }
+    }
+
+    impl EventSource for FakeSource {
+        fn readable(&self) -> bool {
+            let packets = self.packets.lock().unwrap();
+            if packets.is_empty() {
+                // Resets the eventfd so epoll doesn't report it again
+                let mut counter = [0u8; 8];
+                unsafe {
+                    libc::read(
+                        self.fd.as_raw_fd(),
+                        counter.as_mut_ptr() as *mut libc::c_void,
+                        counter.len(),
+                    )
+                };
+            }
+            !packets.is_empty()
+        }
+
+        fn read_events(&mut self, buffers: &mut [BytesMut]) -> Result<Events, PerfBufferError> {
+            if self.failing.load(Ordering::Relaxed) {
+                return Err(PerfBufferError::NoBuffers);
+            }
+            let mut packets = self.packets.lock().unwrap();
+            let mut read = 0;
+            for buf in buffers.iter_mut() {
+                let Some(packet) = packets.pop_front() else {
+                    break;
+                };
+                // SAFETY: PacketLog is plain old data
+                let bytes = unsafe {
+                    std::slice::from_raw_parts(
+                        &packet as *const PacketLog as *const u8,
+                        mem::size_of::<PacketLog>(),
+                    )
+                };
+                buf.clear();
+                buf.extend_from_slice(bytes);
+                read += 1;
+            }
+            let lost = mem::take(&mut *self.lost.lock().unwrap());
+            Ok(Events { read, lost })
+        }
+    }
+
+    fn packet(dest_port: u16) -> PacketLog {
+        PacketLog {
+            source: [10, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            dest: [10, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            action: 0,
+            dest_port,
+            src_port: 0,
+            proto: 17,
+            version: 4,
+            class: [0; 16],
+            audit: 0,
+            tcp_flags: 0,
+            ttl: 64,
+            dscp: 0,
+            captured_len: 0,
+            packet_len: 60,
+            ifindex: 2,
+        }
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn reads_every_source_until_dropped() {
+        let sources = vec![FakeSource::new(), FakeSource::new()];
+        let stats = Arc::new(LoggerStats::default());
+        let sinks = Sinks::default();
+        let ports = Arc::new(Mutex::new(Vec::new()));
+        let sink_ports = ports.clone();
+        sinks.add(move |event: &PacketEvent| {
+            sink_ports
+                .lock()
+                .unwrap()
+                .push(event.destination_port.unwrap())
+        });
+        let reader = BlockingReader::spawn(sources.clone(), stats.clone(), sinks).unwrap();
+
+        sources[0].push(packet(1), 0);
+        sources[1].push(packet(2), 3);
+        // Invalid IP version, counted as dropped
+        sources[1].push(
+            PacketLog {
+                version: 5,
+                ..packet(3)
+            },
+            0,
+        );
+        for port in 4..20 {
+            sources[0].push(packet(port), 0);
+        }
+        wait_until(|| ports.lock().unwrap().len() == 18);
+        wait_until(|| stats.dropped.load(Ordering::Relaxed) == 1);
+
+        let mut ports = ports.lock().unwrap().clone();
+        ports.sort_unstable();
+        assert_eq!(ports, [&[1, 2][..], &(4..20).collect::<Vec<_>>()].concat());
+        assert_eq!(stats.lost.load(Ordering::Relaxed), 3);
+
+        // Joins the thread, the test hangs if it doesn't stop
+        drop(reader);
+        sources[0].push(packet(20), 0);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(sources[0].readable());
+    }
+
+    #[test]
+    fn stops_while_idle() {
+        let reader = BlockingReader::spawn(
+            vec![FakeSource::new()],
+            Default::default(),
+            Sinks::default(),
+        )
+        .unwrap();
+        let start = Instant::now();
+        drop(reader);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn failing_sources_stop_alone() {
+        let sources = vec![FakeSource::new(), FakeSource::new()];
+        let stats = Arc::new(LoggerStats::default());
+        let sinks = Sinks::default();
+        let ports = Arc::new(Mutex::new(Vec::new()));
+        let sink_ports = ports.clone();
+        sinks.add(move |event: &PacketEvent| {
+            sink_ports
+                .lock()
+                .unwrap()
+                .push(event.destination_port.unwrap())
+        });
+        let _reader = BlockingReader::spawn(sources.clone(), stats.clone(), sinks).unwrap();
+
+        sources[0].failing.store(true, Ordering::Relaxed);
+        sources[0].push(packet(1), 0);
+        wait_until(|| stats.readers.load(Ordering::Relaxed) == 1);
+        let errors = stats.errors.lock().unwrap().clone();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("buffer 0: "), "{errors:?}");
+
+        sources[1].push(packet(2), 0);
+        wait_until(|| *ports.lock().unwrap() == [2]);
+    }
+}
@@ -0,0 +1,355 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use uuid::Uuid;
+
+use crate::{Counters, Firewall, IdCounters, LoggerHealth, Result};
+
+// Scrapers that stop sending their request don't hold the server for longer than this
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// Longest request head read, scrapers send a couple of short lines
+const MAX_REQUEST_LINES: usize = 64;
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Values exported by the [MetricsServer], read from the eBPF maps and the state kept in userspace.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Snapshot {
+    pub(crate) counters: Counters,
+    pub(crate) id_counters: HashMap<u128, IdCounters>,
+    pub(crate) rule_trie_v4: usize,
+    pub(crate) rule_trie_v6: usize,
+    pub(crate) classifier_v4: usize,
+    pub(crate) classifier_v6: usize,
+    pub(crate) classifier_mark: usize,
+    pub(crate) classifier_iface: usize,
+    pub(crate) logger: LoggerHealth,
+}
+
+impl Snapshot {
+    /// Formats the values in the Prometheus text exposition format.
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = &self.counters;
+
+        family(
+            &mut out,
+            "firewall_packets_total",
+            "counter",
+            "Packets seen by the firewall, by outcome.",
+        );
+        for (outcome, value) in [
+            ("accepted", counters.accepted),
+            ("dropped_by_rule", counters.dropped_by_rule),
+            ("dropped_by_default", counters.dropped_by_default),
+            ("dropped_by_list", counters.dropped_by_list),
+            ("parse_failure", counters.parse_failures),
+            ("non_ip", counters.non_ip),
+        ] {
+            sample(
+                &mut out,
+                "firewall_packets_total",
+                &[("outcome", outcome)],
+                value,
+            );
+        }
+
+        family(
+            &mut out,
+            "firewall_ip_packets_total",
+            "counter",
+            "IPv4 and IPv6 packets seen by the firewall.",
+        );
+        sample(
+            &mut out,
+            "firewall_ip_packets_total",
+            &[("version", "4")],
+            counters.ipv4,
+        );
+        sample(
+            &mut out,
+            "firewall_ip_packets_total",
+            &[("version", "6")],
+            counters.ipv6,
+        );
+
+        family(
+            &mut out,
+            "firewall_id_packets_total",
+            "counter",
+            "Packets seen from each source id by verdict, reset when the id is evicted.",
+        );
+        let mut ids: Vec<_> = self.id_counters.iter().collect();
+        ids.sort_unstable_by_key(|(&id, _)| id);
+        for (&id, counters) in ids {
+            let id = Uuid::from_u128(id).to_string();
+            for (verdict, value) in [
+                ("accepted", counters.accepted),
+                ("dropped", counters.dropped),
+            ] {
+                let labels = [("id", id.as_str()), ("verdict", verdict)];
+                sample(&mut out, "firewall_id_packets_total", &labels, value);
+            }
+        }
+
+        family(
+            &mut out,
+            "firewall_rule_trie_entries",
+            "gauge",
+            "Entries in use in the rule tries.",
+        );
+        for (ip_family, value) in [("ipv4", self.rule_trie_v4), ("ipv6", self.rule_trie_v6)] {
+            let labels = [("family", ip_family)];
+            sample(
+                &mut out,
+                "firewall_rule_trie_entries",
+                &labels,
+                value as u64,
+            );
+        }
+
+        family(
+            &mut out,
+            "firewall_classifier_entries",
+            "gauge",
+            "Entries in the maps classifying sources into ids.",
+        );
+        for (classifier, value) in [
+            ("ipv4", self.classifier_v4),
+            ("ipv6", self.classifier_v6),
+            ("mark", self.classifier_mark),
+            ("iface", self.classifier_iface),
+        ] {
+            let labels = [("classifier", classifier)];
+            sample(
+                &mut out,
+                "firewall_classifier_entries",
+                &labels,
+                value as u64,
+            );
+        }
+
+        family(
+            &mut out,
+            "firewall_logger_lost_events_total",
+            "counter",
+            "Packet events overwritten before the logger could read them.",
+        );
+        sample(
+            &mut out,
+            "firewall_logger_lost_events_total",
+            &[],
+            self.logger.lost_events,
+        );
+
+        family(
+            &mut out,
+            "firewall_logger_dropped_events_total",
+            "counter",
+            "Packet events read by the logger that couldn't be formatted.",
+        );
+        sample(
+            &mut out,
+            "firewall_logger_dropped_events_total",
+            &[],
+            self.logger.dropped_events,
+        );
+
+        family(
+            &mut out,
+            "firewall_logger_readers",
+            "gauge",
+            "Per-CPU buffers the logger is still reading.",
+        );
+        sample(
+            &mut out,
+            "firewall_logger_readers",
+            &[],
+            self.logger.readers as u64,
+        );
+
+        out
+    }
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    // Writing to a String can't fail
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+// Label values are fixed names or uuids, neither needs escaping
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: u64) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<_> = labels
+            .iter()
+            .map(|(label, value)| format!("{label}=\"{value}\""))
+            .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {value}");
+}
+
+/// Background thread serving the firewall's counters in the Prometheus text format, needs the `metrics` feature.
+///
+/// Every request is answered with the current values whatever its path,
+/// the maps are read with the firewall locked. The thread is stopped when this is dropped.
+///
+/// # Example
+/// ```no_run
+/// # use std::sync::{Arc, Mutex};
+/// # use firewall::{Firewall, MetricsServer};
+/// let fw = Arc::new(Mutex::new(Firewall::new("eth0").unwrap()));
+/// fw.lock().unwrap().start_logging().unwrap();
+/// let metrics = MetricsServer::spawn(fw.clone(), "127.0.0.1:9477").unwrap();
+/// ```
+pub struct MetricsServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Binds `addr` and spawns the thread answering the scrapes.
+    pub fn spawn(firewall: Arc<Mutex<Firewall>>, addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let handle = std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thread_stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                let result = stream.and_then(|stream| serve(stream, &firewall));
+                if let Err(e) = result {
+                    tracing::debug!("Couldn't serve metrics: {e}");
+                }
+            }
+        });
+
+        Ok(Self {
+            addr,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    /// Address the server is listening on, useful when binding port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Wakes up the thread blocked accepting connections
+        if TcpStream::connect(self.addr).is_err() {
+            return;
+        }
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn serve(stream: TcpStream, firewall: &Mutex<Firewall>) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    // The request itself doesn't matter, but it's read up to the blank line ending its head
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    for _ in 0..MAX_REQUEST_LINES {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let snapshot = match firewall.lock() {
+        Ok(firewall) => firewall.metrics().map_err(|e| e.to_string()),
+        Err(_) => Err("firewall lock poisoned".to_string()),
+    };
+    let (status, body) = match snapshot {
+        Ok(snapshot) => ("200 OK", snapshot.render()),
+        Err(e) => ("500 Internal Server Error", format!("{e}\n")),
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    (&stream).flush()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::Snapshot;
+    use crate::{Counters, IdCounters, LoggerHealth};
+
+    #[test]
+    fn renders_every_family() {
+        let snapshot = Snapshot {
+            counters: Counters {
+                accepted: 10,
+                dropped_by_rule: 3,
+                ipv4: 12,
+                ipv6: 1,
+                ..Default::default()
+            },
+            id_counters: HashMap::from([(
+                1,
+                IdCounters {
+                    accepted: 4,
+                    dropped: 2,
+                },
+            )]),
+            rule_trie_v4: 5,
+            classifier_v4: 2,
+            classifier_mark: 1,
+            logger: LoggerHealth {
+                lost_events: 7,
+                readers: 4,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let text = snapshot.render();
+        let id = "00000000-0000-0000-0000-000000000001";
+
+        for line in [
+            "# TYPE firewall_packets_total counter",
+            "firewall_packets_total{outcome=\"accepted\"} 10",
+            "firewall_packets_total{outcome=\"dropped_by_rule\"} 3",
+            "firewall_packets_total{outcome=\"non_ip\"} 0",
+            "firewall_ip_packets_total{version=\"4\"} 12",
+            &format!("firewall_id_packets_total{{id=\"{id}\",verdict=\"accepted\"}} 4"),
+            &format!("firewall_id_packets_total{{id=\"{id}\",verdict=\"dropped\"}} 2"),
+            "# TYPE firewall_rule_trie_entries gauge",
+            "firewall_rule_trie_entries{family=\"ipv4\"} 5",
+            "firewall_rule_trie_entries{family=\"ipv6\"} 0",
+            "firewall_classifier_entries{classifier=\"mark\"} 1",
+            "firewall_logger_lost_events_total 7",
+            "firewall_logger_dropped_events_total 0",
+            "firewall_logger_readers 4",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {line:?} in\n{text}"
+            );
+        }
+    }
+}
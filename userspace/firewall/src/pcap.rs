@@ -0,0 +1,381 @@
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    io::{self, Write},
+    sync::Mutex,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use firewall_common::{Action, MAX_CAPTURE_LEN};
+use uuid::Uuid;
+
+use crate::{LinkType, PacketEvent, PacketSink};
+
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const IF_NAME: u16 = 2;
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_RAW: u16 = 101;
+// Packets are written through at most this late, the rest is flushed when the sink is dropped
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// [PacketSink] writing the captured bytes of each packet in the PCAPNG format, e.g. to open them with Wireshark.
+///
+/// Each packet has a comment with the action taken, the id its source was classified with and its interface.
+/// Packets are only captured once enabled with [Firewall::set_capture_length](crate::Firewall::set_capture_length),
+/// events without captured bytes are skipped. The sink stops at the first error writing.
+///
+/// The writer is flushed at most once a second while packets are written, and when the sink is dropped.
+///
+/// # Example
+/// ```no_run
+/// # use std::{fs::File, io::BufWriter};
+/// # use firewall::{Firewall, PcapngSink};
+/// let mut fw = Firewall::new("eth0").unwrap();
+/// fw.set_capture_length(256).unwrap();
+/// let file = BufWriter::new(File::create("firewall.pcapng").unwrap());
+/// fw.add_packet_sink(PcapngSink::new(file).unwrap()).unwrap();
+/// ```
+pub struct PcapngSink<W: Write> {
+    state: Mutex<State<W>>,
+}
+
+struct State<W: Write> {
+    writer: W,
+    // Interface description block of each ifindex, numbered in the order they were written, and its name
+    interfaces: HashMap<u32, (u32, Option<String>)>,
+    flushed_at: Instant,
+    failed: bool,
+}
+
+impl<W: Write + Send> PcapngSink<W> {
+    /// Writes the section header to `writer`, interfaces are described as packets from them show up.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // Section length isn't known
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        writer.write_all(&block(SECTION_HEADER, &body))?;
+        writer.flush()?;
+        Ok(Self {
+            state: Mutex::new(State {
+                writer,
+                interfaces: HashMap::new(),
+                flushed_at: Instant::now(),
+                failed: false,
+            }),
+        })
+    }
+}
+
+impl<W: Write> State<W> {
+    fn write(&mut self, event: &PacketEvent) -> io::Result<()> {
+        if !self.interfaces.contains_key(&event.ifindex) {
+            let name = interface_name(event.ifindex);
+            self.describe_interface(name.as_deref())?;
+            let interface = self.interfaces.len() as u32;
+            self.interfaces.insert(event.ifindex, (interface, name));
+        }
+        let (interface, name) = &self.interfaces[&event.ifindex];
+        let comment = comment(event, name.as_deref());
+
+        let micros = event
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut body = Vec::new();
+        body.extend_from_slice(&interface.to_le_bytes());
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(event.data.len() as u32).to_le_bytes());
+        body.extend_from_slice(&event.packet_len.to_le_bytes());
+        body.extend_from_slice(&event.data);
+        pad(&mut body);
+        option(&mut body, OPT_COMMENT, comment.as_bytes());
+        option(&mut body, OPT_END, &[]);
+        self.writer.write_all(&block(ENHANCED_PACKET, &body))?;
+        if self.flushed_at.elapsed() >= FLUSH_INTERVAL {
+            self.writer.flush()?;
+            self.flushed_at = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn describe_interface(&mut self, name: Option<&str>) -> io::Result<()> {
+        // Same fallback as when attaching the firewall
+        let link_type = name
+            .and_then(|name| LinkType::detect(name).ok().flatten())
+            .unwrap_or(LinkType::Ethernet);
+        let mut body = Vec::new();
+        body.extend_from_slice(
+            &match link_type {
+                LinkType::Ethernet => LINKTYPE_ETHERNET,
+                LinkType::RawIp => LINKTYPE_RAW,
+            }
+            .to_le_bytes(),
+        );
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&(MAX_CAPTURE_LEN as u32).to_le_bytes());
+        if let Some(name) = name {
+            option(&mut body, IF_NAME, name.as_bytes());
+        }
+        option(&mut body, OPT_END, &[]);
+        self.writer.write_all(&block(INTERFACE_DESCRIPTION, &body))
+    }
+}
+
+impl<W: Write> Drop for State<W> {
+    fn drop(&mut self) {
+        if !self.failed {
+            let _ = self.writer.flush();
+        }
+    }
+}
+
+impl<W: Write + Send> PacketSink for PcapngSink<W> {
+    fn handle(&self, event: &PacketEvent) {
+        if event.data.is_empty() {
+            return;
+        }
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        if state.failed {
+            return;
+        }
+        if let Err(e) = state.write(event) {
+            tracing::warn!("Couldn't write packet capture: {e}");
+            state.failed = true;
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().map_or(true, |state| state.failed)
+    }
+}
+
+fn comment(event: &PacketEvent, interface: Option<&str>) -> String {
+    let action = match (event.action, event.would_drop) {
+        (Action::Accept, _) => "accept",
+        (Action::Reject, false) => "reject",
+        (Action::Reject, true) => "reject (accepted by audit mode)",
+    };
+    let id = event
+        .id
+        .map_or_else(|| "none".to_string(), |id| Uuid::from_u128(id).to_string());
+    let interface = interface.map_or_else(|| event.ifindex.to_string(), str::to_string);
+    format!("action: {action}, id: {id}, interface: {interface}")
+}
+
+fn interface_name(ifindex: u32) -> Option<String> {
+    let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+    // SAFETY: `name` has room for IF_NAMESIZE bytes as required
+    if unsafe { libc::if_indextoname(ifindex, name.as_mut_ptr()) }.is_null() {
+        return None;
+    }
+    // SAFETY: if_indextoname wrote a nul terminated name
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+// Blocks repeat their total length after the body, which is padded to 32 bits
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let total = (body.len() + 12) as u32;
+    let mut block = Vec::with_capacity(total as usize);
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&total.to_le_bytes());
+    block.extend_from_slice(body);
+    block.extend_from_slice(&total.to_le_bytes());
+    block
+}
+
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn pad(body: &mut Vec<u8>) {
+    while body.len() % 4 != 0 {
+        body.push(0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use firewall_common::Action;
+
+    use super::{
+        PcapngSink, ENHANCED_PACKET, FLUSH_INTERVAL, INTERFACE_DESCRIPTION, SECTION_HEADER,
+    };
+    use crate::{PacketEvent, PacketSink};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn event(ifindex: u32, data: &[u8]) -> PacketEvent {
+        PacketEvent {
+            source_ip: "10.0.0.1".parse().unwrap(),
+            destination_ip: "10.0.0.2".parse().unwrap(),
+            destination_port: Some(53),
+            source_port: Some(4000),
+            action: Action::Reject,
+            would_drop: false,
+            protocol: 17,
+            tcp_flags: None,
+            ttl: 64,
+            dscp: 0,
+            id: Some(1),
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_micros((1 << 32) + 7),
+            packet_len: 100,
+            ifindex,
+            data: data.to_vec(),
+        }
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    // Splits the file into (type, body) blocks checking both lengths match
+    fn blocks(bytes: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let total = u32_at(bytes, offset + 4) as usize;
+            assert_eq!(total % 4, 0);
+            assert_eq!(u32_at(bytes, offset + total - 4) as usize, total);
+            blocks.push((
+                u32_at(bytes, offset),
+                &bytes[offset + 8..offset + total - 4],
+            ));
+            offset += total;
+        }
+        blocks
+    }
+
+    #[test]
+    fn writes_packets_with_comments() {
+        let out = Shared::default();
+        let sink = PcapngSink::new(out.clone()).unwrap();
+        // Interfaces that don't exist
+        sink.handle(&event(u32::MAX - 1, &[1, 2, 3, 4, 5]));
+        sink.handle(&event(u32::MAX - 1, &[]));
+        sink.handle(&event(u32::MAX, &[6]));
+
+        let bytes = out.0.lock().unwrap().clone();
+        let blocks = blocks(&bytes);
+        let types: Vec<_> = blocks.iter().map(|(block_type, _)| *block_type).collect();
+        assert_eq!(
+            types,
+            [
+                SECTION_HEADER,
+                INTERFACE_DESCRIPTION,
+                ENHANCED_PACKET,
+                INTERFACE_DESCRIPTION,
+                ENHANCED_PACKET
+            ]
+        );
+        assert_eq!(u32_at(blocks[0].1, 0), 0x1A2B_3C4D);
+        // Ethernet when the link type can't be detected
+        assert_eq!(&blocks[1].1[..2], &[1, 0]);
+
+        let packet = blocks[2].1;
+        assert_eq!(u32_at(packet, 0), 0);
+        assert_eq!(u32_at(packet, 4), 1);
+        assert_eq!(u32_at(packet, 8), 7);
+        assert_eq!(u32_at(packet, 12), 5);
+        assert_eq!(u32_at(packet, 16), 100);
+        assert_eq!(&packet[20..28], &[1, 2, 3, 4, 5, 0, 0, 0]);
+        let comment = format!(
+            "action: reject, id: 00000000-0000-0000-0000-000000000001, interface: {}",
+            u32::MAX - 1
+        );
+        assert_eq!(u16::from_le_bytes([packet[28], packet[29]]), 1);
+        assert_eq!(
+            usize::from(u16::from_le_bytes([packet[30], packet[31]])),
+            comment.len()
+        );
+        assert_eq!(&packet[32..32 + comment.len()], comment.as_bytes());
+
+        assert_eq!(u32_at(blocks[4].1, 0), 1);
+    }
+
+    #[test]
+    fn stops_after_a_write_error() {
+        struct Failing(usize);
+
+        impl Write for Failing {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.0 == 0 {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                self.0 -= 1;
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let sink = PcapngSink::new(Failing(1)).unwrap();
+        assert!(!sink.is_closed());
+        sink.handle(&event(u32::MAX, &[1]));
+        assert!(sink.is_closed());
+    }
+
+    #[test]
+    fn flushes_periodically_and_on_drop() {
+        struct Flushes(Arc<Mutex<usize>>);
+
+        impl Write for Flushes {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                *self.0.lock().unwrap() += 1;
+                Ok(())
+            }
+        }
+
+        let flushes = Arc::new(Mutex::new(0));
+        let sink = PcapngSink::new(Flushes(flushes.clone())).unwrap();
+        assert_eq!(*flushes.lock().unwrap(), 1);
+        sink.handle(&event(u32::MAX, &[1]));
+        sink.handle(&event(u32::MAX, &[2]));
+        assert_eq!(*flushes.lock().unwrap(), 1);
+
+        sink.state.lock().unwrap().flushed_at = Instant::now().checked_sub(FLUSH_INTERVAL).unwrap();
+        sink.handle(&event(u32::MAX, &[3]));
+        assert_eq!(*flushes.lock().unwrap(), 2);
+
+        drop(sink);
+        assert_eq!(*flushes.lock().unwrap(), 3);
+    }
+}
@@ -0,0 +1,205 @@
+use firewall_common::Action;
+use ipnet::IpNet;
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::{Error, Result, Rule};
+
+/// Complete configuration of a [Firewall](crate::Firewall): default action, ids and rules.
+///
+/// Applied with [apply_policy](crate::Firewall::apply_policy), meant to be kept in a file in one of the formats below.
+///
+/// | Field            | Value                                                    | Default    |
+/// |------------------|----------------------------------------------------------|------------|
+/// | `default_action` | `"Accept"` or `"Reject"`                                 | `"Reject"` |
+/// | `ids`            | List of maps with an `id`, greater than 0, and its `ips` | empty      |
+/// | `rules`          | List of [Rule]s                                          | empty      |
+///
+/// Unknown fields are rejected so typos don't go unnoticed.
+///
+/// # Example
+/// In TOML, parsed with [from_toml](Self::from_toml):
+/// ```toml
+/// default_action = "Reject"
+///
+/// [[ids]]
+/// id = 1
+/// ips = ["10.0.0.5/32", "10.0.0.6/32"]
+///
+/// [[rules]]
+/// dest = "10.0.1.0/24"
+/// id = 1
+/// ports = "8000-8100"
+/// protocol = "tcp"
+///
+/// [[rules]]
+/// dest = "0.0.0.0/0"
+/// ports = 53
+/// protocol = "udp"
+/// ```
+///
+/// The same policy in JSON, parsed with [from_json](Self::from_json):
+/// ```
+/// # use firewall::Policy;
+/// let policy = Policy::from_json(r#"{
+///     "default_action": "Reject",
+///     "ids": [{"id": 1, "ips": ["10.0.0.5/32", "10.0.0.6/32"]}],
+///     "rules": [
+///         {"dest": "10.0.1.0/24", "id": 1, "ports": "8000-8100", "protocol": "tcp"},
+///         {"dest": "0.0.0.0/0", "ports": 53, "protocol": "udp"}
+///     ]
+/// }"#).unwrap();
+/// assert_eq!(policy.rules.len(), 2);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    pub default_action: Action,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ids: Vec<IdAssignment>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+}
+
+/// Networks associated with an id in a [Policy], see [add_id](crate::Firewall::add_id).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdAssignment {
+    #[serde(deserialize_with = "deserialize_id")]
+    pub id: u128,
+    pub ips: Vec<IpNet>,
+}
+
+impl Policy {
+    /// Parses a policy in JSON.
+    ///
+    /// Errors are [Error::InvalidPolicy] with the line of the offending value.
+    pub fn from_json(policy: &str) -> Result<Self> {
+        serde_json::from_str(policy).map_err(|e| Error::InvalidPolicy {
+            line: (e.line() > 0).then_some(e.line()),
+            message: e.to_string(),
+        })
+    }
+
+    /// Parses a policy in TOML, requires the `toml` feature.
+    ///
+    /// Errors are [Error::InvalidPolicy] with the line of the offending value.
+    #[cfg(feature = "toml")]
+    pub fn from_toml(policy: &str) -> Result<Self> {
+        toml::from_str(policy).map_err(|e: toml::de::Error| Error::InvalidPolicy {
+            line: e.line_col().map(|(line, _)| line + 1),
+            message: e.to_string(),
+        })
+    }
+
+    /// Parses a policy in YAML, requires the `yaml` feature.
+    ///
+    /// Errors are [Error::InvalidPolicy] with the line of the offending value.
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(policy: &str) -> Result<Self> {
+        serde_yaml::from_str(policy).map_err(|e| Error::InvalidPolicy {
+            line: e.location().map(|location| location.line()),
+            message: e.to_string(),
+        })
+    }
+
+    /// Id and network pairs, as taken by [replace_ruleset](crate::Firewall::replace_ruleset).
+    pub(crate) fn id_pairs(&self) -> Vec<(IpNet, u128)> {
+        self.ids
+            .iter()
+            .flat_map(|assignment| assignment.ips.iter().map(|&ip| (ip, assignment.id)))
+            .collect()
+    }
+
+    /// Checks the ids and rules, policies built in code don't go through deserialization.
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.ids.iter().any(|assignment| assignment.id == 0) {
+            return Err(Error::InvalidId);
+        }
+        self.rules.iter().try_for_each(Rule::check)
+    }
+}
+
+fn deserialize_id<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<u128, D::Error> {
+    let id = u128::deserialize(deserializer)?;
+    if id == 0 {
+        return Err(de::Error::custom(Error::InvalidId));
+    }
+    Ok(id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::Policy;
+    use crate::{Action, Error, Protocol, Rule};
+
+    #[test]
+    fn parses_json_policy() {
+        let policy = Policy::from_json(
+            r#"{
+                "default_action": "accept",
+                "ids": [{"id": 7, "ips": ["10.0.0.5/32"]}],
+                "rules": [{"dest": "10.0.1.0/24", "id": 7, "ports": 22}]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(policy.default_action, Action::Accept);
+        assert_eq!(policy.id_pairs(), vec![("10.0.0.5/32".parse().unwrap(), 7)]);
+        assert_eq!(
+            policy.rules,
+            vec![Rule::new("10.0.1.0/24".parse().unwrap())
+                .with_id(7)
+                .with_range(22..=22, Protocol::Generic)]
+        );
+    }
+
+    #[test]
+    fn policy_round_trips() {
+        let policy = Policy {
+            default_action: Action::Accept,
+            ids: Vec::new(),
+            rules: vec![
+                Rule::new("fafa::/96".parse().unwrap()).with_range(80..=443, Protocol::TCP),
+                Rule::new("10.0.0.0/8".parse().unwrap())
+                    .with_ttl(0..=4)
+                    .with_dscp(46),
+            ],
+        };
+        let json = serde_json::to_string(&policy).unwrap();
+        assert_eq!(Policy::from_json(&json).unwrap(), policy);
+    }
+
+    fn error_line(policy: &str) -> Option<usize> {
+        match Policy::from_json(policy) {
+            Err(Error::InvalidPolicy { line, .. }) => line,
+            res => panic!("expected an invalid policy, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn invalid_values_error_at_their_line() {
+        let bad_cidr = "{\n\"rules\": [\n{\"dest\": \"10.0.0.0/33\"}\n]\n}";
+        assert_eq!(error_line(bad_cidr), Some(3));
+
+        let bad_range = "{\n\"rules\": [\n{\"dest\": \"10.0.0.0/8\"},\n{\"dest\": \"10.0.0.0/8\", \"ports\": \"0-80\"}\n]\n}";
+        assert_eq!(error_line(bad_range), Some(4));
+
+        let zero_id = "{\n\"ids\": [\n{\"id\": 0, \"ips\": []}\n]\n}";
+        assert_eq!(error_line(zero_id), Some(3));
+
+        let zero_rule_id = "{\n\"rules\": [\n{\"dest\": \"10.0.0.0/8\", \"id\": 0}\n]\n}";
+        assert_eq!(error_line(zero_rule_id), Some(3));
+    }
+
+    #[test]
+    fn check_rejects_invalid_rules() {
+        let policy = Policy {
+            rules: vec![Rule::new("10.0.0.0/8".parse().unwrap()).with_range(0..=80, Protocol::TCP)],
+            ..Default::default()
+        };
+        assert!(matches!(policy.check(), Err(Error::InvalidPort)));
+    }
+}
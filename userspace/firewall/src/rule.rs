@@ -1,21 +1,72 @@
-use firewall_common::GENERIC_PROTO;
+use firewall_common::{HeaderFilter, GENERIC_PROTO};
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
-use std::ops::RangeInclusive;
+use serde::{
+    de::{self, Unexpected, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{fmt, marker::PhantomData, ops::RangeInclusive, str::FromStr};
+
+use crate::{
+    rule_tracker::{header_check, port_range_check},
+    Error, HostnameRule, Result,
+};
+
+mod syntax;
 
 // TODO: Use a builder pattern to hide variant visisibility.
 /// Rule for the [Firewall](crate::Firewall).
-#[derive(Debug, PartialEq, Eq, Clone)]
+///
+/// With serde a rule is a map with the fields below, only `dest` is required.
+/// Ranges are either a single number or a string such as `"8000-8100"`,
+/// `ports` also takes a comma separated list such as `"80,443,8000-8100"`.
+///
+/// | Field       | Value                                        | Builder                                |
+/// |-------------|----------------------------------------------|----------------------------------------|
+/// | `dest`      | Destination network, e.g. `"10.0.0.0/8"`     | [new](Rule::new)                       |
+/// | `id`        | Source id, greater than 0                    | [with_id](Rule::with_id)               |
+/// | `ports`     | Port ranges, `0` for all ports               | [with_ranges](Rule::with_ranges)       |
+/// | `protocol`  | `"tcp"`, `"udp"` or `"generic"`, the default | [with_ranges](Rule::with_ranges)       |
+/// | `tcp_flags` | Map with `flags` and `mask`                  | [with_tcp_flags](Rule::with_tcp_flags) |
+/// | `ttl`       | TTL or hop limit range                       | [with_ttl](Rule::with_ttl)             |
+/// | `dscp`      | DSCP class                                   | [with_dscp](Rule::with_dscp)           |
+///
+/// Deserializing fails for rules [add_rule](crate::Firewall::add_rule) would reject on their own,
+/// e.g. invalid port ranges, and for an id of 0.
+///
+/// Rules also have a one-line form, written with [Display](#impl-Display-for-Rule) and parsed with
+/// [FromStr](#impl-FromStr-for-Rule), e.g. `tcp 10.0.0.0/8 ports 80,443,8000-8100 id 42`.
+///
+/// # Example
+/// ```
+/// # use firewall::{Protocol, Rule};
+/// let rule: Rule =
+///     serde_json::from_str(r#"{"dest": "10.0.0.0/8", "id": 42, "ports": "80-443", "protocol": "tcp"}"#)
+///         .unwrap();
+/// assert_eq!(
+///     rule,
+///     Rule::new("10.0.0.0/8".parse().unwrap())
+///         .with_id(42)
+///         .with_range(80..=443, Protocol::TCP)
+/// );
+/// assert_eq!(rule.to_string(), "tcp 10.0.0.0/8 ports 80-443 id 42");
+/// assert_eq!(rule, "tcp 10.0.0.0/8 ports 80-443 id 42".parse().unwrap());
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+#[serde(try_from = "RuleSpec", into = "RuleSpec")]
 pub enum Rule {
     V4(RuleImpl<Ipv4Net>),
     V6(RuleImpl<Ipv6Net>),
 }
 
 #[doc(hidden)]
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub struct RuleImpl<T> {
     pub(crate) id: Option<u128>,
     pub(crate) dest: T,
-    pub(crate) port_range: Option<PortRange>,
+    /// Sorted and without duplicates, matches every port if empty.
+    pub(crate) ports: Vec<RangeInclusive<u16>>,
+    pub(crate) proto: Protocol,
+    pub(crate) header: HeaderMatch,
 }
 
 impl<T> RuleImpl<T> {
@@ -23,11 +74,13 @@ impl<T> RuleImpl<T> {
         Self {
             dest,
             id: None,
-            port_range: None,
+            ports: Vec::new(),
+            proto: Protocol::default(),
+            header: HeaderMatch::default(),
         }
     }
 
-    fn with_id(self, id: u128) -> Self {
+    pub(crate) fn with_id(self, id: u128) -> Self {
         Self {
             id: Some(id),
             ..self
@@ -35,14 +88,103 @@ impl<T> RuleImpl<T> {
     }
 
     pub(crate) fn with_range(self, range: RangeInclusive<u16>, proto: Protocol) -> Self {
+        self.with_ranges([range], proto)
+    }
+
+    // Ranges are sorted so that rules with the same ranges are equal whatever their order
+    pub(crate) fn with_ranges(
+        self,
+        ranges: impl IntoIterator<Item = RangeInclusive<u16>>,
+        proto: Protocol,
+    ) -> Self {
+        let mut ports: Vec<_> = ranges.into_iter().collect();
+        ports.sort_by_key(|range| (*range.start(), *range.end()));
+        ports.dedup();
         Self {
-            port_range: Some(PortRange {
-                ports: range,
-                proto,
-            }),
+            ports,
+            proto,
             ..self
         }
     }
+
+    pub(crate) fn with_proto(self, proto: Protocol) -> Self {
+        Self { proto, ..self }
+    }
+
+    pub(crate) fn with_tcp_flags(self, flags: u8, mask: u8) -> Self {
+        Self {
+            header: HeaderMatch {
+                tcp_flags: Some((flags, mask)),
+                ..self.header
+            },
+            ..self
+        }
+    }
+
+    pub(crate) fn with_ttl(self, ttl: RangeInclusive<u8>) -> Self {
+        Self {
+            header: HeaderMatch {
+                ttl: Some(ttl),
+                ..self.header
+            },
+            ..self
+        }
+    }
+
+    pub(crate) fn with_dscp(self, dscp: u8) -> Self {
+        Self {
+            header: HeaderMatch {
+                dscp: Some(dscp),
+                ..self.header
+            },
+            ..self
+        }
+    }
+
+    pub(crate) fn with_header(self, header: HeaderMatch) -> Self {
+        Self { header, ..self }
+    }
+
+    pub(crate) fn with_dest<U>(self, dest: U) -> RuleImpl<U> {
+        RuleImpl {
+            id: self.id,
+            dest,
+            ports: self.ports,
+            proto: self.proto,
+            header: self.header,
+        }
+    }
+
+    /// Port ranges as stored by the rule tracker, a single range for all ports if the rule has none.
+    pub(crate) fn port_ranges(&self) -> Vec<PortRange> {
+        if self.ports.is_empty() {
+            return vec![PortRange {
+                ports: 0..=0,
+                proto: self.proto,
+            }];
+        }
+        self.ports
+            .iter()
+            .map(|ports| PortRange {
+                ports: ports.clone(),
+                proto: self.proto,
+            })
+            .collect()
+    }
+
+    /// Checks what can be checked without the other rules of the firewall, ids must be greater than 0.
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.id == Some(0) {
+            return Err(Error::InvalidId);
+        }
+        for port_range in self.port_ranges() {
+            if !port_range_check(&port_range) {
+                return Err(Error::InvalidPort);
+            }
+            header_check(&self.header, &port_range)?;
+        }
+        Ok(())
+    }
 }
 
 impl Rule {
@@ -61,6 +203,21 @@ impl Rule {
         }
     }
 
+    /// Creates a new [HostnameRule] whose destinations are the addresses `hostname` resolves to.
+    ///
+    /// The rule is configured with the same methods as a `Rule` and added with
+    /// [add_hostname_rule](crate::Firewall::add_hostname_rule).
+    ///
+    /// # Example
+    /// ```
+    /// # use firewall::{Protocol, Rule};
+    /// // Rule that matches HTTPS to any address of repo.example.com
+    /// Rule::for_hostname("repo.example.com").with_range(443..=443, Protocol::TCP);
+    /// ```
+    pub fn for_hostname(hostname: impl Into<String>) -> HostnameRule {
+        HostnameRule::new(hostname)
+    }
+
     /// Gives the source ID for the `Rule`.
     ///
     /// For a rule with an ID to match any packet, first you need to associate an IP or multiple IPs with that id.
@@ -98,13 +255,373 @@ impl Rule {
     /// # use firewall::{Protocol, Firewall};
     /// Rule::new("10.5.6.1/32".parse().unwrap()).with_range(100..=433, Protocol::UDP);
     /// ```
-    ///  
     pub fn with_range(self, range: RangeInclusive<u16>, proto: Protocol) -> Self {
         match self {
             Rule::V4(r) => Rule::V4(r.with_range(range, proto)),
             Rule::V6(r) => Rule::V6(r.with_range(range, proto)),
         }
     }
+
+    /// Sets several port ranges for the `Rule`, it matches any of them.
+    ///
+    /// Same as [with_range](Rule::with_range) for each range, all of them share `proto`.
+    /// The ranges are kept sorted and without duplicates, so the order they're given in doesn't matter.
+    /// Without ranges the rule matches every port.
+    ///
+    /// # Example
+    /// ```
+    /// # use firewall::{Protocol, Rule};
+    /// // Rule that matches HTTP and HTTPS
+    /// Rule::new("10.5.6.1/32".parse().unwrap()).with_ranges([80..=80, 443..=443], Protocol::TCP);
+    /// ```
+    pub fn with_ranges(
+        self,
+        ranges: impl IntoIterator<Item = RangeInclusive<u16>>,
+        proto: Protocol,
+    ) -> Self {
+        match self {
+            Rule::V4(r) => Rule::V4(r.with_ranges(ranges, proto)),
+            Rule::V6(r) => Rule::V6(r.with_ranges(ranges, proto)),
+        }
+    }
+
+    /// Restricts the `Rule` to TCP packets whose flags, masked by `mask`, are equal to `flags`.
+    ///
+    /// Flags outside of `mask` are ignored, `mask` must be greater than 0 and hold every flag in `flags`.
+    /// A rule with a flags predicate only matches TCP, so it can't have a [Protocol::UDP] port range,
+    /// with a [Protocol::Generic] range only its TCP ports are used.
+    ///
+    /// Header predicates of a rule count as one, see [with_ttl](Rule::with_ttl).
+    ///
+    /// # Example
+    /// ```
+    /// # use firewall::{Rule, TcpFlags};
+    /// // Rule that matches connections being initiated, SYN without ACK
+    /// Rule::new("10.5.6.1/32".parse().unwrap())
+    ///     .with_tcp_flags(TcpFlags::SYN, TcpFlags::SYN | TcpFlags::ACK);
+    /// // Rule that matches XMAS scans
+    /// let xmas = TcpFlags::FIN | TcpFlags::PSH | TcpFlags::URG;
+    /// Rule::new("0.0.0.0/0".parse().unwrap()).with_tcp_flags(xmas, xmas);
+    /// ```
+    pub fn with_tcp_flags(self, flags: u8, mask: u8) -> Self {
+        match self {
+            Rule::V4(r) => Rule::V4(r.with_tcp_flags(flags, mask)),
+            Rule::V6(r) => Rule::V6(r.with_tcp_flags(flags, mask)),
+        }
+    }
+
+    /// Restricts the `Rule` to packets with a TTL, or hop limit for IPv6, within `ttl`.
+    ///
+    /// The range can't be empty.
+    ///
+    /// Predicates on header fields are checked after the destination lookup, a rule can combine
+    /// [with_tcp_flags](Rule::with_tcp_flags), [with_ttl](Rule::with_ttl) and [with_dscp](Rule::with_dscp)
+    /// and all of them must hold for the rule to match.
+    /// At most [MAX_HEADER_FILTERS](firewall_common::MAX_HEADER_FILTERS) distinct combinations of
+    /// predicates and protocol can be in use at the same time.
+    ///
+    /// # Example
+    /// ```
+    /// # use firewall::Rule;
+    /// // Rule that matches packets about to expire, e.g. traceroute probes
+    /// Rule::new("0.0.0.0/0".parse().unwrap()).with_ttl(0..=4);
+    /// ```
+    pub fn with_ttl(self, ttl: RangeInclusive<u8>) -> Self {
+        match self {
+            Rule::V4(r) => Rule::V4(r.with_ttl(ttl)),
+            Rule::V6(r) => Rule::V6(r.with_ttl(ttl)),
+        }
+    }
+
+    /// Restricts the `Rule` to packets marked with the DSCP class `dscp`.
+    ///
+    /// `dscp` must be lower than 64, see [with_ttl](Rule::with_ttl) for how predicates combine.
+    ///
+    /// # Example
+    /// ```
+    /// # use firewall::Rule;
+    /// // Rule that matches expedited forwarding traffic to the voice gateways
+    /// Rule::new("10.5.6.0/24".parse().unwrap()).with_dscp(46);
+    /// ```
+    pub fn with_dscp(self, dscp: u8) -> Self {
+        match self {
+            Rule::V4(r) => Rule::V4(r.with_dscp(dscp)),
+            Rule::V6(r) => Rule::V6(r.with_dscp(dscp)),
+        }
+    }
+}
+
+impl Rule {
+    // Protocol of a rule without port ranges, `with_range` sets it otherwise
+    pub(crate) fn with_proto(self, proto: Protocol) -> Self {
+        match self {
+            Rule::V4(r) => Rule::V4(r.with_proto(proto)),
+            Rule::V6(r) => Rule::V6(r.with_proto(proto)),
+        }
+    }
+
+    pub(crate) fn check(&self) -> Result<()> {
+        match self {
+            Rule::V4(r) => r.check(),
+            Rule::V6(r) => r.check(),
+        }
+    }
+}
+
+// Representation of a `Rule` with serde
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    dest: IpNet,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<u128>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ports: Option<PortList>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    protocol: Option<Protocol>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tcp_flags: Option<TcpFlagsSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl: Option<Span<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dscp: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TcpFlagsSpec {
+    flags: u8,
+    mask: u8,
+}
+
+impl TryFrom<RuleSpec> for Rule {
+    type Error = Error;
+
+    fn try_from(spec: RuleSpec) -> Result<Self> {
+        let mut rule = Rule::new(spec.dest);
+        if let Some(id) = spec.id {
+            rule = rule.with_id(id);
+        }
+        let ports = spec.ports.map(|ports| ports.0).unwrap_or_default();
+        rule = rule.with_ranges(ports, spec.protocol.unwrap_or_default());
+        if let Some(TcpFlagsSpec { flags, mask }) = spec.tcp_flags {
+            rule = rule.with_tcp_flags(flags, mask);
+        }
+        if let Some(ttl) = spec.ttl {
+            rule = rule.with_ttl(ttl.0);
+        }
+        if let Some(dscp) = spec.dscp {
+            rule = rule.with_dscp(dscp);
+        }
+        rule.check()?;
+        Ok(rule)
+    }
+}
+
+impl From<Rule> for RuleSpec {
+    fn from(rule: Rule) -> Self {
+        let (dest, id, ports, proto, header) = match rule {
+            Rule::V4(r) => (IpNet::V4(r.dest), r.id, r.ports, r.proto, r.header),
+            Rule::V6(r) => (IpNet::V6(r.dest), r.id, r.ports, r.proto, r.header),
+        };
+        Self {
+            dest,
+            id,
+            ports: (!ports.is_empty()).then_some(PortList(ports)),
+            protocol: (proto != Protocol::Generic).then_some(proto),
+            tcp_flags: header
+                .tcp_flags
+                .map(|(flags, mask)| TcpFlagsSpec { flags, mask }),
+            ttl: header.ttl.map(Span),
+            dscp: header.dscp,
+        }
+    }
+}
+
+/// Parses either a single value or an inclusive range such as `8000-8100`.
+pub(crate) fn parse_span<T: FromStr + PartialOrd>(s: &str) -> Option<RangeInclusive<T>> {
+    let (start, end) = s.split_once('-').unwrap_or((s, s));
+    let range = start.trim().parse().ok()?..=end.trim().parse().ok()?;
+    (range.start() <= range.end()).then_some(range)
+}
+
+/// Parses a comma separated list of ports and ranges such as `80,443,8000-8100`.
+pub(crate) fn parse_port_list(s: &str) -> Option<Vec<RangeInclusive<u16>>> {
+    s.split(',').map(parse_span).collect()
+}
+
+// Inclusive range written as a single number if it has one value or as `"start-end"` otherwise
+struct Span<T>(RangeInclusive<T>);
+
+impl<T: Serialize + fmt::Display + PartialEq> Serialize for Span<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if self.0.start() == self.0.end() {
+            self.0.start().serialize(serializer)
+        } else {
+            serializer.collect_str(&format_args!("{}-{}", self.0.start(), self.0.end()))
+        }
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Span<T>
+where
+    T: TryFrom<u64> + FromStr + PartialOrd + Copy,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_any(SpanVisitor(PhantomData))
+    }
+}
+
+struct SpanVisitor<T>(PhantomData<T>);
+
+impl<'de, T> Visitor<'de> for SpanVisitor<T>
+where
+    T: TryFrom<u64> + FromStr + PartialOrd + Copy,
+{
+    type Value = Span<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a number or a range such as \"8000-8100\"")
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<Self::Value, E> {
+        let value = T::try_from(v).map_err(|_| E::invalid_value(Unexpected::Unsigned(v), &self))?;
+        Ok(Span(value..=value))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<Self::Value, E> {
+        let unsigned =
+            u64::try_from(v).map_err(|_| E::invalid_value(Unexpected::Signed(v), &self))?;
+        self.visit_u64(unsigned)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Self::Value, E> {
+        parse_span(v)
+            .map(Span)
+            .ok_or_else(|| E::invalid_value(Unexpected::Str(v), &self))
+    }
+}
+
+// Port ranges written as a single number if there's only one port or as `"80,443,8000-8100"` otherwise
+struct PortList(Vec<RangeInclusive<u16>>);
+
+impl fmt::Display for PortList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, range) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            if range.start() == range.end() {
+                write!(f, "{}", range.start())?;
+            } else {
+                write!(f, "{}-{}", range.start(), range.end())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for PortList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self.0.as_slice() {
+            [range] if range.start() == range.end() => range.start().serialize(serializer),
+            _ => serializer.collect_str(self),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PortList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_any(PortListVisitor)
+    }
+}
+
+struct PortListVisitor;
+
+impl<'de> Visitor<'de> for PortListVisitor {
+    type Value = PortList;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a port or a list of ports and ranges such as \"80,443,8000-8100\"")
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<Self::Value, E> {
+        let port =
+            u16::try_from(v).map_err(|_| E::invalid_value(Unexpected::Unsigned(v), &self))?;
+        Ok(PortList(vec![port..=port]))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<Self::Value, E> {
+        let unsigned =
+            u64::try_from(v).map_err(|_| E::invalid_value(Unexpected::Signed(v), &self))?;
+        self.visit_u64(unsigned)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Self::Value, E> {
+        parse_port_list(v)
+            .map(PortList)
+            .ok_or_else(|| E::invalid_value(Unexpected::Str(v), &self))
+    }
+}
+
+/// Flags of the TCP header to use with [with_tcp_flags](Rule::with_tcp_flags).
+pub struct TcpFlags;
+
+impl TcpFlags {
+    pub const FIN: u8 = 0x01;
+    pub const SYN: u8 = 0x02;
+    pub const RST: u8 = 0x04;
+    pub const PSH: u8 = 0x08;
+    pub const ACK: u8 = 0x10;
+    pub const URG: u8 = 0x20;
+    pub const ECE: u8 = 0x40;
+    pub const CWR: u8 = 0x80;
+}
+
+const DSCP_MASK: u8 = 0x3F;
+
+/// Predicates on header fields of a rule, `None` fields match any packet.
+#[derive(Debug, PartialEq, Eq, Clone, Default, Hash)]
+pub(crate) struct HeaderMatch {
+    /// Flags and mask.
+    pub(crate) tcp_flags: Option<(u8, u8)>,
+    pub(crate) ttl: Option<RangeInclusive<u8>>,
+    pub(crate) dscp: Option<u8>,
+}
+
+impl HeaderMatch {
+    /// Whether every packet satisfies the predicates.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.tcp_flags.is_none()
+            && self.dscp.is_none()
+            && self.ttl.as_ref().map_or(true, |ttl| *ttl == (0..=u8::MAX))
+    }
+
+    pub(crate) fn filter(&self, proto: Protocol) -> HeaderFilter {
+        let (tcp_flags, tcp_flags_mask) = self.tcp_flags.unwrap_or_default();
+        let ttl = self.ttl.clone().unwrap_or(0..=u8::MAX);
+        HeaderFilter {
+            proto: proto as u8,
+            tcp_flags,
+            tcp_flags_mask,
+            ttl_min: *ttl.start(),
+            ttl_max: *ttl.end(),
+            dscp: self.dscp.unwrap_or_default(),
+            dscp_mask: if self.dscp.is_some() { DSCP_MASK } else { 0 },
+            pad: 0,
+        }
+    }
+
+    #[cfg(any(test, feature = "pinning"))]
+    pub(crate) fn from_filter(filter: &HeaderFilter) -> Option<(Protocol, Self)> {
+        let header = Self {
+            tcp_flags: (filter.tcp_flags_mask != 0)
+                .then_some((filter.tcp_flags, filter.tcp_flags_mask)),
+            ttl: Some(filter.ttl_min..=filter.ttl_max).filter(|ttl| *ttl != (0..=u8::MAX)),
+            dscp: (filter.dscp_mask != 0).then_some(filter.dscp),
+        };
+        Some((Protocol::from_u8(filter.proto)?, header))
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
@@ -140,8 +657,11 @@ impl PortRange {
 }
 
 /// Struct with Protocol types to specify what a given port range affects when creating a [Rule] with [with_range](Rule::with_range).
+///
+/// With serde it's written as `"tcp"`, `"udp"` or `"generic"`.
 #[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Protocol {
     /// TCP Protocol port range.
     TCP = 0x06u8,
@@ -158,6 +678,16 @@ impl Default for Protocol {
 }
 
 impl Protocol {
+    #[cfg(any(test, feature = "pinning"))]
+    pub(crate) fn from_u8(proto: u8) -> Option<Self> {
+        match proto {
+            x if x == Self::TCP as u8 => Some(Self::TCP),
+            x if x == Self::UDP as u8 => Some(Self::UDP),
+            GENERIC_PROTO => Some(Self::Generic),
+            _ => None,
+        }
+    }
+
     fn unfold(&self) -> Vec<Self> {
         if *self == Protocol::Generic {
             vec![Self::TCP, Self::UDP]
@@ -0,0 +1,405 @@
+//! One-line syntax for rules, e.g. `tcp 10.0.0.0/8 ports 80,443,8000-8100 id 42`.
+//!
+//! ```text
+//! [tcp | udp | generic] DEST [ports LIST] [id ID] [ttl RANGE] [dscp DSCP] [flags FLAGS/MASK]
+//! ```
+//!
+//! Clauses after the destination can come in any order but only once each.
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use ipnet::IpNet;
+
+use super::{parse_span, PortList, PortRange, Protocol, Rule, RuleImpl, TcpFlags};
+use crate::{Error, Result};
+
+const KEYWORDS: [&str; 5] = ["ports", "id", "ttl", "dscp", "flags"];
+
+const FLAG_NAMES: [(&str, u8); 8] = [
+    ("fin", TcpFlags::FIN),
+    ("syn", TcpFlags::SYN),
+    ("rst", TcpFlags::RST),
+    ("psh", TcpFlags::PSH),
+    ("ack", TcpFlags::ACK),
+    ("urg", TcpFlags::URG),
+    ("ece", TcpFlags::ECE),
+    ("cwr", TcpFlags::CWR),
+];
+
+#[derive(Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    /// Counting characters from 1.
+    column: usize,
+}
+
+impl<'a> Token<'a> {
+    fn error(&self, reason: impl Into<String>) -> Error {
+        Error::InvalidRuleSyntax {
+            token: self.text.to_string(),
+            column: self.column,
+            reason: reason.into(),
+        }
+    }
+
+    /// Splits the token on `separator`, keeping track of the columns.
+    fn split(self, separator: char) -> impl Iterator<Item = Token<'a>> {
+        let mut column = self.column;
+        self.text.split(separator).map(move |text| {
+            let token = Token { text, column };
+            column += text.chars().count() + 1;
+            token
+        })
+    }
+}
+
+fn tokenize(s: &str) -> impl Iterator<Item = Token<'_>> {
+    let mut column = 1;
+    let mut start = None;
+    let mut tokens = Vec::new();
+    for (i, c) in s.char_indices() {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some((i, column)),
+            (true, Some((begin, token_column))) => {
+                tokens.push(Token {
+                    text: &s[begin..i],
+                    column: token_column,
+                });
+                start = None;
+            }
+            _ => {}
+        }
+        column += 1;
+    }
+    if let Some((begin, token_column)) = start {
+        tokens.push(Token {
+            text: &s[begin..],
+            column: token_column,
+        });
+    }
+    tokens.into_iter()
+}
+
+fn parse_protocol(s: &str) -> Option<Protocol> {
+    match s {
+        "tcp" => Some(Protocol::TCP),
+        "udp" => Some(Protocol::UDP),
+        "generic" => Some(Protocol::Generic),
+        _ => None,
+    }
+}
+
+// Networks or single addresses
+fn parse_dest(token: Token) -> Result<IpNet> {
+    token
+        .text
+        .parse()
+        .or_else(|_| token.text.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| token.error("expected a network such as 10.0.0.0/8"))
+}
+
+fn parse_ports(token: Token, proto: Protocol, rule: Rule) -> Result<Rule> {
+    let mut ranges = Vec::new();
+    for range in token.split(',') {
+        let ports = parse_span(range.text)
+            .ok_or_else(|| range.error("expected a port or a range such as 8000-8100"))?;
+        let port_range = PortRange {
+            ports: ports.clone(),
+            proto,
+        };
+        if !port_range.valid_range() {
+            return Err(range.error(Error::InvalidPort.to_string()));
+        }
+        ranges.push(ports);
+    }
+    Ok(rule.with_ranges(ranges, proto))
+}
+
+fn parse_flag_names(token: Token) -> Result<u8> {
+    if token.text == "none" {
+        return Ok(0);
+    }
+    token.split(',').try_fold(0, |flags, name| {
+        FLAG_NAMES
+            .iter()
+            .find(|(flag_name, _)| *flag_name == name.text)
+            .map(|(_, flag)| flags | flag)
+            .ok_or_else(|| name.error("unknown TCP flag"))
+    })
+}
+
+fn parse_tcp_flags(token: Token) -> Result<(u8, u8)> {
+    let mut parts = token.split('/');
+    let (flags, mask) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(flags), Some(mask), None) => (flags, mask),
+        _ => return Err(token.error("expected flags and mask such as syn/syn,ack")),
+    };
+    let (flags, mask) = (parse_flag_names(flags)?, parse_flag_names(mask)?);
+    if mask == 0 || flags & !mask != 0 {
+        return Err(token.error(Error::InvalidTcpFlags.to_string()));
+    }
+    Ok((flags, mask))
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    /// Parses a rule in the one-line syntax, see [Display](#impl-Display-for-Rule).
+    ///
+    /// Errors are [Error::InvalidRuleSyntax] pointing at the offending token.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut tokens = tokenize(s);
+        let missing = Token {
+            text: "",
+            column: s.chars().count() + 1,
+        };
+        let first = tokens
+            .next()
+            .ok_or_else(|| missing.error("expected a destination"))?;
+        let (proto, dest) = match parse_protocol(first.text) {
+            Some(proto) => {
+                let dest = tokens
+                    .next()
+                    .ok_or_else(|| missing.error("expected a destination"))?;
+                (proto, dest)
+            }
+            None => (Protocol::Generic, first),
+        };
+        let mut rule = Rule::new(parse_dest(dest)?).with_proto(proto);
+
+        let mut seen = Vec::new();
+        while let Some(keyword) = tokens.next() {
+            if !KEYWORDS.contains(&keyword.text) {
+                return Err(keyword.error(format!(
+                    "unknown keyword, expected one of {}",
+                    KEYWORDS.join(", ")
+                )));
+            }
+            if seen.contains(&keyword.text) {
+                return Err(keyword.error("repeated keyword"));
+            }
+            seen.push(keyword.text);
+
+            let value = tokens
+                .next()
+                .ok_or_else(|| missing.error(format!("expected a value for {}", keyword.text)))?;
+            rule = match keyword.text {
+                "ports" => parse_ports(value, proto, rule)?,
+                "id" => match value.text.parse() {
+                    Ok(0) => return Err(value.error(Error::InvalidId.to_string())),
+                    Ok(id) => rule.with_id(id),
+                    Err(_) => return Err(value.error("expected a number")),
+                },
+                "ttl" => rule.with_ttl(
+                    parse_span(value.text)
+                        .ok_or_else(|| value.error("expected a TTL or a range such as 0-4"))?,
+                ),
+                "dscp" => match value.text.parse() {
+                    Ok(dscp) if dscp <= 0x3F => rule.with_dscp(dscp),
+                    Ok(_) => return Err(value.error(Error::InvalidDscp.to_string())),
+                    Err(_) => return Err(value.error("expected a number")),
+                },
+                "flags" => {
+                    if proto == Protocol::UDP {
+                        return Err(keyword.error(Error::InvalidTcpFlags.to_string()));
+                    }
+                    let (flags, mask) = parse_tcp_flags(value)?;
+                    rule.with_tcp_flags(flags, mask)
+                }
+                _ => unreachable!("keyword already checked"),
+            };
+        }
+        Ok(rule)
+    }
+}
+
+struct FlagNames(u8);
+
+impl fmt::Display for FlagNames {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("none");
+        }
+        let names = FLAG_NAMES
+            .iter()
+            .filter(|(_, flag)| self.0 & flag != 0)
+            .map(|(name, _)| *name);
+        for (i, name) in names.enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            f.write_str(name)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_rule<T>(f: &mut fmt::Formatter, rule: &RuleImpl<T>, dest: IpNet) -> fmt::Result {
+    match rule.proto {
+        Protocol::TCP => write!(f, "tcp {dest}")?,
+        Protocol::UDP => write!(f, "udp {dest}")?,
+        Protocol::Generic => write!(f, "{dest}")?,
+    }
+    if !rule.ports.is_empty() {
+        write!(f, " ports {}", PortList(rule.ports.clone()))?;
+    }
+    if let Some(id) = rule.id {
+        write!(f, " id {id}")?;
+    }
+    if let Some(ttl) = &rule.header.ttl {
+        if ttl.start() == ttl.end() {
+            write!(f, " ttl {}", ttl.start())?;
+        } else {
+            write!(f, " ttl {}-{}", ttl.start(), ttl.end())?;
+        }
+    }
+    if let Some(dscp) = rule.header.dscp {
+        write!(f, " dscp {dscp}")?;
+    }
+    if let Some((flags, mask)) = rule.header.tcp_flags {
+        write!(f, " flags {}/{}", FlagNames(flags), FlagNames(mask))?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Rule {
+    /// Writes the rule in a one-line syntax that [FromStr](#impl-FromStr-for-Rule) parses back,
+    /// e.g. `tcp 10.0.0.0/8 ports 80,443,8000-8100 id 42`.
+    ///
+    /// ```text
+    /// [tcp | udp | generic] DEST [ports LIST] [id ID] [ttl RANGE] [dscp DSCP] [flags FLAGS/MASK]
+    /// ```
+    ///
+    /// Ports and TTL ranges are written as `start-end`, flags as comma separated names among
+    /// `fin`, `syn`, `rst`, `psh`, `ack`, `urg`, `ece` and `cwr`, or `none`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rule::V4(r) => write_rule(f, r, IpNet::V4(r.dest)),
+            Rule::V6(r) => write_rule(f, r, IpNet::V6(r.dest)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{rule::PortRange, Error, Protocol, Rule, TcpFlags};
+
+    fn error_at(rule: &str) -> (String, usize) {
+        match rule.parse::<Rule>() {
+            Err(Error::InvalidRuleSyntax { token, column, .. }) => (token, column),
+            res => panic!("expected a syntax error, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_rule() {
+        let rule: Rule = "tcp 10.0.0.0/8 ports 80,443,8000-8100 id 42"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            rule,
+            Rule::new("10.0.0.0/8".parse().unwrap())
+                .with_ranges([80..=80, 443..=443, 8000..=8100], Protocol::TCP)
+                .with_id(42)
+        );
+    }
+
+    #[test]
+    fn ports_expand_to_port_ranges() {
+        let rule: Rule = "fafa::/64 ports 22,8000-8100".parse().unwrap();
+        let rule = match rule {
+            Rule::V6(rule) => rule,
+            Rule::V4(_) => panic!("expected an IPv6 rule"),
+        };
+        assert_eq!(
+            rule.port_ranges(),
+            vec![
+                PortRange {
+                    ports: 22..=22,
+                    proto: Protocol::Generic
+                },
+                PortRange {
+                    ports: 8000..=8100,
+                    proto: Protocol::Generic
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn rules_round_trip() {
+        let rules = [
+            Rule::new("10.0.0.0/8".parse().unwrap()),
+            Rule::new("10.0.0.0/8".parse().unwrap()).with_range(0..=0, Protocol::UDP),
+            Rule::new("fafa::/96".parse().unwrap())
+                .with_id(7)
+                .with_ranges([80..=80, 8000..=8100], Protocol::TCP),
+            Rule::new("0.0.0.0/0".parse().unwrap())
+                .with_tcp_flags(TcpFlags::SYN, TcpFlags::SYN | TcpFlags::ACK)
+                .with_ttl(0..=4)
+                .with_dscp(46),
+            Rule::new("10.1.0.0/16".parse().unwrap())
+                .with_range(53..=53, Protocol::TCP)
+                .with_tcp_flags(0, TcpFlags::RST)
+                .with_ttl(64..=64),
+        ];
+        for rule in rules {
+            assert_eq!(rule.to_string().parse::<Rule>().unwrap(), rule);
+        }
+        assert_eq!(
+            "tcp 10.0.0.0/8 ports 80,443 id 42 flags syn/syn,ack"
+                .parse::<Rule>()
+                .unwrap()
+                .to_string(),
+            "tcp 10.0.0.0/8 ports 80,443 id 42 flags syn/syn,ack"
+        );
+    }
+
+    #[test]
+    fn port_order_and_duplicates_are_ignored() {
+        let rule: Rule = "tcp 10.0.0.0/8 ports 443,80,443".parse().unwrap();
+        assert_eq!(
+            rule,
+            Rule::new("10.0.0.0/8".parse().unwrap())
+                .with_ranges([80..=80, 443..=443], Protocol::TCP)
+        );
+        assert_eq!(rule.to_string(), "tcp 10.0.0.0/8 ports 80,443");
+
+        // A single range replaces the previous ones along with their protocol
+        assert_eq!(
+            Rule::new("10.0.0.0/8".parse().unwrap())
+                .with_range(53..=53, Protocol::UDP)
+                .with_range(443..=443, Protocol::TCP),
+            Rule::new("10.0.0.0/8".parse().unwrap()).with_range(443..=443, Protocol::TCP)
+        );
+    }
+
+    #[test]
+    fn errors_point_at_offending_token() {
+        assert_eq!(
+            error_at("tcp 10.0.0.0/33 ports 80"),
+            ("10.0.0.0/33".to_string(), 5)
+        );
+        assert_eq!(
+            error_at("tcp 10.0.0.0/8 ports 80,0-443"),
+            ("0-443".to_string(), 25)
+        );
+        assert_eq!(error_at("10.0.0.0/8 id 0"), ("0".to_string(), 15));
+        assert_eq!(error_at("10.0.0.0/8 port 80"), ("port".to_string(), 12));
+        assert_eq!(error_at("10.0.0.0/8 id 1 id 2"), ("id".to_string(), 17));
+        assert_eq!(error_at("10.0.0.0/8  ttl"), ("".to_string(), 16));
+        assert_eq!(error_at("10.0.0.0/8 dscp 64"), ("64".to_string(), 17));
+        assert_eq!(
+            error_at("udp 10.0.0.0/8 flags syn/syn"),
+            ("flags".to_string(), 16)
+        );
+        assert_eq!(
+            error_at("10.0.0.0/8 flags syn/syn,akc"),
+            ("akc".to_string(), 26)
+        );
+        assert_eq!(
+            error_at("10.0.0.0/8 flags syn,ack/ack"),
+            ("syn,ack/ack".to_string(), 18)
+        );
+        assert_eq!(error_at(""), ("".to_string(), 1));
+    }
+}
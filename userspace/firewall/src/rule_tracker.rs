@@ -2,22 +2,28 @@ mod rule_trie;
 mod test;
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::Debug,
     hash::Hash,
 };
 
-use firewall_common::{RuleStore, RuleStoreError};
+use aya::{
+    maps::{lpm_trie::Key, MapError},
+    Pod,
+};
+use firewall_common::{
+    HeaderFilter, RuleStore, RuleStoreError, HEADER_FILTER_PROTO, MAX_HEADER_FILTERS,
+};
 use ipnet::{Ipv4Net, Ipv6Net};
 
 use crate::{
     as_octet::AsOctets,
-    cidr::{AsKey, AsNum, Contains, Normalize, Normalized},
-    rule::{self, Protocol, RuleImpl},
+    cidr::{AsKey, AsNum, Contains, FromKey, Normalize, Normalized},
+    rule::{self, HeaderMatch, Protocol, RuleImpl},
     Error, Result,
 };
 
-use self::rule_trie::RuleTrie;
+pub(crate) use self::rule_trie::RuleTrie;
 
 type StoreResult<T = ()> = std::result::Result<T, RuleStoreError>;
 
@@ -59,6 +65,34 @@ where
     RuleStore::new(&resolve_overlap(port_ranges))
 }
 
+// Writes an entry along with the key the eBPF program records its hits under
+fn insert_entry<K: Pod>(
+    store: &mut impl RuleTrie<K, RuleStore>,
+    key: &Key<K>,
+    entry: RuleStore,
+) -> std::result::Result<(), MapError> {
+    store.insert(key, entry.with_hit_key(hit_key(key)))
+}
+
+/// Key for the last hit of the entry stored with `key`, a FNV-1a hash of the trie key.
+///
+/// It only depends on the trie key so an entry keeps its hits when rewritten or moved to the other generation.
+pub(crate) fn hit_key<K: Pod>(key: &Key<K>) -> u64 {
+    let prefix_len = key.prefix_len;
+    let data = key.data;
+    // SAFETY: `K` is `Pod` so it's valid to read as bytes for its size
+    let data = unsafe {
+        std::slice::from_raw_parts((&data as *const K).cast::<u8>(), std::mem::size_of::<K>())
+    };
+    prefix_len
+        .to_ne_bytes()
+        .iter()
+        .chain(data)
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
 fn resolve_overlap<T>(port_ranges: &mut [&PortRange<T>]) -> Vec<(u16, u16)>
 where
     T: AsNum + AsOctets,
@@ -88,14 +122,35 @@ where
 pub(crate) type RuleTrackerV4 = RuleTracker<Ipv4Net>;
 pub(crate) type RuleTrackerV6 = RuleTracker<Ipv6Net>;
 
+#[derive(Clone)]
 pub(crate) struct RuleTracker<T>
 where
     T: AsNum + AsOctets + AsKey + Normalize,
     T::Octets: AsRef<[u8]>,
 {
-    rule_map: HashMap<(u128, Protocol, Normalized<T>), HashSet<PortRange<T>>>,
+    // Keyed by the protocol used in the trie keys, see `key_proto`
+    rule_map: HashMap<(u128, u8, Normalized<T>), HashSet<PortRange<T>>>,
+    // Header predicates by slot, a protocol of 0 means the slot is empty
+    header_filters: HeaderFilters,
+    // Rules as they were added, `rule_map` holds them unfolded and propagated
+    rules: HashSet<RuleImpl<T>>,
+    // Rules added for hostname rules, they share the entries of the rules in `rules` with the same ranges
+    resolved: HashSet<RuleImpl<T>>,
 }
 
+pub(crate) type HeaderFilters = [HeaderFilter; MAX_HEADER_FILTERS as usize];
+
+const EMPTY_FILTER: HeaderFilter = HeaderFilter {
+    proto: 0,
+    tcp_flags: 0,
+    tcp_flags_mask: 0,
+    ttl_min: 0,
+    ttl_max: 0,
+    dscp: 0,
+    dscp_mask: 0,
+    pad: 0,
+};
+
 impl<T> Debug for RuleTracker<T>
 where
     T: AsNum + AsKey + Normalize + AsOctets + Debug,
@@ -104,6 +159,9 @@ where
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("RuleTracker")
             .field("rule_map", &self.rule_map)
+            .field("header_filters", &self.header_filters)
+            .field("rules", &self.rules)
+            .field("resolved", &self.resolved)
             .finish()
     }
 }
@@ -129,6 +187,9 @@ where
     fn new_with_name() -> Result<Self> {
         Ok(Self {
             rule_map: HashMap::new(),
+            header_filters: [EMPTY_FILTER; MAX_HEADER_FILTERS as usize],
+            rules: HashSet::new(),
+            resolved: HashSet::new(),
         })
     }
 }
@@ -141,139 +202,413 @@ where
     pub(crate) fn add_rule(
         &mut self,
         store: &mut impl RuleTrie<T::KeySize, RuleStore>,
-        RuleImpl {
-            id,
-            dest,
-            port_range,
-        }: &RuleImpl<T>,
+        rule: &RuleImpl<T>,
     ) -> Result<()> {
-        if !port_range_check(port_range) {
-            return Err(Error::InvalidPort);
+        if !self.resolved.contains(rule) {
+            self.add_rule_impl(store, rule)?;
         }
+        self.rules.insert(rule.clone());
+        Ok(())
+    }
 
-        let port_range = PortRange {
-            ports: port_range.clone().unwrap_or_default(),
-            origin: dest.clone(),
-        };
+    /// Adds a rule resolved from a hostname rule, see [HostnameTracker](crate::hostname::HostnameTracker).
+    ///
+    /// It's tracked apart from the rules added with [add_rule](Self::add_rule), an identical
+    /// rule added either way keeps its entries until both are removed and ranges it shares with
+    /// an overlapping rule stay as long as that rule does.
+    pub(crate) fn add_resolved_rule(
+        &mut self,
+        store: &mut impl RuleTrie<T::KeySize, RuleStore>,
+        rule: &RuleImpl<T>,
+    ) -> Result<()> {
+        if !self.rules.contains(rule) && !self.resolved.contains(rule) {
+            self.add_rule_impl(store, rule)?;
+        }
+        self.resolved.insert(rule.clone());
+        Ok(())
+    }
 
-        // Checks to prevent rollback
-        for port_range in port_range.unfold() {
-            let proto = port_range.ports.proto;
-            let id = id.unwrap_or(0);
+    /// Removes a rule added with [add_resolved_rule](Self::add_resolved_rule).
+    pub(crate) fn remove_resolved_rule(
+        &mut self,
+        store: &mut impl RuleTrie<T::KeySize, RuleStore>,
+        rule: &RuleImpl<T>,
+    ) -> Result<()> {
+        if self.resolved.contains(rule) && !self.rules.contains(rule) {
+            self.remove_rule_impl(store, rule)?;
+        }
+        self.resolved.remove(rule);
+        Ok(())
+    }
+
+    fn add_rule_impl(
+        &mut self,
+        store: &mut impl RuleTrie<T::KeySize, RuleStore>,
+        rule: &RuleImpl<T>,
+    ) -> Result<()> {
+        let RuleImpl { id, dest, .. } = rule;
+        let id = id.unwrap_or(0);
+
+        // Slots are only taken once the checks pass
+        let mut header_filters = self.header_filters;
+        let mut keyed = Vec::new();
+        for port_range in rule.port_ranges() {
+            if !port_range_check(&port_range) {
+                return Err(Error::InvalidPort);
+            }
+
+            header_check(&rule.header, &port_range)?;
+            let slot = match header_filter(&rule.header, &port_range) {
+                Some(filter) => {
+                    let slot = self.header_filter_slot(&header_filters, &filter)?;
+                    header_filters[slot as usize] = filter;
+                    Some(slot)
+                }
+                None => None,
+            };
+
+            let port_range = PortRange {
+                ports: port_range,
+                origin: dest.clone(),
+            };
+            keyed.extend(unfold_keyed(&port_range, slot));
+        }
+        let keyed = group_by_proto(keyed);
 
-            self.check_range_len(&port_range, id, dest)?;
+        // Checks to prevent rollback
+        for (&proto, port_ranges) in &keyed {
+            self.check_range_len(port_ranges, id, proto, dest)?;
             self.reverse_propagate_check(store, dest, id, proto)?;
-            self.propagate_check(store, port_range, id, proto)?;
+            self.propagate_check(store, port_ranges, dest, id, proto)?;
         }
 
-        // Apply modifications
-        for port_range in port_range.unfold() {
-            let proto = port_range.ports.proto;
-            let id = id.unwrap_or(0);
+        self.header_filters = header_filters;
 
-            let port_ranges = self
+        // Apply modifications
+        for (&proto, port_ranges) in &keyed {
+            let entry = self
                 .rule_map
-                .entry((id, port_range.ports.proto, Normalized::new(dest.clone())))
-                .and_modify(|e| {
-                    e.insert(port_range.clone());
-                })
-                .or_insert_with(|| HashSet::from([port_range.clone()]));
+                .entry((id, proto, Normalized::new(dest.clone())))
+                .or_default();
+            entry.extend(port_ranges.iter().cloned());
 
-            store.insert(
-                &dest.as_key(id, proto as u8),
-                to_rule_store(&*port_ranges)
+            insert_entry(
+                store,
+                &dest.as_key(id, proto),
+                to_rule_store(&*entry)
                     .expect("Incorrect number of rules, should've errored in the previous check"),
             )?;
 
             self.reverse_propagate(store, dest, id, proto)?;
-            self.propagate(store, port_range, id, proto)?;
+            self.propagate(store, port_ranges, dest, id, proto)?;
+        }
+        Ok(())
+    }
+
+    /// Removes all rules from the tracker and `store`.
+    pub(crate) fn clear(&mut self, store: &mut impl RuleTrie<T::KeySize, RuleStore>) -> Result<()> {
+        for (id, proto, ip) in self.rule_map.keys() {
+            store.remove(&ip.ip.as_key(*id, *proto))?;
+        }
+        self.rule_map.clear();
+        self.header_filters = [EMPTY_FILTER; MAX_HEADER_FILTERS as usize];
+        self.rules.clear();
+        self.resolved.clear();
+        Ok(())
+    }
+
+    /// Rules as they were added, rules that were added several times are only returned once.
+    ///
+    /// Rules added with [add_resolved_rule](Self::add_resolved_rule) aren't included.
+    pub(crate) fn rules(&self) -> impl Iterator<Item = &RuleImpl<T>> {
+        self.rules.iter()
+    }
+
+    /// Entries written to the trie, each rule takes one per destination, id and protocol.
+    pub(crate) fn trie_entries(&self) -> usize {
+        self.rule_map.len()
+    }
+
+    /// Header predicates in use by the rules, indexed by slot.
+    pub(crate) fn header_filters(&self) -> &HeaderFilters {
+        &self.header_filters
+    }
+
+    // Slot already holding the predicate or else the first one not in use
+    fn header_filter_slot(&self, filters: &HeaderFilters, filter: &HeaderFilter) -> Result<u8> {
+        filters
+            .iter()
+            .position(|f| f == filter)
+            .or_else(|| {
+                (0..filters.len()).find(|&slot| {
+                    // Slots taken by other ranges of the rule being added aren't in use yet
+                    !self.slot_in_use(slot) && filters[slot] == self.header_filters[slot]
+                })
+            })
+            .map(|slot| slot as u8)
+            .ok_or(Error::HeaderFiltersExhausted)
+    }
+
+    fn slot_in_use(&self, slot: usize) -> bool {
+        self.rule_map
+            .keys()
+            .any(|(_, proto, _)| *proto as usize == HEADER_FILTER_PROTO as usize + slot)
+    }
+
+    /// Trie keys whose entry in `self` differs from the one in `other`, including the keys only one of them has.
+    pub(crate) fn changed_keys(&self, other: &Self) -> Vec<Key<T::KeySize>> {
+        let changed = self
+            .rule_map
+            .iter()
+            .filter(|(key, port_ranges)| other.rule_map.get(*key) != Some(*port_ranges))
+            .map(|(key, _)| key);
+        let removed = other
+            .rule_map
+            .keys()
+            .filter(|key| !self.rule_map.contains_key(*key));
+        changed
+            .chain(removed)
+            .map(|(id, proto, ip)| ip.ip.as_key(*id, *proto))
+            .collect()
+    }
+
+    /// Writes to `store` the entries `self` has for each of the `keys`,
+    /// keys without an entry in `self` are removed.
+    pub(crate) fn write_entries(
+        &self,
+        store: &mut impl RuleTrie<T::KeySize, RuleStore>,
+        keys: &[Key<T::KeySize>],
+    ) -> Result<()>
+    where
+        T: FromKey,
+    {
+        self.write_entries_impl(store, keys, false)
+    }
+
+    /// Same as [write_entries](Self::write_entries) ignoring the keys that can't be removed.
+    ///
+    /// Used with the tracker as it was to undo partially written entries,
+    /// entries only the new state has might have never made it to the map.
+    pub(crate) fn rollback(
+        &self,
+        store: &mut impl RuleTrie<T::KeySize, RuleStore>,
+        keys: &[Key<T::KeySize>],
+    ) -> Result<()>
+    where
+        T: FromKey,
+    {
+        self.write_entries_impl(store, keys, true)
+    }
+
+    fn write_entries_impl(
+        &self,
+        store: &mut impl RuleTrie<T::KeySize, RuleStore>,
+        keys: &[Key<T::KeySize>],
+        undo: bool,
+    ) -> Result<()>
+    where
+        T: FromKey,
+    {
+        for key in keys {
+            let Some((id, proto, ip)) = T::from_key(key) else {
+                continue;
+            };
+            match self.rule_map.get(&(id, proto, Normalized::new(ip))) {
+                Some(port_ranges) => insert_entry(store, key, to_rule_store(port_ranges)?)?,
+                None => match store.remove(key) {
+                    Err(_) if undo => {}
+                    res => res?,
+                },
+            }
         }
         Ok(())
     }
 
     fn check_range_len(
         &self,
-        port_range: &PortRange<T>,
+        port_ranges: &[PortRange<T>],
         id: u128,
+        proto: u8,
         dest: &T,
     ) -> std::result::Result<(), RuleStoreError> {
-        if let Some(port_ranges) =
-            self.rule_map
-                .get(&(id, port_range.ports.proto, Normalized::new(dest.clone())))
+        if let Some(stored) = self
+            .rule_map
+            .get(&(id, proto, Normalized::new(dest.clone())))
         {
-            to_rule_store(port_ranges.iter().chain([port_range]))?;
+            to_rule_store(stored.iter().chain(port_ranges))?;
             Ok(())
         } else {
+            to_rule_store(port_ranges)?;
             Ok(())
         }
     }
 
     fn check_range_len_remove(
         &self,
-        port_range: &PortRange<T>,
+        port_ranges: &[PortRange<T>],
         id: u128,
+        proto: u8,
         dest: &T,
     ) -> std::result::Result<(), RuleStoreError> {
-        if let Some(port_ranges) =
-            self.rule_map
-                .get(&(id, port_range.ports.proto, Normalized::new(dest.clone())))
+        if let Some(stored) = self
+            .rule_map
+            .get(&(id, proto, Normalized::new(dest.clone())))
         {
-            to_rule_store(port_ranges.iter().filter(|p| p != &port_range))?;
+            to_rule_store(stored.iter().filter(|p| !port_ranges.contains(p)))?;
             Ok(())
         } else {
             Ok(())
         }
     }
+    /// Removes a rule added with [add_rule](Self::add_rule), rules that aren't tracked are ignored.
+    ///
+    /// Ranges the rule shares with other tracked rules are kept for them.
     pub(crate) fn remove_rule(
         &mut self,
         store: &mut impl RuleTrie<T::KeySize, RuleStore>,
-        RuleImpl {
-            id,
-            dest,
-            port_range,
-        }: &RuleImpl<T>,
+        rule: &RuleImpl<T>,
+    ) -> Result<()> {
+        if !self.rules.contains(rule) {
+            return Ok(());
+        }
+        if !self.resolved.contains(rule) {
+            self.remove_rule_impl(store, rule)?;
+        }
+        self.rules.remove(rule);
+        Ok(())
+    }
+
+    fn remove_rule_impl(
+        &mut self,
+        store: &mut impl RuleTrie<T::KeySize, RuleStore>,
+        rule: &RuleImpl<T>,
     ) -> Result<()> {
-        let port_range = PortRange {
-            ports: port_range.clone().unwrap_or_default(),
-            origin: dest.clone(),
-        };
-
-        for port_range in port_range.unfold() {
-            let proto = port_range.ports.proto;
-            let id = id.unwrap_or(0);
-            self.check_range_len_remove(&port_range, id, dest)?;
-            self.propagate_removal_check(&port_range, proto, id)?;
+        let RuleImpl { id, dest, .. } = rule;
+
+        // Entries hold each range once, ranges another rule for the same destination and id
+        // also holds must stay
+        let shared: HashSet<_> = self
+            .rules
+            .iter()
+            .chain(&self.resolved)
+            .filter(|other| *other != rule && other.id == *id && other.dest == *dest)
+            .flat_map(|other| self.stored_ranges(other))
+            .collect();
+        let mut keyed = self.stored_ranges(rule);
+        keyed.retain(|range| !shared.contains(range));
+        let keyed = group_by_proto(keyed);
+        let id = id.unwrap_or(0);
+
+        for (&proto, port_ranges) in &keyed {
+            self.check_range_len_remove(port_ranges, id, proto, dest)?;
+            self.propagate_removal_check(port_ranges, dest, proto, id)?;
         }
 
-        for port_range in port_range.unfold() {
-            let proto = port_range.ports.proto;
-            let id = id.unwrap_or(0);
-            if let std::collections::hash_map::Entry::Occupied(_) = self
+        for (&proto, port_ranges) in &keyed {
+            if let Some(entry) = self
                 .rule_map
-                .entry((id, proto, Normalized::new(dest.clone())))
-                .and_modify(|e| {
-                    e.remove(&port_range);
-                })
+                .get_mut(&(id, proto, Normalized::new(dest.clone())))
             {
-                self.propagate_removal(store, port_range, proto, id)?;
+                for port_range in port_ranges {
+                    entry.remove(port_range);
+                }
+                self.propagate_removal(store, port_ranges, dest, proto, id)?;
             }
 
             self.rule_map.retain(|_, v| !v.is_empty());
         }
+
+        for slot in 0..self.header_filters.len() {
+            if !self.slot_in_use(slot) {
+                self.header_filters[slot] = EMPTY_FILTER;
+            }
+        }
         Ok(())
     }
 
+    // Ranges of `rule` by the protocol used in their trie keys, as stored in its own entries
+    fn stored_ranges(&self, rule: &RuleImpl<T>) -> Vec<(u8, PortRange<T>)> {
+        let mut keyed = Vec::new();
+        for port_range in rule.port_ranges() {
+            let slot = match header_filter(&rule.header, &port_range) {
+                Some(filter) => {
+                    match self.header_filters.iter().position(|f| *f == filter) {
+                        Some(slot) => Some(slot as u8),
+                        // No rule uses this predicate
+                        None => continue,
+                    }
+                }
+                None => None,
+            };
+
+            let port_range = PortRange {
+                ports: port_range,
+                origin: rule.dest.clone(),
+            };
+            keyed.extend(unfold_keyed(&port_range, slot));
+        }
+        keyed
+    }
+
+    /// Last time each tracked rule matched a packet, `hits` gives the last hit of the entries by their hit key.
+    ///
+    /// Entries hold the ranges propagated from their parents, so a rule counts as hit
+    /// whenever an entry holding any of its ranges matched, even if it's the entry of a child.
+    /// Since the eBPF program only records which entry matched, rules sharing an entry share its hits.
+    pub(crate) fn last_hits(
+        &self,
+        hits: &HashMap<u64, u64>,
+    ) -> impl Iterator<Item = (&RuleImpl<T>, Option<u64>)> + '_ {
+        let mut range_hits: HashMap<(u128, u8, &PortRange<T>), u64> = HashMap::new();
+        for ((id, proto, ip), port_ranges) in &self.rule_map {
+            let Some(&hit) = hits.get(&hit_key(&ip.ip.as_key(*id, *proto))) else {
+                continue;
+            };
+            for port_range in port_ranges {
+                let last = range_hits.entry((*id, *proto, port_range)).or_default();
+                *last = (*last).max(hit);
+            }
+        }
+
+        self.rules.iter().map(move |rule| {
+            let id = rule.id.unwrap_or(0);
+            let last_hit = rule
+                .port_ranges()
+                .into_iter()
+                .flat_map(|port_range| {
+                    let slot = header_filter(&rule.header, &port_range).and_then(|filter| {
+                        self.header_filters
+                            .iter()
+                            .position(|f| *f == filter)
+                            .map(|slot| slot as u8)
+                    });
+                    let port_range = PortRange {
+                        ports: port_range,
+                        origin: rule.dest.clone(),
+                    };
+                    unfold_keyed(&port_range, slot)
+                })
+                .filter_map(|(proto, port_range)| range_hits.get(&(id, proto, &port_range)))
+                .max()
+                .copied();
+            (rule, last_hit)
+        })
+    }
+
     fn propagate_removal_check(
         &mut self,
-        port_range: &PortRange<T>,
-        proto: Protocol,
+        port_ranges: &[PortRange<T>],
+        origin: &T,
+        proto: u8,
         id: u128,
     ) -> Result<()> {
         for (_, v) in self.rule_map.iter().filter(|((k_id, k_proto, k_ip), _)| {
-            *k_id == id && *k_proto == proto && port_range.origin.contains(&k_ip.ip)
+            *k_id == id && *k_proto == proto && origin.contains(&k_ip.ip)
         }) {
             let mut v = v.clone();
-            v.remove(port_range);
+            for port_range in port_ranges {
+                v.remove(port_range);
+            }
             if !v.is_empty() {
                 to_rule_store(&v)?;
             }
@@ -284,25 +619,29 @@ where
     fn propagate_removal(
         &mut self,
         store: &mut impl RuleTrie<T::KeySize, RuleStore>,
-        port_range: PortRange<T>,
-        proto: Protocol,
+        port_ranges: &[PortRange<T>],
+        origin: &T,
+        proto: u8,
         id: u128,
     ) -> Result<()> {
         for ((k_id, k_proto, k_ip), v) in
             self.rule_map
                 .iter_mut()
                 .filter(|((k_id, k_proto, k_ip), _)| {
-                    *k_id == id && *k_proto == proto && port_range.origin.contains(&k_ip.ip)
+                    *k_id == id && *k_proto == proto && origin.contains(&k_ip.ip)
                 })
         {
-            v.remove(&port_range);
+            for port_range in port_ranges {
+                v.remove(port_range);
+            }
             if !v.is_empty() {
-                store.insert(
-                    &k_ip.ip.as_key(*k_id, *k_proto as u8),
+                insert_entry(
+                    store,
+                    &k_ip.ip.as_key(*k_id, *k_proto),
                     to_rule_store(&*v).expect("Should error on check before"),
                 )?;
             } else {
-                store.remove(&k_ip.ip.as_key(*k_id, *k_proto as u8))?;
+                store.remove(&k_ip.ip.as_key(*k_id, *k_proto))?;
             }
         }
         Ok(())
@@ -311,47 +650,50 @@ where
     fn propagate(
         &mut self,
         store: &mut impl RuleTrie<T::KeySize, RuleStore>,
-        port_range: PortRange<T>,
+        port_ranges: &[PortRange<T>],
+        origin: &T,
         id: u128,
-        proto: Protocol,
+        proto: u8,
     ) -> Result<()> {
-        self.propagate_impl(store, port_range, id, proto, Method::Modify)
+        self.propagate_impl(store, port_ranges, origin, id, proto, Method::Modify)
     }
 
     fn propagate_check(
         &mut self,
         store: &mut impl RuleTrie<T::KeySize, RuleStore>,
-        port_range: PortRange<T>,
+        port_ranges: &[PortRange<T>],
+        origin: &T,
         id: u128,
-        proto: Protocol,
+        proto: u8,
     ) -> Result<()> {
-        self.propagate_impl(store, port_range, id, proto, Method::Check)
+        self.propagate_impl(store, port_ranges, origin, id, proto, Method::Check)
     }
 
     fn propagate_impl(
         &mut self,
         store: &mut impl RuleTrie<T::KeySize, RuleStore>,
-        port_range: PortRange<T>,
+        port_ranges: &[PortRange<T>],
+        origin: &T,
         id: u128,
-        proto: Protocol,
+        proto: u8,
         method: Method,
     ) -> Result<()> {
         for ((k_id, k_proto, k_ip), v) in
             self.rule_map
                 .iter_mut()
                 .filter(|((k_id, k_proto, k_ip), _)| {
-                    *k_id == id && *k_proto == proto && port_range.origin.contains(&k_ip.ip)
+                    *k_id == id && *k_proto == proto && origin.contains(&k_ip.ip)
                 })
         {
             match method {
                 Method::Check => {
-                    let port_ranges = v.iter().chain([&port_range]);
-                    to_rule_store(port_ranges)?;
+                    to_rule_store(v.iter().chain(port_ranges))?;
                 }
                 Method::Modify => {
-                    v.insert(port_range.clone());
-                    store.insert(
-                        &k_ip.ip.as_key(*k_id, *k_proto as u8),
+                    v.extend(port_ranges.iter().cloned());
+                    insert_entry(
+                        store,
+                        &k_ip.ip.as_key(*k_id, *k_proto),
                         to_rule_store(&*v).expect("Should error on check"),
                     )?;
                 }
@@ -366,7 +708,7 @@ where
         store: &mut impl RuleTrie<T::KeySize, RuleStore>,
         cidr: &T,
         id: u128,
-        proto: Protocol,
+        proto: u8,
     ) -> Result<()> {
         self.reverse_propagate_impl(store, cidr, id, proto, Method::Modify)
     }
@@ -376,7 +718,7 @@ where
         store: &mut impl RuleTrie<T::KeySize, RuleStore>,
         cidr: &T,
         id: u128,
-        proto: Protocol,
+        proto: u8,
     ) -> Result<()> {
         self.reverse_propagate_impl(store, cidr, id, proto, Method::Modify)
     }
@@ -386,7 +728,7 @@ where
         store: &mut impl RuleTrie<T::KeySize, RuleStore>,
         cidr: &T,
         id: u128,
-        proto: Protocol,
+        proto: u8,
         method: Method,
     ) -> Result<()> {
         let overlapping_parents = self.get_overlapping_parents(cidr, id, proto);
@@ -401,8 +743,9 @@ where
                 Method::Modify => {
                     port_ranges.extend(overlapping_parents);
 
-                    store.insert(
-                        &cidr.as_key(id, proto as u8),
+                    insert_entry(
+                        store,
+                        &cidr.as_key(id, proto),
                         to_rule_store(&*port_ranges).expect("Should error on check"),
                     )?;
                 }
@@ -411,12 +754,7 @@ where
         Ok(())
     }
 
-    fn get_overlapping_parents(
-        &self,
-        cidr: &T,
-        id: u128,
-        proto: Protocol,
-    ) -> HashSet<PortRange<T>> {
+    fn get_overlapping_parents(&self, cidr: &T, id: u128, proto: u8) -> HashSet<PortRange<T>> {
         self.rule_map
             .iter()
             .filter(|((k_id, k_proto, k_ip), _)| {
@@ -428,14 +766,169 @@ where
     }
 }
 
+#[cfg(any(test, feature = "pinning"))]
+impl<T> RuleTracker<T>
+where
+    T: AsNum + AsKey + AsOctets + Eq + Hash + Clone + Normalize + Contains + FromKey,
+    T::Octets: AsRef<[u8]>,
+{
+    /// Sets the header predicates by slot, must be called before [restore](Self::restore).
+    pub(crate) fn restore_header_filters(&mut self, filters: HeaderFilters) {
+        self.header_filters = filters;
+    }
+
+    /// Rebuilds the tracked rules from the entries of a rule trie.
+    ///
+    /// Entries already contain the ranges propagated from their parents,
+    /// so for each entry only the ports not covered by a parent are added back as rules.
+    /// The resulting entries are the same as the ones read, though rules that were
+    /// fully shadowed by a parent rule can't be told apart from it.
+    pub(crate) fn restore(
+        &mut self,
+        entries: impl IntoIterator<Item = (Key<T::KeySize>, RuleStore)>,
+    ) -> Result<()> {
+        let entries: Vec<_> = entries
+            .into_iter()
+            .filter_map(|(key, store)| {
+                let (id, proto, ip) = T::from_key(&key)?;
+                // Entries for a header predicate without a slot are unreachable
+                self.rule_from_key_proto(proto)?;
+                Some((id, proto, ip, store.ranges().collect::<Vec<_>>()))
+            })
+            .collect();
+
+        for (id, proto, ip, ranges) in &entries {
+            let inherited: Vec<_> = entries
+                .iter()
+                .filter(|(p_id, p_proto, p_ip, _)| {
+                    p_id == id && p_proto == proto && p_ip != ip && p_ip.contains(ip)
+                })
+                .flat_map(|(_, _, _, ranges)| ranges.iter().copied())
+                .collect();
+
+            let (protocol, header) = self
+                .rule_from_key_proto(*proto)
+                .expect("Filtered out entries with unknown protocols");
+            for (start, end) in own_ranges(ranges, &inherited) {
+                let mut rule = RuleImpl::new(ip.clone()).with_range(start..=end, protocol);
+                if *id != 0 {
+                    rule = rule.with_id(*id);
+                }
+                rule = rule.with_header(header.clone());
+                self.add_rule(&mut (), &rule)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Protocol and header predicates of the rules stored with `proto` in their key
+    fn rule_from_key_proto(&self, proto: u8) -> Option<(Protocol, HeaderMatch)> {
+        match proto.checked_sub(HEADER_FILTER_PROTO) {
+            Some(slot) if (slot as u32) < MAX_HEADER_FILTERS => {
+                HeaderMatch::from_filter(&self.header_filters[slot as usize])
+            }
+            _ => Some((Protocol::from_u8(proto)?, HeaderMatch::default())),
+        }
+    }
+}
+
+// Ranges in `ranges` that aren't covered by `inherited`
+#[cfg(any(test, feature = "pinning"))]
+fn own_ranges(ranges: &[(u16, u16)], inherited: &[(u16, u16)]) -> Vec<(u16, u16)> {
+    // (0, 0) matches all ports
+    let all_ports = (0, 0);
+    if inherited.contains(&all_ports) {
+        return Vec::new();
+    }
+
+    let mut own = Vec::new();
+    for &range in ranges {
+        if range == all_ports {
+            own.push(range);
+            continue;
+        }
+
+        let mut pieces = vec![range];
+        for &(i_start, i_end) in inherited {
+            pieces = pieces
+                .into_iter()
+                .flat_map(|(start, end)| {
+                    let mut res = Vec::new();
+                    if start < i_start {
+                        res.push((start, end.min(i_start - 1)));
+                    }
+                    if end > i_end {
+                        res.push((start.max(i_end + 1), end));
+                    }
+                    res
+                })
+                .collect();
+        }
+        own.extend(pieces);
+    }
+    own
+}
+
 enum Method {
     Check,
     Modify,
 }
 
-fn port_range_check(port_range: &Option<rule::PortRange>) -> bool {
-    match port_range {
-        Some(range) => range.valid_range(),
-        None => true,
+// Port ranges of a rule by the protocol used in their trie keys
+fn group_by_proto<T>(keyed: Vec<(u8, PortRange<T>)>) -> BTreeMap<u8, Vec<PortRange<T>>>
+where
+    T: AsNum + AsOctets,
+    T::Octets: AsRef<[u8]>,
+{
+    let mut grouped: BTreeMap<u8, Vec<PortRange<T>>> = BTreeMap::new();
+    for (proto, port_range) in keyed {
+        grouped.entry(proto).or_default().push(port_range);
     }
+    grouped
+}
+
+// Unfolds the port range by protocol along with the protocol used in the trie keys,
+// rules with header predicates aren't unfolded, they are stored under their predicate's slot
+// which already holds the protocol
+fn unfold_keyed<T>(port_range: &PortRange<T>, slot: Option<u8>) -> Vec<(u8, PortRange<T>)>
+where
+    T: AsNum + AsOctets + Clone,
+    T::Octets: AsRef<[u8]>,
+{
+    match slot {
+        Some(slot) => vec![(HEADER_FILTER_PROTO + slot, port_range.clone())],
+        None => port_range
+            .unfold()
+            .into_iter()
+            .map(|p| (p.ports.proto as u8, p))
+            .collect(),
+    }
+}
+
+// Filter to store in a slot for the rule, none if the rule has no header predicates
+fn header_filter(header: &HeaderMatch, port_range: &rule::PortRange) -> Option<HeaderFilter> {
+    (!header.is_empty()).then(|| header.filter(port_range.proto))
+}
+
+pub(crate) fn header_check(header: &HeaderMatch, port_range: &rule::PortRange) -> Result<()> {
+    if let Some((flags, mask)) = header.tcp_flags {
+        let udp_only = port_range.proto == Protocol::UDP;
+        // Flags outside the mask are never compared, so the rule couldn't match anything
+        if mask == 0 || flags & !mask != 0 || udp_only {
+            return Err(Error::InvalidTcpFlags);
+        }
+    }
+
+    if matches!(&header.ttl, Some(ttl) if ttl.is_empty()) {
+        return Err(Error::InvalidTtl);
+    }
+
+    if matches!(header.dscp, Some(dscp) if dscp > 0x3F) {
+        return Err(Error::InvalidDscp);
+    }
+    Ok(())
+}
+
+pub(crate) fn port_range_check(port_range: &rule::PortRange) -> bool {
+    port_range.valid_range()
 }
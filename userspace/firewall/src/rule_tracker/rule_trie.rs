@@ -11,6 +11,17 @@ pub trait RuleTrie<K: Pod, V: Pod> {
     fn remove(&mut self, key: &Key<K>) -> Result<(), MapError>;
 }
 
+// Used to update the tracker without touching any map
+impl<K: Pod, V: Pod> RuleTrie<K, V> for () {
+    fn insert(&mut self, _: &Key<K>, _: V) -> Result<(), MapError> {
+        Ok(())
+    }
+
+    fn remove(&mut self, _: &Key<K>) -> Result<(), MapError> {
+        Ok(())
+    }
+}
+
 impl<T: AsMut<MapData>, K: Pod, V: Pod> RuleTrie<K, V> for LpmTrie<T, K, V> {
     fn insert(&mut self, key: &Key<K>, value: V) -> Result<(), MapError> {
         LpmTrie::insert(self, key, value, 0)
@@ -2,40 +2,27 @@
 
 mod test_data;
 
-use aya::Pod;
-
 use crate::{
     as_octet::AsOctets,
     cidr::{AsKey, AsNum, Normalize},
     rule::RuleImpl,
-    Protocol::{Generic, UDP},
-    Result,
+    rule_tracker::{hit_key, RuleTrie, EMPTY_FILTER},
+    Error,
+    Protocol::{Generic, TCP, UDP},
+    Result, TcpFlags,
+};
+use aya::{
+    maps::{lpm_trie::Key, MapError},
+    Pod,
 };
+use firewall_common::{HeaderFilter, RuleStore, HEADER_FILTER_PROTO, MAX_HEADER_FILTERS};
+use ipnet::Ipv4Net;
 
 use core::fmt::Debug;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use self::test_data::TestRun;
 
-use super::rule_trie::RuleTrie;
-
-impl<K: Pod, V: Pod> RuleTrie<K, V> for () {
-    fn insert(
-        &mut self,
-        _: &aya::maps::lpm_trie::Key<K>,
-        _: V,
-    ) -> core::result::Result<(), aya::maps::MapError> {
-        Ok(())
-    }
-
-    fn remove(
-        &mut self,
-        _: &aya::maps::lpm_trie::Key<K>,
-    ) -> core::result::Result<(), aya::maps::MapError> {
-        Ok(())
-    }
-}
-
 impl<T> crate::rule_tracker::RuleTracker<T>
 where
     T: AsNum + Debug + AsKey + AsOctets + Normalize,
@@ -44,6 +31,9 @@ where
     pub fn new_test() -> Result<Self> {
         Ok(Self {
             rule_map: HashMap::new(),
+            header_filters: [EMPTY_FILTER; MAX_HEADER_FILTERS as usize],
+            rules: HashSet::new(),
+            resolved: HashSet::new(),
         })
     }
 }
@@ -139,3 +129,502 @@ fn remove_ipv6_rule_works() {
         .expect_false("fafa::1:0:0:0/96", &[(UDP, 800)])
         .run();
 }
+
+#[test]
+fn restore_ipv4_from_entries_works() {
+    let rule_tracker = test_data::prepare_ipv4();
+    let mut restored = crate::rule_tracker::RuleTracker::new_test().unwrap();
+    restored.restore(test_data::entries(&rule_tracker)).unwrap();
+
+    assert_eq!(
+        test_data::entries_ranges(&rule_tracker),
+        test_data::entries_ranges(&restored)
+    );
+    let test_run = TestRun::with(restored);
+    test_data::prepared_expect_v4(test_run).run();
+}
+
+#[test]
+fn restore_ipv6_from_entries_works() {
+    let rule_tracker = test_data::prepare_ipv6();
+    let mut restored = crate::rule_tracker::RuleTracker::new_test().unwrap();
+    restored.restore(test_data::entries(&rule_tracker)).unwrap();
+
+    assert_eq!(
+        test_data::entries_ranges(&rule_tracker),
+        test_data::entries_ranges(&restored)
+    );
+    let test_run = TestRun::with(restored);
+    test_data::prepared_expect_v6(test_run).run();
+}
+
+// Trie kept in memory by hit key, writes fail once `writes_left` reaches 0
+#[derive(Default)]
+struct MemoryTrie {
+    entries: HashMap<u64, Vec<(u16, u16)>>,
+    writes_left: Option<usize>,
+}
+
+impl MemoryTrie {
+    fn with_entries<T>(rule_tracker: &crate::rule_tracker::RuleTracker<T>) -> Self
+    where
+        T: AsNum + Debug + AsKey + AsOctets + Normalize + Eq + std::hash::Hash + Clone,
+        T::Octets: AsRef<[u8]>,
+    {
+        let mut trie = Self::default();
+        for (key, entry) in test_data::entries(rule_tracker) {
+            trie.insert(&key, entry).unwrap();
+        }
+        trie
+    }
+
+    fn write(&mut self) -> std::result::Result<(), MapError> {
+        match &mut self.writes_left {
+            Some(0) => Err(MapError::KeyNotFound),
+            Some(left) => {
+                *left -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl<K: Pod> RuleTrie<K, RuleStore> for MemoryTrie {
+    fn insert(&mut self, key: &Key<K>, value: RuleStore) -> std::result::Result<(), MapError> {
+        self.write()?;
+        self.entries.insert(hit_key(key), value.ranges().collect());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &Key<K>) -> std::result::Result<(), MapError> {
+        self.write()?;
+        self.entries
+            .remove(&hit_key(key))
+            .map(|_| ())
+            .ok_or(MapError::KeyNotFound)
+    }
+}
+
+// Same changes as a transaction adding and removing rules over the prepared ones
+fn changed_ipv4_tracker() -> crate::rule_tracker::RuleTracker<Ipv4Net> {
+    let mut next = test_data::prepare_ipv4();
+    next.add_rule(
+        &mut (),
+        &RuleImpl::new("10.1.2.0/24".parse().unwrap()).with_range(80..=80, TCP),
+    )
+    .unwrap();
+    next.add_rule(
+        &mut (),
+        &RuleImpl::new("10.1.2.3/32".parse().unwrap()).with_ttl(0..=4),
+    )
+    .unwrap();
+    next.remove_rule(
+        &mut (),
+        &RuleImpl::new("10.1.1.0/24".parse().unwrap()).with_range(200..=800, UDP),
+    )
+    .unwrap();
+    next
+}
+
+#[test]
+fn changed_entries_are_written() {
+    let rule_tracker = test_data::prepare_ipv4();
+    let next = changed_ipv4_tracker();
+    let mut store = MemoryTrie::with_entries(&rule_tracker);
+
+    let changed = next.changed_keys(&rule_tracker);
+    next.write_entries(&mut store, &changed).unwrap();
+    assert_eq!(store.entries, MemoryTrie::with_entries(&next).entries);
+}
+
+#[test]
+fn failed_write_is_rolled_back() {
+    let rule_tracker = test_data::prepare_ipv4();
+    let next = changed_ipv4_tracker();
+    let mut store = MemoryTrie::with_entries(&rule_tracker);
+    let before = store.entries.clone();
+
+    let changed = next.changed_keys(&rule_tracker);
+    assert!(changed.len() > 2);
+    // The map fails partway through the batch
+    store.writes_left = Some(changed.len() / 2);
+    assert!(next.write_entries(&mut store, &changed).is_err());
+    assert_ne!(store.entries, before);
+
+    store.writes_left = None;
+    rule_tracker.rollback(&mut store, &changed).unwrap();
+    assert_eq!(store.entries, before);
+    // The tracker the changes were computed from is left untouched
+    assert_eq!(
+        test_data::entries_ranges(&rule_tracker),
+        test_data::entries_ranges(&test_data::prepare_ipv4())
+    );
+    assert_eq!(
+        rule_tracker.header_filters(),
+        test_data::prepare_ipv4().header_filters()
+    );
+}
+
+#[test]
+fn clear_removes_all_rules() {
+    let mut rule_tracker = test_data::prepare_ipv4();
+    rule_tracker.clear(&mut ()).unwrap();
+    assert!(rule_tracker.rule_map.is_empty());
+}
+
+#[test]
+fn tcp_flags_rule_uses_own_slot() {
+    let mut rule_tracker = crate::rule_tracker::RuleTracker::<Ipv4Net>::new_test().unwrap();
+    let syn = RuleImpl::new("10.1.1.0/24".parse().unwrap())
+        .with_range(80..=80, Generic)
+        .with_tcp_flags(TcpFlags::SYN, TcpFlags::SYN | TcpFlags::ACK);
+    rule_tracker.add_rule(&mut (), &syn).unwrap();
+
+    assert_eq!(
+        rule_tracker.header_filters()[0],
+        HeaderFilter {
+            tcp_flags: TcpFlags::SYN,
+            tcp_flags_mask: TcpFlags::SYN | TcpFlags::ACK,
+            ..Default::default()
+        }
+    );
+    let keys: Vec<_> = rule_tracker
+        .rule_map
+        .keys()
+        .map(|(_, proto, _)| *proto)
+        .collect();
+    assert_eq!(keys, vec![HEADER_FILTER_PROTO]);
+
+    rule_tracker.remove_rule(&mut (), &syn).unwrap();
+    assert!(rule_tracker.rule_map.is_empty());
+    assert_eq!(rule_tracker.header_filters()[0], EMPTY_FILTER);
+}
+
+#[test]
+fn tcp_flags_rule_inherits_only_from_same_predicate() {
+    let mut rule_tracker = crate::rule_tracker::RuleTracker::<Ipv4Net>::new_test().unwrap();
+    rule_tracker
+        .add_rule(
+            &mut (),
+            &RuleImpl::new("10.1.0.0/16".parse().unwrap()).with_range(22..=22, TCP),
+        )
+        .unwrap();
+    rule_tracker
+        .add_rule(
+            &mut (),
+            &RuleImpl::new("10.1.1.0/24".parse().unwrap())
+                .with_range(80..=80, TCP)
+                .with_tcp_flags(TcpFlags::SYN, TcpFlags::SYN),
+        )
+        .unwrap();
+
+    let ranges = test_data::entries_ranges(&rule_tracker);
+    let flagged: Vec<_> = ranges
+        .iter()
+        .filter(|((_, proto, _), _)| *proto == HEADER_FILTER_PROTO)
+        .map(|(_, ranges)| ranges.clone())
+        .collect();
+    assert_eq!(flagged, vec![vec![(80, 80)]]);
+}
+
+#[test]
+fn invalid_tcp_flags_rule_errors() {
+    let mut rule_tracker = crate::rule_tracker::RuleTracker::<Ipv4Net>::new_test().unwrap();
+    let no_mask = RuleImpl::new("10.1.1.0/24".parse().unwrap()).with_tcp_flags(TcpFlags::SYN, 0);
+    assert!(matches!(
+        rule_tracker.add_rule(&mut (), &no_mask),
+        Err(Error::InvalidTcpFlags)
+    ));
+
+    // SYN can never be set once masked by ACK
+    let outside_mask = RuleImpl::new("10.1.1.0/24".parse().unwrap())
+        .with_tcp_flags(TcpFlags::SYN | TcpFlags::ACK, TcpFlags::ACK);
+    assert!(matches!(
+        rule_tracker.add_rule(&mut (), &outside_mask),
+        Err(Error::InvalidTcpFlags)
+    ));
+
+    let udp = RuleImpl::new("10.1.1.0/24".parse().unwrap())
+        .with_range(53..=53, UDP)
+        .with_tcp_flags(TcpFlags::SYN, TcpFlags::SYN);
+    assert!(matches!(
+        rule_tracker.add_rule(&mut (), &udp),
+        Err(Error::InvalidTcpFlags)
+    ));
+}
+
+#[test]
+fn header_filters_exhausted_errors() {
+    let mut rule_tracker = crate::rule_tracker::RuleTracker::<Ipv4Net>::new_test().unwrap();
+    for mask in 1..=MAX_HEADER_FILTERS as u8 {
+        let rule = RuleImpl::new("10.1.1.0/24".parse().unwrap()).with_tcp_flags(0, mask);
+        rule_tracker.add_rule(&mut (), &rule).unwrap();
+    }
+
+    let rule = RuleImpl::new("10.1.1.0/24".parse().unwrap()).with_tcp_flags(0, 0xFF);
+    assert!(matches!(
+        rule_tracker.add_rule(&mut (), &rule),
+        Err(Error::HeaderFiltersExhausted)
+    ));
+}
+
+#[test]
+fn restore_tcp_flags_rules_works() {
+    let mut rule_tracker = test_data::prepare_ipv4();
+    rule_tracker
+        .add_rule(
+            &mut (),
+            &RuleImpl::new("10.1.1.0/24".parse().unwrap())
+                .with_id(1)
+                .with_tcp_flags(TcpFlags::FIN | TcpFlags::SYN, TcpFlags::FIN | TcpFlags::SYN),
+        )
+        .unwrap();
+
+    let mut restored = crate::rule_tracker::RuleTracker::new_test().unwrap();
+    restored.restore_header_filters(*rule_tracker.header_filters());
+    restored.restore(test_data::entries(&rule_tracker)).unwrap();
+
+    assert_eq!(
+        test_data::entries_ranges(&rule_tracker),
+        test_data::entries_ranges(&restored)
+    );
+}
+
+#[test]
+fn header_rules_with_different_protocols_use_different_slots() {
+    let mut rule_tracker = crate::rule_tracker::RuleTracker::<Ipv4Net>::new_test().unwrap();
+    let voice = RuleImpl::new("10.1.1.0/24".parse().unwrap()).with_dscp(46);
+    rule_tracker
+        .add_rule(&mut (), &voice.clone().with_range(5060..=5061, UDP))
+        .unwrap();
+    rule_tracker
+        .add_rule(&mut (), &voice.clone().with_range(5060..=5061, UDP))
+        .unwrap();
+    rule_tracker
+        .add_rule(&mut (), &voice.with_range(5060..=5061, TCP))
+        .unwrap();
+
+    let filters = rule_tracker.header_filters();
+    assert_eq!((filters[0].proto, filters[0].dscp), (UDP as u8, 46));
+    assert_eq!((filters[1].proto, filters[1].dscp), (TCP as u8, 46));
+    assert_eq!(filters[2], EMPTY_FILTER);
+}
+
+#[test]
+fn ttl_rule_is_not_unfolded() {
+    let mut rule_tracker = crate::rule_tracker::RuleTracker::<Ipv4Net>::new_test().unwrap();
+    let rule = RuleImpl::new("10.1.1.0/24".parse().unwrap()).with_ttl(0..=4);
+    rule_tracker.add_rule(&mut (), &rule).unwrap();
+
+    assert_eq!(
+        rule_tracker.header_filters()[0],
+        HeaderFilter {
+            ttl_max: 4,
+            ..Default::default()
+        }
+    );
+    let keys: Vec<_> = rule_tracker
+        .rule_map
+        .keys()
+        .map(|(_, proto, _)| *proto)
+        .collect();
+    assert_eq!(keys, vec![HEADER_FILTER_PROTO]);
+
+    // A TTL range covering every value is the same as no predicate
+    let any_ttl = RuleImpl::new("10.1.1.0/24".parse().unwrap()).with_ttl(0..=255);
+    rule_tracker.add_rule(&mut (), &any_ttl).unwrap();
+    assert_eq!(rule_tracker.header_filters()[1], EMPTY_FILTER);
+    assert_eq!(rule_tracker.rule_map.len(), 3);
+}
+
+#[test]
+fn invalid_ttl_and_dscp_rules_error() {
+    let mut rule_tracker = crate::rule_tracker::RuleTracker::<Ipv4Net>::new_test().unwrap();
+    #[allow(clippy::reversed_empty_ranges)]
+    let empty_ttl = RuleImpl::new("10.1.1.0/24".parse().unwrap()).with_ttl(10..=5);
+    assert!(matches!(
+        rule_tracker.add_rule(&mut (), &empty_ttl),
+        Err(Error::InvalidTtl)
+    ));
+
+    let dscp = RuleImpl::new("10.1.1.0/24".parse().unwrap()).with_dscp(64);
+    assert!(matches!(
+        rule_tracker.add_rule(&mut (), &dscp),
+        Err(Error::InvalidDscp)
+    ));
+}
+
+#[test]
+fn restore_header_rules_works() {
+    let mut rule_tracker = test_data::prepare_ipv4();
+    rule_tracker
+        .add_rule(
+            &mut (),
+            &RuleImpl::new("10.1.0.0/16".parse().unwrap())
+                .with_id(1)
+                .with_ttl(0..=4),
+        )
+        .unwrap();
+    rule_tracker
+        .add_rule(
+            &mut (),
+            &RuleImpl::new("10.1.1.0/24".parse().unwrap())
+                .with_range(5060..=5061, UDP)
+                .with_ttl(0..=4)
+                .with_dscp(46),
+        )
+        .unwrap();
+
+    let mut restored = crate::rule_tracker::RuleTracker::new_test().unwrap();
+    restored.restore_header_filters(*rule_tracker.header_filters());
+    restored.restore(test_data::entries(&rule_tracker)).unwrap();
+
+    assert_eq!(
+        test_data::entries_ranges(&rule_tracker),
+        test_data::entries_ranges(&restored)
+    );
+    assert_eq!(rule_tracker.header_filters(), restored.header_filters());
+}
+
+#[test]
+fn parent_rule_is_hit_through_child_entry() {
+    let mut rule_tracker = crate::rule_tracker::RuleTracker::<Ipv4Net>::new_test().unwrap();
+    let parent = RuleImpl::new("10.0.0.0/8".parse().unwrap()).with_range(80..=80, TCP);
+    let child = RuleImpl::new("10.1.0.0/16".parse().unwrap()).with_range(443..=443, TCP);
+    let other = RuleImpl::new("192.168.0.0/16".parse().unwrap()).with_range(22..=22, TCP);
+    for rule in [&parent, &child, &other] {
+        rule_tracker.add_rule(&mut (), rule).unwrap();
+    }
+
+    // Only the child entry matched, e.g. a packet to 10.1.0.1:80
+    let child_key: Ipv4Net = "10.1.0.0/16".parse().unwrap();
+    let hits = HashMap::from([(hit_key(&child_key.as_key(0, TCP as u8)), 42)]);
+    let last_hits: HashMap<_, _> = rule_tracker.last_hits(&hits).collect();
+
+    assert_eq!(last_hits[&parent], Some(42));
+    assert_eq!(last_hits[&child], Some(42));
+    assert_eq!(last_hits[&other], None);
+}
+
+#[test]
+fn removed_rule_is_no_longer_tracked() {
+    let mut rule_tracker = crate::rule_tracker::RuleTracker::<Ipv4Net>::new_test().unwrap();
+    let rule = RuleImpl::new("10.0.0.0/8".parse().unwrap()).with_range(80..=80, Generic);
+    rule_tracker.add_rule(&mut (), &rule).unwrap();
+    assert_eq!(rule_tracker.last_hits(&HashMap::new()).count(), 1);
+
+    rule_tracker.remove_rule(&mut (), &rule).unwrap();
+    assert_eq!(rule_tracker.last_hits(&HashMap::new()).count(), 0);
+}
+
+#[test]
+fn removing_a_rule_keeps_overlapping_rules() {
+    let mut rule_tracker = crate::rule_tracker::RuleTracker::<Ipv4Net>::new_test().unwrap();
+    let web = RuleImpl::new("10.0.0.0/8".parse().unwrap()).with_range(80..=80, TCP);
+    let web_tls =
+        RuleImpl::new("10.0.0.0/8".parse().unwrap()).with_ranges([80..=80, 443..=443], TCP);
+    let child = RuleImpl::new("10.1.0.0/16".parse().unwrap()).with_range(22..=22, TCP);
+    for rule in [&web, &web_tls, &child] {
+        rule_tracker.add_rule(&mut (), rule).unwrap();
+    }
+    let mut store = MemoryTrie::with_entries(&rule_tracker);
+    let before = store.entries.clone();
+
+    // Never added, so it doesn't take the ports of the rules that were
+    let untracked = RuleImpl::new("10.0.0.0/8".parse().unwrap()).with_range(443..=443, TCP);
+    rule_tracker.remove_rule(&mut store, &untracked).unwrap();
+    assert_eq!(store.entries, before);
+
+    rule_tracker.remove_rule(&mut store, &web).unwrap();
+    assert_eq!(store.entries, before);
+    TestRun::with(rule_tracker.clone())
+        .expect_true("10.0.0.0/8", &[(TCP, 80), (TCP, 443)])
+        .expect_true("10.1.0.0/16", &[(TCP, 22), (TCP, 80), (TCP, 443)])
+        .run();
+
+    rule_tracker.remove_rule(&mut store, &web_tls).unwrap();
+    assert_eq!(
+        store.entries,
+        MemoryTrie::with_entries(&rule_tracker).entries
+    );
+    TestRun::with(rule_tracker.clone())
+        .expect_false("10.1.0.0/16", &[(TCP, 80), (TCP, 443)])
+        .expect_true("10.1.0.0/16", &[(TCP, 22)])
+        .run();
+    let rules: HashSet<_> = rule_tracker.rules().cloned().collect();
+    assert_eq!(rules, HashSet::from([child]));
+}
+
+#[test]
+fn rules_are_returned_as_added() {
+    let mut rule_tracker = crate::rule_tracker::RuleTracker::<Ipv4Net>::new_test().unwrap();
+    let parent = RuleImpl::new("10.0.0.0/8".parse().unwrap()).with_range(80..=80, Generic);
+    let child = RuleImpl::new("10.1.0.0/16".parse().unwrap()).with_ttl(0..=4);
+    rule_tracker.add_rule(&mut (), &parent).unwrap();
+    rule_tracker.add_rule(&mut (), &child).unwrap();
+    rule_tracker.add_rule(&mut (), &parent).unwrap();
+
+    let rules: HashSet<_> = rule_tracker.rules().cloned().collect();
+    assert_eq!(rules, HashSet::from([parent, child]));
+}
+
+#[test]
+fn resolved_rules_keep_identical_added_rules() {
+    let mut rule_tracker = crate::rule_tracker::RuleTracker::<Ipv4Net>::new_test().unwrap();
+    let rule = RuleImpl::new("10.0.0.1/32".parse().unwrap()).with_range(443..=443, TCP);
+    let mut store = MemoryTrie::default();
+
+    rule_tracker.add_rule(&mut store, &rule).unwrap();
+    rule_tracker.add_resolved_rule(&mut store, &rule).unwrap();
+    rule_tracker
+        .remove_resolved_rule(&mut store, &rule)
+        .unwrap();
+    assert_eq!(store.entries.len(), 1);
+    assert_eq!(rule_tracker.rules().count(), 1);
+
+    rule_tracker.add_resolved_rule(&mut store, &rule).unwrap();
+    rule_tracker.remove_rule(&mut store, &rule).unwrap();
+    assert_eq!(store.entries.len(), 1);
+    assert_eq!(rule_tracker.rules().count(), 0);
+
+    rule_tracker
+        .remove_resolved_rule(&mut store, &rule)
+        .unwrap();
+    assert!(store.entries.is_empty());
+    assert!(rule_tracker.rule_map.is_empty());
+}
+
+#[test]
+fn resolved_rules_keep_overlapping_added_rules() {
+    let mut rule_tracker = crate::rule_tracker::RuleTracker::<Ipv4Net>::new_test().unwrap();
+    let added =
+        RuleImpl::new("10.0.0.1/32".parse().unwrap()).with_ranges([443..=443, 8443..=8443], TCP);
+    let resolved = RuleImpl::new("10.0.0.1/32".parse().unwrap()).with_range(443..=443, TCP);
+    let mut store = MemoryTrie::default();
+
+    rule_tracker.add_rule(&mut store, &added).unwrap();
+    rule_tracker
+        .add_resolved_rule(&mut store, &resolved)
+        .unwrap();
+    // The hostname stopped resolving to the address
+    rule_tracker
+        .remove_resolved_rule(&mut store, &resolved)
+        .unwrap();
+    TestRun::with(rule_tracker.clone())
+        .expect_true("10.0.0.1/32", &[(TCP, 443), (TCP, 8443)])
+        .run();
+
+    rule_tracker
+        .add_resolved_rule(&mut store, &resolved)
+        .unwrap();
+    rule_tracker.remove_rule(&mut store, &added).unwrap();
+    TestRun::with(rule_tracker.clone())
+        .expect_true("10.0.0.1/32", &[(TCP, 443)])
+        .expect_false("10.0.0.1/32", &[(TCP, 8443)])
+        .run();
+    assert_eq!(
+        store.entries,
+        MemoryTrie::with_entries(&rule_tracker).entries
+    );
+}
@@ -5,6 +5,8 @@ use std::{
     str::FromStr,
 };
 
+use aya::maps::lpm_trie::Key;
+use firewall_common::RuleStore;
 use ipnet::{Ipv4Net, Ipv6Net};
 
 use crate::{
@@ -193,6 +195,43 @@ pub(crate) fn prepared_expect_v4(test_run: TestRun<Ipv4Net>) -> TestRun<Ipv4Net>
         )
 }
 
+// Emulates the entries that'd be found in the rule trie for the given tracker
+pub(crate) fn entries<T>(rule_tracker: &RuleTracker<T>) -> Vec<(Key<T::KeySize>, RuleStore)>
+where
+    T: AsNum + Eq + Hash + Clone + AsOctets + AsKey + Normalize,
+    T::Octets: AsRef<[u8]>,
+{
+    rule_tracker
+        .rule_map
+        .iter()
+        .map(|((id, proto, ip), port_ranges)| {
+            (
+                ip.ip.as_key(*id, *proto),
+                to_rule_store(port_ranges).unwrap(),
+            )
+        })
+        .collect()
+}
+
+pub(crate) fn entries_ranges<T>(
+    rule_tracker: &RuleTracker<T>,
+) -> HashMap<(u128, u8, Normalized<T>), Vec<(u16, u16)>>
+where
+    T: AsNum + Eq + Hash + Clone + AsOctets + AsKey + Normalize,
+    T::Octets: AsRef<[u8]>,
+{
+    rule_tracker
+        .rule_map
+        .iter()
+        .map(|(k, port_ranges)| {
+            (
+                k.clone(),
+                to_rule_store(port_ranges).unwrap().ranges().collect(),
+            )
+        })
+        .collect()
+}
+
 type Port = (Protocol, u16);
 #[derive(Debug)]
 pub(crate) struct TestRun<T>
@@ -222,10 +261,11 @@ where
         println!("{self:#?}");
         for ((id, cidr), ports) in self.expect_true.clone() {
             for (proto, port) in ports {
-                let rule_map =
-                    self.rule_tracker
-                        .rule_map
-                        .get(&(id, proto, Normalized::new(cidr.clone())));
+                let rule_map = self.rule_tracker.rule_map.get(&(
+                    id,
+                    proto as u8,
+                    Normalized::new(cidr.clone()),
+                ));
                 assert!(
                     rule_map.is_some(),
                     "rule_map for id {id} cidr {cidr:?} protocol {proto:?} port {port:?} is none"
@@ -240,10 +280,11 @@ where
 
         for ((id, cidr), ports) in self.expect_false.clone() {
             for (proto, port) in ports {
-                let rule_map =
-                    self.rule_tracker
-                        .rule_map
-                        .get(&(id, proto, Normalized::new(cidr.clone())));
+                let rule_map = self.rule_tracker.rule_map.get(&(
+                    id,
+                    proto as u8,
+                    Normalized::new(cidr.clone()),
+                ));
                 if !rule_map.is_none() {
                     let rule_store = to_rule_store(rule_map.unwrap()).unwrap();
                     assert!(
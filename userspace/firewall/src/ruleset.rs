@@ -0,0 +1,374 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use aya::{
+    maps::{lpm_trie::Key, Array, HashMap as BpfHashMap, LpmTrie, MapData},
+    Bpf,
+};
+use firewall_common::RuleStore;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+
+use crate::{
+    cidr::AsKey,
+    classifier::{ClassifierV4, ClassifierV6},
+    rule_tracker::{HeaderFilters, RuleTrackerV4, RuleTrackerV6},
+    transaction::Operation,
+    Error::MapNotFound,
+    Result, Rule, HEADER_FILTERS_IPV4, HEADER_FILTERS_IPV6, RULE_HITS, RULE_MAP_IPV4,
+    RULE_MAP_IPV6,
+};
+
+type TrieV4<'a> = LpmTrie<&'a mut MapData, <Ipv4Net as AsKey>::KeySize, RuleStore>;
+type TrieV6<'a> = LpmTrie<&'a mut MapData, <Ipv6Net as AsKey>::KeySize, RuleStore>;
+
+/// Rules and source ids stored in one generation of maps.
+///
+/// The eBPF program only looks at the generation selected in the config,
+/// so a complete ruleset can be built in the other generation and swapped in with a single update.
+#[derive(Clone)]
+pub(crate) struct Ruleset {
+    generation: usize,
+    pub(crate) rule_tracker_v4: RuleTrackerV4,
+    pub(crate) rule_tracker_v6: RuleTrackerV6,
+    pub(crate) classifier_v4: ClassifierV4,
+    pub(crate) classifier_v6: ClassifierV6,
+}
+
+/// Entries that differ between two [Ruleset]s of the same generation, see [Ruleset::changes].
+#[derive(Default)]
+pub(crate) struct Changes {
+    rules_v4: Vec<Key<<Ipv4Net as AsKey>::KeySize>>,
+    rules_v6: Vec<Key<<Ipv6Net as AsKey>::KeySize>>,
+    header_filters_v4: bool,
+    header_filters_v6: bool,
+    ids_v4: Vec<[u8; 4]>,
+    ids_v6: Vec<[u8; 16]>,
+}
+
+impl Changes {
+    /// Adds the entries of `other`, entries in both are kept twice which is harmless to write.
+    pub(crate) fn extend(&mut self, other: Changes) {
+        self.rules_v4.extend(other.rules_v4);
+        self.rules_v6.extend(other.rules_v6);
+        self.header_filters_v4 |= other.header_filters_v4;
+        self.header_filters_v6 |= other.header_filters_v6;
+        self.ids_v4.extend(other.ids_v4);
+        self.ids_v6.extend(other.ids_v6);
+    }
+}
+
+impl Ruleset {
+    pub(crate) fn new(generation: usize) -> Result<Self> {
+        Ok(Self {
+            generation,
+            rule_tracker_v4: RuleTrackerV4::new()?,
+            rule_tracker_v6: RuleTrackerV6::new()?,
+            classifier_v4: ClassifierV4::new(generation)?,
+            classifier_v6: ClassifierV6::new(generation)?,
+        })
+    }
+
+    pub(crate) fn generation(&self) -> usize {
+        self.generation
+    }
+
+    fn trie_v4<'a>(&self, bpf: &'a mut Bpf) -> Result<TrieV4<'a>> {
+        Ok(LpmTrie::try_from(
+            bpf.map_mut(RULE_MAP_IPV4[self.generation])
+                .ok_or(MapNotFound)?,
+        )?)
+    }
+
+    fn trie_v6<'a>(&self, bpf: &'a mut Bpf) -> Result<TrieV6<'a>> {
+        Ok(LpmTrie::try_from(
+            bpf.map_mut(RULE_MAP_IPV6[self.generation])
+                .ok_or(MapNotFound)?,
+        )?)
+    }
+
+    pub(crate) fn add_rule(&mut self, bpf: &mut Bpf, rule: &Rule) -> Result<()> {
+        self.with_header_filters(bpf, |ruleset, bpf| match &rule {
+            Rule::V4(r) => ruleset
+                .rule_tracker_v4
+                .add_rule(&mut ruleset.trie_v4(bpf)?, r),
+            Rule::V6(r) => ruleset
+                .rule_tracker_v6
+                .add_rule(&mut ruleset.trie_v6(bpf)?, r),
+        })
+    }
+
+    pub(crate) fn remove_rule(&mut self, bpf: &mut Bpf, rule: &Rule) -> Result<()> {
+        self.with_header_filters(bpf, |ruleset, bpf| match &rule {
+            Rule::V4(r) => ruleset
+                .rule_tracker_v4
+                .remove_rule(&mut ruleset.trie_v4(bpf)?, r),
+            Rule::V6(r) => ruleset
+                .rule_tracker_v6
+                .remove_rule(&mut ruleset.trie_v6(bpf)?, r),
+        })
+    }
+
+    /// Adds a rule resolved from a hostname rule, it doesn't affect an identical rule added with [add_rule](Self::add_rule).
+    pub(crate) fn add_resolved_rule(&mut self, bpf: &mut Bpf, rule: &Rule) -> Result<()> {
+        self.with_header_filters(bpf, |ruleset, bpf| match &rule {
+            Rule::V4(r) => ruleset
+                .rule_tracker_v4
+                .add_resolved_rule(&mut ruleset.trie_v4(bpf)?, r),
+            Rule::V6(r) => ruleset
+                .rule_tracker_v6
+                .add_resolved_rule(&mut ruleset.trie_v6(bpf)?, r),
+        })
+    }
+
+    pub(crate) fn remove_resolved_rule(&mut self, bpf: &mut Bpf, rule: &Rule) -> Result<()> {
+        self.with_header_filters(bpf, |ruleset, bpf| match &rule {
+            Rule::V4(r) => ruleset
+                .rule_tracker_v4
+                .remove_resolved_rule(&mut ruleset.trie_v4(bpf)?, r),
+            Rule::V6(r) => ruleset
+                .rule_tracker_v6
+                .remove_resolved_rule(&mut ruleset.trie_v6(bpf)?, r),
+        })
+    }
+
+    // Runs `f` and then writes the header predicates that changed in the meantime
+    fn with_header_filters(
+        &mut self,
+        bpf: &mut Bpf,
+        f: impl FnOnce(&mut Self, &mut Bpf) -> Result<()>,
+    ) -> Result<()> {
+        let before_v4 = *self.rule_tracker_v4.header_filters();
+        let before_v6 = *self.rule_tracker_v6.header_filters();
+        let res = f(self, bpf);
+        self.write_header_filters(bpf, Some((&before_v4, &before_v6)))?;
+        res
+    }
+
+    // Writes the header predicates of the trackers, only the ones that differ from `before` if given
+    fn write_header_filters(
+        &self,
+        bpf: &mut Bpf,
+        before: Option<(&HeaderFilters, &HeaderFilters)>,
+    ) -> Result<()> {
+        write_header_filters(
+            bpf,
+            HEADER_FILTERS_IPV4[self.generation],
+            before.map(|(v4, _)| v4),
+            self.rule_tracker_v4.header_filters(),
+        )?;
+        write_header_filters(
+            bpf,
+            HEADER_FILTERS_IPV6[self.generation],
+            before.map(|(_, v6)| v6),
+            self.rule_tracker_v6.header_filters(),
+        )
+    }
+
+    pub(crate) fn add_id(&mut self, bpf: &mut Bpf, ip: IpNet, id: u128) -> Result<()> {
+        match ip {
+            IpNet::V4(ip) => self.classifier_v4.insert(bpf, ip, id),
+            IpNet::V6(ip) => self.classifier_v6.insert(bpf, ip, id),
+        }
+    }
+
+    pub(crate) fn remove_id(&mut self, bpf: &mut Bpf, ip: &IpNet) -> Result<()> {
+        match ip {
+            IpNet::V4(ip) => self.classifier_v4.remove(bpf, ip),
+            IpNet::V6(ip) => self.classifier_v6.remove(bpf, ip),
+        }
+    }
+
+    /// Rules as they were added, without the resolved ones.
+    pub(crate) fn rules(&self) -> Vec<Rule> {
+        let v4 = self.rule_tracker_v4.rules().cloned().map(Rule::V4);
+        let v6 = self.rule_tracker_v6.rules().cloned().map(Rule::V6);
+        v4.chain(v6).collect()
+    }
+
+    /// Ids associated with at least one ip.
+    pub(crate) fn ids(&self) -> impl Iterator<Item = u128> + '_ {
+        self.classifier_v4.ids().chain(self.classifier_v6.ids())
+    }
+
+    pub(crate) fn ips_for_id(&self, id: u128) -> Vec<IpAddr> {
+        let v4 = self
+            .classifier_v4
+            .entries(id)
+            .map(|&ip| IpAddr::V4(Ipv4Addr::from(ip)));
+        let v6 = self
+            .classifier_v6
+            .entries(id)
+            .map(|&ip| IpAddr::V6(Ipv6Addr::from(ip)));
+        v4.chain(v6).collect()
+    }
+
+    pub(crate) fn id_for_ip(&self, ip: IpAddr) -> Option<u128> {
+        match ip {
+            IpAddr::V4(ip) => self.classifier_v4.id_of(&ip.octets()),
+            IpAddr::V6(ip) => self.classifier_v6.id_of(&ip.octets()),
+        }
+    }
+
+    /// Applies `operation` only to the userland state, used to validate operations.
+    pub(crate) fn apply_local(&mut self, operation: &Operation) -> Result<()> {
+        match operation {
+            Operation::AddRule(Rule::V4(r)) => self.rule_tracker_v4.add_rule(&mut (), r),
+            Operation::AddRule(Rule::V6(r)) => self.rule_tracker_v6.add_rule(&mut (), r),
+            Operation::RemoveRule(Rule::V4(r)) => self.rule_tracker_v4.remove_rule(&mut (), r),
+            Operation::RemoveRule(Rule::V6(r)) => self.rule_tracker_v6.remove_rule(&mut (), r),
+            Operation::AddId(IpNet::V4(ip), id) => self.classifier_v4.insert_local(ip, *id),
+            Operation::AddId(IpNet::V6(ip), id) => self.classifier_v6.insert_local(ip, *id),
+            Operation::RemoveId(IpNet::V4(ip)) => self.classifier_v4.remove_local(ip),
+            Operation::RemoveId(IpNet::V6(ip)) => self.classifier_v6.remove_local(ip),
+        }
+    }
+
+    /// Entries of the maps that have to be rewritten for them to go from `other` to `self`.
+    pub(crate) fn changes(&self, other: &Self) -> Changes {
+        Changes {
+            rules_v4: self.rule_tracker_v4.changed_keys(&other.rule_tracker_v4),
+            rules_v6: self.rule_tracker_v6.changed_keys(&other.rule_tracker_v6),
+            header_filters_v4: self.rule_tracker_v4.header_filters()
+                != other.rule_tracker_v4.header_filters(),
+            header_filters_v6: self.rule_tracker_v6.header_filters()
+                != other.rule_tracker_v6.header_filters(),
+            ids_v4: self.classifier_v4.changed(&other.classifier_v4),
+            ids_v6: self.classifier_v6.changed(&other.classifier_v6),
+        }
+    }
+
+    /// Writes the entries `self` has for everything in `changes`, entries `self` doesn't have are removed.
+    pub(crate) fn write_changes(&self, bpf: &mut Bpf, changes: &Changes) -> Result<()> {
+        self.write_changes_impl(bpf, changes, false)
+    }
+
+    /// Same as [write_changes](Self::write_changes) with the ruleset as it was before `changes`,
+    /// to undo them after writing them failed midway.
+    pub(crate) fn rollback(&self, bpf: &mut Bpf, changes: &Changes) -> Result<()> {
+        self.write_changes_impl(bpf, changes, true)
+    }
+
+    // Header predicates are written after the entries, so entries using a new slot
+    // only match once it's written and entries of a released slot are gone before it's cleared
+    fn write_changes_impl(&self, bpf: &mut Bpf, changes: &Changes, undo: bool) -> Result<()> {
+        if undo {
+            self.rule_tracker_v4
+                .rollback(&mut self.trie_v4(bpf)?, &changes.rules_v4)?;
+            self.rule_tracker_v6
+                .rollback(&mut self.trie_v6(bpf)?, &changes.rules_v6)?;
+        } else {
+            self.rule_tracker_v4
+                .write_entries(&mut self.trie_v4(bpf)?, &changes.rules_v4)?;
+            self.rule_tracker_v6
+                .write_entries(&mut self.trie_v6(bpf)?, &changes.rules_v6)?;
+        }
+        if changes.header_filters_v4 {
+            write_header_filters(
+                bpf,
+                HEADER_FILTERS_IPV4[self.generation],
+                None,
+                self.rule_tracker_v4.header_filters(),
+            )?;
+        }
+        if changes.header_filters_v6 {
+            write_header_filters(
+                bpf,
+                HEADER_FILTERS_IPV6[self.generation],
+                None,
+                self.rule_tracker_v6.header_filters(),
+            )?;
+        }
+        if undo {
+            self.classifier_v4.rollback(bpf, &changes.ids_v4)?;
+            self.classifier_v6.rollback(bpf, &changes.ids_v6)?;
+        } else {
+            self.classifier_v4.write_entries(bpf, &changes.ids_v4)?;
+            self.classifier_v6.write_entries(bpf, &changes.ids_v6)?;
+        }
+        Ok(())
+    }
+
+    /// Last time in `bpf_ktime` each rule matched a packet, `None` if it never did.
+    pub(crate) fn last_hits(&self, bpf: &Bpf) -> Result<Vec<(Rule, Option<u64>)>> {
+        let map = BpfHashMap::try_from(bpf.map(RULE_HITS).ok_or(MapNotFound)?)?;
+        let hits = map
+            .iter()
+            .collect::<std::result::Result<HashMap<u64, u64>, _>>()?;
+
+        let v4 = self
+            .rule_tracker_v4
+            .last_hits(&hits)
+            .map(|(rule, hit)| (Rule::V4(rule.clone()), hit));
+        let v6 = self
+            .rule_tracker_v6
+            .last_hits(&hits)
+            .map(|(rule, hit)| (Rule::V6(rule.clone()), hit));
+        Ok(v4.chain(v6).collect())
+    }
+
+    /// Removes every rule and id of this generation from the maps.
+    pub(crate) fn clear(&mut self, bpf: &mut Bpf) -> Result<()> {
+        self.rule_tracker_v4.clear(&mut self.trie_v4(bpf)?)?;
+        self.rule_tracker_v6.clear(&mut self.trie_v6(bpf)?)?;
+        self.write_header_filters(bpf, None)?;
+        self.classifier_v4.clear(bpf)?;
+        self.classifier_v6.clear(bpf)?;
+        Ok(())
+    }
+
+    /// Rebuilds the tracked rules and ids from the maps of this generation.
+    #[cfg(feature = "pinning")]
+    pub(crate) fn restore(&mut self, bpf: &Bpf) -> Result<()> {
+        self.rule_tracker_v4
+            .restore_header_filters(read_header_filters(
+                bpf,
+                HEADER_FILTERS_IPV4[self.generation],
+            )?);
+        let trie = LpmTrie::try_from(bpf.map(RULE_MAP_IPV4[self.generation]).ok_or(MapNotFound)?)?;
+        self.rule_tracker_v4
+            .restore(trie.iter().collect::<std::result::Result<Vec<_>, _>>()?)?;
+        self.rule_tracker_v6
+            .restore_header_filters(read_header_filters(
+                bpf,
+                HEADER_FILTERS_IPV6[self.generation],
+            )?);
+        let trie = LpmTrie::try_from(bpf.map(RULE_MAP_IPV6[self.generation]).ok_or(MapNotFound)?)?;
+        self.rule_tracker_v6
+            .restore(trie.iter().collect::<std::result::Result<Vec<_>, _>>()?)?;
+
+        self.classifier_v4.restore(bpf)?;
+        self.classifier_v6.restore(bpf)?;
+        Ok(())
+    }
+}
+
+fn write_header_filters(
+    bpf: &mut Bpf,
+    map_name: &str,
+    before: Option<&HeaderFilters>,
+    filters: &HeaderFilters,
+) -> Result<()> {
+    if before == Some(filters) {
+        return Ok(());
+    }
+
+    let mut map = Array::try_from(bpf.map_mut(map_name).ok_or(MapNotFound)?)?;
+    for (slot, filter) in filters.iter().enumerate() {
+        if before.map_or(true, |before| before[slot] != *filter) {
+            map.set(slot as u32, *filter, 0)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "pinning")]
+fn read_header_filters(bpf: &Bpf, map_name: &str) -> Result<HeaderFilters> {
+    let map = Array::try_from(bpf.map(map_name).ok_or(MapNotFound)?)?;
+    let mut filters = HeaderFilters::default();
+    for (slot, filter) in filters.iter_mut().enumerate() {
+        *filter = map.get(&(slot as u32), 0)?;
+    }
+    Ok(filters)
+}
@@ -0,0 +1,218 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+};
+
+use firewall_common::Action;
+use ipnet::IpNet;
+
+use crate::{transaction::Operation, Policy, Rule};
+
+/// Changes made by [Firewall::sync](crate::Firewall::sync) to reach the desired [Policy].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub rules_added: Vec<Rule>,
+    pub rules_removed: Vec<Rule>,
+    /// IPs that had no id, with the id they were given.
+    pub ids_added: Vec<(IpNet, u128)>,
+    /// IPs whose id was replaced, with their new id.
+    pub ids_changed: Vec<(IpNet, u128)>,
+    pub ids_removed: Vec<IpNet>,
+    /// New default action, `None` if it was already the desired one.
+    pub default_action: Option<Action>,
+}
+
+impl SyncReport {
+    /// Whether the firewall already matched the desired policy.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Computes the minimal changes from the current state to `desired`.
+    ///
+    /// `rules` are the ones added to the firewall without the rules of hostname rules, which are never removed.
+    pub(crate) fn diff(
+        rules: &[Rule],
+        ids: &HashMap<IpAddr, u128>,
+        default_action: Action,
+        desired: &Policy,
+    ) -> Self {
+        let installed: HashSet<&Rule> = rules.iter().collect();
+        let wanted: HashSet<&Rule> = desired.rules.iter().collect();
+        let rules_removed = rules
+            .iter()
+            .filter(|rule| !wanted.contains(rule))
+            .cloned()
+            .collect();
+        let mut seen = HashSet::new();
+        let rules_added = desired
+            .rules
+            .iter()
+            .filter(|rule| !installed.contains(rule) && seen.insert(*rule))
+            .cloned()
+            .collect();
+
+        // Classifiers are keyed by address and a later assignment of the same address wins
+        let wanted_ids: HashMap<IpAddr, (IpNet, u128)> = desired
+            .id_pairs()
+            .into_iter()
+            .map(|(ip, id)| (ip.addr(), (ip, id)))
+            .collect();
+        let mut report = Self {
+            rules_added,
+            rules_removed,
+            default_action: (desired.default_action != default_action)
+                .then_some(desired.default_action),
+            ..Default::default()
+        };
+        for (&ip, &(net, id)) in &wanted_ids {
+            match ids.get(&ip) {
+                None => report.ids_added.push((net, id)),
+                Some(&current) if current != id => report.ids_changed.push((net, id)),
+                Some(_) => {}
+            }
+        }
+        report.ids_removed = ids
+            .keys()
+            .filter(|ip| !wanted_ids.contains_key(ip))
+            .map(|&ip| IpNet::from(ip))
+            .collect();
+        report.ids_added.sort_unstable();
+        report.ids_changed.sort_unstable();
+        report.ids_removed.sort_unstable();
+        report
+    }
+
+    /// Operations applying the rule and id changes while `default_action` is in effect.
+    ///
+    /// They are ordered so no packet is accepted in between unless the current or the desired state accepts it.
+    /// With a default `Reject` rules and ids grant access, so they are removed first and added last.
+    /// With a default `Accept` they deny access and it's the other way around, ids are changed in
+    /// place after adding rules so both the old and new rules of the id apply in the meantime.
+    pub(crate) fn operations(&self, default_action: Action) -> Vec<Operation> {
+        let add_rules = self.rules_added.iter().cloned().map(Operation::AddRule);
+        let remove_rules = self
+            .rules_removed
+            .iter()
+            .cloned()
+            .map(Operation::RemoveRule);
+        let add_ids = self
+            .ids_added
+            .iter()
+            .map(|&(ip, id)| Operation::AddId(ip, id));
+        // Inserting replaces the previous id with a single map update
+        let change_ids = self
+            .ids_changed
+            .iter()
+            .map(|&(ip, id)| Operation::AddId(ip, id));
+        let remove_ids = self.ids_removed.iter().copied().map(Operation::RemoveId);
+
+        match default_action {
+            Action::Reject => remove_rules
+                .chain(remove_ids)
+                .chain(change_ids)
+                .chain(add_ids)
+                .chain(add_rules)
+                .collect(),
+            Action::Accept => add_rules
+                .chain(add_ids)
+                .chain(change_ids)
+                .chain(remove_rules)
+                .chain(remove_ids)
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use firewall_common::Action;
+
+    use super::SyncReport;
+    use crate::{transaction::Operation, IdAssignment, Policy, Rule};
+
+    fn rule(dest: &str) -> Rule {
+        Rule::new(dest.parse().unwrap())
+    }
+
+    fn policy(rules: &[Rule], ids: &[(&str, u128)]) -> Policy {
+        Policy {
+            default_action: Action::Reject,
+            ids: ids
+                .iter()
+                .map(|&(ip, id)| IdAssignment {
+                    id,
+                    ips: vec![ip.parse().unwrap()],
+                })
+                .collect(),
+            rules: rules.to_vec(),
+        }
+    }
+
+    fn kind(operation: &Operation) -> &'static str {
+        match operation {
+            Operation::AddRule(_) => "add rule",
+            Operation::RemoveRule(_) => "remove rule",
+            Operation::AddId(..) => "add id",
+            Operation::RemoveId(_) => "remove id",
+        }
+    }
+
+    #[test]
+    fn diff_is_minimal() {
+        let installed = [rule("10.0.0.0/8"), rule("10.1.0.0/16"), rule("fafa::/64")];
+        let ids = HashMap::from([
+            ("10.0.0.5".parse().unwrap(), 1),
+            ("10.0.0.6".parse().unwrap(), 1),
+            ("10.0.0.7".parse().unwrap(), 2),
+        ]);
+        let desired = policy(
+            &[rule("10.0.0.0/8"), rule("10.2.0.0/16"), rule("10.2.0.0/16")],
+            &[("10.0.0.5/32", 1), ("10.0.0.7/32", 3), ("10.0.0.8/32", 2)],
+        );
+
+        let report = SyncReport::diff(&installed, &ids, Action::Reject, &desired);
+        assert_eq!(
+            report,
+            SyncReport {
+                rules_added: vec![rule("10.2.0.0/16")],
+                rules_removed: vec![rule("10.1.0.0/16"), rule("fafa::/64")],
+                ids_added: vec![("10.0.0.8/32".parse().unwrap(), 2)],
+                ids_changed: vec![("10.0.0.7/32".parse().unwrap(), 3)],
+                ids_removed: vec!["10.0.0.6/32".parse().unwrap()],
+                default_action: None,
+            }
+        );
+
+        let current = policy(&installed[..2], &[("10.0.0.5/32", 1)]);
+        let ids = HashMap::from([("10.0.0.5".parse().unwrap(), 1)]);
+        assert!(SyncReport::diff(&installed[..2], &ids, Action::Reject, &current).is_empty());
+    }
+
+    #[test]
+    fn operations_never_widen_access() {
+        let report = SyncReport {
+            rules_added: vec![rule("10.2.0.0/16")],
+            rules_removed: vec![rule("10.1.0.0/16")],
+            ids_added: vec![("10.0.0.8/32".parse().unwrap(), 2)],
+            ids_changed: vec![("10.0.0.7/32".parse().unwrap(), 3)],
+            ids_removed: vec!["10.0.0.6/32".parse().unwrap()],
+            default_action: None,
+        };
+
+        // Rules and ids grant access, revoke first
+        let kinds: Vec<_> = report.operations(Action::Reject).iter().map(kind).collect();
+        assert_eq!(
+            kinds,
+            ["remove rule", "remove id", "add id", "add id", "add rule"]
+        );
+        // Rules and ids deny access, add first
+        let kinds: Vec<_> = report.operations(Action::Accept).iter().map(kind).collect();
+        assert_eq!(
+            kinds,
+            ["add rule", "add id", "add id", "remove rule", "remove id"]
+        );
+    }
+}
@@ -0,0 +1,558 @@
+use std::{
+    io, mem,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    time::Duration,
+};
+
+use aya::programs::{ProgramError, ProgramFd, SchedClassifier};
+
+use crate::{classifier::IfIndex, Result};
+
+// Not exported by libc
+const TCA_BPF_FD: u16 = 6;
+const TCA_BPF_NAME: u16 = 7;
+const TCA_BPF_FLAGS: u16 = 8;
+const TCA_BPF_FLAG_ACT_DIRECT: u32 = 1;
+const NLA_F_NESTED: u16 = 1 << 15;
+// Ingress hook of the clsact qdisc, `TC_H_MAKE(TC_H_CLSACT, TC_H_MIN_INGRESS)`
+const CLSACT_INGRESS: u32 = 0xFFFF_FFF2;
+const ETH_P_ALL: u16 = 0x0003;
+
+// Time to wait for the kernel to answer a request
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const TC_ACT_UNSPEC: i32 = -1;
+const TC_ACT_OK: i32 = 0;
+
+/// Options for attaching the firewall's classifier to the tc ingress hook of an interface,
+/// see [Firewall::new_with_options](crate::Firewall::new_with_options).
+///
+/// Filters on the same hook run by ascending priority, use these to order the firewall
+/// relative to other classifiers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AttachOptions {
+    /// Priority of the filter, 0 lets the kernel pick one.
+    pub priority: u16,
+    /// Handle of the filter, 0 lets the kernel pick one.
+    /// Attaching fails if a filter with the same priority and handle already exists.
+    pub handle: u32,
+}
+
+/// Verdict given to accepted packets, see [Firewall::set_accept_verdict](crate::Firewall::set_accept_verdict).
+///
+/// Dropped packets are always given `TC_ACT_SHOT`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AcceptVerdict {
+    /// `TC_ACT_OK`, the packet is accepted and no other filter on the hook sees it.
+    #[default]
+    Ok,
+    /// `TC_ACT_UNSPEC`, the packet continues to the next filter on the hook.
+    ///
+    /// The firewall is attached in direct-action mode, where this is the only verdict that reaches
+    /// later filters, `TC_ACT_PIPE` would end the classification like `TC_ACT_OK`.
+    Unspec,
+}
+
+impl AcceptVerdict {
+    pub(crate) fn as_tc_action(&self) -> i32 {
+        match self {
+            Self::Ok => TC_ACT_OK,
+            Self::Unspec => TC_ACT_UNSPEC,
+        }
+    }
+}
+
+/// Classifier attached to the ingress hook of an interface, detached when dropped unless [kept](Self::keep).
+#[derive(Debug)]
+pub(crate) struct TcFilter {
+    ifindex: IfIndex,
+    priority: u16,
+    handle: u32,
+    keep: bool,
+}
+
+impl TcFilter {
+    /// Attaches `program` in direct action mode, the clsact qdisc must already exist.
+    pub(crate) fn attach(
+        program: &SchedClassifier,
+        name: &str,
+        iface: &str,
+        options: AttachOptions,
+    ) -> Result<Self> {
+        let ifindex = IfIndex::from_name(iface)?;
+        let prog_fd = program.fd().ok_or(ProgramError::NotLoaded)?;
+
+        let mut request = Request::new(
+            libc::RTM_NEWTFILTER,
+            libc::NLM_F_CREATE | libc::NLM_F_EXCL | libc::NLM_F_ECHO,
+            ifindex,
+            options.priority,
+            options.handle,
+        );
+        request.attr(libc::TCA_KIND, b"bpf\0");
+        let nested = request.nested_start(libc::TCA_OPTIONS);
+        request.attr(TCA_BPF_FD, &(prog_fd as u32).to_ne_bytes());
+        request.attr(TCA_BPF_NAME, &[name.as_bytes(), b"\0"].concat());
+        request.attr(TCA_BPF_FLAGS, &TCA_BPF_FLAG_ACT_DIRECT.to_ne_bytes());
+        request.nested_end(nested);
+
+        // The kernel echoes the filter back with the priority and handle it picked
+        let created = request.send()?;
+        let (priority, handle) = created.unwrap_or((options.priority, options.handle));
+        Ok(Self {
+            ifindex,
+            priority,
+            handle,
+            keep: false,
+        })
+    }
+
+    /// Filter attached by another process, e.g. the one that pinned the firewall.
+    #[cfg(feature = "pinning")]
+    pub(crate) fn from_parts(ifindex: IfIndex, priority: u16, handle: u32) -> Self {
+        Self {
+            ifindex,
+            priority,
+            handle,
+            keep: false,
+        }
+    }
+
+    #[cfg(feature = "pinning")]
+    pub(crate) fn ifindex(&self) -> IfIndex {
+        self.ifindex
+    }
+
+    #[cfg(feature = "pinning")]
+    pub(crate) fn priority(&self) -> u16 {
+        self.priority
+    }
+
+    #[cfg(feature = "pinning")]
+    pub(crate) fn handle(&self) -> u32 {
+        self.handle
+    }
+
+    /// Detaches the filter, returning the error instead of logging it as dropping does.
+    #[cfg(feature = "pinning")]
+    pub(crate) fn remove(mut self) -> Result<()> {
+        self.keep = true;
+        self.detach()
+    }
+
+    /// Leaves the filter attached after dropping `self`.
+    pub(crate) fn keep(mut self) {
+        self.keep = true;
+    }
+
+    fn detach(&self) -> Result<()> {
+        let mut request = Request::new(
+            libc::RTM_DELTFILTER,
+            0,
+            self.ifindex,
+            self.priority,
+            self.handle,
+        );
+        request.attr(libc::TCA_KIND, b"bpf\0");
+        request.send()?;
+        Ok(())
+    }
+}
+
+impl Drop for TcFilter {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+        if let Err(e) = self.detach() {
+            tracing::warn!("Couldn't detach the firewall from the interface: {e}");
+        }
+    }
+}
+
+// `struct tcmsg` from `linux/rtnetlink.h`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TcMsg {
+    family: u8,
+    pad1: u8,
+    pad2: u16,
+    ifindex: i32,
+    handle: u32,
+    parent: u32,
+    info: u32,
+}
+
+// Netlink request for a tc filter on the ingress hook
+struct Request {
+    buf: Vec<u8>,
+}
+
+impl Request {
+    fn new(ty: u16, flags: i32, ifindex: IfIndex, priority: u16, handle: u32) -> Self {
+        let header = libc::nlmsghdr {
+            nlmsg_len: 0,
+            nlmsg_type: ty,
+            nlmsg_flags: (libc::NLM_F_REQUEST | libc::NLM_F_ACK | flags) as u16,
+            nlmsg_seq: 1,
+            nlmsg_pid: 0,
+        };
+        let tcmsg = TcMsg {
+            family: libc::AF_UNSPEC as u8,
+            pad1: 0,
+            pad2: 0,
+            ifindex: ifindex.0 as i32,
+            handle,
+            parent: CLSACT_INGRESS,
+            info: (priority as u32) << 16 | ETH_P_ALL.to_be() as u32,
+        };
+        let mut buf = Vec::new();
+        buf.extend_from_slice(as_bytes(&header));
+        buf.extend_from_slice(as_bytes(&tcmsg));
+        Self { buf }
+    }
+
+    fn attr(&mut self, ty: u16, data: &[u8]) {
+        let len = (mem::size_of::<libc::nlattr>() + data.len()) as u16;
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&ty.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.align();
+    }
+
+    fn nested_start(&mut self, ty: u16) -> usize {
+        let start = self.buf.len();
+        self.attr(ty | NLA_F_NESTED, &[]);
+        start
+    }
+
+    fn nested_end(&mut self, start: usize) {
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    fn align(&mut self) {
+        while self.buf.len() % 4 != 0 {
+            self.buf.push(0);
+        }
+    }
+
+    // Request as sent, with the length of the message set
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf
+    }
+
+    // Sends the request and waits for its ack,
+    // returns the priority and handle of the filter if the kernel echoed it
+    fn send(self) -> io::Result<Option<(u16, u32)>> {
+        let buf = self.finish();
+
+        // SAFETY: plain socket creation, the descriptor is owned right away
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW, libc::NETLINK_ROUTE) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just created and isn't owned by anything else
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        set_receive_timeout(socket.as_raw_fd(), RECEIVE_TIMEOUT)?;
+
+        // SAFETY: `buf` is valid for its whole length
+        let sent = unsafe { libc::send(socket.as_raw_fd(), buf.as_ptr().cast(), buf.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut created = None;
+        loop {
+            let messages = receive(socket.as_raw_fd())?;
+            if let Some(ack) = process(&messages, &mut created) {
+                return ack;
+            }
+        }
+    }
+}
+
+// Goes through the answers to a request, keeping the filter echoed by the kernel in `created`,
+// returns the outcome of the request once its ack is found
+fn process(
+    messages: &[(u16, Vec<u8>)],
+    created: &mut Option<(u16, u32)>,
+) -> Option<io::Result<Option<(u16, u32)>>> {
+    for (ty, payload) in messages {
+        match *ty {
+            libc::RTM_NEWTFILTER if payload.len() >= mem::size_of::<TcMsg>() => {
+                // SAFETY: the payload starts with a `tcmsg`, read unaligned from the buffer
+                let tcmsg = unsafe { payload.as_ptr().cast::<TcMsg>().read_unaligned() };
+                *created = Some(((tcmsg.info >> 16) as u16, tcmsg.handle));
+            }
+            ty if ty == libc::NLMSG_ERROR as u16 && payload.len() >= 4 => {
+                let error = i32::from_ne_bytes(payload[..4].try_into().unwrap());
+                return Some(if error == 0 {
+                    Ok(*created)
+                } else {
+                    Err(io::Error::from_raw_os_error(-error))
+                });
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// Makes `recv` on `fd` fail instead of blocking forever if the kernel never answers
+fn set_receive_timeout(fd: RawFd, timeout: Duration) -> io::Result<()> {
+    let timeval = libc::timeval {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+    };
+    // SAFETY: `timeval` is valid for its size
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            (&timeval as *const libc::timeval).cast(),
+            mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Receives a batch of netlink messages as their type and payload
+fn receive(fd: RawFd) -> io::Result<Vec<(u16, Vec<u8>)>> {
+    let mut buf = vec![0u8; 8192];
+    // SAFETY: `buf` is valid for its whole length
+    let read = unsafe { libc::recv(fd, buf.as_mut_ptr().cast(), buf.len(), 0) };
+    if read < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::WouldBlock {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "No answer from the kernel to the netlink request",
+            ));
+        }
+        return Err(e);
+    }
+    parse(&buf[..read as usize])
+}
+
+// Splits a batch of netlink messages into their type and payload
+fn parse(buf: &[u8]) -> io::Result<Vec<(u16, Vec<u8>)>> {
+    let header_len = mem::size_of::<libc::nlmsghdr>();
+    let mut messages = Vec::new();
+    let mut offset = 0;
+    while offset < buf.len() {
+        if offset + header_len > buf.len() {
+            return Err(truncated());
+        }
+        // SAFETY: there are at least `header_len` bytes left, read unaligned from the buffer
+        let header = unsafe {
+            buf[offset..]
+                .as_ptr()
+                .cast::<libc::nlmsghdr>()
+                .read_unaligned()
+        };
+        let len = header.nlmsg_len as usize;
+        if len < header_len || offset + len > buf.len() {
+            return Err(truncated());
+        }
+        messages.push((
+            header.nlmsg_type,
+            buf[offset + header_len..offset + len].to_vec(),
+        ));
+        // Messages are aligned to 4 bytes
+        offset += (len + 3) & !3;
+    }
+    Ok(messages)
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Truncated netlink message")
+}
+
+fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    // SAFETY: `T` is a plain C struct without padding, valid to read as bytes for its size
+    unsafe { std::slice::from_raw_parts((value as *const T).cast(), mem::size_of::<T>()) }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io, mem,
+        os::unix::{io::AsRawFd, net::UnixDatagram},
+        time::Duration,
+    };
+
+    use super::{
+        as_bytes, parse, process, receive, set_receive_timeout, Request, TcMsg, CLSACT_INGRESS,
+        NLA_F_NESTED, TCA_BPF_FD, TCA_BPF_FLAGS, TCA_BPF_FLAG_ACT_DIRECT, TCA_BPF_NAME,
+    };
+    use crate::classifier::IfIndex;
+
+    fn attr(ty: u16, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+        buf.extend_from_slice(&ty.to_ne_bytes());
+        buf.extend_from_slice(data);
+        buf.resize((buf.len() + 3) & !3, 0);
+        buf
+    }
+
+    fn message(ty: u16, payload: &[u8]) -> Vec<u8> {
+        let header = libc::nlmsghdr {
+            nlmsg_len: (mem::size_of::<libc::nlmsghdr>() + payload.len()) as u32,
+            nlmsg_type: ty,
+            nlmsg_flags: 0,
+            nlmsg_seq: 1,
+            nlmsg_pid: 0,
+        };
+        let mut buf = as_bytes(&header).to_vec();
+        buf.extend_from_slice(payload);
+        buf.resize((buf.len() + 3) & !3, 0);
+        buf
+    }
+
+    fn tcmsg(priority: u16, handle: u32) -> Vec<u8> {
+        as_bytes(&TcMsg {
+            family: 0,
+            pad1: 0,
+            pad2: 0,
+            ifindex: 3,
+            handle,
+            parent: CLSACT_INGRESS,
+            info: (priority as u32) << 16,
+        })
+        .to_vec()
+    }
+
+    fn ack(error: i32) -> (u16, Vec<u8>) {
+        (libc::NLMSG_ERROR as u16, error.to_ne_bytes().to_vec())
+    }
+
+    #[test]
+    fn attach_request_layout() {
+        let mut request = Request::new(
+            libc::RTM_NEWTFILTER,
+            libc::NLM_F_CREATE | libc::NLM_F_EXCL | libc::NLM_F_ECHO,
+            IfIndex(3),
+            49152,
+            1,
+        );
+        request.attr(libc::TCA_KIND, b"bpf\0");
+        let nested = request.nested_start(libc::TCA_OPTIONS);
+        request.attr(TCA_BPF_FD, &7u32.to_ne_bytes());
+        request.attr(TCA_BPF_NAME, b"fw\0");
+        request.attr(TCA_BPF_FLAGS, &TCA_BPF_FLAG_ACT_DIRECT.to_ne_bytes());
+        request.nested_end(nested);
+        let buf = request.finish();
+
+        let mut expected = Vec::new();
+        // nlmsghdr: length, type, request | ack | create | excl | echo, sequence and port
+        expected.extend_from_slice(&72u32.to_ne_bytes());
+        expected.extend_from_slice(&libc::RTM_NEWTFILTER.to_ne_bytes());
+        expected.extend_from_slice(&0x060Du16.to_ne_bytes());
+        expected.extend_from_slice(&1u32.to_ne_bytes());
+        expected.extend_from_slice(&0u32.to_ne_bytes());
+        // tcmsg: AF_UNSPEC and padding, ifindex, handle, parent and priority with ETH_P_ALL in network order
+        expected.extend_from_slice(&[0, 0, 0, 0]);
+        expected.extend_from_slice(&3i32.to_ne_bytes());
+        expected.extend_from_slice(&1u32.to_ne_bytes());
+        expected.extend_from_slice(&0xFFFF_FFF2u32.to_ne_bytes());
+        expected.extend_from_slice(&(0xC000_0000 | u32::from(0x0003u16.to_be())).to_ne_bytes());
+        expected.extend(attr(libc::TCA_KIND, b"bpf\0"));
+        // The nested header covers its attributes, the name is padded to 4 bytes
+        expected.extend_from_slice(&28u16.to_ne_bytes());
+        expected.extend_from_slice(&(libc::TCA_OPTIONS | NLA_F_NESTED).to_ne_bytes());
+        expected.extend(attr(TCA_BPF_FD, &7u32.to_ne_bytes()));
+        expected.extend(attr(TCA_BPF_NAME, b"fw\0"));
+        expected.extend(attr(TCA_BPF_FLAGS, &1u32.to_ne_bytes()));
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn detach_request_layout() {
+        let mut request = Request::new(libc::RTM_DELTFILTER, 0, IfIndex(3), 1, 2);
+        request.attr(libc::TCA_KIND, b"bpf\0");
+        let buf = request.finish();
+
+        assert_eq!(buf.len(), 44);
+        assert_eq!(buf[..4], 44u32.to_ne_bytes());
+        assert_eq!(buf[4..6], libc::RTM_DELTFILTER.to_ne_bytes());
+        // Request and ack only
+        assert_eq!(buf[6..8], 0x0005u16.to_ne_bytes());
+        assert_eq!(buf[24..28], 2u32.to_ne_bytes());
+        assert_eq!(buf[36..], attr(libc::TCA_KIND, b"bpf\0"));
+    }
+
+    #[test]
+    fn batch_is_split_into_messages() {
+        let mut buf = message(libc::RTM_NEWTFILTER, &tcmsg(49152, 1));
+        // Payloads that aren't a multiple of 4 bytes are padded
+        buf.extend(message(libc::RTM_NEWTFILTER, &[1, 2, 3, 4, 5]));
+        buf.extend(message(libc::NLMSG_ERROR as u16, &0i32.to_ne_bytes()));
+
+        assert_eq!(
+            parse(&buf).unwrap(),
+            vec![
+                (libc::RTM_NEWTFILTER, tcmsg(49152, 1)),
+                (libc::RTM_NEWTFILTER, vec![1, 2, 3, 4, 5]),
+                ack(0),
+            ]
+        );
+        assert!(parse(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_messages_error() {
+        let buf = message(libc::RTM_NEWTFILTER, &tcmsg(49152, 1));
+        // Header cut short
+        let err = parse(&buf[..10]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Payload cut short
+        let err = parse(&buf[..buf.len() - 4]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Length smaller than the header
+        let mut buf = buf;
+        buf[..4].copy_from_slice(&8u32.to_ne_bytes());
+        let err = parse(&buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn echoed_filter_is_returned_with_the_ack() {
+        let mut created = None;
+        let echo = [(libc::RTM_NEWTFILTER, tcmsg(49152, 7))];
+        assert!(process(&echo, &mut created).is_none());
+
+        let outcome = process(&[ack(0)], &mut created).unwrap();
+        assert_eq!(outcome.unwrap(), Some((49152, 7)));
+    }
+
+    #[test]
+    fn error_ack_is_returned() {
+        let mut created = None;
+        let messages = [
+            (libc::RTM_NEWTFILTER, vec![0; 4]),
+            ack(-libc::EEXIST),
+            (libc::RTM_NEWTFILTER, tcmsg(49152, 7)),
+        ];
+        let err = process(&messages, &mut created).unwrap().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+        // Short payloads are ignored and nothing is read past the ack
+        assert_eq!(created, None);
+    }
+
+    #[test]
+    fn missing_answer_times_out() {
+        let (socket, _peer) = UnixDatagram::pair().unwrap();
+        set_receive_timeout(socket.as_raw_fd(), Duration::from_millis(10)).unwrap();
+
+        let err = receive(socket.as_raw_fd()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,250 @@
+use aya::Bpf;
+use ipnet::IpNet;
+
+use crate::{
+    ruleset::{Changes, Ruleset},
+    Result, Rule,
+};
+
+#[derive(Debug, Clone)]
+pub(crate) enum Operation {
+    AddRule(Rule),
+    RemoveRule(Rule),
+    AddId(IpNet, u128),
+    RemoveId(IpNet),
+}
+
+/// A batch of changes to a [Firewall](crate::Firewall) that is applied as a whole.
+///
+/// Changes are queued and nothing is written to the eBPF maps until [Transaction::commit] is called.
+/// They are then written one after the other in the order they were queued, so packets go through
+/// the same intermediate states as when making each change on its own.
+/// All changes are validated before touching any map and if a map update still fails midway,
+/// every entry written by the transaction is restored so the firewall is left as it was before.
+///
+/// Dropping a transaction without committing discards the queued changes.
+///
+/// # Example
+/// ```no_run
+/// # use firewall::{Firewall, Rule};
+/// let mut fw = Firewall::new("eth0").unwrap();
+/// let mut tx = fw.transaction();
+/// tx.add_id("10.0.0.6/32".parse().unwrap(), 1)
+///     .add_rule(&Rule::new("10.0.1.0/24".parse().unwrap()).with_id(1))
+///     .remove_rule(&Rule::new("10.0.0.5/32".parse().unwrap()));
+/// tx.commit().unwrap();
+/// ```
+pub struct Transaction<'a> {
+    bpf: &'a mut Bpf,
+    ruleset: &'a mut Ruleset,
+    operations: Vec<Operation>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(bpf: &'a mut Bpf, ruleset: &'a mut Ruleset) -> Self {
+        Self {
+            bpf,
+            ruleset,
+            operations: Vec::new(),
+        }
+    }
+
+    /// Queues adding `rule`, see [Firewall::add_rule](crate::Firewall::add_rule).
+    pub fn add_rule(&mut self, rule: &Rule) -> &mut Self {
+        self.operations.push(Operation::AddRule(rule.clone()));
+        self
+    }
+
+    /// Queues removing `rule`, see [Firewall::remove_rule](crate::Firewall::remove_rule).
+    pub fn remove_rule(&mut self, rule: &Rule) -> &mut Self {
+        self.operations.push(Operation::RemoveRule(rule.clone()));
+        self
+    }
+
+    /// Queues associating `ip` with `id`, see [Firewall::add_id](crate::Firewall::add_id).
+    pub fn add_id(&mut self, ip: IpNet, id: u128) -> &mut Self {
+        self.operations.push(Operation::AddId(ip, id));
+        self
+    }
+
+    /// Queues removing the id of `ip`, see [Firewall::remove_id](crate::Firewall::remove_id).
+    pub fn remove_id(&mut self, ip: &IpNet) -> &mut Self {
+        self.operations.push(Operation::RemoveId(*ip));
+        self
+    }
+
+    pub(crate) fn push(&mut self, operation: Operation) -> &mut Self {
+        self.operations.push(operation);
+        self
+    }
+
+    /// Applies all queued changes in order, each one is completely written before the next one.
+    ///
+    /// On error none of the changes remain applied and the first error found is returned,
+    /// unless restoring the maps also fails in which case that error is returned instead.
+    pub fn commit(self) -> Result<()> {
+        commit_operations(self.ruleset, self.bpf, &self.operations)
+    }
+}
+
+/// Maps a [Ruleset] is written to, tests replace the ones of [Bpf].
+pub(crate) trait RulesetMaps {
+    /// See [Ruleset::write_changes].
+    fn write_changes(&mut self, ruleset: &Ruleset, changes: &Changes) -> Result<()>;
+
+    /// See [Ruleset::rollback].
+    fn rollback(&mut self, ruleset: &Ruleset, changes: &Changes) -> Result<()>;
+}
+
+impl RulesetMaps for Bpf {
+    fn write_changes(&mut self, ruleset: &Ruleset, changes: &Changes) -> Result<()> {
+        ruleset.write_changes(self, changes)
+    }
+
+    fn rollback(&mut self, ruleset: &Ruleset, changes: &Changes) -> Result<()> {
+        ruleset.rollback(self, changes)
+    }
+}
+
+fn commit_operations(
+    ruleset: &mut Ruleset,
+    maps: &mut impl RulesetMaps,
+    operations: &[Operation],
+) -> Result<()> {
+    // Changes are applied to a copy first, so the maps are only touched once all of them are valid
+    let mut validated = ruleset.clone();
+    for operation in operations {
+        validated.apply_local(operation)?;
+    }
+
+    // Writing everything as a single diff would lose the order, e.g. removals meant to go
+    // before additions, so each operation writes what it changed before the next one is applied
+    let mut next = ruleset.clone();
+    let mut written = Changes::default();
+    for operation in operations {
+        let before = next.clone();
+        next.apply_local(operation)?;
+        let changes = next.changes(&before);
+        let res = maps.write_changes(&next, &changes);
+        written.extend(changes);
+        if let Err(e) = res {
+            return maps.rollback(ruleset, &written).and(Err(e));
+        }
+    }
+    *ruleset = next;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::{commit_operations, Operation, RulesetMaps};
+    use crate::{
+        ruleset::{Changes, Ruleset},
+        Error, Result, Rule,
+    };
+
+    // Records the rules of the ruleset each write brings the maps to,
+    // once `writes_left` writes went through the next one fails
+    #[derive(Default)]
+    struct RecordingMaps {
+        writes: Vec<HashSet<Rule>>,
+        writes_left: Option<usize>,
+    }
+
+    impl RulesetMaps for RecordingMaps {
+        fn write_changes(&mut self, ruleset: &Ruleset, _: &Changes) -> Result<()> {
+            match &mut self.writes_left {
+                Some(0) => return Err(Error::MapNotFound),
+                Some(left) => *left -= 1,
+                None => {}
+            }
+            self.writes.push(ruleset.rules().into_iter().collect());
+            Ok(())
+        }
+
+        fn rollback(&mut self, ruleset: &Ruleset, _: &Changes) -> Result<()> {
+            self.writes.push(ruleset.rules().into_iter().collect());
+            Ok(())
+        }
+    }
+
+    fn rule(dest: &str) -> Rule {
+        Rule::new(dest.parse().unwrap())
+    }
+
+    fn rules(dests: &[&str]) -> HashSet<Rule> {
+        dests.iter().map(|dest| rule(dest)).collect()
+    }
+
+    #[test]
+    fn operations_are_written_in_order() {
+        let mut ruleset = Ruleset::new(0).unwrap();
+        ruleset
+            .apply_local(&Operation::AddRule(rule("10.0.0.1/32")))
+            .unwrap();
+        let mut maps = RecordingMaps::default();
+        let operations = [
+            Operation::RemoveRule(rule("10.0.0.1/32")),
+            Operation::AddRule(rule("10.0.0.2/32")),
+            Operation::AddRule(rule("10.0.0.3/32")),
+        ];
+        commit_operations(&mut ruleset, &mut maps, &operations).unwrap();
+
+        assert_eq!(
+            maps.writes,
+            vec![
+                rules(&[]),
+                rules(&["10.0.0.2/32"]),
+                rules(&["10.0.0.2/32", "10.0.0.3/32"]),
+            ]
+        );
+        assert_eq!(
+            ruleset.rules().into_iter().collect::<HashSet<_>>(),
+            rules(&["10.0.0.2/32", "10.0.0.3/32"])
+        );
+    }
+
+    #[test]
+    fn failed_write_rolls_back_every_operation() {
+        let mut ruleset = Ruleset::new(0).unwrap();
+        ruleset
+            .apply_local(&Operation::AddRule(rule("10.0.0.1/32")))
+            .unwrap();
+        let mut maps = RecordingMaps {
+            writes_left: Some(1),
+            ..Default::default()
+        };
+        let operations = [
+            Operation::AddRule(rule("10.0.0.2/32")),
+            Operation::RemoveRule(rule("10.0.0.1/32")),
+        ];
+        assert!(commit_operations(&mut ruleset, &mut maps, &operations).is_err());
+
+        // The first operation was written, then the ruleset as it was is written back
+        assert_eq!(
+            maps.writes,
+            vec![
+                rules(&["10.0.0.1/32", "10.0.0.2/32"]),
+                rules(&["10.0.0.1/32"])
+            ]
+        );
+        assert_eq!(
+            ruleset.rules().into_iter().collect::<HashSet<_>>(),
+            rules(&["10.0.0.1/32"])
+        );
+    }
+
+    #[test]
+    fn invalid_operation_writes_nothing() {
+        let mut ruleset = Ruleset::new(0).unwrap();
+        let mut maps = RecordingMaps::default();
+        let operations = [
+            Operation::AddRule(rule("10.0.0.2/32")),
+            Operation::AddId("10.0.0.2/32".parse().unwrap(), 0),
+        ];
+        assert!(commit_operations(&mut ruleset, &mut maps, &operations).is_err());
+        assert!(maps.writes.is_empty());
+    }
+}
@@ -0,0 +1,349 @@
+//! Manages a pinned firewall, every invocation opens the firewall pinned under `--pin-path`.
+//!
+//! ```sh
+//! fwctl attach --iface eth0
+//! fwctl default-action reject
+//! fwctl id add 10.0.0.5/32 1
+//! fwctl rule add tcp 10.0.1.0/24 ports 80,443 id 1
+//! fwctl rule list
+//! fwctl log --pcap firewall.pcapng
+//! fwctl detach
+//! ```
+use std::{fs::File, io::BufWriter, path::PathBuf, process::exit};
+
+use anyhow::Context;
+use clap::{Parser, Subcommand, ValueEnum};
+use firewall::{Action, AttachOptions, Firewall, PcapngSink, Rule, DEFAULT_PIN_PATH};
+use ipnet::IpNet;
+use tokio::signal;
+
+#[derive(Debug, Parser)]
+#[command(about = "Manages a pinned eBPF firewall")]
+pub struct Options {
+    /// Path in bpffs where the firewall is pinned.
+    #[arg(long, default_value = DEFAULT_PIN_PATH)]
+    pin_path: PathBuf,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Attaches a new firewall to an interface and pins it.
+    Attach {
+        #[arg(short, long)]
+        iface: String,
+        /// Priority of the tc filter, 0 lets the kernel pick one.
+        #[arg(long, default_value_t = 0)]
+        priority: u16,
+        /// Handle of the tc filter, 0 lets the kernel pick one.
+        #[arg(long, default_value_t = 0)]
+        handle: u32,
+    },
+    /// Detaches the firewall from its interface and removes the pins.
+    Detach,
+    /// Sets the action for packets that don't match any rule.
+    DefaultAction { action: DefaultAction },
+    /// Adds, removes or lists rules.
+    #[command(subcommand)]
+    Rule(RuleCommand),
+    /// Adds, removes or lists ids associated with IPs.
+    #[command(subcommand)]
+    Id(IdCommand),
+    /// Prints the packet log until interrupted.
+    Log {
+        /// Also writes the first bytes of each packet to this PCAPNG file.
+        #[arg(long)]
+        pcap: Option<PathBuf>,
+        /// Bytes captured from each packet with `--pcap`.
+        #[arg(long, default_value_t = 256)]
+        capture_len: u16,
+    },
+    /// Prints the packet counters.
+    Counters,
+}
+
+#[derive(Debug, Subcommand)]
+enum RuleCommand {
+    /// Adds a rule, e.g. `tcp 10.0.0.0/8 ports 80,443 id 42`.
+    Add {
+        #[arg(required = true, num_args = 1..)]
+        rule: Vec<String>,
+    },
+    /// Removes a rule, written as it was added.
+    Remove {
+        #[arg(required = true, num_args = 1..)]
+        rule: Vec<String>,
+    },
+    /// Lists the installed rules.
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+enum IdCommand {
+    /// Associates an IP with an id.
+    Add { ip: IpNet, id: u128 },
+    /// Removes the id of an IP.
+    Remove { ip: IpNet },
+    /// Lists ids and their IPs.
+    List,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum DefaultAction {
+    Accept,
+    Reject,
+}
+
+impl From<DefaultAction> for Action {
+    fn from(action: DefaultAction) -> Self {
+        match action {
+            DefaultAction::Accept => Action::Accept,
+            DefaultAction::Reject => Action::Reject,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let opts = Options::parse();
+
+    if let Err(e) = run(opts).await {
+        eprintln!("{:#}", e);
+        exit(1);
+    }
+}
+
+async fn run(opts: Options) -> anyhow::Result<()> {
+    if let Command::Attach {
+        iface,
+        priority,
+        handle,
+    } = &opts.command
+    {
+        let options = AttachOptions {
+            priority: *priority,
+            handle: *handle,
+        };
+        Firewall::new_pinned_with_options(iface, &opts.pin_path, options)
+            .with_context(|| format!("Couldn't attach to {iface}"))?;
+        return Ok(());
+    }
+
+    let mut fw = Firewall::open_pinned(&opts.pin_path).with_context(|| {
+        format!(
+            "Couldn't open the firewall pinned at {}",
+            opts.pin_path.display()
+        )
+    })?;
+    match opts.command {
+        Command::Attach { .. } => unreachable!("handled above"),
+        Command::Detach => fw.detach()?,
+        Command::DefaultAction { action } => fw.set_default_action(action.into())?,
+        Command::Rule(RuleCommand::Add { rule }) => fw.add_rule(&parse_rule(&rule)?)?,
+        Command::Rule(RuleCommand::Remove { rule }) => fw.remove_rule(&parse_rule(&rule)?)?,
+        Command::Rule(RuleCommand::List) => {
+            for rule in fw.rules() {
+                println!("{rule}");
+            }
+        }
+        Command::Id(IdCommand::Add { ip, id }) => fw.add_id(ip, id)?,
+        Command::Id(IdCommand::Remove { ip }) => fw.remove_id(&ip)?,
+        Command::Id(IdCommand::List) => {
+            for id in fw.ids() {
+                let ips: Vec<_> = fw.ips_for_id(id).iter().map(ToString::to_string).collect();
+                println!("{id}\t{}", ips.join(" "));
+            }
+        }
+        Command::Log { pcap, capture_len } => {
+            tracing_subscriber::fmt::init();
+            fw.start_logging()?;
+            if let Some(path) = &pcap {
+                let file = File::create(path)
+                    .with_context(|| format!("Couldn't create {}", path.display()))?;
+                fw.add_packet_sink(PcapngSink::new(BufWriter::new(file))?)?;
+                fw.set_capture_length(capture_len)?;
+            }
+            signal::ctrl_c().await?;
+            // The setting outlives this process, nothing reads the bytes anymore
+            if pcap.is_some() {
+                fw.set_capture_length(0)?;
+            }
+        }
+        Command::Counters => {
+            let counters = fw.counters()?;
+            println!("accepted\t{}", counters.accepted);
+            println!("dropped_by_rule\t{}", counters.dropped_by_rule);
+            println!("dropped_by_default\t{}", counters.dropped_by_default);
+            println!("dropped_by_list\t{}", counters.dropped_by_list);
+            println!("parse_failures\t{}", counters.parse_failures);
+            println!("non_ip\t{}", counters.non_ip);
+            println!("ipv4\t{}", counters.ipv4);
+            println!("ipv6\t{}", counters.ipv6);
+        }
+    }
+    Ok(())
+}
+
+// Rules are taken as separate arguments so they don't need quoting
+fn parse_rule(words: &[String]) -> anyhow::Result<Rule> {
+    let rule = words.join(" ");
+    rule.parse()
+        .with_context(|| format!("Couldn't parse rule `{rule}`"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use clap::{CommandFactory, Parser};
+    use firewall::{Protocol, Rule, DEFAULT_PIN_PATH};
+    use ipnet::IpNet;
+
+    use super::{parse_rule, Command, DefaultAction, IdCommand, Options, RuleCommand};
+
+    fn try_parse(args: &[&str]) -> Result<Options, clap::Error> {
+        Options::try_parse_from(std::iter::once("fwctl").chain(args.iter().copied()))
+    }
+
+    fn parse(args: &[&str]) -> Command {
+        let opts = try_parse(args).unwrap();
+        assert_eq!(opts.pin_path, Path::new(DEFAULT_PIN_PATH));
+        opts.command
+    }
+
+    fn fails(args: &[&str]) -> bool {
+        try_parse(args).is_err()
+    }
+
+    #[test]
+    fn options_are_consistent() {
+        Options::command().debug_assert();
+    }
+
+    #[test]
+    fn pin_path_comes_before_the_command() {
+        let opts = try_parse(&["--pin-path", "/sys/fs/bpf/fw", "detach"]);
+        assert_eq!(opts.unwrap().pin_path, Path::new("/sys/fs/bpf/fw"));
+    }
+
+    #[test]
+    fn attach_parses_the_filter_options() {
+        assert!(matches!(
+            parse(&["attach", "--iface", "eth0"]),
+            Command::Attach { iface, priority: 0, handle: 0 } if iface == "eth0"
+        ));
+        assert!(matches!(
+            parse(&["attach", "-i", "eth1", "--priority", "10", "--handle", "7"]),
+            Command::Attach { iface, priority: 10, handle: 7 } if iface == "eth1"
+        ));
+        assert!(fails(&["attach"]));
+        assert!(fails(&["attach", "-i", "eth0", "--priority", "70000"]));
+    }
+
+    #[test]
+    fn commands_without_arguments_parse() {
+        assert!(matches!(parse(&["detach"]), Command::Detach));
+        assert!(matches!(parse(&["counters"]), Command::Counters));
+        assert!(matches!(
+            parse(&["rule", "list"]),
+            Command::Rule(RuleCommand::List)
+        ));
+        assert!(matches!(
+            parse(&["id", "list"]),
+            Command::Id(IdCommand::List)
+        ));
+        assert!(fails(&[]));
+        assert!(fails(&["rule"]));
+    }
+
+    #[test]
+    fn default_action_takes_accept_or_reject() {
+        assert!(matches!(
+            parse(&["default-action", "accept"]),
+            Command::DefaultAction {
+                action: DefaultAction::Accept
+            }
+        ));
+        assert!(matches!(
+            parse(&["default-action", "reject"]),
+            Command::DefaultAction {
+                action: DefaultAction::Reject
+            }
+        ));
+        assert!(fails(&["default-action", "drop"]));
+    }
+
+    #[test]
+    fn rules_are_taken_as_separate_words() {
+        let Command::Rule(RuleCommand::Add { rule }) = parse(&[
+            "rule",
+            "add",
+            "tcp",
+            "10.0.0.0/8",
+            "ports",
+            "80,443",
+            "id",
+            "42",
+        ]) else {
+            panic!("expected rule add");
+        };
+        assert_eq!(
+            parse_rule(&rule).unwrap(),
+            Rule::new("10.0.0.0/8".parse().unwrap())
+                .with_ranges([80..=80, 443..=443], Protocol::TCP)
+                .with_id(42)
+        );
+
+        let Command::Rule(RuleCommand::Remove { rule }) =
+            parse(&["rule", "remove", "udp 10.0.0.0/8 ports 53"])
+        else {
+            panic!("expected rule remove");
+        };
+        assert_eq!(
+            parse_rule(&rule).unwrap(),
+            Rule::new("10.0.0.0/8".parse().unwrap()).with_range(53..=53, Protocol::UDP)
+        );
+
+        assert!(fails(&["rule", "add"]));
+        let Command::Rule(RuleCommand::Add { rule }) = parse(&["rule", "add", "10.0.0.0/8", "id"])
+        else {
+            panic!("expected rule add");
+        };
+        assert!(parse_rule(&rule).is_err());
+    }
+
+    #[test]
+    fn id_commands_parse_ips_and_ids() {
+        assert!(matches!(
+            parse(&["id", "add", "10.0.0.5/32", "1"]),
+            Command::Id(IdCommand::Add { ip, id: 1 }) if ip == "10.0.0.5/32".parse::<IpNet>().unwrap()
+        ));
+        assert!(matches!(
+            parse(&["id", "remove", "fafa::1/128"]),
+            Command::Id(IdCommand::Remove { ip }) if ip == "fafa::1/128".parse::<IpNet>().unwrap()
+        ));
+        assert!(fails(&["id", "add", "10.0.0.5", "1"]));
+        assert!(fails(&["id", "add", "10.0.0.5/32", "-1"]));
+    }
+
+    #[test]
+    fn log_parses_the_capture_options() {
+        assert!(matches!(
+            parse(&["log"]),
+            Command::Log {
+                pcap: None,
+                capture_len: 256
+            }
+        ));
+        assert!(matches!(
+            parse(&["log", "--pcap", "fw.pcapng", "--capture-len", "64"]),
+            Command::Log {
+                pcap: Some(path),
+                capture_len: 64
+            } if path == PathBuf::from("fw.pcapng")
+        ));
+        assert!(fails(&["log", "--capture-len", "70000"]));
+        assert!(fails(&["log", "--pcap"]));
+    }
+}